@@ -25,7 +25,7 @@ pub fn read_csv_file() -> Result<Vec<(String, String)>, Box<dyn Error>> {
     Ok(records)
 }
 
-fn storage_write(test_data: &Vec<(String, String)>) -> Arc<Storage> {
+fn storage_write(test_data: &[(String, String)]) -> Arc<Storage> {
     let test_size = test_data.len(); // Number of entries to insert and retrieve
     let threads = THREADS; // Example: Using 4 threads, adjust as needed
 
@@ -34,19 +34,24 @@ fn storage_write(test_data: &Vec<(String, String)>) -> Arc<Storage> {
     test_data.par_chunks(test_size / threads).for_each(|chunk| {
         let map = Arc::clone(&sharded_map);
         chunk.iter().for_each(|(key, value)| {
-            map.set_kv(key, value, Duration::from_millis(600));
+            map.set_kv(
+                key.as_bytes(),
+                value.as_bytes(),
+                Some(Duration::from_millis(600)),
+            )
+            .unwrap();
         });
     });
     sharded_map
 }
 
-fn storage_read(test_data: &Vec<(String, String)>, map: &Arc<Storage>) {
+fn storage_read(test_data: &[(String, String)], map: &Arc<Storage>) {
     let test_size = test_data.len();
     let threads = THREADS;
     test_data.par_chunks(test_size / threads).for_each(|chunk| {
         let map = Arc::clone(map);
         chunk.iter().for_each(|(key, _)| {
-            map.get_v(key);
+            let _ = map.get_v(key.as_bytes());
         });
     });
 }
@@ -62,6 +67,64 @@ fn criterion_benchmark(c: &mut Criterion) {
     });
 }
 
+// naive_append appends by reading the value, concatenating and storing the result, which copies
+// the whole value on every append.
+fn naive_append(storage: &Storage, key: &[u8], chunk: &[u8]) {
+    let mut value = storage.get_v(key).unwrap().unwrap_or_default().to_vec();
+    value.extend_from_slice(chunk);
+    storage.set_kv(key, &value, None).unwrap();
+}
+
+fn append_benchmark(c: &mut Criterion) {
+    const CHUNKS: usize = 10_000;
+    let chunk = b"a log line of a few bytes\n";
+    c.bench_function("append in place", |b| {
+        b.iter(|| {
+            let storage = Storage::new(10, 1);
+            for _ in 0..CHUNKS {
+                storage.append(b"log", black_box(chunk)).unwrap();
+            }
+        })
+    });
+    c.bench_function("append by concatenation", |b| {
+        b.iter(|| {
+            let storage = Storage::new(10, 1);
+            for _ in 0..CHUNKS {
+                naive_append(&storage, b"log", black_box(chunk));
+            }
+        })
+    });
+}
+
+// large_value_benchmark reads a large value over and over, which only clones a reference to it.
+fn large_value_benchmark(c: &mut Criterion) {
+    let storage = Arc::new(Storage::new(10, 1));
+    storage
+        .set_kv(b"large", &vec![b'x'; 1 << 20], None)
+        .unwrap();
+    c.bench_function("large value read", |b| {
+        b.iter(|| {
+            (0..THREADS).into_par_iter().for_each(|_| {
+                for _ in 0..1000 {
+                    black_box(storage.get_v(black_box(b"large")).unwrap());
+                }
+            });
+        })
+    });
+}
+
+criterion_group!(
+    name = large_value;
+    config = Criterion::default().sample_size(10);
+    targets = large_value_benchmark
+);
+
+criterion_group!(
+    name = append;
+    config = Criterion::default().sample_size(10);
+    targets = append_benchmark
+);
+
 criterion_group!(
     name = benches;
     config = Criterion::default()
@@ -70,4 +133,4 @@ criterion_group!(
     targets = criterion_benchmark
 );
 
-criterion_main!(benches);
+criterion_main!(append, large_value, benches);
@@ -0,0 +1,260 @@
+//! The append-only file (AOF) makes the data survive restarts. Every write command applied by the
+//! clients is appended to the file, in RESP, once it applied. On startup, the file is replayed
+//! through the same decoding and dispatch as the commands of a client, which rebuilds the
+//! databases.
+//!
+//! A relative expiry is appended as a deadline, so `SET k v PX 10000` is appended as
+//! `SET k v PXAT <unix time>` and `EXPIRE` as `PEXPIREAT`: a key replayed after its deadline is
+//! gone, rather than given its whole TTL again.
+
+use crate::config::AppendFsync;
+use crate::db::Storage;
+use crate::metrics::Metrics;
+use crate::parser::{DecodeLimits, Frame, Parser, PropagatedWrite};
+use std::future::Future;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{self, AsyncWriteExt, BufReader, BufWriter};
+use tokio::sync::mpsc;
+use tracing::{debug, info};
+
+// Size of the buffers of the file, for appending and replaying.
+const AOF_BUFFER_SIZE: usize = 64 * 1024;
+
+/// open_aof opens the append-only file at `path` for appending, creating it if needed.
+pub async fn open_aof(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+}
+
+/// write_aof appends the writes received on `writes` to `file`, until every sender is gone or
+/// `stop` completes, which is how the server shuts it down. The writes received together are
+/// written in one go, then synced to the disk as `fsync` requires. Each write is preceded by a
+/// SELECT when it applies to another database than the previous one. When it stops, everything
+/// received is written and synced, whatever `fsync` is, so that no acknowledged write is lost.
+pub async fn write_aof(
+    file: File,
+    mut writes: mpsc::UnboundedReceiver<PropagatedWrite>,
+    fsync: AppendFsync,
+    stop: impl Future<Output = ()>,
+) -> io::Result<()> {
+    tokio::pin!(stop);
+    let mut file = BufWriter::with_capacity(AOF_BUFFER_SIZE, file);
+    // the database of the replayed commands is 0 until the file selects another one
+    let mut db = 0;
+    // whether some writes were flushed but not synced yet
+    let mut unsynced = false;
+    let mut ticks = tokio::time::interval(Duration::from_secs(1));
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut stopped = false;
+    loop {
+        let write = tokio::select! {
+            write = writes.recv() => write,
+            _ = &mut stop => {
+                while let Ok(write) = writes.try_recv() {
+                    append(&mut file, &mut db, &write).await?;
+                }
+                stopped = true;
+                break;
+            }
+            _ = ticks.tick() => {
+                if unsynced && fsync == AppendFsync::Everysec {
+                    file.get_ref().sync_data().await?;
+                    unsynced = false;
+                }
+                continue;
+            }
+        };
+        let Some(write) = write else {
+            break;
+        };
+        append(&mut file, &mut db, &write).await?;
+        while let Ok(write) = writes.try_recv() {
+            append(&mut file, &mut db, &write).await?;
+        }
+        file.flush().await?;
+        if fsync == AppendFsync::Always {
+            file.get_ref().sync_data().await?;
+        } else {
+            unsynced = true;
+        }
+    }
+    file.flush().await?;
+    if fsync != AppendFsync::No || stopped {
+        file.get_ref().sync_data().await?;
+    }
+    debug!("stop appending to the AOF");
+    Ok(())
+}
+
+// append writes `write` to the file, after a SELECT if it applies to another database than `db`,
+// which is then updated.
+async fn append(
+    file: &mut BufWriter<File>,
+    db: &mut usize,
+    write: &PropagatedWrite,
+) -> io::Result<()> {
+    if write.db != *db {
+        let select = Frame::command(&["SELECT", &write.db.to_string()]);
+        file.write_all(&select.encode_to_vec()).await?;
+        *db = write.db;
+    }
+    file.write_all(&write.command).await
+}
+
+/// replay_aof applies the commands of the append-only file at `path` to `databases`, like if a
+/// client sent them. A missing file is an empty one. A command cut short at the end of the file,
+/// by a crash while it was appended, is ignored.
+pub async fn replay_aof(
+    path: &Path,
+    databases: Arc<[Arc<Storage>]>,
+    limits: DecodeLimits,
+) -> io::Result<()> {
+    let file = match File::open(path).await {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    let reader = BufReader::with_capacity(AOF_BUFFER_SIZE, file);
+    // the replies are not needed, they are dropped
+    let stream = io::join(reader, io::sink());
+    let mut parser = Parser::new(
+        stream,
+        databases[0].clone(),
+        Arc::new(Metrics::default()),
+        AOF_BUFFER_SIZE,
+        limits,
+    )
+    .with_databases(databases.clone());
+    parser.process_frames().await;
+    let keys: usize = databases.iter().map(|storage| storage.len()).sum();
+    info!("replayed the AOF {}, {} keys loaded", path.display(), keys);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn replay_test() {
+        let path = std::env::temp_dir().join(format!("mredis-replay-{}.aof", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let (sink, writes) = mpsc::unbounded_channel();
+        let writer = tokio::spawn(write_aof(
+            open_aof(&path).await.unwrap(),
+            writes,
+            AppendFsync::Always,
+            std::future::pending(),
+        ));
+        let commands: &[(usize, &[u8])] = &[
+            (0, b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n"),
+            (0, b"*3\r\n$3\r\nSET\r\n$1\r\nb\r\n$1\r\n2\r\n"),
+            (1, b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$3\r\none\r\n"),
+            (0, b"*2\r\n$3\r\nDEL\r\n$1\r\nb\r\n"),
+            (0, b"*3\r\n$6\r\nAPPEND\r\n$1\r\na\r\n$1\r\n0\r\n"),
+        ];
+        for (db, command) in commands {
+            let write = PropagatedWrite {
+                db: *db,
+                command: command.to_vec(),
+            };
+            sink.send(write).unwrap();
+        }
+        drop(sink);
+        writer.await.unwrap().unwrap();
+
+        // a crash while appending leaves a command cut short
+        let mut file = open_aof(&path).await.unwrap();
+        file.write_all(b"*3\r\n$3\r\nSET\r\n$1\r\nc").await.unwrap();
+        file.flush().await.unwrap();
+        drop(file);
+
+        let databases: Arc<[Arc<Storage>]> =
+            (0..2).map(|_| Arc::new(Storage::new(100, 4))).collect();
+        replay_aof(&path, databases.clone(), DecodeLimits::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            databases[0].get_v(b"a"),
+            Ok(Some(Bytes::from_static(b"10")))
+        );
+        assert_eq!(databases[0].get_v(b"b"), Ok(None), "b was deleted");
+        assert_eq!(databases[0].get_v(b"c"), Ok(None));
+        assert_eq!(
+            databases[1].get_v(b"a"),
+            Ok(Some(Bytes::from_static(b"one")))
+        );
+        assert_eq!((databases[0].len(), databases[1].len()), (1, 1));
+        std::fs::remove_file(&path).unwrap();
+
+        let missing = std::env::temp_dir().join("mredis-missing.aof");
+        replay_aof(&missing, databases, DecodeLimits::default())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn replay_expired_test() {
+        let path = std::env::temp_dir().join(format!("mredis-expired-{}.aof", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        // the writes are fed by a client, which makes their expiries absolute
+        let (sink, writes) = mpsc::unbounded_channel();
+        let writer = tokio::spawn(write_aof(
+            open_aof(&path).await.unwrap(),
+            writes,
+            AppendFsync::Always,
+            std::future::pending(),
+        ));
+        let (mut client, server) = io::duplex(1024);
+        let mut parser = Parser::new(
+            server,
+            Arc::new(Storage::new(100, 4)),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        )
+        .with_write_sink(Some(sink));
+        let client_task = tokio::spawn(async move { parser.process_frames().await });
+        let requests: &[&[&str]] = &[
+            &["SET", "short", "1", "PX", "50"],
+            &["SET", "long", "1", "PX", "60000"],
+            &["SET", "expired", "1"],
+            &["PEXPIRE", "expired", "50"],
+            &["SET", "kept", "1"],
+        ];
+        for request in requests {
+            client
+                .write_all(&Frame::command(request).encode_to_vec())
+                .await
+                .unwrap();
+        }
+        let mut replies = vec![0; b"+OK\r\n+OK\r\n+OK\r\n:1\r\n+OK\r\n".len()];
+        client.read_exact(&mut replies).await.unwrap();
+        drop(client);
+        client_task.await.unwrap();
+        writer.await.unwrap().unwrap();
+
+        // the server restarts once the short expiries passed
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let databases: Arc<[Arc<Storage>]> = [Arc::new(Storage::new(100, 4))].into();
+        replay_aof(&path, databases.clone(), DecodeLimits::default())
+            .await
+            .unwrap();
+        let one = Ok(Some(Bytes::from_static(b"1")));
+        assert_eq!(databases[0].get_v(b"short"), Ok(None));
+        assert_eq!(databases[0].get_v(b"expired"), Ok(None));
+        assert_eq!(databases[0].get_v(b"long"), one);
+        assert_eq!(databases[0].get_v(b"kept"), one);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
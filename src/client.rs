@@ -0,0 +1,126 @@
+//! A minimal client, to talk to a server from tests or tools without another crate. Requests are
+//! built with `Frame::command` and the replies read with `decode`. An error reply of the server is
+//! returned as an `io::Error` of kind `Other`, holding the message.
+
+use crate::parser::{decode, DecodeLimits, Frame, FrameData, FrameID};
+use tokio::io::{self, AsyncWriteExt, BufStream};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+/// Client is a connection to a server. The commands are sent one at a time, each waits for its
+/// reply.
+pub struct Client {
+    stream: BufStream<TcpStream>,
+}
+
+impl Client {
+    /// connect opens a connection to the server at `addr`.
+    pub async fn connect(addr: impl ToSocketAddrs) -> io::Result<Client> {
+        let stream = TcpStream::connect(addr).await?;
+        Ok(Client {
+            stream: BufStream::new(stream),
+        })
+    }
+
+    /// ping checks that the server answers.
+    pub async fn ping(&mut self) -> io::Result<()> {
+        match self.request(&["PING"]).await? {
+            Frame {
+                frame_data: FrameData::Simple(pong),
+                ..
+            } if pong == "PONG" => Ok(()),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// get returns the string stored at key, None if there is none.
+    pub async fn get(&mut self, key: impl AsRef<[u8]>) -> io::Result<Option<String>> {
+        let reply = self.request(&[b"GET".as_slice(), key.as_ref()]).await?;
+        if reply.frame_type == FrameID::Null {
+            return Ok(None);
+        }
+        let value = reply.get_bulk().ok_or_else(|| unexpected(reply.clone()))?;
+        String::from_utf8(value.to_vec())
+            .map(Some)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    /// set stores value at key.
+    pub async fn set(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> io::Result<()> {
+        let reply = self
+            .request(&[b"SET".as_slice(), key.as_ref(), value.as_ref()])
+            .await?;
+        match reply.frame_data {
+            FrameData::Simple(ok) if ok == "OK" => Ok(()),
+            _ => Err(unexpected(reply)),
+        }
+    }
+
+    /// del removes the keys, and returns how many of them existed.
+    pub async fn del<K: AsRef<[u8]>>(&mut self, keys: &[K]) -> io::Result<i64> {
+        let mut args = vec![b"DEL".as_slice()];
+        args.extend(keys.iter().map(AsRef::as_ref));
+        match self.request(&args).await? {
+            Frame {
+                frame_data: FrameData::Integer(count),
+                ..
+            } => Ok(count),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    // request sends a command and returns its reply. An error reply is returned as an error.
+    async fn request<S: AsRef<[u8]>>(&mut self, args: &[S]) -> io::Result<Frame> {
+        self.stream
+            .write_all(&Frame::command(args).encode_to_vec())
+            .await?;
+        self.stream.flush().await?;
+        let reply = decode(&mut self.stream, DecodeLimits::default())
+            .await
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+        match (reply.frame_type, &reply.frame_data) {
+            (FrameID::SimpleError, FrameData::Simple(message)) => {
+                Err(io::Error::other(message.clone()))
+            }
+            _ => Ok(reply),
+        }
+    }
+}
+
+// unexpected returns the error of a reply which does not have the type the command replies with.
+fn unexpected(reply: Frame) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unexpected reply: {:?}", reply),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::server::Server;
+    use clap::Parser as _;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn round_trip_test() {
+        let cfg = Config::parse_from(["mredis", "--port", "0"]);
+        let server = Arc::new(Server::new(&cfg).await);
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move { server.listen().await });
+
+        let mut client = Client::connect(addr).await.unwrap();
+        client.ping().await.unwrap();
+        assert_eq!(client.get("key").await.unwrap(), None);
+        client.set("key", "value").await.unwrap();
+        assert_eq!(client.get("key").await.unwrap(), Some("value".to_string()));
+        assert_eq!(client.del(&["key", "missing"]).await.unwrap(), 1);
+        assert_eq!(client.get("key").await.unwrap(), None);
+
+        // an error reply is an error
+        client.request(&["LPUSH", "list", "a"]).await.unwrap();
+        let err = client.get("list").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert!(err.to_string().starts_with("WRONGTYPE"), "{}", err);
+    }
+}
@@ -1,6 +1,6 @@
 use clap::{Parser, ValueEnum};
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(name = "mredis")]
 #[command(version = "0.1.0")]
 #[command(about = "Simple distributed cache server", long_about = None)]
@@ -9,15 +9,26 @@ pub struct Config {
     #[clap(name = "address", long, short, default_value = "127.0.0.1")]
     pub ip_addr: String,
 
-    /// Port to listen on.
+    /// Port to listen on. With 0, the system picks a free port, which the server logs once it
+    /// listens.
     #[clap(long, short, default_value = "6379")]
     pub port: u16,
 
-    /// Pre-allocated storage capacity.
+    /// Port of the HTTP health endpoint, `GET /health`, for liveness probes. It listens on the
+    /// same address as the server. The endpoint is not started when unset.
+    #[clap(long)]
+    pub health_port: Option<u16>,
+
+    /// Maximum number of keys, adding a key beyond it evicts another one.
     #[clap(long, short, default_value = "1000000")]
     pub capacity: usize,
 
-    /// Number of storage shards.
+    /// Number of logical databases, clients switch between them with SELECT. The capacity and the
+    /// memory limit apply to each of them.
+    #[clap(long, default_value = "16")]
+    pub databases: usize,
+
+    /// Number of storage shards, rounded up to the next power of two.
     #[clap(name = "shard", long, short, default_value = "8")]
     pub shard_count: usize,
 
@@ -25,15 +36,142 @@ pub struct Config {
     #[clap(name = "buffer", long, short, default_value = "8192")]
     pub network_buffer_size: usize,
 
+    /// Maximum length of a bulk string sent by a client, in bytes.
+    #[clap(long, default_value = "536870912")]
+    pub max_bulk_len: usize,
+
+    /// Maximum number of elements of an array sent by a client.
+    #[clap(long, default_value = "1048576")]
+    pub max_array_elements: usize,
+
+    /// Maximum nesting depth of arrays sent by a client.
+    #[clap(long, default_value = "128")]
+    pub max_nesting_depth: usize,
+
+    /// Accept inline commands, sent as a plain line of text like `PING\r\n`. Load balancers often
+    /// use them for health checks.
+    #[clap(long, default_value_t = true, action = clap::ArgAction::Set)]
+    pub inline_commands: bool,
+
+    /// Maximum length of an inline command, or of a line of a RESP frame, in bytes.
+    #[clap(long, default_value = "65536")]
+    pub max_inline_size: usize,
+
+    /// Disable Nagle's algorithm on client connections, so that small replies are sent right away.
+    #[clap(long, default_value_t = true, action = clap::ArgAction::Set)]
+    pub tcp_nodelay: bool,
+
+    /// Send TCP keepalive probes after a client connection stayed silent for this many seconds,
+    /// to detect dead peers. 0 disables keepalive.
+    #[clap(long, default_value = "300")]
+    pub tcp_keepalive: u64,
+
     /// Maximum number of concurrent connections.
     #[clap(name = "limit", long, short, default_value = "250")]
     pub max_conn: usize,
 
+    /// Close the connection of a client idle for this many seconds. 0 means clients are never
+    /// closed for being idle.
+    #[clap(long, default_value = "0")]
+    pub timeout: u64,
+
+    /// Password clients must send with AUTH before running commands. No password is required
+    /// when unset.
+    #[clap(long)]
+    pub requirepass: Option<String>,
+
+    /// Certificate chain of the server, in a PEM file. When set with `tls-key`, the clients
+    /// connect with TLS.
+    #[clap(long, requires = "tls_key")]
+    pub tls_cert: Option<String>,
+
+    /// Private key of the TLS certificate, in a PEM file.
+    #[clap(long, requires = "tls_cert")]
+    pub tls_key: Option<String>,
+
+    /// Maximum memory used by the keys and values, in bytes. 0 means no limit.
+    #[clap(long, default_value = "0")]
+    pub maxmemory: usize,
+
+    /// What to do when the memory limit is reached.
+    #[clap(long, default_value_t, value_enum)]
+    pub maxmemory_policy: EvictionPolicy,
+
+    /// How often the expired keys are removed in the background, in milliseconds.
+    #[clap(long, default_value = "100", value_parser = clap::value_parser!(u64).range(1..))]
+    pub eviction_interval_ms: u64,
+
+    /// Maximum number of expired keys removed from each shard by a background pass.
+    #[clap(long, default_value = "20", value_parser = clap::value_parser!(u64).range(1..))]
+    pub eviction_sample_size: u64,
+
+    /// Directory of the snapshot and of the append-only file.
+    #[clap(long, default_value = ".")]
+    pub dir: String,
+
+    /// Name of the snapshot file, written by SAVE and BGSAVE. It is loaded on startup, unless the
+    /// append-only file is enabled, which is then loaded instead.
+    #[clap(long, default_value = "dump.rdb")]
+    pub dbfilename: String,
+
+    /// Append the write commands to a file, which is replayed on startup.
+    #[clap(long)]
+    pub appendonly: bool,
+
+    /// Name of the append-only file.
+    #[clap(long, default_value = "appendonly.aof")]
+    pub appendfilename: String,
+
+    /// When the append-only file is synced to the disk.
+    #[clap(long, default_value_t, value_enum)]
+    pub appendfsync: AppendFsync,
+
+    /// Record the commands running for at least this many microseconds in the slow log. 0 records
+    /// every command, a negative value none.
+    #[clap(long, default_value = "10000", allow_negative_numbers = true)]
+    pub slowlog_log_slower_than: i64,
+
+    /// Number of commands kept in the slow log, the oldest ones are dropped first.
+    #[clap(long, default_value = "128")]
+    pub slowlog_max_len: usize,
+
+    /// Accept the DEBUG command, which can block a connection on purpose. Meant for tests only.
+    #[clap(long)]
+    pub enable_debug_commands: bool,
+
+    /// Disable a command, like FLUSHDB, which then replies as an unknown command. Can be repeated.
+    #[clap(long, value_name = "NAME")]
+    pub disable_command: Vec<String>,
+
     /// Max log level.
     #[clap(short, long, default_value_t, value_enum)]
     pub verbosity: Verbosity,
 }
 
+/// EvictionPolicy tells what to do when the storage uses more memory than allowed.
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug, Default)]
+pub enum EvictionPolicy {
+    /// Reject the commands that could use more memory
+    #[default]
+    Noeviction,
+    /// Evict the least recently used keys among the ones with an expiration
+    VolatileLru,
+    /// Evict any key
+    AllkeysRandom,
+}
+
+/// AppendFsync tells when the append-only file is synced to the disk.
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug, Default)]
+pub enum AppendFsync {
+    /// After every batch of writes, the safest and the slowest
+    Always,
+    /// Once per second, losing at most a second of writes on a crash
+    #[default]
+    Everysec,
+    /// Whenever the operating system decides to
+    No,
+}
+
 /// Verbosity logging verbosity
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug, Default)]
 pub enum Verbosity {
@@ -1,22 +1,266 @@
 //! Sharded Map for caching
 //! This file describe a shared concurrent hashmap used as the backend storage for the cache.
-//! We do not store the state for eviction. Time-based eviction is used and we perform lazy eviction.
-//! To make reads faster, we decided to not perform lazy eviction there. But performing eviction
-//! only during sets might not be sufficient. So @TODO: implement a scheduled eviction in addition
-//! to the lazy one.
+//! Keys can have an expiry deadline. Expired keys are never returned, but they are only removed
+//! lazily: a write removes the expired keys of the shard it locks, and `purge_expired` removes the
+//! expired keys of every shard. `run_active_eviction` also removes them in the background, for
+//! the keys which are never written again. The storage also holds at most `capacity` keys, adding
+//! a key to a full storage evicts another one.
 
-use std::collections::BinaryHeap;
-use std::fmt::Debug;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+use std::fmt;
+use std::fmt::{Debug, Display, Formatter};
 use std::hash::{Hash, Hasher};
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Arc, RwLock};
-use std::time::{Duration, Instant};
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{
+    mpsc, Arc, Mutex, OnceLock, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError,
+};
+use std::time::{Duration, Instant, SystemTime};
 
-use rustc_hash::FxHashMap;
+use bytes::Bytes;
+use rustc_hash::{FxHashMap, FxHashSet, FxHasher};
+use tokio::sync::{broadcast, oneshot};
+use tracing::{debug, warn};
+
+use crate::config::EvictionPolicy;
+use crate::glob::glob_match;
+use crate::snapshot::{decode_entry, encode_end, encode_entry};
+
+/// Value is what is stored behind a key. A command only works on one kind of value and fails with
+/// `StorageError::WrongType` when the key holds another kind.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Value {
+    // Strings are shared with the readers, which get them without copying.
+    Str(Bytes),
+    Hash(FxHashMap<Vec<u8>, Vec<u8>>),
+    Set(FxHashSet<Vec<u8>>),
+    // Sorted set, stored as member to score. Members are only sorted when they are read.
+    ZSet(FxHashMap<Vec<u8>, f64>),
+    // List, from head to tail. An empty list is removed, like in Redis.
+    List(VecDeque<Vec<u8>>),
+}
+
+impl Value {
+    // mem_size approximates the memory used by the value with the length of the data it holds.
+    fn mem_size(&self) -> usize {
+        match self {
+            Value::Str(value) => value.len(),
+            Value::Hash(hash) => hash.iter().map(|(f, v)| f.len() + v.len()).sum(),
+            Value::Set(set) => set.iter().map(|member| member.len()).sum(),
+            Value::ZSet(zset) => zset.keys().map(|member| member.len() + SCORE_SIZE).sum(),
+            Value::List(list) => list.iter().map(|element| element.len()).sum(),
+        }
+    }
+}
+
+impl Value {
+    // as_str returns the string held by the value. It fails with WrongType for another kind of
+    // value, it is where the string commands check the type of the key.
+    fn as_str(&self) -> Result<&Bytes, StorageError> {
+        match self {
+            Value::Str(value) => Ok(value),
+            _ => Err(StorageError::WrongType),
+        }
+    }
+}
+
+// Memory accounted for a sorted set score.
+const SCORE_SIZE: usize = std::mem::size_of::<f64>();
+
+/// ExpiryUpdate is the change of expiry requested along with a read, by GETEX.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ExpiryUpdate {
+    // Leave the expiry as it is
+    Keep,
+    // Remove the expiry, the key no longer expires
+    Persist,
+    // Expire the key after the given time from now
+    After(Duration),
+}
+
+/// SetCondition is the condition for a conditional set to happen.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SetCondition {
+    // Only set the key if it does not exist, like SET NX
+    NotExists,
+    // Only set the key if it already exists, like SET XX
+    Exists,
+}
+
+/// RenameResult tells what a rename did.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RenameResult {
+    Renamed,
+    // The source key does not exist
+    NoSuchKey,
+    // The destination key exists and the rename was asked not to overwrite it
+    DestinationExists,
+}
+
+/// BlockingPop is the outcome of blocking_pop.
+#[derive(Debug)]
+pub enum BlockingPop {
+    // An element was popped right away, from the given key
+    Popped(Vec<u8>, Vec<u8>),
+    // The lists were all empty, the client waits for a push
+    Blocked(PopWaiter),
+}
+
+/// PopWaiter is a client blocked by blocking_pop until a push on one of its keys serves it. It
+/// must be given back to cancel_pop once it stops waiting, served or not.
+#[derive(Debug)]
+pub struct PopWaiter {
+    keys: Vec<Vec<u8>>,
+    client: Arc<BlockedClient>,
+    popped: oneshot::Receiver<(Vec<u8>, Vec<u8>)>,
+}
+
+impl PopWaiter {
+    /// popped returns the key and the element popped for the client, once a push served it.
+    pub async fn popped(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
+        (&mut self.popped).await.ok()
+    }
+}
+
+// BlockedClient is a client blocked by blocking_pop, as registered on each of its keys. The
+// first push on any of them takes the sender and sends it the popped element, so it is served
+// once.
+#[derive(Debug)]
+struct BlockedClient {
+    // whether the client pops from the head of the lists, or from their tail
+    head: bool,
+    reply: Mutex<Option<oneshot::Sender<(Vec<u8>, Vec<u8>)>>>,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum StorageError {
+    // The key exists but holds a value of another kind than the one expected by the operation
+    WrongType,
+    // The storage uses more memory than allowed and the eviction policy does not free any
+    OutOfMemory,
+    // The key holds a string which is not a 64 bits integer
+    NotInteger,
+    // An increment would overflow a 64 bits integer
+    Overflow,
+    // The key holds a string which is not a finite floating point number
+    NotFloat,
+    // A float increment would make the value infinite
+    NotFinite,
+}
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::WrongType => write!(
+                f,
+                "WRONGTYPE Operation against a key holding the wrong kind of value"
+            ),
+            StorageError::OutOfMemory => {
+                write!(f, "OOM command not allowed when used memory > 'maxmemory'.")
+            }
+            StorageError::NotInteger => write!(f, "ERR value is not an integer or out of range"),
+            StorageError::Overflow => write!(f, "ERR increment or decrement would overflow"),
+            StorageError::NotFloat => write!(f, "ERR value is not a valid float"),
+            StorageError::NotFinite => write!(f, "ERR increment would produce NaN or Infinity"),
+        }
+    }
+}
+
+/// scan_collection implements the cursor scheme used to iterate over a single collection. The
+/// elements are visited in the order of their `position`, see scan_position, and the cursor is
+/// the position to resume from. It visits the `count` elements coming next (COUNT is only a hint,
+/// like in Redis, elements sharing a position are visited together) and returns them with the
+/// next cursor, 0 once the whole collection has been visited.
+/// A position only depends on the element, not on the other elements nor on the capacity of the
+/// collection, so a full iteration returns every element present from start to end exactly once,
+/// whatever is added or removed in between. Each step goes through the whole collection though.
+fn scan_collection<T>(
+    elements: impl Iterator<Item = T>,
+    cursor: usize,
+    count: usize,
+    position: impl Fn(&T) -> usize,
+) -> (usize, Vec<T>) {
+    let mut visited: Vec<(usize, T)> = elements
+        .map(|element| (position(&element), element))
+        .filter(|(at, _)| *at >= cursor)
+        .collect();
+    let count = count.max(1);
+    let mut next = 0;
+    if visited.len() > count {
+        let (_, (last, _), _) = visited.select_nth_unstable_by_key(count - 1, |(at, _)| *at);
+        let last = *last;
+        // there is something after last, so last + 1 cannot overflow
+        if visited.iter().any(|(at, _)| *at > last) {
+            next = last + 1;
+        }
+        visited.retain(|(at, _)| *at <= last);
+    }
+    (
+        next,
+        visited.into_iter().map(|(_, element)| element).collect(),
+    )
+}
+
+/// scan_position returns the position of `element` in the iteration order of the SCAN family: a
+/// hash of the element, which is the same for the whole life of the element.
+fn scan_position(element: &[u8]) -> usize {
+    let mut hasher = FxHasher::default();
+    hasher.write(element);
+    hasher.finish() as usize
+}
+
+// splitmix64 scrambles a counter into a pseudo-random number, to pick the keys to evict.
+fn splitmix64(counter: u64) -> u64 {
+    let mut z = counter.wrapping_add(1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+// Entry is a value with its expiry deadline, None when the key does not expire.
+struct Entry {
+    value: Value,
+    expires_at: Option<Instant>,
+    // A pinned key is never evicted to make room, it can still expire.
+    pinned: bool,
+    // When the key was last read, in milliseconds since the storage was created. It is atomic
+    // so that reads can update it with only a read lock on the shard.
+    last_access: AtomicU64,
+}
+
+impl Entry {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|deadline| deadline <= now)
+    }
+}
+
+// Strings holding up to this many bytes are reported with the embstr encoding, like in Redis.
+const EMBSTR_MAX_LEN: usize = 44;
+
+// Seed of the hash picking the shard of a key. Without it, the keys of a shard would also share
+// the high bits of the hash of the shard map, which it uses to tell its entries apart.
+const SHARD_HASH_SEED: u64 = 0x9e37_79b9_7f4a_7c15;
+
+// Maximum number of expired keys a write removes, so that a write does not get slow because a lot
+// of keys expired at once.
+const LAZY_EVICTION_BATCH: usize = 16;
+
+// Number of keys of a shard considered to pick one to evict when the memory limit is reached, like
+// maxmemory-samples in Redis.
+const EVICTION_SAMPLES: usize = 5;
+
+// Number of entries of a shard an eviction walks at most to find its samples, so that a write
+// over the memory limit does not get slower as the shard grows.
+const EVICTION_MAX_STEPS: usize = 256;
 
 struct Shard {
-    storage: FxHashMap<String, String>,
-    eviction_state: BinaryHeap<(Instant, String)>,
+    storage: FxHashMap<Vec<u8>, Entry>,
+    // Expiry deadlines, the earliest first. A record gets stale when its key is removed or gets
+    // another deadline, stale records are dropped when they come up.
+    eviction_state: BinaryHeap<Reverse<(Instant, Vec<u8>)>>,
+    // Clients blocked by BLPOP or BRPOP on the keys of the shard, in the order they blocked.
+    blocked: FxHashMap<Vec<u8>, VecDeque<Arc<BlockedClient>>>,
 }
 
 impl Shard {
@@ -24,53 +268,212 @@ impl Shard {
         Shard {
             storage: FxHashMap::default(),
             eviction_state: BinaryHeap::new(),
+            blocked: FxHashMap::default(),
         }
     }
 
-    fn get_value_by_key(&self, key: &str) -> Option<&String> {
-        self.storage.get(key)
+    // get_value_by_key returns the value stored at key, None if there is none or it has expired.
+    fn get_value_by_key(&self, key: &[u8], now: Instant) -> Option<&Value> {
+        self.get_entry(key, now).map(|entry| &entry.value)
     }
 
-    // Add_or_update_kv add a new entry if it does not exist. Update the entry and return the old
-    // one if it already exists.
-    fn add_or_update_kv(&mut self, key: &str, data: &str, expiry: Instant) -> Option<String> {
-        self.eviction_state.push((expiry, key.to_string()));
-        self.storage.insert(key.to_string(), data.to_string())
+    // get_entry returns the entry of key, None if the key does not exist or expired.
+    fn get_entry(&self, key: &[u8], now: Instant) -> Option<&Entry> {
+        self.storage.get(key).filter(|entry| !entry.is_expired(now))
     }
 
-    fn del_entry(&mut self, key: &str) -> usize {
-        if self.storage.remove(key).is_some() {
-            1
-        } else {
-            0
+    // get_str returns the string stored at key, None if the key does not exist.
+    fn get_str(&self, key: &[u8], now: Instant) -> Result<Option<&Bytes>, StorageError> {
+        self.get_value_by_key(key, now)
+            .map(Value::as_str)
+            .transpose()
+    }
+
+    // get_set returns the set stored at key, None if the key does not exist.
+    fn get_set(
+        &self,
+        key: &[u8],
+        now: Instant,
+    ) -> Result<Option<&FxHashSet<Vec<u8>>>, StorageError> {
+        match self.get_value_by_key(key, now) {
+            None => Ok(None),
+            Some(Value::Set(set)) => Ok(Some(set)),
+            Some(_) => Err(StorageError::WrongType),
+        }
+    }
+
+    // get_zset returns the sorted set stored at key, None if the key does not exist.
+    fn get_zset(
+        &self,
+        key: &[u8],
+        now: Instant,
+    ) -> Result<Option<&FxHashMap<Vec<u8>, f64>>, StorageError> {
+        match self.get_value_by_key(key, now) {
+            None => Ok(None),
+            Some(Value::ZSet(zset)) => Ok(Some(zset)),
+            Some(_) => Err(StorageError::WrongType),
+        }
+    }
+
+    // get_list returns the list stored at key, None if the key does not exist.
+    fn get_list(
+        &self,
+        key: &[u8],
+        now: Instant,
+    ) -> Result<Option<&VecDeque<Vec<u8>>>, StorageError> {
+        match self.get_value_by_key(key, now) {
+            None => Ok(None),
+            Some(Value::List(list)) => Ok(Some(list)),
+            Some(_) => Err(StorageError::WrongType),
+        }
+    }
+
+    // get_hash returns the hash stored at key, None if the key does not exist.
+    fn get_hash(
+        &self,
+        key: &[u8],
+        now: Instant,
+    ) -> Result<Option<&FxHashMap<Vec<u8>, Vec<u8>>>, StorageError> {
+        match self.get_value_by_key(key, now) {
+            None => Ok(None),
+            Some(Value::Hash(hash)) => Ok(Some(hash)),
+            Some(_) => Err(StorageError::WrongType),
         }
     }
 
-    fn latest_is_expired(&self) -> bool {
-        if let Some((instant, _)) = self.eviction_state.peek() {
-            if Instant::now() > *instant {
-                return true;
+    // set_expiry changes the expiry deadline of an existing key. The eviction record of an
+    // unchanged deadline is still there, it is not pushed again.
+    fn set_expiry(&mut self, key: &[u8], expires_at: Option<Instant>) {
+        if let Some(entry) = self.storage.get_mut(key) {
+            if entry.expires_at == expires_at {
+                return;
+            }
+            entry.expires_at = expires_at;
+            if let Some(deadline) = expires_at {
+                self.eviction_state.push(Reverse((deadline, key.to_vec())));
             }
         }
-        false
     }
 
-    fn del_latest(&mut self) {
-        if let Some((_, key)) = self.eviction_state.pop() {
-            self.storage.remove(&key);
+    // pop_expired returns the next expired key, dropping its eviction record. The key is still
+    // stored, it is up to the caller to remove it.
+    fn pop_expired(&mut self, now: Instant) -> Option<Vec<u8>> {
+        while let Some(Reverse((deadline, _))) = self.eviction_state.peek() {
+            if *deadline > now {
+                return None;
+            }
+            let Reverse((deadline, key)) = self.eviction_state.pop().unwrap();
+            let current = self.storage.get(&key).and_then(|entry| entry.expires_at);
+            if current == Some(deadline) {
+                return Some(key);
+            }
+        }
+        None
+    }
+}
+
+// ShardLock is a shard behind its lock. It counts how many times the lock was taken, and how
+// many times it could not be taken right away, to spot the shards which are too hot.
+struct ShardLock {
+    lock: RwLock<Shard>,
+    // Isolates the transactions from the commands of the other clients, see Storage::gate. The
+    // storage itself never takes it.
+    gate: Arc<tokio::sync::RwLock<()>>,
+    reads: AtomicU64,
+    writes: AtomicU64,
+    contended: AtomicU64,
+}
+
+/// ShardStat is a snapshot of the activity of a shard, see Storage::shard_stats.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct ShardStat {
+    /// Number of keys stored, expired ones included until they are removed.
+    pub keys: usize,
+    /// Number of times the lock was taken to read.
+    pub reads: u64,
+    /// Number of times the lock was taken to write.
+    pub writes: u64,
+    /// Number of times the lock was already taken when an operation needed it.
+    pub contended: u64,
+}
+
+impl ShardLock {
+    fn new() -> Self {
+        ShardLock {
+            lock: RwLock::new(Shard::new()),
+            gate: Arc::default(),
+            reads: AtomicU64::new(0),
+            writes: AtomicU64::new(0),
+            contended: AtomicU64::new(0),
+        }
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, Shard> {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        match self.lock.try_read() {
+            Ok(guard) => guard,
+            Err(TryLockError::WouldBlock) => {
+                self.contended.fetch_add(1, Ordering::Relaxed);
+                self.lock.read().unwrap()
+            }
+            Err(TryLockError::Poisoned(err)) => panic!("{}", err),
+        }
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Shard> {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        match self.lock.try_write() {
+            Ok(guard) => guard,
+            Err(TryLockError::WouldBlock) => {
+                self.contended.fetch_add(1, Ordering::Relaxed);
+                self.lock.write().unwrap()
+            }
+            Err(TryLockError::Poisoned(err)) => panic!("{}", err),
         }
     }
 }
 
-// We implement lazy eviction.
-// When an item is expired, it is kept in the cache and removed either during get or set requests.
+// Values removed by UNLINK are dropped by a dedicated thread, started on the first UNLINK, so
+// that freeing a large value does not delay the commands.
+static LAZY_FREE: OnceLock<mpsc::Sender<Vec<Value>>> = OnceLock::new();
+
+// lazy_free hands `values` to the lazy free thread, which drops them.
+fn lazy_free(values: Vec<Value>) {
+    let sender = LAZY_FREE.get_or_init(|| {
+        let (sender, receiver) = mpsc::channel::<Vec<Value>>();
+        std::thread::Builder::new()
+            .name("lazy-free".to_string())
+            .spawn(move || receiver.into_iter().for_each(drop))
+            .expect("failed to start the lazy free thread");
+        sender
+    });
+    // the thread never stops, sending cannot fail
+    let _ = sender.send(values);
+}
+
+// Number of key events a slow listener can lag behind before missing some.
+const KEY_EVENTS_CAPACITY: usize = 1024;
+
 pub struct Storage {
+    // Maximum number of keys, adding a key beyond it evicts another one.
     capacity: usize,
-    // shard_count should be a power of two.
+    // shard_count is a power of two.
     shard_count: usize,
-    shards: Vec<Arc<RwLock<Shard>>>,
-    // we don't want to lock a mutex to get the size as it is a frequent operation.
+    shards: Vec<ShardLock>,
+    // Number of keys stored, expired ones included until they are removed. We don't want to lock
+    // every shard to get the size as it is a frequent operation.
     size: AtomicUsize,
+    // Approximation of the memory used by the keys and values, in bytes.
+    used_memory: AtomicUsize,
+    // Memory limit in bytes, 0 means no limit.
+    max_memory: usize,
+    eviction_policy: EvictionPolicy,
+    // Where the next eviction starts looking for a key, so that evictions spread over the shards.
+    eviction_cursor: AtomicUsize,
+    // Modified keys are published here for the connections tracking them.
+    key_events: broadcast::Sender<Vec<u8>>,
+    // The access times of the keys are counted from here.
+    created_at: Instant,
 }
 
 impl Debug for Storage {
@@ -79,114 +482,3338 @@ impl Debug for Storage {
             .field("capacity", &self.capacity)
             .field("shard_count", &self.shard_count)
             .field("size", &self.size)
+            .field("used_memory", &self.used_memory)
+            .field("max_memory", &self.max_memory)
+            .field("eviction_policy", &self.eviction_policy)
             .finish()
     }
 }
 
 impl Storage {
-    /// new creates a new storage. shard_count must be a power of two or the function panics.
+    /// new creates a new storage without memory limit. shard_count is rounded up to the next power
+    /// of two.
     pub fn new(capacity: usize, shard_count: usize) -> Self {
-        assert!(
-            shard_count.is_power_of_two(),
-            "shard_count must be a power of two"
-        );
+        Self::with_memory_limit(capacity, shard_count, 0, EvictionPolicy::default())
+    }
+
+    /// with_memory_limit creates a new storage which applies `eviction_policy` once it uses more
+    /// than `max_memory` bytes. A `max_memory` of 0 means no limit. shard_count is rounded up to
+    /// the next power of two, as the shard of a key is picked by masking its hash.
+    pub fn with_memory_limit(
+        capacity: usize,
+        mut shard_count: usize,
+        max_memory: usize,
+        eviction_policy: EvictionPolicy,
+    ) -> Self {
+        if !shard_count.is_power_of_two() {
+            let rounded = shard_count.next_power_of_two();
+            warn!(
+                "shard count {} is not a power of two, using {} shards instead",
+                shard_count, rounded
+            );
+            shard_count = rounded;
+        }
         // Assuming shards are equally distributed
         let mut shards = Vec::with_capacity(shard_count);
         for _ in 0..shard_count {
-            let shard = Arc::new(RwLock::new(Shard::new()));
-            shards.push(shard);
+            shards.push(ShardLock::new());
         }
         Storage {
             capacity,
             shard_count,
             shards,
             size: Default::default(),
+            used_memory: Default::default(),
+            max_memory,
+            eviction_policy,
+            eviction_cursor: Default::default(),
+            key_events: broadcast::channel(KEY_EVENTS_CAPACITY).0,
+            created_at: Instant::now(),
+        }
+    }
+
+    // access_time converts an instant to a last access time of an entry.
+    fn access_time(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.created_at).as_millis() as u64
+    }
+
+    /// len returns the number of keys stored. Expired keys are counted until they are removed.
+    pub fn len(&self) -> usize {
+        self.size.load(Ordering::Relaxed)
+    }
+
+    /// is_empty tells whether the storage holds no key.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// used_memory returns an approximation of the memory used by the keys and values, in bytes.
+    pub fn used_memory(&self) -> usize {
+        self.used_memory.load(Ordering::Relaxed)
+    }
+
+    // check_memory tells whether a write command can run, evicting keys first if the policy
+    // allows it. Like in Redis, we check the memory used before running the command, we do not
+    // try to predict what it will use.
+    fn check_memory(&self) -> Result<(), StorageError> {
+        self.check_memory_holding(None)
+    }
+
+    // check_memory_holding is check_memory for a caller holding the lock of the shard `held`.
+    fn check_memory_holding(&self, held: Option<usize>) -> Result<(), StorageError> {
+        while self.max_memory != 0 && self.used_memory() > self.max_memory {
+            if !self.evict_one(held) {
+                return Err(StorageError::OutOfMemory);
+            }
+        }
+        Ok(())
+    }
+
+    // evict_one removes a key picked by the eviction policy, and tells whether there was one to
+    // remove. Pinned keys are never picked. The keys are sampled from a random position, within
+    // the first EVICTION_MAX_STEPS entries of the shard for allkeys-random: the hash order is
+    // unrelated to the keys, and the window moves on as its keys are evicted. A caller holding
+    // the lock of the shard `held` skips it, and the shards whose lock is taken: waiting for one
+    // could deadlock with a caller holding it. The other callers wait for the locks.
+    fn evict_one(&self, held: Option<usize>) -> bool {
+        if self.eviction_policy == EvictionPolicy::Noeviction {
+            return false;
+        }
+        let start = self.eviction_cursor.fetch_add(1, Ordering::Relaxed);
+        for i in 0..self.shard_count {
+            let index = (start + i) & (self.shard_count - 1);
+            let shard = &self.shards[index];
+            let mut shard = match held {
+                None => shard.write(),
+                Some(held) if held == index => continue,
+                Some(_) => match shard.lock.try_write() {
+                    Ok(shard) => shard,
+                    Err(_) => continue,
+                },
+            };
+            let random = splitmix64(start as u64);
+            let samples: Vec<_> = match self.eviction_policy {
+                // the keys with an expiration are the ones with a deadline record, stale records
+                // are passed over
+                EvictionPolicy::VolatileLru => {
+                    let records = shard.eviction_state.as_slice();
+                    let offset = (random % records.len().max(1) as u64) as usize;
+                    records[offset..]
+                        .iter()
+                        .chain(&records[..offset])
+                        .take(EVICTION_MAX_STEPS)
+                        .filter_map(|Reverse((_, key))| shard.storage.get_key_value(key))
+                        .filter(|(_, entry)| !entry.pinned && entry.expires_at.is_some())
+                        .take(EVICTION_SAMPLES)
+                        .collect()
+                }
+                _ => {
+                    let window = shard.storage.len().clamp(1, EVICTION_MAX_STEPS);
+                    let offset = (random % window as u64) as usize;
+                    let entries = shard.storage.iter();
+                    entries
+                        .clone()
+                        .skip(offset)
+                        .chain(entries.take(offset))
+                        .take(EVICTION_MAX_STEPS)
+                        .filter(|(_, entry)| !entry.pinned)
+                        .take(EVICTION_SAMPLES)
+                        .collect()
+                }
+            };
+            let victim = match self.eviction_policy {
+                EvictionPolicy::VolatileLru => samples
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.last_access.load(Ordering::Relaxed)),
+                _ => samples.get(((random >> 32) % samples.len().max(1) as u64) as usize),
+            };
+            let Some(victim) = victim.map(|(key, _)| key.to_vec()) else {
+                continue;
+            };
+            debug!(
+                "maxmemory reached, evicting {:?}",
+                String::from_utf8_lossy(&victim)
+            );
+            self.remove_key(&mut shard, &victim);
+            return true;
+        }
+        false
+    }
+
+    // update_used_memory records that an operation allocated `added` bytes and freed `removed`.
+    fn update_used_memory(&self, added: usize, removed: usize) {
+        if added >= removed {
+            self.used_memory
+                .fetch_add(added - removed, Ordering::Relaxed);
+        } else {
+            let used = self
+                .used_memory
+                .fetch_sub(removed - added, Ordering::Relaxed);
+            debug_assert!(used >= removed - added, "the used memory underflowed");
+        }
+    }
+
+    /// subscribe_key_events returns a receiver of the keys modified from now on.
+    pub(crate) fn subscribe_key_events(&self) -> broadcast::Receiver<Vec<u8>> {
+        self.key_events.subscribe()
+    }
+
+    // notify_modified publishes a modified key. It is cheap when nobody listens.
+    fn notify_modified(&self, key: &[u8]) {
+        if self.key_events.receiver_count() > 0 {
+            // an error only means that every receiver went away in the meantime
+            let _ = self.key_events.send(key.to_vec());
         }
     }
-    fn get_shard(&self, key: &str) -> &Arc<RwLock<Shard>> {
-        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+
+    fn shard_index(&self, key: &[u8]) -> usize {
+        let mut hasher = FxHasher::default();
+        hasher.write_u64(SHARD_HASH_SEED);
         key.hash(&mut hasher);
         let hash = hasher.finish();
-        let shard_index = (hash as usize) & (self.shard_count - 1);
-        &self.shards[shard_index]
+        // a bit of an Fx hash only depends on the bits of the key below it, so the high bits are
+        // the best mixed ones. Reversed, they are the ones kept by the mask.
+        let hash = hash.reverse_bits();
+        (hash as usize) & (self.shard_count - 1)
     }
 
-    pub fn set_kv(&self, key: &str, value: &str, ttl: Duration) -> Option<String> {
-        let shard = self.get_shard(key);
-        let mut shard = shard.write().unwrap();
-        // lazy eviction, remove the latest key if it has expired
-        if shard.latest_is_expired() {
-            shard.del_latest();
+    fn get_shard(&self, key: &[u8]) -> &ShardLock {
+        &self.shards[self.shard_index(key)]
+    }
+
+    /// gate_index returns the index of the gate of `key`, see gate.
+    pub(crate) fn gate_index(&self, key: &[u8]) -> usize {
+        self.shard_index(key)
+    }
+
+    /// gate_count returns the number of gates, one per shard.
+    pub(crate) fn gate_count(&self) -> usize {
+        self.shard_count
+    }
+
+    /// gate returns the lock isolating the transactions on a shard. A command holds the gates of
+    /// the shards of its keys shared while it runs, a transaction holds the gates of all its keys
+    /// exclusively. The storage never takes them: the connections do, in the order of the
+    /// indexes so that they cannot deadlock.
+    pub(crate) fn gate(&self, index: usize) -> Arc<tokio::sync::RwLock<()>> {
+        self.shards[index].gate.clone()
+    }
+
+    /// lock_contention returns, for each shard, how many times its lock was already taken when
+    /// an operation needed it.
+    pub fn lock_contention(&self) -> Vec<u64> {
+        self.shards
+            .iter()
+            .map(|shard| shard.contended.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    /// shard_stats returns a snapshot of the activity of each shard. The counters are read one by
+    /// one, so they are not consistent with each other under load. Reading the number of keys
+    /// takes each lock, without counting it.
+    pub fn shard_stats(&self) -> Vec<ShardStat> {
+        self.shards
+            .iter()
+            .map(|shard| ShardStat {
+                keys: shard.lock.read().unwrap().storage.len(),
+                reads: shard.reads.load(Ordering::Relaxed),
+                writes: shard.writes.load(Ordering::Relaxed),
+                contended: shard.contended.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    // insert_key adds a key which is not in the shard yet. Together with remove_key, it is the
+    // only way to add or remove keys, so that the size and the used memory never drift from what
+    // the shards hold. It evicts a key of the shard first if the storage is full.
+    fn insert_key(&self, shard: &mut Shard, key: &[u8], value: Value, expires_at: Option<Instant>) {
+        if self.len() >= self.capacity {
+            // expired keys have already been removed by the write, any key which is not pinned
+            // will do. If they are all pinned, the storage goes over its capacity.
+            let victim = shard
+                .storage
+                .iter()
+                .find(|(_, entry)| !entry.pinned)
+                .map(|(victim, _)| victim.clone());
+            if let Some(victim) = victim {
+                self.remove_key(shard, &victim);
+            }
         }
-        let response = shard.add_or_update_kv(key, value, Instant::now() + ttl);
-        if response.is_some() {
-            self.size.fetch_add(1, Ordering::Release);
+        self.size.fetch_add(1, Ordering::Relaxed);
+        self.update_used_memory(key.len() + value.mem_size(), 0);
+        if let Some(deadline) = expires_at {
+            shard.eviction_state.push(Reverse((deadline, key.to_vec())));
         }
-        response
+        let entry = Entry {
+            value,
+            expires_at,
+            pinned: false,
+            last_access: AtomicU64::new(self.access_time(Instant::now())),
+        };
+        shard.storage.insert(key.to_vec(), entry);
     }
 
-    pub fn get_v(&self, key: &str) -> Option<String> {
-        let shard = self.get_shard(key);
-        let shard = shard.read().unwrap();
-        let maybe_entry = shard.get_value_by_key(key);
-        // lazy eviction, remove the latest key if it has expired
-        maybe_entry.cloned()
+    // remove_key removes a key from the shard and returns its entry, None if there was no key.
+    fn remove_key(&self, shard: &mut Shard, key: &[u8]) -> Option<Entry> {
+        let entry = shard.storage.remove(key)?;
+        // only a key which was counted is removed, the size cannot underflow
+        let size = self.size.fetch_sub(1, Ordering::Relaxed);
+        debug_assert!(size > 0, "the size underflowed");
+        self.update_used_memory(0, key.len() + entry.value.mem_size());
+        self.notify_modified(key);
+        Some(entry)
+    }
+
+    // prepare_write is the lazy eviction, run by every write before it touches the shard: it
+    // removes a batch of expired keys, and `key` itself if it has expired so that the write sees
+    // it as missing.
+    fn prepare_write(&self, shard: &mut Shard, key: &[u8], now: Instant) {
+        for _ in 0..LAZY_EVICTION_BATCH {
+            match shard.pop_expired(now) {
+                Some(expired) => self.remove_key(shard, &expired),
+                None => break,
+            };
+        }
+        if shard
+            .storage
+            .get(key)
+            .is_some_and(|entry| entry.is_expired(now))
+        {
+            self.remove_key(shard, key);
+        }
+    }
+
+    // value_for_write returns the value stored at key to modify it, creating it with `empty` first
+    // if the key does not exist. It must be called after prepare_write.
+    fn value_for_write<'a>(
+        &self,
+        shard: &'a mut Shard,
+        key: &[u8],
+        empty: impl FnOnce() -> Value,
+    ) -> &'a mut Value {
+        if !shard.storage.contains_key(key) {
+            self.insert_key(shard, key, empty(), None);
+        }
+        &mut shard.storage.get_mut(key).unwrap().value
+    }
+
+    /// purge_expired removes the expired keys of every shard and returns how many it removed.
+    pub fn purge_expired(&self) -> usize {
+        self.purge_expired_batch(usize::MAX)
     }
 
-    pub(crate) fn del_entries(&self, keys: &Vec<String>) -> usize {
+    /// purge_expired_batch removes up to `max_per_shard` expired keys of each shard, the ones
+    /// which expired first, and returns how many it removed. It bounds how long a shard is locked.
+    pub fn purge_expired_batch(&self, max_per_shard: usize) -> usize {
+        let now = Instant::now();
         let mut count = 0;
-        for key in keys {
-            let shard = self.get_shard(key);
-            let mut bucket = shard.write().unwrap();
-            count += bucket.del_entry(key);
+        for shard in &self.shards {
+            let mut shard = shard.write();
+            for _ in 0..max_per_shard {
+                let Some(expired) = shard.pop_expired(now) else {
+                    break;
+                };
+                self.remove_key(&mut shard, &expired);
+                count += 1;
+            }
         }
-        self.size.fetch_sub(count, Ordering::Relaxed);
         count
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// run_active_eviction removes the expired keys in the background, every `interval`, up to
+    /// `sample_size` keys per shard and per pass. Without it, an expired key which is never
+    /// written again stays in memory until a write locks its shard. It runs until the task is
+    /// aborted.
+    pub async fn run_active_eviction(self: Arc<Self>, interval: Duration, sample_size: usize) {
+        let mut ticks = tokio::time::interval(interval);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            let purged = self.purge_expired_batch(sample_size);
+            if purged > 0 {
+                debug!("active eviction removed {} expired keys", purged);
+            }
+        }
+    }
 
-    #[test]
-    fn db_handler_test() {
-        let storage = Storage::new(100, 8);
+    /// snapshot writes every key which has not expired to `w`, in the format of the snapshot
+    /// files. A shard is copied under its read lock then written, so the writes to a shard only
+    /// wait for the copy, not for `w`.
+    pub fn snapshot(&self, mut w: impl Write) -> io::Result<()> {
+        let (now, system_now) = (Instant::now(), SystemTime::now());
+        let mut out = Vec::new();
+        for shard in &self.shards {
+            out.clear();
+            let shard = shard.read();
+            for (key, entry) in shard.storage.iter() {
+                if entry.is_expired(now) {
+                    continue;
+                }
+                let expires_at = entry
+                    .expires_at
+                    .map(|deadline| system_now + deadline.saturating_duration_since(now));
+                encode_entry(&mut out, key, &entry.value, expires_at, entry.pinned);
+            }
+            drop(shard);
+            w.write_all(&out)?;
+        }
+        out.clear();
+        encode_end(&mut out);
+        w.write_all(&out)
+    }
 
-        // check set and get
-        storage.set_kv("Key1", "V1", Duration::from_millis(300));
-        let v = storage.get_v("Key1").unwrap();
-        assert_eq!(v, "V1", "Value should exist and be V1");
-        let v2 = storage.get_v("Key2");
-        assert_eq!(v2, None, "There should be no value for key2");
+    /// load reads keys written by `snapshot` from `r` and stores them, replacing the keys with the
+    /// same name. The keys which expired in the meantime are skipped. It returns how many keys
+    /// were stored.
+    pub fn load(&self, mut r: impl Read) -> io::Result<usize> {
+        let mut count = 0;
+        while let Some(entry) = decode_entry(&mut r)? {
+            let (now, system_now) = (Instant::now(), SystemTime::now());
+            let expires_at = match entry.expires_at {
+                None => None,
+                Some(deadline) => match deadline.duration_since(system_now) {
+                    Ok(remaining) => Some(now + remaining),
+                    Err(_) => continue,
+                },
+            };
+            let key = &entry.key;
+            let shard = self.get_shard(key);
+            let mut shard = shard.write();
+            self.prepare_write(&mut shard, key, now);
+            self.remove_key(&mut shard, key);
+            self.insert_key(&mut shard, key, entry.value, expires_at);
+            shard.storage.get_mut(key.as_slice()).unwrap().pinned = entry.pinned;
+            count += 1;
+        }
+        Ok(count)
+    }
 
-        // check update
-        let old_v = storage
-            .set_kv("Key1", "UpdateV1", Duration::from_millis(300))
-            .unwrap();
-        assert_eq!(
-            old_v, "V1",
-            "Set kv on an existing key should return the old value"
-        );
-        let v1 = storage.get_v("Key1").unwrap();
-        assert_eq!(
-            v1, "UpdateV1",
-            "Calling set on existing key should update value"
+    /// set_kv sets the string value of a key and returns the previous string value, if any. The
+    /// key expires after `ttl`, or never if there is none, whatever its previous expiry was.
+    pub fn set_kv(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        ttl: Option<Duration>,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        self.check_memory()?;
+        let now = Instant::now();
+        let shard = self.get_shard(key);
+        let mut shard = shard.write();
+        self.prepare_write(&mut shard, key, now);
+        let old = self.replace_value(&mut shard, key, value, ttl.map(|ttl| now + ttl));
+        match old {
+            Some(Value::Str(old)) => Ok(Some(old.into())),
+            _ => Ok(None),
+        }
+    }
+
+    /// set_kv_keepttl sets the string value of a key like set_kv, but the key keeps the expiry it
+    /// had. A new key never expires.
+    pub fn set_kv_keepttl(
+        &self,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        self.check_memory()?;
+        let now = Instant::now();
+        let shard = self.get_shard(key);
+        let mut shard = shard.write();
+        // an expired key is removed here, its expiry is not kept
+        self.prepare_write(&mut shard, key, now);
+        let expires_at = shard.storage.get(key).and_then(|entry| entry.expires_at);
+        let old = self.replace_value(&mut shard, key, value, expires_at);
+        match old {
+            Some(Value::Str(old)) => Ok(Some(old.into())),
+            _ => Ok(None),
+        }
+    }
+
+    /// set_kv_conditional sets the string value of a key like set_kv, if the key meets the
+    /// condition, and tells whether it did. An expired key is considered as not existing.
+    pub fn set_kv_conditional(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        ttl: Option<Duration>,
+        condition: SetCondition,
+    ) -> Result<bool, StorageError> {
+        self.check_memory()?;
+        let now = Instant::now();
+        let shard = self.get_shard(key);
+        let mut shard = shard.write();
+        self.prepare_write(&mut shard, key, now);
+        let exists = shard.storage.contains_key(key);
+        if exists != (condition == SetCondition::Exists) {
+            return Ok(false);
+        }
+        self.replace_value(&mut shard, key, value, ttl.map(|ttl| now + ttl));
+        Ok(true)
+    }
+
+    /// msetnx sets the string values of every key, only if none of them exists, and tells whether
+    /// it did. The shards of the keys are all locked, in index order so that concurrent calls
+    /// cannot deadlock, so no key can be created between the check and the writes. Like in Redis,
+    /// the last value of a key given several times wins.
+    pub fn msetnx(&self, pairs: &[(Vec<u8>, Vec<u8>)]) -> Result<bool, StorageError> {
+        self.check_memory()?;
+        let now = Instant::now();
+        let mut indexes: Vec<usize> = pairs.iter().map(|(key, _)| self.shard_index(key)).collect();
+        indexes.sort_unstable();
+        indexes.dedup();
+        let mut shards: Vec<_> = indexes
+            .iter()
+            .map(|&index| self.shards[index].write())
+            .collect();
+        // position returns the position of the shard of key among the locked ones
+        let position = |key: &[u8]| indexes.binary_search(&self.shard_index(key)).unwrap();
+
+        for (key, _) in pairs {
+            let shard = &mut shards[position(key)];
+            self.prepare_write(shard, key, now);
+            if shard.storage.contains_key(key.as_slice()) {
+                return Ok(false);
+            }
+        }
+        for (key, value) in pairs {
+            self.replace_value(&mut shards[position(key)], key, value, None);
+        }
+        Ok(true)
+    }
+
+    /// get_set sets the string value of a key and returns the previous one, if any. Like in
+    /// Redis, the expiry of the key is cleared. Unlike set_kv, it fails if the key holds another
+    /// kind of value, which is then left untouched.
+    pub fn get_set(&self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        self.check_memory()?;
+        let shard = self.get_shard(key);
+        let mut shard = shard.write();
+        let now = Instant::now();
+        self.prepare_write(&mut shard, key, now);
+        shard.get_str(key, now)?;
+        match self.replace_value(&mut shard, key, value, None) {
+            Some(Value::Str(old)) => Ok(Some(old.into())),
+            _ => Ok(None),
+        }
+    }
+
+    // replace_value stores a string at key with the given expiry, whatever the key held, and
+    // returns the previous value. It must be called after prepare_write.
+    fn replace_value(
+        &self,
+        shard: &mut Shard,
+        key: &[u8],
+        value: &[u8],
+        expires_at: Option<Instant>,
+    ) -> Option<Value> {
+        let old = match shard.storage.get_mut(key) {
+            Some(entry) => {
+                let old =
+                    std::mem::replace(&mut entry.value, Value::Str(Bytes::copy_from_slice(value)));
+                self.update_used_memory(value.len(), old.mem_size());
+                shard.set_expiry(key, expires_at);
+                Some(old)
+            }
+            None => {
+                let value = Value::Str(Bytes::copy_from_slice(value));
+                self.insert_key(shard, key, value, expires_at);
+                None
+            }
+        };
+        self.notify_modified(key);
+        old
+    }
+
+    /// get_v returns the string stored at key. It fails if the key holds another kind of value.
+    pub fn get_v(&self, key: &[u8]) -> Result<Option<Bytes>, StorageError> {
+        let shard = self.get_shard(key);
+        let shard = shard.read();
+        let now = Instant::now();
+        let Some(entry) = shard.get_entry(key, now) else {
+            return Ok(None);
+        };
+        entry
+            .last_access
+            .store(self.access_time(now), Ordering::Relaxed);
+        Ok(Some(entry.value.as_str()?.clone()))
+    }
+
+    /// getrange returns the bytes of the string stored at key from offset `start` to `end`, both
+    /// included. Like in Redis, negative offsets count from the end, -1 being the last byte, and
+    /// out of range offsets are clamped to the string. A missing key is an empty string.
+    pub fn getrange(&self, key: &[u8], start: i64, end: i64) -> Result<Bytes, StorageError> {
+        let Some(value) = self.get_v(key)? else {
+            return Ok(Bytes::new());
+        };
+        let len = value.len() as i64;
+        let start = if start < 0 { len + start } else { start }.max(0);
+        // an empty string has no last byte, any range is empty
+        let end = if end < 0 { len + end } else { end }.max(0).min(len - 1);
+        if start > end {
+            return Ok(Bytes::new());
+        }
+        // the range shares the stored value, nothing is copied
+        Ok(value.slice(start as usize..=end as usize))
+    }
+
+    /// touch marks the key as accessed, like a read would, and tells whether it exists. The access
+    /// time is atomic, so the shard read lock is enough.
+    pub fn touch(&self, key: &[u8]) -> bool {
+        let shard = self.get_shard(key);
+        let shard = shard.read();
+        let now = Instant::now();
+        let Some(entry) = shard.get_entry(key, now) else {
+            return false;
+        };
+        entry
+            .last_access
+            .store(self.access_time(now), Ordering::Relaxed);
+        true
+    }
+
+    /// object_encoding returns the name of the encoding Redis would use for the value stored at
+    /// key, None if the key does not exist. Strings are reported as `int` when they hold an
+    /// integer, `embstr` when they are short and `raw` otherwise.
+    pub fn object_encoding(&self, key: &[u8]) -> Option<&'static str> {
+        let shard = self.get_shard(key);
+        let shard = shard.read();
+        let encoding = match shard.get_value_by_key(key, Instant::now())? {
+            Value::Str(value) => {
+                let is_integer = std::str::from_utf8(value)
+                    .is_ok_and(|value| value.parse::<i64>().is_ok_and(|n| n.to_string() == value));
+                if is_integer {
+                    "int"
+                } else if value.len() <= EMBSTR_MAX_LEN {
+                    "embstr"
+                } else {
+                    "raw"
+                }
+            }
+            Value::Hash(_) | Value::Set(_) => "hashtable",
+            Value::ZSet(_) => "skiplist",
+            Value::List(_) => "quicklist",
+        };
+        Some(encoding)
+    }
+
+    /// object_idletime returns how long ago the key was last read, or written if it was never
+    /// read since. It returns None if the key does not exist. It does not count as an access.
+    pub fn object_idletime(&self, key: &[u8]) -> Option<Duration> {
+        let shard = self.get_shard(key);
+        let shard = shard.read();
+        let now = Instant::now();
+        let last_access = shard
+            .get_entry(key, now)?
+            .last_access
+            .load(Ordering::Relaxed);
+        let idle = self.access_time(now).saturating_sub(last_access);
+        Some(Duration::from_millis(idle))
+    }
+
+    /// get_del removes the string stored at key and returns it. Reading and removing happen under
+    /// the same lock, so no other write can happen in between. It fails if the key holds another
+    /// kind of value, which is then left untouched.
+    pub fn get_del(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        let shard = self.get_shard(key);
+        let mut shard = shard.write();
+        let now = Instant::now();
+        self.prepare_write(&mut shard, key, now);
+        if shard.get_str(key, now)?.is_none() {
+            return Ok(None);
+        }
+        match self.remove_key(&mut shard, key).map(|entry| entry.value) {
+            Some(Value::Str(value)) => Ok(Some(value.into())),
+            _ => Ok(None),
+        }
+    }
+
+    /// get_or_insert_with returns the string stored at key. If there is none, it stores the value
+    /// computed by `default`, which expires after `ttl` if any, and returns it. The lookup and the
+    /// insertion happen under the same lock, so concurrent callers on a missing key compute the
+    /// value only once: the others get the stored value.
+    pub fn get_or_insert_with(
+        &self,
+        key: &[u8],
+        ttl: Option<Duration>,
+        default: impl FnOnce() -> Vec<u8>,
+    ) -> Result<Vec<u8>, StorageError> {
+        let now = Instant::now();
+        let index = self.shard_index(key);
+        let mut shard = self.shards[index].write();
+        self.prepare_write(&mut shard, key, now);
+        if let Some(value) = shard.get_str(key, now)? {
+            return Ok(value.to_vec());
+        }
+        // reads never fail with OOM, so only check the memory when we are about to write
+        self.check_memory_holding(Some(index))?;
+        let value = default();
+        self.insert_key(
+            &mut shard,
+            key,
+            Value::Str(Bytes::copy_from_slice(&value)),
+            ttl.map(|ttl| now + ttl),
         );
+        self.notify_modified(key);
+        Ok(value)
+    }
 
-        // check delete
-        let num_deleted = storage.del_entries(&vec!["Key1".to_string()]);
-        assert_eq!(num_deleted, 1, "should delete 1 key");
-        let v2 = storage.get_v("Key1");
-        assert_eq!(v2, None, "Key1 entry should have been deleted");
-        storage.set_kv("Key1", "V1", Duration::from_millis(300));
-        storage.set_kv("Key2", "V1", Duration::from_millis(300));
-        let num_deleted = storage.del_entries(&vec!["Key1".to_string(), "Key2".to_string()]);
-        assert_eq!(num_deleted, 2, "should delete 2 key");
+    /// append appends `chunk` to the string stored at key, creating it if needed, and returns the
+    /// length of the result. The string is extended in place, so building a value with many
+    /// appends only copies it when its buffer grows, not on every append.
+    pub fn append(&self, key: &[u8], chunk: &[u8]) -> Result<usize, StorageError> {
+        self.check_memory()?;
+        let shard = self.get_shard(key);
+        let mut shard = shard.write();
+        let now = Instant::now();
+        self.prepare_write(&mut shard, key, now);
+        shard.get_str(key, now)?;
+        let Value::Str(value) = self.value_for_write(&mut shard, key, || Value::Str(Bytes::new()))
+        else {
+            unreachable!("the value was checked to be a string");
+        };
+        // the buffer of the value is reused as long as no reader shares it, so that appending
+        // does not copy the whole value every time
+        let mut buffer = Vec::from(std::mem::take(value));
+        buffer.extend_from_slice(chunk);
+        let len = buffer.len();
+        *value = buffer.into();
+        self.update_used_memory(chunk.len(), 0);
+        self.notify_modified(key);
+        Ok(len)
+    }
 
-        // check ordering
-        storage.set_kv("ent1", "V1", Duration::from_millis(180));
-        storage.set_kv("ent2", "V1", Duration::from_millis(300));
-        storage.set_kv("ent3", "V1", Duration::from_millis(100));
+    /// setrange overwrites the string stored at key from `offset` with `chunk`, creating it if
+    /// needed, and returns the length of the result. A string shorter than `offset` is padded with
+    /// zero bytes first. An empty chunk changes nothing, it does not create the key either. The
+    /// caller bounds `offset`, the string is allocated up to it.
+    pub fn setrange(&self, key: &[u8], offset: usize, chunk: &[u8]) -> Result<usize, StorageError> {
+        self.check_memory()?;
+        let shard = self.get_shard(key);
+        let mut shard = shard.write();
+        let now = Instant::now();
+        self.prepare_write(&mut shard, key, now);
+        let current = shard.get_str(key, now)?.map_or(0, Bytes::len);
+        if chunk.is_empty() {
+            return Ok(current);
+        }
+        let Value::Str(value) = self.value_for_write(&mut shard, key, || Value::Str(Bytes::new()))
+        else {
+            unreachable!("the value was checked to be a string");
+        };
+        // like append, the buffer of the value is reused as long as no reader shares it
+        let mut buffer = Vec::from(std::mem::take(value));
+        let end = offset + chunk.len();
+        if buffer.len() < end {
+            buffer.resize(end, 0);
+        }
+        buffer[offset..end].copy_from_slice(chunk);
+        let len = buffer.len();
+        *value = buffer.into();
+        self.update_used_memory(len - current, 0);
+        self.notify_modified(key);
+        Ok(len)
+    }
+
+    /// incr_by adds `delta` to the integer stored at key, a missing key counting as 0, and returns
+    /// the new value. The key keeps its expiry. The read and the write happen under the same lock,
+    /// so concurrent increments are never lost.
+    pub fn incr_by(&self, key: &[u8], delta: i64) -> Result<i64, StorageError> {
+        self.check_memory()?;
+        let shard = self.get_shard(key);
+        let mut shard = shard.write();
+        let now = Instant::now();
+        self.prepare_write(&mut shard, key, now);
+        let current = match shard.get_str(key, now)? {
+            Some(value) => std::str::from_utf8(value)
+                .ok()
+                .and_then(|value| value.parse::<i64>().ok())
+                .ok_or(StorageError::NotInteger)?,
+            None => 0,
+        };
+        let expires_at = shard.get_entry(key, now).and_then(|entry| entry.expires_at);
+        let value = current.checked_add(delta).ok_or(StorageError::Overflow)?;
+        self.replace_value(&mut shard, key, value.to_string().as_bytes(), expires_at);
+        Ok(value)
+    }
+
+    /// incr_by_float adds `delta` to the number stored at key, a missing key counting as 0, and
+    /// returns the new value as it is stored. Like in Redis, the value is written in plain decimal
+    /// notation, without trailing zeros nor exponent. The key keeps its expiry.
+    pub fn incr_by_float(&self, key: &[u8], delta: f64) -> Result<String, StorageError> {
+        self.check_memory()?;
+        let shard = self.get_shard(key);
+        let mut shard = shard.write();
+        let now = Instant::now();
+        self.prepare_write(&mut shard, key, now);
+        let current = match shard.get_str(key, now)? {
+            Some(value) => std::str::from_utf8(value)
+                .ok()
+                .and_then(|value| value.parse::<f64>().ok())
+                .filter(|value| value.is_finite())
+                .ok_or(StorageError::NotFloat)?,
+            None => 0.0,
+        };
+        let expires_at = shard.get_entry(key, now).and_then(|entry| entry.expires_at);
+        let value = current + delta;
+        if !value.is_finite() {
+            return Err(StorageError::NotFinite);
+        }
+        // the shortest representation which parses back to the same value, never in scientific
+        // notation. Adding 0 turns a negative zero into a positive one.
+        let value = (value + 0.0).to_string();
+        self.replace_value(&mut shard, key, value.as_bytes(), expires_at);
+        Ok(value)
+    }
+
+    /// get_ex returns the string stored at key and changes its expiry as requested. It fails if
+    /// the key holds another kind of value, whose expiry is then left untouched.
+    pub fn get_ex(
+        &self,
+        key: &[u8],
+        update: ExpiryUpdate,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        let now = Instant::now();
+        let shard = self.get_shard(key);
+        let mut shard = shard.write();
+        self.prepare_write(&mut shard, key, now);
+        let Some(value) = shard.get_str(key, now)? else {
+            return Ok(None);
+        };
+        let value = value.to_vec();
+        match update {
+            ExpiryUpdate::Keep => {}
+            ExpiryUpdate::Persist => shard.set_expiry(key, None),
+            ExpiryUpdate::After(ttl) => shard.set_expiry(key, Some(now + ttl)),
+        }
+        Ok(Some(value))
+    }
+
+    /// persist removes the expiry of a key and tells whether there was one. Its record in the
+    /// eviction heap is left behind, it is dropped as stale when it comes up.
+    pub fn persist(&self, key: &[u8]) -> bool {
+        let shard = self.get_shard(key);
+        let mut shard = shard.write();
+        self.prepare_write(&mut shard, key, Instant::now());
+        match shard.storage.get_mut(key) {
+            Some(entry) if entry.expires_at.is_some() => {
+                entry.expires_at = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// expire makes a key expire after `ttl_ms` milliseconds and tells whether the key exists. A
+    /// non-positive TTL deletes the key right away, instead of leaving an expired key behind.
+    pub fn expire(&self, key: &[u8], ttl_ms: i64) -> bool {
+        let now = Instant::now();
+        let shard = self.get_shard(key);
+        let mut shard = shard.write();
+        self.prepare_write(&mut shard, key, now);
+        if !shard.storage.contains_key(key) {
+            return false;
+        }
+        match u64::try_from(ttl_ms) {
+            Ok(ttl_ms) if ttl_ms > 0 => {
+                let expires_at = now.checked_add(Duration::from_millis(ttl_ms));
+                // a deadline too far to be represented is as good as no deadline
+                shard.set_expiry(key, expires_at);
+            }
+            _ => {
+                self.remove_key(&mut shard, key);
+            }
+        }
+        true
+    }
+
+    /// compare_and_set replaces the string stored at key by `new` if it is equal to `expected`,
+    /// and tells whether it did. Without an expected value, the key is only set if it does not
+    /// exist, which is how a lock is taken. The check and the write happen under the same shard
+    /// lock, so only one of racing calls can win. The key expires after `ttl` if there is one,
+    /// otherwise a replaced key keeps its expiry.
+    pub fn compare_and_set(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: &[u8],
+        ttl: Option<Duration>,
+    ) -> Result<bool, StorageError> {
+        self.check_memory()?;
+        let now = Instant::now();
+        let shard = self.get_shard(key);
+        let mut shard = shard.write();
+        self.prepare_write(&mut shard, key, now);
+        let current = shard.storage.get_mut(key).map(|entry| &mut entry.value);
+        match (current, expected) {
+            (Some(Value::Str(value)), Some(expected)) if value == expected => {
+                *value = Bytes::copy_from_slice(new);
+                self.update_used_memory(new.len(), expected.len());
+                if let Some(ttl) = ttl {
+                    shard.set_expiry(key, Some(now + ttl));
+                }
+                self.notify_modified(key);
+            }
+            (None, None) => {
+                self.replace_value(&mut shard, key, new, ttl.map(|ttl| now + ttl));
+            }
+            (Some(Value::Str(_)), Some(_)) | (None, Some(_)) | (Some(_), None) => return Ok(false),
+            (Some(_), Some(_)) => return Err(StorageError::WrongType),
+        }
+        Ok(true)
+    }
+
+    /// hset sets the given fields of the hash stored at key, creating the hash if needed. It
+    /// returns the number of fields that were added, not counting the updated ones.
+    pub fn hset(&self, key: &[u8], pairs: &[(Vec<u8>, Vec<u8>)]) -> Result<usize, StorageError> {
+        self.check_memory()?;
+        let shard = self.get_shard(key);
+        let mut shard = shard.write();
+        self.prepare_write(&mut shard, key, Instant::now());
+        let hash = match self.value_for_write(&mut shard, key, || Value::Hash(FxHashMap::default()))
+        {
+            Value::Hash(hash) => hash,
+            _ => return Err(StorageError::WrongType),
+        };
+        let (mut added, mut allocated, mut freed) = (0, 0, 0);
+        for (field, value) in pairs {
+            match hash.insert(field.clone(), value.clone()) {
+                Some(old) => freed += old.len(),
+                None => {
+                    added += 1;
+                    allocated += field.len();
+                }
+            }
+            allocated += value.len();
+        }
+        self.update_used_memory(allocated, freed);
+        self.notify_modified(key);
+        Ok(added)
+    }
+
+    /// sadd adds members to the set stored at key, creating the set if needed. It returns the
+    /// number of members that were not already in the set.
+    pub fn sadd(&self, key: &[u8], members: &[Vec<u8>]) -> Result<usize, StorageError> {
+        self.check_memory()?;
+        let shard = self.get_shard(key);
+        let mut shard = shard.write();
+        self.prepare_write(&mut shard, key, Instant::now());
+        let set = match self.value_for_write(&mut shard, key, || Value::Set(FxHashSet::default())) {
+            Value::Set(set) => set,
+            _ => return Err(StorageError::WrongType),
+        };
+        let (mut added, mut allocated) = (0, 0);
+        for member in members {
+            if set.insert(member.clone()) {
+                added += 1;
+                allocated += member.len();
+            }
+        }
+        self.update_used_memory(allocated, 0);
+        self.notify_modified(key);
+        Ok(added)
+    }
+
+    /// zadd adds members with their score to the sorted set stored at key, creating it if needed.
+    /// The score of existing members is updated. It returns the number of members added.
+    pub fn zadd(&self, key: &[u8], members: &[(f64, Vec<u8>)]) -> Result<usize, StorageError> {
+        self.check_memory()?;
+        let shard = self.get_shard(key);
+        let mut shard = shard.write();
+        self.prepare_write(&mut shard, key, Instant::now());
+        let zset = match self.value_for_write(&mut shard, key, || Value::ZSet(FxHashMap::default()))
+        {
+            Value::ZSet(zset) => zset,
+            _ => return Err(StorageError::WrongType),
+        };
+        let (mut added, mut allocated) = (0, 0);
+        for (score, member) in members {
+            if zset.insert(member.clone(), *score).is_none() {
+                added += 1;
+                allocated += member.len() + SCORE_SIZE;
+            }
+        }
+        self.update_used_memory(allocated, 0);
+        self.notify_modified(key);
+        Ok(added)
+    }
+
+    /// lpush inserts elements at the head of the list stored at key, creating the list if needed,
+    /// and returns the length of the list. The elements are inserted one after the other, so the
+    /// last one ends up first.
+    pub fn lpush(&self, key: &[u8], elements: &[Vec<u8>]) -> Result<usize, StorageError> {
+        self.push(key, elements, true)
+    }
+
+    /// rpush appends elements at the tail of the list stored at key, creating the list if needed,
+    /// and returns the length of the list.
+    pub fn rpush(&self, key: &[u8], elements: &[Vec<u8>]) -> Result<usize, StorageError> {
+        self.push(key, elements, false)
+    }
+
+    // push implements lpush and rpush, `head` tells at which end of the list the elements go.
+    fn push(&self, key: &[u8], elements: &[Vec<u8>], head: bool) -> Result<usize, StorageError> {
+        self.check_memory()?;
+        let shard = self.get_shard(key);
+        let mut shard = shard.write();
+        self.prepare_write(&mut shard, key, Instant::now());
+        let list = match self.value_for_write(&mut shard, key, || Value::List(VecDeque::new())) {
+            Value::List(list) => list,
+            _ => return Err(StorageError::WrongType),
+        };
+        let mut allocated = 0;
+        for element in elements {
+            allocated += element.len();
+            if head {
+                list.push_front(element.clone());
+            } else {
+                list.push_back(element.clone());
+            }
+        }
+        let len = list.len();
+        self.update_used_memory(allocated, 0);
+        self.notify_modified(key);
+        self.serve_blocked(&mut shard, key);
+        Ok(len)
+    }
+
+    // serve_blocked pops an element of the list stored at key for each client blocked on it, in
+    // the order they blocked, as long as there are elements. A client already served through
+    // another key, or gone, is skipped. It must be called once expired keys were removed.
+    fn serve_blocked(&self, shard: &mut Shard, key: &[u8]) {
+        let Some(mut queue) = shard.blocked.remove(key) else {
+            return;
+        };
+        let mut freed = 0;
+        let mut emptied = false;
+        if let Some(Value::List(list)) = shard.storage.get_mut(key).map(|entry| &mut entry.value) {
+            while !list.is_empty() {
+                let Some(client) = queue.pop_front() else {
+                    break;
+                };
+                // the sender is sent to under the lock, so that cancel_pop finds the element
+                let mut reply = client.reply.lock().unwrap();
+                let Some(sender) = reply.take() else {
+                    continue;
+                };
+                let element = if client.head {
+                    list.pop_front().unwrap()
+                } else {
+                    list.pop_back().unwrap()
+                };
+                let len = element.len();
+                match sender.send((key.to_vec(), element)) {
+                    Ok(()) => freed += len,
+                    // the client went away without cancelling, the element stays
+                    Err((_, element)) if client.head => list.push_front(element),
+                    Err((_, element)) => list.push_back(element),
+                }
+            }
+            emptied = list.is_empty();
+        }
+        if !queue.is_empty() {
+            shard.blocked.insert(key.to_vec(), queue);
+        }
+        self.update_used_memory(0, freed);
+        if emptied {
+            self.remove_key(shard, key);
+        } else if freed > 0 {
+            self.notify_modified(key);
+        }
+    }
+
+    /// blocking_pop pops an element from the first non-empty list of `keys`, from its head or
+    /// from its tail. If they are all empty, the client is registered on each key instead: the
+    /// clients blocked on a key are served in the order they blocked, by the pushes to come, see
+    /// PopWaiter.
+    pub fn blocking_pop(&self, keys: &[Vec<u8>], head: bool) -> Result<BlockingPop, StorageError> {
+        for key in keys {
+            if let Some(element) = self.pop(key, 1, head)?.pop() {
+                return Ok(BlockingPop::Popped(key.clone(), element));
+            }
+        }
+        let (sender, popped) = oneshot::channel();
+        let client = Arc::new(BlockedClient {
+            head,
+            reply: Mutex::new(Some(sender)),
+        });
+        for key in keys {
+            let shard = self.get_shard(key);
+            let mut shard = shard.write();
+            shard
+                .blocked
+                .entry(key.clone())
+                .or_default()
+                .push_back(client.clone());
+            // a push may have come since the list was found empty
+            if matches!(shard.get_list(key, Instant::now()), Ok(Some(list)) if !list.is_empty()) {
+                self.serve_blocked(&mut shard, key);
+            }
+        }
+        Ok(BlockingPop::Blocked(PopWaiter {
+            keys: keys.to_vec(),
+            client,
+            popped,
+        }))
+    }
+
+    /// cancel_pop unregisters a client blocked by blocking_pop which stops waiting. It returns
+    /// the element popped for the client, if a push served it in the meantime.
+    pub fn cancel_pop(&self, mut waiter: PopWaiter) -> Option<(Vec<u8>, Vec<u8>)> {
+        let served = waiter.client.reply.lock().unwrap().take().is_none();
+        for key in &waiter.keys {
+            let shard = self.get_shard(key);
+            let mut shard = shard.write();
+            if let Some(queue) = shard.blocked.get_mut(key) {
+                queue.retain(|client| !Arc::ptr_eq(client, &waiter.client));
+                if queue.is_empty() {
+                    shard.blocked.remove(key);
+                }
+            }
+        }
+        if served {
+            waiter.popped.try_recv().ok()
+        } else {
+            None
+        }
+    }
+
+    /// lpop removes and returns up to `count` elements from the head of the list stored at key.
+    /// The list is removed once it is empty.
+    pub fn lpop(&self, key: &[u8], count: usize) -> Result<Vec<Vec<u8>>, StorageError> {
+        self.pop(key, count, true)
+    }
+
+    /// rpop removes and returns up to `count` elements from the tail of the list stored at key,
+    /// the last element first. The list is removed once it is empty.
+    pub fn rpop(&self, key: &[u8], count: usize) -> Result<Vec<Vec<u8>>, StorageError> {
+        self.pop(key, count, false)
+    }
+
+    // pop implements lpop and rpop, `head` tells at which end of the list the elements are taken.
+    fn pop(&self, key: &[u8], count: usize, head: bool) -> Result<Vec<Vec<u8>>, StorageError> {
+        let shard = self.get_shard(key);
+        let mut shard = shard.write();
+        self.prepare_write(&mut shard, key, Instant::now());
+        let list = match shard.storage.get_mut(key).map(|entry| &mut entry.value) {
+            None => return Ok(Vec::new()),
+            Some(Value::List(list)) => list,
+            Some(_) => return Err(StorageError::WrongType),
+        };
+        let count = count.min(list.len());
+        let popped: Vec<Vec<u8>> = if head {
+            list.drain(..count).collect()
+        } else {
+            list.drain(list.len() - count..).rev().collect()
+        };
+        let emptied = list.is_empty();
+        self.update_used_memory(0, popped.iter().map(|element| element.len()).sum());
+        if emptied {
+            self.remove_key(&mut shard, key);
+        } else if !popped.is_empty() {
+            self.notify_modified(key);
+        }
+        Ok(popped)
+    }
+
+    /// lrange returns the elements of the list stored at key from index `start` to `stop`, both
+    /// included. Like in Redis, negative indices count from the tail, -1 being the last element,
+    /// and out of range indices are clamped to the list.
+    pub fn lrange(&self, key: &[u8], start: i64, stop: i64) -> Result<Vec<Vec<u8>>, StorageError> {
+        let shard = self.get_shard(key);
+        let shard = shard.read();
+        let Some(list) = shard.get_list(key, Instant::now())? else {
+            return Ok(Vec::new());
+        };
+        let len = list.len() as i64;
+        let start = if start < 0 {
+            (len + start).max(0)
+        } else {
+            start
+        };
+        let stop = if stop < 0 {
+            len + stop
+        } else {
+            stop.min(len - 1)
+        };
+        if start > stop {
+            return Ok(Vec::new());
+        }
+        Ok(list
+            .range(start as usize..=stop as usize)
+            .cloned()
+            .collect())
+    }
+
+    /// hkeys returns the field names of the hash stored at key, in no particular order.
+    pub fn hkeys(&self, key: &[u8]) -> Result<Vec<Vec<u8>>, StorageError> {
+        let shard = self.get_shard(key);
+        let shard = shard.read();
+        let hash = shard.get_hash(key, Instant::now())?;
+        Ok(hash.map_or_else(Vec::new, |hash| hash.keys().cloned().collect()))
+    }
+
+    /// hvals returns the values of the hash stored at key, in no particular order.
+    pub fn hvals(&self, key: &[u8]) -> Result<Vec<Vec<u8>>, StorageError> {
+        let shard = self.get_shard(key);
+        let shard = shard.read();
+        let hash = shard.get_hash(key, Instant::now())?;
+        Ok(hash.map_or_else(Vec::new, |hash| hash.values().cloned().collect()))
+    }
+
+    /// hget returns the value of a field of the hash stored at key.
+    pub fn hget(&self, key: &[u8], field: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        let shard = self.get_shard(key);
+        let shard = shard.read();
+        let hash = shard.get_hash(key, Instant::now())?;
+        Ok(hash.and_then(|hash| hash.get(field).cloned()))
+    }
+
+    /// hgetall returns the fields of the hash stored at key with their value, in no particular
+    /// order.
+    pub fn hgetall(&self, key: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, StorageError> {
+        let shard = self.get_shard(key);
+        let shard = shard.read();
+        let hash = shard.get_hash(key, Instant::now())?;
+        Ok(hash.map_or_else(Vec::new, |hash| {
+            hash.iter()
+                .map(|(field, value)| (field.clone(), value.clone()))
+                .collect()
+        }))
+    }
+
+    /// hdel removes fields from the hash stored at key and returns how many it removed. The hash
+    /// is removed once it has no field left.
+    pub fn hdel(&self, key: &[u8], fields: &[Vec<u8>]) -> Result<usize, StorageError> {
+        let shard = self.get_shard(key);
+        let mut shard = shard.write();
+        self.prepare_write(&mut shard, key, Instant::now());
+        let hash = match shard.storage.get_mut(key).map(|entry| &mut entry.value) {
+            None => return Ok(0),
+            Some(Value::Hash(hash)) => hash,
+            Some(_) => return Err(StorageError::WrongType),
+        };
+        let (mut removed, mut freed) = (0, 0);
+        for field in fields {
+            if let Some(value) = hash.remove(field) {
+                removed += 1;
+                freed += field.len() + value.len();
+            }
+        }
+        let emptied = hash.is_empty();
+        self.update_used_memory(0, freed);
+        if emptied {
+            self.remove_key(&mut shard, key);
+        } else if removed > 0 {
+            self.notify_modified(key);
+        }
+        Ok(removed)
+    }
+
+    /// hscan performs one step of the incremental iteration over the hash stored at key. It
+    /// returns the next cursor and a flat list of field/value pairs, or only fields when
+    /// `no_values` is set. Only fields matching the glob `pattern` are returned.
+    pub fn hscan(
+        &self,
+        key: &[u8],
+        cursor: usize,
+        pattern: &[u8],
+        count: usize,
+        no_values: bool,
+    ) -> Result<(usize, Vec<Vec<u8>>), StorageError> {
+        let shard = self.get_shard(key);
+        let shard = shard.read();
+        let hash = match shard.get_hash(key, Instant::now())? {
+            Some(hash) => hash,
+            None => return Ok((0, Vec::new())),
+        };
+        let (next, pairs) = scan_collection(hash.iter(), cursor, count, |(field, _)| {
+            scan_position(field)
+        });
+        let mut items = Vec::with_capacity(pairs.len() * 2);
+        for (field, value) in pairs {
+            if !glob_match(pattern, field) {
+                continue;
+            }
+            items.push(field.clone());
+            if !no_values {
+                items.push(value.clone());
+            }
+        }
+        Ok((next, items))
+    }
+
+    /// sscan performs one step of the incremental iteration over the set stored at key. It returns
+    /// the next cursor and the members matching the glob `pattern`.
+    pub fn sscan(
+        &self,
+        key: &[u8],
+        cursor: usize,
+        pattern: &[u8],
+        count: usize,
+    ) -> Result<(usize, Vec<Vec<u8>>), StorageError> {
+        let shard = self.get_shard(key);
+        let shard = shard.read();
+        let set = match shard.get_set(key, Instant::now())? {
+            Some(set) => set,
+            None => return Ok((0, Vec::new())),
+        };
+        let (next, members) =
+            scan_collection(set.iter(), cursor, count, |member| scan_position(member));
+        let members = members
+            .into_iter()
+            .filter(|member| glob_match(pattern, member))
+            .cloned()
+            .collect();
+        Ok((next, members))
+    }
+
+    /// zscan performs one step of the incremental iteration over the sorted set stored at key. It
+    /// returns the next cursor and a flat list of member/score pairs for the members matching the
+    /// glob `pattern`.
+    pub fn zscan(
+        &self,
+        key: &[u8],
+        cursor: usize,
+        pattern: &[u8],
+        count: usize,
+    ) -> Result<(usize, Vec<Vec<u8>>), StorageError> {
+        let shard = self.get_shard(key);
+        let shard = shard.read();
+        let zset = match shard.get_zset(key, Instant::now())? {
+            Some(zset) => zset,
+            None => return Ok((0, Vec::new())),
+        };
+        let (next, pairs) = scan_collection(zset.iter(), cursor, count, |(member, _)| {
+            scan_position(member)
+        });
+        let mut items = Vec::with_capacity(pairs.len() * 2);
+        for (member, score) in pairs {
+            if !glob_match(pattern, member) {
+                continue;
+            }
+            items.push(member.clone());
+            items.push(score.to_string().into_bytes());
+        }
+        Ok((next, items))
+    }
+
+    /// scan performs one step of the incremental iteration over the whole keyspace. It returns
+    /// the next cursor and the keys matching the glob `pattern`. The cursor encodes the shard to
+    /// scan in its low bits and the position to resume from in that shard in the others, see
+    /// scan_collection, so that each step only locks the shards it visits, one at a time. It
+    /// visits about `count` keys, possibly across several shards, and returns a cursor of 0 once
+    /// every shard has been visited.
+    pub fn scan(&self, cursor: usize, pattern: &[u8], count: usize) -> (usize, Vec<Vec<u8>>) {
+        let shard_bits = self.shard_count.trailing_zeros();
+        let mut index = cursor & (self.shard_count - 1);
+        let mut from = cursor >> shard_bits;
+        let mut budget = count.max(1);
+        let mut keys = Vec::new();
+        let now = Instant::now();
+        while index < self.shard_count && budget > 0 {
+            let shard = self.shards[index].read();
+            // the low bits of the cursor hold the shard, the positions are shifted to fit
+            let (next, visited) =
+                scan_collection(shard.storage.iter(), from, budget, |(key, _)| {
+                    scan_position(key) >> shard_bits
+                });
+            budget -= budget.min(visited.len());
+            keys.extend(
+                visited
+                    .into_iter()
+                    .filter(|(key, entry)| !entry.is_expired(now) && glob_match(pattern, key))
+                    .map(|(key, _)| key.clone()),
+            );
+            if next != 0 {
+                return ((next << shard_bits) | index, keys);
+            }
+            // the shard is done, carry on with the next one if there is budget left
+            index += 1;
+            from = 0;
+        }
+        // the next shard starts from position 0, so its cursor is its index
+        let next = if index < self.shard_count { index } else { 0 };
+        (next, keys)
+    }
+
+    /// rename moves the value stored at `src`, with its expiry, to `dst`. An existing `dst` is
+    /// overwritten, unless `nx` is set. When the keys live in different shards, both shards are
+    /// locked in index order, so that concurrent renames cannot deadlock.
+    pub fn rename(&self, src: &[u8], dst: &[u8], nx: bool) -> RenameResult {
+        let now = Instant::now();
+        let (src_index, dst_index) = (self.shard_index(src), self.shard_index(dst));
+        if src_index == dst_index {
+            let mut shard = self.shards[src_index].write();
+            return self.move_key(&mut shard, None, src, dst, nx, now);
+        }
+        let (mut src_shard, mut dst_shard) = if src_index < dst_index {
+            let src_shard = self.shards[src_index].write();
+            (src_shard, self.shards[dst_index].write())
+        } else {
+            let dst_shard = self.shards[dst_index].write();
+            (self.shards[src_index].write(), dst_shard)
+        };
+        self.move_key(&mut src_shard, Some(&mut dst_shard), src, dst, nx, now)
+    }
+
+    // move_key implements rename once the shards are locked. `dst_shard` is None when both keys
+    // live in `src_shard`.
+    fn move_key(
+        &self,
+        src_shard: &mut Shard,
+        mut dst_shard: Option<&mut Shard>,
+        src: &[u8],
+        dst: &[u8],
+        nx: bool,
+        now: Instant,
+    ) -> RenameResult {
+        // target returns the shard of the destination key
+        fn target<'a>(src: &'a mut Shard, dst: &'a mut Option<&mut Shard>) -> &'a mut Shard {
+            match dst {
+                Some(dst) => dst,
+                None => src,
+            }
+        }
+
+        self.prepare_write(src_shard, src, now);
+        if !src_shard.storage.contains_key(src) {
+            return RenameResult::NoSuchKey;
+        }
+        self.prepare_write(target(src_shard, &mut dst_shard), dst, now);
+        let dst_exists = target(src_shard, &mut dst_shard).storage.contains_key(dst);
+        if dst_exists && nx {
+            return RenameResult::DestinationExists;
+        }
+        if src == dst {
+            return RenameResult::Renamed;
+        }
+
+        let entry = self.remove_key(src_shard, src).unwrap();
+        let dst_shard = target(src_shard, &mut dst_shard);
+        self.remove_key(dst_shard, dst);
+        self.insert_key(dst_shard, dst, entry.value, entry.expires_at);
+        dst_shard.storage.get_mut(dst).unwrap().pinned = entry.pinned;
+        self.notify_modified(dst);
+        RenameResult::Renamed
+    }
+
+    /// set_pinned pins or unpins the given keys and returns how many of them exist. Pinned keys
+    /// are never evicted when the storage is full, but they still expire. Overwriting a pinned
+    /// key keeps it pinned, deleting it drops the pin.
+    pub fn set_pinned(&self, keys: &[Vec<u8>], pinned: bool) -> usize {
+        let now = Instant::now();
+        let mut count = 0;
+        for key in keys {
+            let shard = self.get_shard(key);
+            let mut shard = shard.write();
+            if let Some(entry) = shard.storage.get_mut(key) {
+                if !entry.is_expired(now) {
+                    entry.pinned = pinned;
+                    count += 1;
+                }
+            }
+        }
+        count
+    }
+
+    /// del_entries removes the given keys and returns how many of them existed.
+    pub(crate) fn del_entries(&self, keys: &[Vec<u8>]) -> usize {
+        let (removed, _) = self.remove_entries(keys);
+        removed.into_iter().filter(|&removed| removed).count()
+    }
+
+    /// del_entries_detailed removes the given keys like del_entries, and tells for each of them,
+    /// in order, whether it existed. A key given twice is only removed by its first occurrence.
+    pub(crate) fn del_entries_detailed(&self, keys: &[Vec<u8>]) -> Vec<bool> {
+        self.remove_entries(keys).0
+    }
+
+    /// unlink_entries removes the given keys like del_entries, but their values are freed later
+    /// by another thread. The keys are gone when it returns, and the memory they used is no
+    /// longer counted, only releasing it to the allocator is deferred.
+    pub(crate) fn unlink_entries(&self, keys: &[Vec<u8>]) -> usize {
+        let (removed, values) = self.remove_entries(keys);
+        if !values.is_empty() {
+            lazy_free(values);
+        }
+        removed.into_iter().filter(|&removed| removed).count()
+    }
+
+    // remove_entries removes the given keys and tells for each of them whether it existed, along
+    // with the values which were removed.
+    fn remove_entries(&self, keys: &[Vec<u8>]) -> (Vec<bool>, Vec<Value>) {
+        let now = Instant::now();
+        let mut removed = Vec::with_capacity(keys.len());
+        let mut values = Vec::new();
+        for key in keys {
+            let shard = self.get_shard(key);
+            let mut shard = shard.write();
+            // an expired key is removed all the same, but it did not exist anymore for the caller
+            match self.remove_key(&mut shard, key) {
+                Some(entry) => {
+                    removed.push(!entry.is_expired(now));
+                    values.push(entry.value);
+                }
+                None => removed.push(false),
+            }
+        }
+        (removed, values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn db_handler_test() {
+        let storage = Storage::new(100, 8);
+
+        // check set and get
+        storage
+            .set_kv(b"Key1", b"V1", Some(Duration::from_millis(300)))
+            .unwrap();
+        let v = storage.get_v(b"Key1").unwrap().unwrap();
+        assert_eq!(v, b"V1".as_slice(), "Value should exist and be V1");
+        let v2 = storage.get_v(b"Key2").unwrap();
+        assert_eq!(v2, None, "There should be no value for key2");
+
+        // check update
+        let old_v = storage
+            .set_kv(b"Key1", b"UpdateV1", Some(Duration::from_millis(300)))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            old_v, b"V1",
+            "Set kv on an existing key should return the old value"
+        );
+        let v1 = storage.get_v(b"Key1").unwrap().unwrap();
+        assert_eq!(
+            v1,
+            b"UpdateV1".as_slice(),
+            "Calling set on existing key should update value"
+        );
+
+        // check delete
+        let num_deleted = storage.del_entries(&[b"Key1".to_vec()]);
+        assert_eq!(num_deleted, 1, "should delete 1 key");
+        let v2 = storage.get_v(b"Key1").unwrap();
+        assert_eq!(v2, None, "Key1 entry should have been deleted");
+        storage
+            .set_kv(b"Key1", b"V1", Some(Duration::from_millis(300)))
+            .unwrap();
+        storage
+            .set_kv(b"Key2", b"V1", Some(Duration::from_millis(300)))
+            .unwrap();
+        let num_deleted = storage.del_entries(&[b"Key1".to_vec(), b"Key2".to_vec()]);
+        assert_eq!(num_deleted, 2, "should delete 2 key");
+
+        // check ordering
+        storage
+            .set_kv(b"ent1", b"V1", Some(Duration::from_millis(180)))
+            .unwrap();
+        storage
+            .set_kv(b"ent2", b"V1", Some(Duration::from_millis(300)))
+            .unwrap();
+        storage
+            .set_kv(b"ent3", b"V1", Some(Duration::from_millis(100)))
+            .unwrap();
+    }
+
+    #[test]
+    fn binary_keys_and_values_test() {
+        let storage = Storage::new(100, 8);
+        storage.set_kv(b"\0key\xff", b"\xc3\x28\0", None).unwrap();
+        storage.set_kv(b"\0key", b"other", None).unwrap();
+        assert_eq!(
+            storage.get_v(b"\0key\xff"),
+            Ok(Some(Bytes::from_static(b"\xc3\x28\0"))),
+            "keys and values are not required to be UTF-8"
+        );
+        assert_eq!(storage.append(b"\0key\xff", b"\xff"), Ok(4));
+        let (_, mut keys) = storage.scan(0, b"\0key*", 100);
+        keys.sort();
+        assert_eq!(keys, vec![b"\0key".to_vec(), b"\0key\xff".to_vec()]);
+        assert_eq!(storage.used_memory(), 5 + 4 + 4 + 5);
+    }
+
+    #[test]
+    fn hash_keys_and_values_test() {
+        let storage = Storage::new(100, 8);
+        let pairs = vec![
+            (b"f1".to_vec(), b"v1".to_vec()),
+            (b"f2".to_vec(), b"v2".to_vec()),
+            (b"f3".to_vec(), b"v3".to_vec()),
+        ];
+        assert_eq!(storage.hset(b"hash", &pairs), Ok(3), "should add 3 fields");
+
+        let mut keys = storage.hkeys(b"hash").unwrap();
+        keys.sort();
+        assert_eq!(keys, vec![b"f1", b"f2", b"f3"]);
+        let mut values = storage.hvals(b"hash").unwrap();
+        values.sort();
+        assert_eq!(values, vec![b"v1", b"v2", b"v3"]);
+
+        assert!(storage.hkeys(b"missing").unwrap().is_empty());
+        assert!(storage.hvals(b"missing").unwrap().is_empty());
+
+        storage
+            .set_kv(b"string", b"value", Some(Duration::from_secs(10)))
+            .unwrap();
+        assert_eq!(storage.hkeys(b"string"), Err(StorageError::WrongType));
+        assert_eq!(storage.hvals(b"string"), Err(StorageError::WrongType));
+        assert_eq!(storage.get_v(b"hash"), Err(StorageError::WrongType));
+    }
+
+    #[test]
+    fn hash_fields_test() {
+        let storage = Storage::new(100, 8);
+        let pairs = vec![
+            (b"f1".to_vec(), b"v1".to_vec()),
+            (b"f2".to_vec(), b"v2".to_vec()),
+        ];
+        assert_eq!(storage.hset(b"hash", &pairs), Ok(2));
+        let update = vec![
+            (b"f1".to_vec(), b"new".to_vec()),
+            (b"f3".to_vec(), b"v3".to_vec()),
+        ];
+        assert_eq!(storage.hset(b"hash", &update), Ok(1), "f1 is only updated");
+        assert_eq!(storage.hget(b"hash", b"f1"), Ok(Some(b"new".to_vec())));
+        assert_eq!(storage.hget(b"hash", b"nope"), Ok(None));
+        assert_eq!(storage.hget(b"missing", b"f1"), Ok(None));
+
+        let mut all = storage.hgetall(b"hash").unwrap();
+        all.sort();
+        assert_eq!(
+            all,
+            vec![
+                (b"f1".to_vec(), b"new".to_vec()),
+                (b"f2".to_vec(), b"v2".to_vec()),
+                (b"f3".to_vec(), b"v3".to_vec()),
+            ]
+        );
+        assert_eq!(storage.hgetall(b"missing"), Ok(vec![]));
+
+        assert_eq!(
+            storage.hdel(b"hash", &[b"f1".to_vec(), b"nope".to_vec()]),
+            Ok(1)
+        );
+        assert_eq!(storage.hget(b"hash", b"f1"), Ok(None));
+        assert_eq!(
+            storage.used_memory(),
+            b"hash".len() + b"f2v2".len() + b"f3v3".len()
+        );
+        assert_eq!(
+            storage.hdel(b"hash", &[b"f2".to_vec(), b"f3".to_vec()]),
+            Ok(2)
+        );
+        assert!(storage.is_empty(), "a hash without fields is removed");
+        assert_eq!(storage.used_memory(), 0);
+        assert_eq!(storage.hdel(b"hash", &[b"f1".to_vec()]), Ok(0));
+
+        storage.set_kv(b"string", b"value", None).unwrap();
+        assert_eq!(
+            storage.hset(b"string", &pairs),
+            Err(StorageError::WrongType)
+        );
+        assert_eq!(storage.hget(b"string", b"f1"), Err(StorageError::WrongType));
+        assert_eq!(storage.hgetall(b"string"), Err(StorageError::WrongType));
+        assert_eq!(
+            storage.hdel(b"string", &[b"f1".to_vec()]),
+            Err(StorageError::WrongType)
+        );
+        let (keys, memory) = recount(&storage);
+        assert_eq!((storage.len(), storage.used_memory()), (keys, memory));
+    }
+
+    #[test]
+    fn hscan_test() {
+        let storage = Storage::new(100, 8);
+        let mut pairs = Vec::new();
+        for i in 0..50 {
+            pairs.push((
+                format!("user:{}", i).into_bytes(),
+                i.to_string().into_bytes(),
+            ));
+            pairs.push((
+                format!("item:{}", i).into_bytes(),
+                i.to_string().into_bytes(),
+            ));
+        }
+        storage.hset(b"hash", &pairs).unwrap();
+
+        let mut cursor = 0;
+        let mut fields = Vec::new();
+        let mut iterations = 0;
+        loop {
+            let (next, items) = storage.hscan(b"hash", cursor, b"user:*", 7, false).unwrap();
+            for pair in items.chunks(2) {
+                assert_eq!(
+                    pair[0],
+                    [b"user:", pair[1].as_slice()].concat(),
+                    "field and value go together"
+                );
+                fields.push(pair[0].clone());
+            }
+            iterations += 1;
+            cursor = next;
+            if cursor == 0 {
+                break;
+            }
+        }
+        fields.sort();
+        let mut expected: Vec<Vec<u8>> = (0..50)
+            .map(|i| format!("user:{}", i).into_bytes())
+            .collect();
+        expected.sort();
+        assert_eq!(fields, expected, "should return every matching field once");
+        assert_eq!(
+            iterations, 15,
+            "COUNT 7 should visit 100 fields in 15 steps"
+        );
+
+        let (cursor, items) = storage.hscan(b"hash", 0, b"item:4?", 1000, true).unwrap();
+        assert_eq!(cursor, 0, "a big enough COUNT completes in one step");
+        assert_eq!(items.len(), 10, "NOVALUES should only return fields");
+
+        assert_eq!(
+            storage.hscan(b"missing", 0, b"*", 10, false),
+            Ok((0, Vec::new()))
+        );
+    }
+
+    #[test]
+    fn hscan_modified_test() {
+        let storage = Storage::new(100, 8);
+        let fields: Vec<(Vec<u8>, Vec<u8>)> = (0..100)
+            .map(|i| (format!("field:{}", i).into_bytes(), b"v".to_vec()))
+            .collect();
+        storage.hset(b"hash", &fields).unwrap();
+
+        // between the steps, a field is removed and enough are added to resize the hash
+        let mut cursor = 0;
+        let mut found = Vec::new();
+        let mut removed = Vec::new();
+        let mut step = 0;
+        loop {
+            let (next, items) = storage
+                .hscan(b"hash", cursor, b"field:*", 10, true)
+                .unwrap();
+            found.extend(items);
+            cursor = next;
+            if cursor == 0 {
+                break;
+            }
+            if let Some((field, _)) = fields.get(step * 10) {
+                storage.hdel(b"hash", std::slice::from_ref(field)).unwrap();
+                removed.push(field.clone());
+            }
+            let added: Vec<(Vec<u8>, Vec<u8>)> = (0..50)
+                .map(|i| (format!("new:{}:{}", step, i).into_bytes(), b"v".to_vec()))
+                .collect();
+            storage.hset(b"hash", &added).unwrap();
+            step += 1;
+        }
+        let mut expected: Vec<Vec<u8>> = fields
+            .iter()
+            .map(|(field, _)| field.clone())
+            .filter(|field| !removed.contains(field))
+            .collect();
+        expected.sort();
+        found.retain(|field| !removed.contains(field));
+        found.sort();
+        assert_eq!(
+            found, expected,
+            "every field present from start to end is returned once"
+        );
+    }
+
+    #[test]
+    fn sscan_test() {
+        let storage = Storage::new(100, 8);
+        let members: Vec<Vec<u8>> = (0..30)
+            .map(|i| format!("even:{}", i * 2).into_bytes())
+            .chain((0..30).map(|i| format!("odd:{}", i * 2 + 1).into_bytes()))
+            .collect();
+        assert_eq!(storage.sadd(b"set", &members), Ok(60));
+        assert_eq!(
+            storage.sadd(b"set", &members[..5]),
+            Ok(0),
+            "members are unique"
+        );
+
+        let mut cursor = 0;
+        let mut found = Vec::new();
+        loop {
+            let (next, items) = storage.sscan(b"set", cursor, b"odd:*", 4).unwrap();
+            found.extend(items);
+            cursor = next;
+            if cursor == 0 {
+                break;
+            }
+        }
+        found.sort();
+        let mut expected = members[30..].to_vec();
+        expected.sort();
+        assert_eq!(found, expected, "should return every matching member once");
+
+        storage
+            .hset(b"hash", &[(b"f".to_vec(), b"v".to_vec())])
+            .unwrap();
+        assert_eq!(
+            storage.sscan(b"hash", 0, b"*", 10),
+            Err(StorageError::WrongType)
+        );
+    }
+
+    #[test]
+    fn zscan_test() {
+        let storage = Storage::new(100, 8);
+        let members: Vec<(f64, Vec<u8>)> = (0..40)
+            .map(|i| (i as f64 + 0.5, format!("member:{}", i).into_bytes()))
+            .collect();
+        assert_eq!(storage.zadd(b"zset", &members), Ok(40));
+
+        let mut cursor = 0;
+        let mut found = Vec::new();
+        loop {
+            let (next, items) = storage.zscan(b"zset", cursor, b"member:1*", 3).unwrap();
+            for pair in items.chunks(2) {
+                found.push((pair[0].clone(), pair[1].clone()));
+            }
+            cursor = next;
+            if cursor == 0 {
+                break;
+            }
+        }
+        found.sort();
+        let mut expected: Vec<(Vec<u8>, Vec<u8>)> = members
+            .iter()
+            .filter(|(_, member)| member.starts_with(b"member:1"))
+            .map(|(score, member)| (member.clone(), score.to_string().into_bytes()))
+            .collect();
+        expected.sort();
+        assert_eq!(found.len(), 11, "member:1 and member:10 to member:19");
+        assert_eq!(found, expected, "should return members with their score");
+    }
+
+    #[test]
+    fn compare_and_set_test() {
+        let storage = Storage::new(100, 8);
+        storage
+            .set_kv(b"key", b"v1", Some(Duration::from_secs(10)))
+            .unwrap();
+
+        assert_eq!(
+            storage.compare_and_set(b"key", Some(b"v1"), b"v2", None),
+            Ok(true)
+        );
+        assert_eq!(
+            storage.get_v(b"key"),
+            Ok(Some(Bytes::from_static(b"v2"))),
+            "value should be set when the expected value matches"
+        );
+
+        assert_eq!(
+            storage.compare_and_set(b"key", Some(b"v1"), b"v3", None),
+            Ok(false)
+        );
+        assert_eq!(
+            storage.get_v(b"key"),
+            Ok(Some(Bytes::from_static(b"v2"))),
+            "value should not change when the expected value does not match"
+        );
+
+        let shard = storage.get_shard(b"key").read();
+        assert!(
+            shard.storage[b"key".as_slice()].expires_at.is_some(),
+            "value should keep its expiry without a TTL"
+        );
+        drop(shard);
+
+        assert_eq!(
+            storage.compare_and_set(b"missing", Some(b""), b"v1", None),
+            Ok(false)
+        );
+        assert_eq!(
+            storage.get_v(b"missing"),
+            Ok(None),
+            "a missing key never matches an expected value"
+        );
+        assert_eq!(
+            storage.compare_and_set(b"key", None, b"v1", None),
+            Ok(false),
+            "an existing key is not set without an expected value"
+        );
+        assert_eq!(
+            storage.compare_and_set(b"lock", None, b"owner", Some(Duration::from_millis(1))),
+            Ok(true)
+        );
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(
+            storage.compare_and_set(b"lock", None, b"other", None),
+            Ok(true),
+            "an expired lock can be taken again"
+        );
+        assert_eq!(
+            storage.get_v(b"lock"),
+            Ok(Some(Bytes::from_static(b"other")))
+        );
+
+        storage.sadd(b"set", &[b"m".to_vec()]).unwrap();
+        assert_eq!(
+            storage.compare_and_set(b"set", Some(b"m"), b"v1", None),
+            Err(StorageError::WrongType)
+        );
+        let (keys, memory) = recount(&storage);
+        assert_eq!((storage.len(), storage.used_memory()), (keys, memory));
+    }
+
+    #[test]
+    fn compare_and_set_race_test() {
+        let storage = Arc::new(Storage::new(100, 8));
+        let barrier = Arc::new(std::sync::Barrier::new(16));
+        let threads: Vec<_> = (0..16)
+            .map(|i| {
+                let (storage, barrier) = (storage.clone(), barrier.clone());
+                std::thread::spawn(move || {
+                    let owner = format!("owner:{}", i);
+                    barrier.wait();
+                    let locked = storage
+                        .compare_and_set(b"lock", None, owner.as_bytes(), None)
+                        .unwrap();
+                    barrier.wait();
+                    let current = storage.get_v(b"lock").unwrap().unwrap();
+                    barrier.wait();
+                    let next = format!("next:{}", i);
+                    let swapped = storage
+                        .compare_and_set(b"lock", Some(&current), next.as_bytes(), None)
+                        .unwrap();
+                    (locked, swapped)
+                })
+            })
+            .collect();
+        let results: Vec<_> = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect();
+        assert_eq!(
+            results.iter().filter(|(locked, _)| *locked).count(),
+            1,
+            "only one caller should take the lock"
+        );
+        assert_eq!(
+            results.iter().filter(|(_, swapped)| *swapped).count(),
+            1,
+            "only one caller should replace the value they all expect"
+        );
+    }
+
+    #[test]
+    fn list_test() {
+        let storage = Storage::new(100, 8);
+        let elements =
+            |items: &[&[u8]]| -> Vec<Vec<u8>> { items.iter().map(|i| i.to_vec()).collect() };
+        assert_eq!(storage.rpush(b"list", &elements(&[b"c", b"d"])), Ok(2));
+        assert_eq!(storage.lpush(b"list", &elements(&[b"b", b"a"])), Ok(4));
+        assert_eq!(
+            storage.lrange(b"list", 0, -1),
+            Ok(elements(&[b"a", b"b", b"c", b"d"])),
+            "LPUSH inserts the elements one after the other at the head"
+        );
+
+        for (start, stop, expected) in [
+            (1, 2, elements(&[b"b", b"c"])),
+            (-2, -1, elements(&[b"c", b"d"])),
+            (-100, 1, elements(&[b"a", b"b"])),
+            (2, 100, elements(&[b"c", b"d"])),
+            (3, 1, vec![]),
+            (4, 10, vec![]),
+            (0, -5, vec![]),
+        ] {
+            assert_eq!(
+                storage.lrange(b"list", start, stop),
+                Ok(expected),
+                "LRANGE {} {}",
+                start,
+                stop
+            );
+        }
+        assert_eq!(storage.lrange(b"missing", 0, -1), Ok(vec![]));
+
+        assert_eq!(storage.lpop(b"list", 1), Ok(elements(&[b"a"])));
+        assert_eq!(storage.rpop(b"list", 2), Ok(elements(&[b"d", b"c"])));
+        assert_eq!(storage.used_memory(), b"list".len() + 1);
+        assert_eq!(storage.lpop(b"list", 10), Ok(elements(&[b"b"])));
+        assert_eq!(storage.len(), 0, "an empty list is removed");
+        assert_eq!(storage.used_memory(), 0);
+        assert_eq!(storage.rpop(b"list", 1), Ok(vec![]));
+
+        storage.set_kv(b"string", b"value", None).unwrap();
+        assert_eq!(
+            storage.lpush(b"string", &elements(&[b"a"])),
+            Err(StorageError::WrongType)
+        );
+        assert_eq!(storage.lpop(b"string", 1), Err(StorageError::WrongType));
+        assert_eq!(
+            storage.lrange(b"string", 0, -1),
+            Err(StorageError::WrongType)
+        );
+        storage.rpush(b"list", &elements(&[b"a"])).unwrap();
+        assert_eq!(storage.get_v(b"list"), Err(StorageError::WrongType));
+        let (keys, memory) = recount(&storage);
+        assert_eq!((storage.len(), storage.used_memory()), (keys, memory));
+    }
+
+    #[tokio::test]
+    async fn blocking_pop_test() {
+        let storage = Storage::new(100, 8);
+        let keys = |keys: &[&[u8]]| -> Vec<Vec<u8>> { keys.iter().map(|k| k.to_vec()).collect() };
+        storage.rpush(b"a", &keys(&[b"1", b"2"])).unwrap();
+        let popped = storage.blocking_pop(&keys(&[b"missing", b"a"]), false);
+        assert!(
+            matches!(popped, Ok(BlockingPop::Popped(key, element)) if key == b"a" && element == b"2"),
+            "the first non-empty list is popped right away"
+        );
+        storage.set_kv(b"string", b"v", None).unwrap();
+        assert!(matches!(
+            storage.blocking_pop(&keys(&[b"string"]), true),
+            Err(StorageError::WrongType)
+        ));
+
+        // the clients blocked on a key are served in the order they blocked
+        let blocked = |keys: Vec<Vec<u8>>| match storage.blocking_pop(&keys, true) {
+            Ok(BlockingPop::Blocked(waiter)) => waiter,
+            popped => panic!("should block, got {:?}", popped),
+        };
+        let mut first = blocked(keys(&[b"b", b"c"]));
+        let mut second = blocked(keys(&[b"c"]));
+        let third = blocked(keys(&[b"c"]));
+        assert_eq!(storage.rpush(b"c", &keys(&[b"x", b"y"])), Ok(2));
+        assert_eq!(first.popped().await, Some((b"c".to_vec(), b"x".to_vec())));
+        assert_eq!(second.popped().await, Some((b"c".to_vec(), b"y".to_vec())));
+        assert_eq!(storage.len(), 2, "the served list is emptied and removed");
+        assert_eq!(storage.cancel_pop(first), None);
+        assert_eq!(storage.cancel_pop(second), None);
+
+        // a served client is not served again through its other keys, nor a cancelled one
+        assert_eq!(storage.cancel_pop(third), None);
+        storage.rpush(b"b", &keys(&[b"z"])).unwrap();
+        storage.rpush(b"c", &keys(&[b"z"])).unwrap();
+        assert_eq!(storage.lrange(b"b", 0, -1), Ok(keys(&[b"z"])));
+        assert_eq!(storage.lrange(b"c", 0, -1), Ok(keys(&[b"z"])));
+
+        // an element popped for a client which stops waiting is returned by cancel_pop
+        let waiter = blocked(keys(&[b"d"]));
+        storage.rpush(b"d", &keys(&[b"w"])).unwrap();
+        assert_eq!(
+            storage.cancel_pop(waiter),
+            Some((b"d".to_vec(), b"w".to_vec()))
+        );
+        let (count, memory) = recount(&storage);
+        assert_eq!((storage.len(), storage.used_memory()), (count, memory));
+    }
+
+    #[test]
+    fn get_del_test() {
+        let storage = Storage::new(100, 8);
+        storage.set_kv(b"key", b"value", None).unwrap();
+        assert_eq!(storage.get_del(b"key"), Ok(Some(b"value".to_vec())));
+        assert_eq!(storage.get_v(b"key"), Ok(None), "key should be removed");
+        assert_eq!(storage.get_del(b"key"), Ok(None));
+        assert_eq!(storage.len(), 0);
+
+        storage
+            .set_kv(b"expiring", b"value", Some(Duration::from_millis(1)))
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(storage.get_del(b"expiring"), Ok(None), "key has expired");
+        assert_eq!(storage.len(), 0, "expired key should be removed");
+
+        storage.sadd(b"set", &[b"m".to_vec()]).unwrap();
+        assert_eq!(storage.get_del(b"set"), Err(StorageError::WrongType));
+        assert_eq!(storage.len(), 1, "a key of another type is kept");
+    }
+
+    #[test]
+    fn set_kv_conditional_test() {
+        let storage = Storage::new(100, 8);
+        assert_eq!(
+            storage.set_kv_conditional(b"key", b"v1", None, SetCondition::Exists),
+            Ok(false),
+            "XX should not create a key"
+        );
+        assert_eq!(
+            storage.set_kv_conditional(b"key", b"v1", None, SetCondition::NotExists),
+            Ok(true)
+        );
+        assert_eq!(
+            storage.set_kv_conditional(b"key", b"v2", None, SetCondition::NotExists),
+            Ok(false),
+            "NX should not overwrite a key"
+        );
+        assert_eq!(storage.get_v(b"key"), Ok(Some(Bytes::from_static(b"v1"))));
+        assert_eq!(
+            storage.set_kv_conditional(b"key", b"v3", None, SetCondition::Exists),
+            Ok(true)
+        );
+        assert_eq!(storage.get_v(b"key"), Ok(Some(Bytes::from_static(b"v3"))));
+
+        storage
+            .set_kv(b"expired", b"old", Some(Duration::from_millis(1)))
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(
+            storage.set_kv_conditional(b"expired", b"new", None, SetCondition::NotExists),
+            Ok(true),
+            "an expired key does not exist"
+        );
+        assert_eq!(
+            storage.get_v(b"expired"),
+            Ok(Some(Bytes::from_static(b"new")))
+        );
+        assert_eq!(storage.len(), 2);
+    }
+
+    #[test]
+    fn get_set_test() {
+        let storage = Storage::new(100, 8);
+        assert_eq!(
+            storage.get_set(b"key", b"v1"),
+            Ok(None),
+            "absent key has no previous value"
+        );
+        assert_eq!(storage.get_v(b"key"), Ok(Some(Bytes::from_static(b"v1"))));
+
+        storage
+            .set_kv(b"key", b"v2", Some(Duration::from_millis(1)))
+            .unwrap();
+        assert_eq!(
+            storage.get_set(b"key", b"v3"),
+            Ok(Some(b"v2".to_vec())),
+            "should return the previous value"
+        );
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(
+            storage.get_v(b"key"),
+            Ok(Some(Bytes::from_static(b"v3"))),
+            "the expiry should be cleared"
+        );
+
+        storage.sadd(b"set", &[b"m".to_vec()]).unwrap();
+        assert_eq!(storage.get_set(b"set", b"v"), Err(StorageError::WrongType));
+    }
+
+    #[test]
+    fn get_ex_test() {
+        let storage = Storage::new(100, 8);
+        assert_eq!(storage.get_ex(b"missing", ExpiryUpdate::Keep), Ok(None));
+
+        storage.set_kv(b"key", b"value", None).unwrap();
+        assert_eq!(
+            storage.get_ex(b"key", ExpiryUpdate::After(Duration::from_millis(1))),
+            Ok(Some(b"value".to_vec()))
+        );
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(storage.get_v(b"key"), Ok(None), "key should have expired");
+
+        storage
+            .set_kv(b"key", b"value", Some(Duration::from_millis(1)))
+            .unwrap();
+        assert_eq!(
+            storage.get_ex(b"key", ExpiryUpdate::Persist),
+            Ok(Some(b"value".to_vec()))
+        );
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(
+            storage.get_v(b"key"),
+            Ok(Some(Bytes::from_static(b"value"))),
+            "key should no longer expire"
+        );
+
+        storage.sadd(b"set", &[b"m".to_vec()]).unwrap();
+        assert_eq!(
+            storage.get_ex(b"set", ExpiryUpdate::Persist),
+            Err(StorageError::WrongType)
+        );
+    }
+
+    #[test]
+    fn rename_test() {
+        let storage = Storage::new(100, 8);
+        assert_eq!(
+            storage.rename(b"missing", b"dst", false),
+            RenameResult::NoSuchKey
+        );
+
+        storage
+            .set_kv(b"src", b"value", Some(Duration::from_secs(10)))
+            .unwrap();
+        assert_eq!(storage.rename(b"src", b"dst", false), RenameResult::Renamed);
+        assert_eq!(storage.get_v(b"src"), Ok(None));
+        assert_eq!(
+            storage.get_v(b"dst"),
+            Ok(Some(Bytes::from_static(b"value")))
+        );
+        let shard = storage.get_shard(b"dst").read();
+        assert!(
+            shard.storage[b"dst".as_slice()].expires_at.is_some(),
+            "expiry should move with the value"
+        );
+        drop(shard);
+
+        storage.set_kv(b"other", b"other", None).unwrap();
+        assert_eq!(
+            storage.rename(b"dst", b"other", true),
+            RenameResult::DestinationExists
+        );
+        assert_eq!(
+            storage.get_v(b"dst"),
+            Ok(Some(Bytes::from_static(b"value")))
+        );
+        assert_eq!(
+            storage.rename(b"dst", b"other", false),
+            RenameResult::Renamed
+        );
+        assert_eq!(
+            storage.get_v(b"other"),
+            Ok(Some(Bytes::from_static(b"value")))
+        );
+        assert_eq!(storage.len(), 1);
+        assert_eq!(storage.used_memory(), b"other".len() + b"value".len());
+
+        // many keys, so that some of them are in other shards, renamed back and forth
+        let storage = Arc::new(Storage::new(1000, 8));
+        for i in 0..16 {
+            storage
+                .set_kv(format!("a:{}", i).as_bytes(), b"v", None)
+                .unwrap();
+        }
+        let threads: Vec<_> = (0..2)
+            .map(|t| {
+                let storage = storage.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..500 {
+                        for i in 0..16 {
+                            let (a, b) = (format!("a:{}", i), format!("b:{}", i));
+                            if t == 0 {
+                                storage.rename(a.as_bytes(), b.as_bytes(), false);
+                            } else {
+                                storage.rename(b.as_bytes(), a.as_bytes(), false);
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(
+            storage.len(),
+            16,
+            "renames should neither lose nor duplicate keys"
+        );
+    }
+
+    #[test]
+    fn get_or_insert_with_test() {
+        let storage = Arc::new(Storage::new(100, 8));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(std::sync::Barrier::new(16));
+        let threads: Vec<_> = (0..16)
+            .map(|i| {
+                let (storage, calls, barrier) = (storage.clone(), calls.clone(), barrier.clone());
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    storage
+                        .get_or_insert_with(b"key", None, || {
+                            calls.fetch_add(1, Ordering::SeqCst);
+                            format!("value from {}", i).into_bytes()
+                        })
+                        .unwrap()
+                })
+            })
+            .collect();
+        let values: Vec<Vec<u8>> = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect();
+        assert_eq!(
+            calls.load(Ordering::SeqCst),
+            1,
+            "default should be computed once"
+        );
+        assert!(
+            values.iter().all(|value| *value == values[0]),
+            "every caller should get the stored value"
+        );
+        assert_eq!(storage.get_v(b"key"), Ok(Some(values[0].clone().into())));
+
+        storage.sadd(b"set", &[b"m".to_vec()]).unwrap();
+        assert_eq!(
+            storage.get_or_insert_with(b"set", None, || b"v".to_vec()),
+            Err(StorageError::WrongType)
+        );
+    }
+
+    #[test]
+    fn persist_test() {
+        let storage = Storage::new(100, 8);
+        assert!(!storage.persist(b"missing"), "a missing key has no expiry");
+        storage.set_kv(b"forever", b"value", None).unwrap();
+        assert!(!storage.persist(b"forever"), "the key has no expiry");
+
+        storage
+            .set_kv(b"key", b"value", Some(Duration::from_millis(1)))
+            .unwrap();
+        assert!(storage.persist(b"key"));
+        std::thread::sleep(Duration::from_millis(5));
+        storage.set_kv(b"other", b"value", None).unwrap();
+        assert_eq!(storage.purge_expired(), 0, "the stale record is skipped");
+        assert_eq!(
+            storage.get_v(b"key"),
+            Ok(Some(Bytes::from_static(b"value"))),
+            "key should survive its original expiry"
+        );
+    }
+
+    #[test]
+    fn append_test() {
+        let storage = Storage::new(100, 4);
+        let mut expected = Vec::new();
+        for i in 0..1000 {
+            let chunk = format!("{},", i);
+            expected.extend_from_slice(chunk.as_bytes());
+            assert_eq!(storage.append(b"log", chunk.as_bytes()), Ok(expected.len()));
+        }
+        let read = storage.get_v(b"log").unwrap().unwrap();
+        assert_eq!(read, expected);
+        assert_eq!(storage.used_memory(), b"log".len() + expected.len());
+        assert_eq!(
+            storage.get_v(b"log").unwrap().unwrap().as_ptr(),
+            read.as_ptr(),
+            "readers should share the stored value"
+        );
+        storage.append(b"log", b"end").unwrap();
+        assert_eq!(read, expected, "a value already read is not modified");
+        expected.extend_from_slice(b"end");
+        assert_eq!(storage.get_v(b"log"), Ok(Some(expected.clone().into())));
+        assert_eq!(storage.used_memory(), b"log".len() + expected.len());
+
+        storage
+            .set_kv(b"ttl", b"a", Some(Duration::from_secs(60)))
+            .unwrap();
+        assert_eq!(storage.append(b"ttl", b"b"), Ok(2));
+        let shard = storage.get_shard(b"ttl").read();
+        assert!(
+            shard.storage[b"ttl".as_slice()].expires_at.is_some(),
+            "append should keep the expiry"
+        );
+        drop(shard);
+
+        storage.sadd(b"set", &[b"m".to_vec()]).unwrap();
+        assert_eq!(storage.append(b"set", b"x"), Err(StorageError::WrongType));
+        let (keys, memory) = recount(&storage);
+        assert_eq!((storage.len(), storage.used_memory()), (keys, memory));
+    }
+
+    #[test]
+    fn incr_by_test() {
+        let storage = Storage::new(100, 4);
+        assert_eq!(storage.incr_by(b"counter", 5), Ok(5), "missing key is 0");
+        assert_eq!(storage.incr_by(b"counter", -7), Ok(-2));
+        assert_eq!(
+            storage.get_v(b"counter"),
+            Ok(Some(Bytes::from_static(b"-2")))
+        );
+
+        storage
+            .set_kv(b"ttl", b"1", Some(Duration::from_secs(60)))
+            .unwrap();
+        assert_eq!(storage.incr_by(b"ttl", 1), Ok(2));
+        let shard = storage.get_shard(b"ttl").read();
+        assert!(
+            shard.storage[b"ttl".as_slice()].expires_at.is_some(),
+            "increment should keep the expiry"
+        );
+        drop(shard);
+
+        storage.set_kv(b"text", b"one", None).unwrap();
+        assert_eq!(storage.incr_by(b"text", 1), Err(StorageError::NotInteger));
+        storage
+            .set_kv(b"max", i64::MAX.to_string().as_bytes(), None)
+            .unwrap();
+        assert_eq!(storage.incr_by(b"max", 1), Err(StorageError::Overflow));
+        assert_eq!(storage.get_v(b"max"), Ok(Some(i64::MAX.to_string().into())));
+        storage.sadd(b"set", &[b"m".to_vec()]).unwrap();
+        assert_eq!(storage.incr_by(b"set", 1), Err(StorageError::WrongType));
+
+        // concurrent increments of the same key
+        let storage = Arc::new(Storage::new(100, 4));
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let storage = storage.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        storage.incr_by(b"counter", 1).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(
+            storage.get_v(b"counter"),
+            Ok(Some(Bytes::from_static(b"8000"))),
+            "no increment should be lost"
+        );
+    }
+
+    #[test]
+    fn incr_by_float_test() {
+        let storage = Storage::new(100, 4);
+        storage.set_kv(b"key", b"10.50", None).unwrap();
+        for (delta, expected) in [
+            (0.1, "10.6"),
+            (-5.6, "5"),
+            (3.0e3, "3005"),
+            (-3005.0, "0"),
+            (-0.0, "0"),
+            (1.5e-5, "0.000015"),
+            (1e20, "100000000000000000000"),
+        ] {
+            assert_eq!(
+                storage.incr_by_float(b"key", delta),
+                Ok(expected.to_string())
+            );
+            assert_eq!(
+                storage.get_v(b"key"),
+                Ok(Some(Bytes::copy_from_slice(expected.as_bytes())))
+            );
+        }
+        assert_eq!(
+            storage.incr_by_float(b"missing", -2.5),
+            Ok("-2.5".to_string()),
+            "missing key is 0"
+        );
+        storage.set_kv(b"exp", b"5.0e3", None).unwrap();
+        assert_eq!(storage.incr_by_float(b"exp", 2.0e2), Ok("5200".to_string()));
+
+        storage
+            .set_kv(b"ttl", b"1", Some(Duration::from_secs(60)))
+            .unwrap();
+        assert_eq!(storage.incr_by_float(b"ttl", 0.5), Ok("1.5".to_string()));
+        let shard = storage.get_shard(b"ttl").read();
+        assert!(
+            shard.storage[b"ttl".as_slice()].expires_at.is_some(),
+            "increment should keep the expiry"
+        );
+        drop(shard);
+
+        storage.set_kv(b"text", b"one", None).unwrap();
+        assert_eq!(
+            storage.incr_by_float(b"text", 1.0),
+            Err(StorageError::NotFloat)
+        );
+        storage.set_kv(b"inf", b"inf", None).unwrap();
+        assert_eq!(
+            storage.incr_by_float(b"inf", 1.0),
+            Err(StorageError::NotFloat)
+        );
+        storage
+            .set_kv(b"max", f64::MAX.to_string().as_bytes(), None)
+            .unwrap();
+        assert_eq!(
+            storage.incr_by_float(b"max", f64::MAX),
+            Err(StorageError::NotFinite)
+        );
+        storage.sadd(b"set", &[b"m".to_vec()]).unwrap();
+        assert_eq!(
+            storage.incr_by_float(b"set", 1.0),
+            Err(StorageError::WrongType)
+        );
+    }
+
+    #[test]
+    fn expire_test() {
+        let storage = Storage::new(100, 4);
+        assert!(
+            !storage.expire(b"missing", 100),
+            "missing key has no expiry"
+        );
+        assert!(!storage.expire(b"missing", 0));
+
+        storage.set_kv(b"key", b"value", None).unwrap();
+        assert!(storage.expire(b"key", 5));
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(storage.get_v(b"key"), Ok(None), "key should expire");
+
+        storage.set_kv(b"key", b"value", None).unwrap();
+        assert!(
+            storage.expire(b"key", 0),
+            "non-positive TTL deletes the key"
+        );
+        assert_eq!(storage.len(), 0, "deleted key should not linger");
+        assert_eq!(storage.used_memory(), 0);
+        storage.set_kv(b"key", b"value", None).unwrap();
+        assert!(storage.expire(b"key", -10));
+        assert!(storage.is_empty());
+
+        storage.set_kv(b"key", b"value", None).unwrap();
+        assert!(
+            storage.expire(b"key", i64::MAX),
+            "can expire in a very long time"
+        );
+        assert_eq!(
+            storage.get_v(b"key"),
+            Ok(Some(Bytes::from_static(b"value")))
+        );
+    }
+
+    #[test]
+    fn pinned_keys_test() {
+        let storage = Storage::new(5, 1);
+        storage.set_kv(b"critical", b"value", None).unwrap();
+        assert_eq!(
+            storage.set_pinned(&[b"critical".to_vec(), b"missing".to_vec()], true),
+            1,
+            "only existing keys can be pinned"
+        );
+
+        for i in 0..20 {
+            storage
+                .set_kv(format!("key:{}", i).as_bytes(), b"value", None)
+                .unwrap();
+        }
+        assert_eq!(storage.len(), 5, "unpinned keys should be evicted");
+        assert_eq!(
+            storage.get_v(b"critical"),
+            Ok(Some(Bytes::from_static(b"value"))),
+            "pinned key should survive"
+        );
+
+        assert_eq!(storage.set_pinned(&[b"critical".to_vec()], false), 1);
+        assert!(!storage.get_shard(b"critical").read().storage[b"critical".as_slice()].pinned);
+
+        storage
+            .set_kv(b"expiring", b"value", Some(Duration::from_millis(1)))
+            .unwrap();
+        storage.set_pinned(&[b"expiring".to_vec()], true);
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(
+            storage.get_v(b"expiring"),
+            Ok(None),
+            "pinned key still expires"
+        );
+    }
+
+    #[test]
+    fn scan_test() {
+        let storage = Storage::new(1000, 8);
+        for i in 0..100 {
+            storage
+                .set_kv(format!("user:{}", i).as_bytes(), b"v", None)
+                .unwrap();
+            storage
+                .set_kv(format!("item:{}", i).as_bytes(), b"v", None)
+                .unwrap();
+        }
+        storage
+            .set_kv(b"user:expired", b"v", Some(Duration::from_millis(1)))
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+
+        let mut cursor = 0;
+        let mut keys = Vec::new();
+        let mut iterations = 0;
+        loop {
+            let (next, found) = storage.scan(cursor, b"user:*", 15);
+            assert!(found.len() <= 15, "COUNT bounds the keys visited per step");
+            keys.extend(found);
+            iterations += 1;
+            cursor = next;
+            if cursor == 0 {
+                break;
+            }
+        }
+        keys.sort();
+        let mut expected: Vec<Vec<u8>> = (0..100)
+            .map(|i| format!("user:{}", i).into_bytes())
+            .collect();
+        expected.sort();
+        assert_eq!(keys, expected, "should return every matching key once");
+        assert!(
+            (14..=21).contains(&iterations),
+            "should visit about COUNT keys per step, took {} steps",
+            iterations
+        );
+
+        let (cursor, keys) = storage.scan(0, b"*", 1000);
+        assert_eq!(cursor, 0, "a big enough COUNT completes in one step");
+        assert_eq!(keys.len(), 200);
+    }
+
+    #[test]
+    fn scan_deleted_test() {
+        let storage = Storage::new(1000, 8);
+        let keys: Vec<Vec<u8>> = (0..200)
+            .map(|i| format!("key:{}", i).into_bytes())
+            .collect();
+        for key in &keys {
+            storage.set_kv(key, b"v", None).unwrap();
+        }
+
+        // between the pages, keys already returned and keys still to come are deleted
+        let mut cursor = 0;
+        let mut found = Vec::new();
+        let mut deleted = Vec::new();
+        loop {
+            let (next, page) = storage.scan(cursor, b"*", 10);
+            cursor = next;
+            if cursor == 0 {
+                found.extend(page);
+                break;
+            }
+            let gone: Vec<Vec<u8>> = keys
+                .iter()
+                .filter(|key| !deleted.contains(*key))
+                .step_by(7)
+                .take(3)
+                .cloned()
+                .collect();
+            storage.del_entries(&gone);
+            deleted.extend(gone);
+            found.extend(page);
+        }
+        found.retain(|key| !deleted.contains(key));
+        found.sort();
+        let mut surviving: Vec<Vec<u8>> = keys
+            .into_iter()
+            .filter(|key| !deleted.contains(key))
+            .collect();
+        surviving.sort();
+        assert!(!deleted.is_empty());
+        assert_eq!(found, surviving, "every surviving key is returned once");
+    }
+
+    #[test]
+    fn lock_contention_test() {
+        let storage = Arc::new(Storage::new(1000, 8));
+        let keys: Vec<Vec<u8>> = (0..)
+            .map(|i| format!("key:{}", i).into_bytes())
+            .filter(|key| storage.shard_index(key) == 3)
+            .take(8)
+            .collect();
+
+        let writers: Vec<_> = keys
+            .into_iter()
+            .map(|key| {
+                let storage = storage.clone();
+                std::thread::spawn(move || {
+                    for i in 0..10000 {
+                        storage
+                            .set_kv(&key, i.to_string().as_bytes(), None)
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let contention = storage.lock_contention();
+        for (index, count) in contention.iter().enumerate() {
+            if index != 3 {
+                assert!(
+                    contention[3] > *count,
+                    "hot shard should be more contended than shard {}: {:?}",
+                    index,
+                    contention
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn shard_stats_test() {
+        let storage = Storage::new(1000, 4);
+        assert_eq!(storage.shard_stats(), vec![ShardStat::default(); 4]);
+
+        let keys: Vec<Vec<u8>> = (0..)
+            .map(|i: u32| format!("key{}", i).into_bytes())
+            .filter(|key| storage.shard_index(key) == 1)
+            .take(10)
+            .collect();
+        for key in &keys {
+            storage.set_kv(key, b"v", None).unwrap();
+            storage.get_v(key).unwrap();
+        }
+        let stats = storage.shard_stats();
+        assert_eq!(
+            stats[1],
+            ShardStat {
+                keys: 10,
+                reads: 10,
+                writes: 10,
+                contended: 0,
+            }
+        );
+        for (index, stat) in stats.iter().enumerate() {
+            if index != 1 {
+                assert_eq!(*stat, ShardStat::default(), "shard {} was not used", index);
+            }
+        }
+    }
+
+    #[test]
+    fn max_memory_noeviction_test() {
+        let storage = Storage::with_memory_limit(100, 8, 100, EvictionPolicy::Noeviction);
+        let mut i = 0;
+        while storage.used_memory() <= 100 {
+            storage
+                .set_kv(
+                    format!("key:{:02}", i).as_bytes(),
+                    b"0123456789",
+                    Some(Duration::from_secs(10)),
+                )
+                .unwrap();
+            i += 1;
+        }
+        assert_eq!(i, 7, "each entry should account for 16 bytes");
+        assert_eq!(storage.used_memory(), 112);
+
+        assert_eq!(
+            storage.set_kv(b"key", b"value", Some(Duration::from_secs(10))),
+            Err(StorageError::OutOfMemory),
+            "writes are rejected once over the limit"
+        );
+        assert_eq!(
+            storage.hset(b"hash", &[(b"f".to_vec(), b"v".to_vec())]),
+            Err(StorageError::OutOfMemory)
+        );
+        assert_eq!(
+            storage.get_v(b"key:00"),
+            Ok(Some(Bytes::from_static(b"0123456789"))),
+            "reads still work"
+        );
+
+        // deleting makes room again
+        storage.del_entries(&[b"key:00".to_vec()]);
+        assert_eq!(storage.used_memory(), 96);
+        assert_eq!(
+            storage.set_kv(b"key", b"value", Some(Duration::from_secs(10))),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn max_memory_volatile_lru_test() {
+        // a single shard, so that every key is sampled
+        let storage = Storage::with_memory_limit(100, 1, 64, EvictionPolicy::VolatileLru);
+        let ttl = Some(Duration::from_secs(10));
+        storage.set_kv(b"keep:0", b"0123456789", None).unwrap();
+        for key in [b"vol:00", b"vol:01", b"vol:02", b"vol:03"] {
+            storage.set_kv(key, b"0123456789", ttl).unwrap();
+            std::thread::sleep(Duration::from_millis(3));
+        }
+        storage.get_v(b"vol:00").unwrap();
+        assert_eq!(storage.used_memory(), 80);
+
+        storage.set_kv(b"vol:04", b"0123456789", ttl).unwrap();
+        assert_eq!(
+            storage.used_memory(),
+            80,
+            "one key was evicted to get under the limit"
+        );
+        assert_eq!(
+            storage.get_v(b"vol:01"),
+            Ok(None),
+            "the least recently used is evicted"
+        );
+        for key in [b"keep:0", b"vol:00", b"vol:02", b"vol:03", b"vol:04"] {
+            assert!(storage.get_v(key).unwrap().is_some());
+        }
+
+        // only the keys with an expiration can be evicted
+        let storage = Storage::with_memory_limit(100, 1, 10, EvictionPolicy::VolatileLru);
+        storage.set_kv(b"keep:0", b"0123456789", None).unwrap();
+        assert_eq!(
+            storage.set_kv(b"vol:00", b"0123456789", ttl),
+            Err(StorageError::OutOfMemory)
+        );
+    }
+
+    #[test]
+    fn max_memory_allkeys_random_test() {
+        let storage = Storage::with_memory_limit(100, 4, 100, EvictionPolicy::AllkeysRandom);
+        for i in 0..50 {
+            let key = format!("key:{:02}", i);
+            storage.set_kv(key.as_bytes(), b"0123456789", None).unwrap();
+            assert!(
+                storage.used_memory() <= 100 + 16,
+                "the memory is brought back under the limit before each write"
+            );
+            assert!(storage.get_v(key.as_bytes()).unwrap().is_some());
+        }
+        assert_eq!(recount(&storage), (storage.len(), storage.used_memory()));
+        assert!(storage.len() < 50);
+
+        // a write holding a shard lock can still evict from the other shards
+        for i in 0..50 {
+            let key = format!("lazy:{:02}", i);
+            storage
+                .get_or_insert_with(key.as_bytes(), None, || b"0123456789".to_vec())
+                .unwrap();
+        }
+        assert!(storage.used_memory() <= 100 + 17);
+    }
+
+    #[test]
+    fn eviction_sampling_test() {
+        for policy in [EvictionPolicy::AllkeysRandom, EvictionPolicy::VolatileLru] {
+            // a single shard, and no memory limit to reach: the keys are evicted one by one
+            let storage = Storage::with_memory_limit(200, 1, usize::MAX, policy);
+            for i in 0..100 {
+                let key = format!("key:{:02}", i);
+                storage
+                    .set_kv(key.as_bytes(), b"v", Some(Duration::from_secs(10)))
+                    .unwrap();
+            }
+            let order: Vec<_> = storage.shards[0].read().storage.keys().cloned().collect();
+            for _ in 0..10 {
+                assert!(storage.evict_one(None));
+            }
+            assert_eq!(storage.len(), 90);
+
+            // the evicted keys are not all taken from the start of the iteration order
+            let last_evicted = order
+                .iter()
+                .rposition(|key| storage.get_v(key).unwrap().is_none())
+                .unwrap();
+            assert!(
+                last_evicted >= order.len() / 2,
+                "{:?} evicted from a fixed prefix",
+                policy
+            );
+        }
+    }
+
+    #[test]
+    fn eviction_waits_for_locks_test() {
+        let storage = Arc::new(Storage::with_memory_limit(
+            100,
+            1,
+            32,
+            EvictionPolicy::AllkeysRandom,
+        ));
+        for key in [b"key:00", b"key:01", b"key:02"] {
+            storage.set_kv(key, b"0123456789", None).unwrap();
+        }
+        assert!(storage.used_memory() > 32);
+
+        // a shard locked for a moment by another operation still gets a key evicted
+        let reading = storage.shards[0].read();
+        let writer = std::thread::spawn({
+            let storage = storage.clone();
+            move || storage.set_kv(b"key:03", b"0123456789", None)
+        });
+        std::thread::sleep(Duration::from_millis(20));
+        drop(reading);
+        assert_eq!(writer.join().unwrap(), Ok(None));
+        assert!(storage.used_memory() <= 32 + 16);
+    }
+
+    // recount walks the shards to get the number of keys and the memory they use, to check the
+    // counters maintained by the storage.
+    fn recount(storage: &Storage) -> (usize, usize) {
+        let (mut keys, mut memory) = (0, 0);
+        for shard in &storage.shards {
+            let shard = shard.read();
+            keys += shard.storage.len();
+            memory += shard
+                .storage
+                .iter()
+                .map(|(key, entry)| key.len() + entry.value.mem_size())
+                .sum::<usize>();
+        }
+        (keys, memory)
+    }
+
+    #[test]
+    fn size_accounting_test() {
+        // a single shard, so that the capacity is exact
+        let storage = Storage::new(20, 1);
+        for i in 0..10 {
+            storage
+                .set_kv(
+                    format!("short:{}", i).as_bytes(),
+                    b"v",
+                    Some(Duration::from_millis(50)),
+                )
+                .unwrap();
+        }
+        for i in 0..5 {
+            storage
+                .hset(
+                    format!("hash:{}", i).as_bytes(),
+                    &[(b"f".to_vec(), b"v".to_vec())],
+                )
+                .unwrap();
+        }
+        assert_eq!(storage.len(), 15);
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(
+            storage.get_v(b"short:0"),
+            Ok(None),
+            "expired keys are not returned"
+        );
+
+        // the writes remove the expired keys, then evict keys once full
+        for i in 0..30 {
+            storage
+                .set_kv(format!("long:{}", i).as_bytes(), b"value", None)
+                .unwrap();
+        }
+        assert_eq!(storage.len(), 20, "the storage should be full");
+        storage.sadd(b"set", &[b"m1".to_vec()]).unwrap();
+        storage
+            .set_kv(b"expiring", b"v", Some(Duration::from_millis(1)))
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(storage.purge_expired(), 1);
+        let keys: Vec<Vec<u8>> = (0..30)
+            .map(|i| format!("long:{}", i).into_bytes())
+            .collect();
+        let deleted = storage.del_entries(&keys);
+        assert!(deleted > 0);
+
+        let (keys, memory) = recount(&storage);
+        assert_eq!(storage.len(), keys, "len should match the stored keys");
+        assert_eq!(storage.used_memory(), memory);
+        assert!(storage.len() <= 20);
+    }
+
+    #[tokio::test]
+    async fn active_eviction_test() {
+        let storage = Arc::new(Storage::new(1000, 2));
+        for i in 0..50 {
+            storage
+                .set_kv(
+                    format!("key:{}", i).as_bytes(),
+                    b"value",
+                    Some(Duration::from_millis(1)),
+                )
+                .unwrap();
+        }
+        storage.set_kv(b"persistent", b"value", None).unwrap();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert_eq!(storage.purge_expired_batch(10), 20, "10 keys per shard");
+
+        let eviction = tokio::spawn(
+            storage
+                .clone()
+                .run_active_eviction(Duration::from_millis(5), 10),
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        eviction.abort();
+        assert_eq!(
+            storage.len(),
+            1,
+            "the expired keys are removed without any traffic"
+        );
+        let (keys, memory) = recount(&storage);
+        assert_eq!(keys, 1);
+        assert_eq!(storage.used_memory(), memory);
+    }
+
+    #[test]
+    fn object_test() {
+        let storage = Storage::new(100, 4);
+        let long = vec![b'a'; EMBSTR_MAX_LEN + 1];
+        let strings: &[(&[u8], &str)] = &[
+            (b"42", "int"),
+            (b"-7", "int"),
+            (b"9223372036854775807", "int"),
+            (b"9223372036854775808", "embstr"),
+            (b"007", "embstr"),
+            (b"1.5", "embstr"),
+            (b"hello", "embstr"),
+            (&long[..EMBSTR_MAX_LEN], "embstr"),
+            (&long, "raw"),
+        ];
+        for (value, encoding) in strings {
+            storage.set_kv(b"key", value, None).unwrap();
+            assert_eq!(
+                storage.object_encoding(b"key"),
+                Some(*encoding),
+                "encoding of {:?}",
+                String::from_utf8_lossy(value)
+            );
+        }
+        storage
+            .hset(b"hash", &[(b"f".to_vec(), b"v".to_vec())])
+            .unwrap();
+        assert_eq!(storage.object_encoding(b"hash"), Some("hashtable"));
+        assert_eq!(storage.object_encoding(b"missing"), None);
+        assert_eq!(storage.object_idletime(b"missing"), None);
+
+        std::thread::sleep(Duration::from_millis(30));
+        let idle = storage.object_idletime(b"key").unwrap();
+        assert!(idle >= Duration::from_millis(30), "idle time should grow");
+        assert!(
+            storage.object_idletime(b"key").unwrap() >= idle,
+            "OBJECT is not an access"
+        );
+        storage.get_v(b"key").unwrap();
+        assert!(
+            storage.object_idletime(b"key").unwrap() < Duration::from_millis(30),
+            "GET should reset the idle time"
+        );
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(storage.touch(b"hash"));
+        assert!(
+            storage.object_idletime(b"hash").unwrap() < Duration::from_millis(30),
+            "TOUCH should reset the idle time"
+        );
+        assert!(!storage.touch(b"missing"));
+        storage
+            .set_kv(b"expired", b"v", Some(Duration::from_millis(1)))
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(!storage.touch(b"expired"), "an expired key does not exist");
+    }
+
+    #[test]
+    fn shard_count_rounding_test() {
+        for (requested, expected) in [(0, 1), (1, 1), (3, 4), (8, 8), (10, 16)] {
+            let storage = Storage::new(100, requested);
+            assert_eq!(
+                storage.shard_count, expected,
+                "{} shards requested",
+                requested
+            );
+            assert_eq!(storage.shards.len(), expected);
+        }
+
+        let storage = Storage::new(100, 10);
+        for i in 0..50 {
+            let key = format!("key:{}", i);
+            storage.set_kv(key.as_bytes(), b"value", None).unwrap();
+            assert_eq!(
+                storage.get_v(key.as_bytes()),
+                Ok(Some(Bytes::from_static(b"value")))
+            );
+        }
+        assert_eq!(storage.len(), 50);
+    }
+
+    #[test]
+    fn shard_distribution_test() {
+        let storage = Storage::new(100, 16);
+        let key_sets: [Vec<Vec<u8>>; 3] = [
+            (0..10000)
+                .map(|i| format!("key:{}", i).into_bytes())
+                .collect(),
+            (0..10000u32).map(|i| i.to_le_bytes().to_vec()).collect(),
+            (0..10000u64)
+                .map(|i| (i << 40).to_be_bytes().to_vec())
+                .collect(),
+        ];
+        for keys in key_sets {
+            let mut counts = [0usize; 16];
+            for key in &keys {
+                counts[storage.shard_index(key)] += 1;
+            }
+            let average = keys.len() / counts.len();
+            assert!(
+                counts
+                    .iter()
+                    .all(|&count| count > average / 2 && count < average * 2),
+                "uneven distribution: {:?}",
+                counts
+            );
+        }
+        assert_eq!(
+            storage.shard_index(b"key"),
+            storage.shard_index(b"key"),
+            "a key always maps to the same shard"
+        );
+    }
+
+    #[test]
+    fn msetnx_test() {
+        let storage = Storage::new(100, 8);
+        let pairs: Vec<(Vec<u8>, Vec<u8>)> = (0..20)
+            .map(|i| {
+                (
+                    format!("key:{}", i).into_bytes(),
+                    format!("v{}", i).into_bytes(),
+                )
+            })
+            .collect();
+        assert_eq!(storage.msetnx(&pairs), Ok(true), "no key exists");
+        for (key, value) in &pairs {
+            assert_eq!(storage.get_v(key), Ok(Some(value.clone().into())));
+        }
+
+        let pairs = [
+            (b"new:1".to_vec(), b"a".to_vec()),
+            (b"key:7".to_vec(), b"b".to_vec()),
+            (b"new:2".to_vec(), b"c".to_vec()),
+        ];
+        assert_eq!(storage.msetnx(&pairs), Ok(false), "key:7 exists");
+        assert_eq!(storage.get_v(b"new:1"), Ok(None), "nothing is set");
+        assert_eq!(storage.get_v(b"new:2"), Ok(None));
+        assert_eq!(storage.get_v(b"key:7"), Ok(Some(Bytes::from_static(b"v7"))));
+
+        storage
+            .set_kv(b"expired", b"old", Some(Duration::from_millis(1)))
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        let pairs = [
+            (b"expired".to_vec(), b"1".to_vec()),
+            (b"expired".to_vec(), b"2".to_vec()),
+        ];
+        assert_eq!(
+            storage.msetnx(&pairs),
+            Ok(true),
+            "expired keys do not exist"
+        );
+        assert_eq!(
+            storage.get_v(b"expired"),
+            Ok(Some(Bytes::from_static(b"2")))
+        );
+        let (keys, memory) = recount(&storage);
+        assert_eq!((storage.len(), storage.used_memory()), (keys, memory));
+    }
+
+    #[test]
+    fn string_commands_wrong_type_test() {
+        let storage = Storage::new(100, 4);
+        storage
+            .hset(b"hash", &[(b"f".to_vec(), b"v".to_vec())])
+            .unwrap();
+        storage.sadd(b"set", &[b"m".to_vec()]).unwrap();
+        storage.zadd(b"zset", &[(1.0, b"m".to_vec())]).unwrap();
+        storage.rpush(b"list", &[b"e".to_vec()]).unwrap();
+        let memory = storage.used_memory();
+
+        for key in [b"hash".as_slice(), b"set", b"zset", b"list"] {
+            let errors = [
+                storage.get_v(key).err(),
+                storage.get_del(key).err(),
+                storage.get_set(key, b"v").err(),
+                storage.get_ex(key, ExpiryUpdate::Persist).err(),
+                storage.get_or_insert_with(key, None, Vec::new).err(),
+                storage.append(key, b"v").err(),
+                storage.incr_by(key, 1).err(),
+                storage.incr_by_float(key, 1.0).err(),
+            ];
+            assert!(
+                errors
+                    .iter()
+                    .all(|error| *error == Some(StorageError::WrongType)),
+                "string commands on {:?}: {:?}",
+                String::from_utf8_lossy(key),
+                errors
+            );
+        }
+        assert_eq!(storage.len(), 4, "the keys are left untouched");
+        assert_eq!(storage.used_memory(), memory);
+    }
+
+    #[test]
+    fn unlink_test() {
+        let storage = Storage::new(1000000, 4);
+        let elements: Vec<Vec<u8>> = (0..10000).map(|i| i.to_string().into_bytes()).collect();
+        storage.rpush(b"list", &elements).unwrap();
+        storage.set_kv(b"string", b"value", None).unwrap();
+        storage
+            .set_kv(b"expired", b"value", Some(Duration::from_millis(1)))
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+
+        let keys = [
+            b"list".to_vec(),
+            b"string".to_vec(),
+            b"expired".to_vec(),
+            b"missing".to_vec(),
+        ];
+        assert_eq!(storage.unlink_entries(&keys), 2);
+        assert_eq!(storage.lrange(b"list", 0, -1), Ok(vec![]));
+        assert_eq!(storage.get_v(b"string"), Ok(None));
+        assert!(storage.is_empty(), "the keys are gone right away");
+        assert_eq!(storage.used_memory(), 0);
+        assert_eq!(storage.unlink_entries(&keys), 0);
+    }
+
+    #[test]
+    fn del_entries_detailed_test() {
+        let storage = Storage::new(1000, 4);
+        storage.set_kv(b"a", b"1", None).unwrap();
+        storage.set_kv(b"c", b"3", None).unwrap();
+        storage
+            .set_kv(b"expired", b"value", Some(Duration::from_millis(1)))
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+
+        let keys = [
+            b"a".to_vec(),
+            b"b".to_vec(),
+            b"expired".to_vec(),
+            b"c".to_vec(),
+            b"a".to_vec(),
+        ];
+        assert_eq!(
+            storage.del_entries_detailed(&keys),
+            [true, false, false, true, false]
+        );
+        assert!(storage.is_empty());
+    }
+
+    #[test]
+    fn del_size_test() {
+        let storage = Storage::new(1000, 4);
+        let absent = [b"a".to_vec(), b"b".to_vec()];
+        for _ in 0..3 {
+            assert_eq!(storage.del_entries(&absent), 0);
+        }
+        assert_eq!(storage.len(), 0);
+
+        // a key which expired is only removed once, whether by DEL or by the purge
+        storage.set_kv(b"kept", b"value", None).unwrap();
+        storage
+            .set_kv(b"expired", b"value", Some(Duration::from_millis(1)))
+            .unwrap();
+        assert_eq!(storage.len(), 2);
+        std::thread::sleep(Duration::from_millis(5));
+        let expired = [b"expired".to_vec()];
+        assert_eq!(storage.del_entries(&expired), 0);
+        assert_eq!(storage.del_entries(&expired), 0);
+        storage.purge_expired();
+        assert_eq!(storage.len(), 1);
+        assert_eq!(storage.del_entries(&[b"kept".to_vec()]), 1);
+        assert_eq!(storage.len(), 0);
+        assert_eq!(storage.used_memory(), 0);
+    }
+
+    #[test]
+    fn set_kv_keepttl_test() {
+        let storage = Storage::new(100, 4);
+        storage
+            .set_kv(b"key", b"v1", Some(Duration::from_millis(50)))
+            .unwrap();
+        assert_eq!(
+            storage.set_kv_keepttl(b"key", b"v2"),
+            Ok(Some(b"v1".to_vec()))
+        );
+        for _ in 0..10 {
+            storage.set_kv_keepttl(b"key", b"v2").unwrap();
+        }
+        assert_eq!(storage.get_v(b"key").unwrap().unwrap(), b"v2".as_slice());
+        let records: usize = storage
+            .shards
+            .iter()
+            .map(|shard| shard.read().eviction_state.len())
+            .sum();
+        assert_eq!(records, 1, "the unchanged expiry is recorded once");
+        std::thread::sleep(Duration::from_millis(80));
+        assert_eq!(
+            storage.get_v(b"key"),
+            Ok(None),
+            "the key expires on its original schedule"
+        );
+
+        // a key without an expiry, or a new one, never expires
+        assert_eq!(storage.set_kv_keepttl(b"key", b"v3"), Ok(None));
+        storage.set_kv(b"persistent", b"v1", None).unwrap();
+        assert_eq!(
+            storage.set_kv_keepttl(b"persistent", b"v2"),
+            Ok(Some(b"v1".to_vec()))
+        );
+        assert_eq!(storage.purge_expired(), 0);
+        assert_eq!(storage.len(), 2);
+    }
+
+    #[test]
+    fn getrange_test() {
+        let storage = Storage::new(100, 4);
+        storage.set_kv(b"key", b"Hello, World", None).unwrap();
+        let cases: &[(i64, i64, &[u8])] = &[
+            (0, 4, b"Hello"),
+            (7, 100, b"World"),
+            (-5, -1, b"World"),
+            (-100, 1, b"He"),
+            (0, -100, b"H"),
+            (5, 2, b""),
+            (-1, -2, b""),
+            (12, 20, b""),
+        ];
+        for &(start, end, expected) in cases {
+            assert_eq!(
+                storage.getrange(b"key", start, end).unwrap(),
+                expected,
+                "range {} {}",
+                start,
+                end
+            );
+        }
+        assert_eq!(storage.getrange(b"missing", 0, -1).unwrap(), b"".as_slice());
+        storage.set_kv(b"empty", b"", None).unwrap();
+        assert_eq!(storage.getrange(b"empty", 0, -1).unwrap(), b"".as_slice());
+        storage.rpush(b"list", &[b"a".to_vec()]).unwrap();
+        assert_eq!(
+            storage.getrange(b"list", 0, -1),
+            Err(StorageError::WrongType)
+        );
+    }
+
+    #[test]
+    fn setrange_test() {
+        let storage = Storage::new(100, 4);
+        storage
+            .set_kv(b"key", b"Hello World", Some(Duration::from_secs(100)))
+            .unwrap();
+        let used = storage.used_memory();
+        assert_eq!(storage.setrange(b"key", 6, b"Redis"), Ok(11));
+        assert_eq!(
+            storage.get_v(b"key").unwrap().unwrap(),
+            b"Hello Redis".as_slice()
+        );
+        assert_eq!(storage.used_memory(), used, "overwriting does not grow");
+        assert_eq!(storage.setrange(b"key", 9, b"dish"), Ok(13));
+        assert_eq!(
+            storage.get_v(b"key").unwrap().unwrap(),
+            b"Hello Reddish".as_slice()
+        );
+        assert_eq!(storage.used_memory(), used + 2);
+        assert_eq!(storage.purge_expired(), 0);
+        assert_eq!(storage.len(), 1, "the key keeps its expiry");
+
+        // beyond the end, the string is padded with zero bytes
+        assert_eq!(storage.setrange(b"new", 3, b"ab"), Ok(5));
+        assert_eq!(
+            storage.get_v(b"new").unwrap().unwrap(),
+            b"\0\0\0ab".as_slice()
+        );
+        assert_eq!(storage.setrange(b"new", 7, b"c"), Ok(8));
+        assert_eq!(
+            storage.get_v(b"new").unwrap().unwrap(),
+            b"\0\0\0ab\0\0c".as_slice()
+        );
+
+        // an empty chunk changes nothing
+        assert_eq!(storage.setrange(b"new", 100, b""), Ok(8));
+        assert_eq!(storage.setrange(b"missing", 3, b""), Ok(0));
+        assert_eq!(storage.len(), 2);
+        storage.rpush(b"list", &[b"a".to_vec()]).unwrap();
+        assert_eq!(
+            storage.setrange(b"list", 0, b"b"),
+            Err(StorageError::WrongType)
+        );
     }
 }
@@ -0,0 +1,133 @@
+//! Glob-style pattern matching as used by the MATCH option of the SCAN family of commands.
+//! It supports the same syntax as Redis:
+//! - `?` matches any single character
+//! - `*` matches any sequence of characters, including an empty one
+//! - `[abc]`, `[^abc]` and `[a-z]` match a character from (or not from) a set
+//! - `\x` matches the character x literally
+
+pub(crate) fn glob_match(pattern: &[u8], input: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // Position to come back to when a match fails after a star: the pattern index right after the
+    // star, and the input index the star has consumed up to.
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while i < input.len() {
+        if p < pattern.len() {
+            match pattern[p] {
+                b'*' => {
+                    backtrack = Some((p + 1, i));
+                    p += 1;
+                    continue;
+                }
+                b'?' => {
+                    p += 1;
+                    i += 1;
+                    continue;
+                }
+                b'[' => {
+                    if let Some((matched, next)) = match_class(pattern, p, input[i]) {
+                        if matched {
+                            p = next;
+                            i += 1;
+                            continue;
+                        }
+                    }
+                }
+                b'\\' if p + 1 < pattern.len() => {
+                    if pattern[p + 1] == input[i] {
+                        p += 2;
+                        i += 1;
+                        continue;
+                    }
+                }
+                c => {
+                    if c == input[i] {
+                        p += 1;
+                        i += 1;
+                        continue;
+                    }
+                }
+            }
+        }
+        // mismatch, let the last star consume one more character if there is one
+        match backtrack {
+            Some((star_p, star_i)) => {
+                backtrack = Some((star_p, star_i + 1));
+                p = star_p;
+                i = star_i + 1;
+            }
+            None => return false,
+        }
+    }
+
+    // the input is consumed, the remaining pattern can only be stars
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+// match_class checks `c` against the class starting at `pattern[start]` (an opening bracket). It
+// returns whether it matched and the pattern index following the class, or None when the class is
+// not closed, in which case the bracket is treated as a literal that does not match.
+fn match_class(pattern: &[u8], start: usize, c: u8) -> Option<(bool, usize)> {
+    let mut p = start + 1;
+    let negate = p < pattern.len() && pattern[p] == b'^';
+    if negate {
+        p += 1;
+    }
+    let mut matched = false;
+    while p < pattern.len() && pattern[p] != b']' {
+        if pattern[p] == b'\\' && p + 1 < pattern.len() {
+            matched |= pattern[p + 1] == c;
+            p += 2;
+        } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' && pattern[p + 2] != b']' {
+            let (low, high) = if pattern[p] <= pattern[p + 2] {
+                (pattern[p], pattern[p + 2])
+            } else {
+                (pattern[p + 2], pattern[p])
+            };
+            matched |= low <= c && c <= high;
+            p += 3;
+        } else {
+            matched |= pattern[p] == c;
+            p += 1;
+        }
+    }
+    if p >= pattern.len() {
+        return None;
+    }
+    Some((matched != negate, p + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"*", b""), "star matches an empty string");
+        assert!(glob_match(b"*", b"anything"), "star matches anything");
+        assert!(glob_match(b"user:*", b"user:42"));
+        assert!(!glob_match(b"user:*", b"item:42"));
+        assert!(glob_match(b"*:42", b"user:42"));
+        assert!(glob_match(b"u*r:*2", b"user:42"), "can backtrack on stars");
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(
+            !glob_match(b"h?llo", b"hllo"),
+            "question mark needs a character"
+        );
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[ae]llo", b"hillo"));
+        assert!(glob_match(b"h[^e]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-c]llo", b"hbllo"));
+        assert!(!glob_match(b"h[a-c]llo", b"hdllo"));
+        assert!(
+            glob_match(b"h\\*llo", b"h*llo"),
+            "can escape special characters"
+        );
+        assert!(!glob_match(b"h\\*llo", b"hello"));
+        assert!(
+            !glob_match(b"exact", b"exactly"),
+            "pattern must consume the input"
+        );
+    }
+}
@@ -0,0 +1,172 @@
+//! A minimal HTTP endpoint for liveness probes, like the ones of Kubernetes. `GET /health` answers
+//! 200 when the server accepts connections and its storage responds, 503 otherwise. The body
+//! reports the number of keys and the uptime, in the format of INFO.
+//!
+//! Only what the probes need of HTTP/1.0 is implemented: the request line is read, the headers
+//! are skipped, and the connection is closed after the response.
+
+use crate::db::Storage;
+use crate::metrics::Metrics;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{self, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, error, warn};
+
+// How long a probe has to send its request, and the storage to answer.
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Longest line of a request, and most headers read. A probe sends a short request.
+const MAX_REQUEST_LINE: u64 = 1024;
+const MAX_HEADERS: usize = 32;
+
+/// serve_health answers the health probes connecting to `listener`, until the listener fails.
+pub async fn serve_health(
+    listener: Arc<TcpListener>,
+    databases: Arc<[Arc<Storage>]>,
+    metrics: Arc<Metrics>,
+) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                // the errors of accept are transient, like running out of file descriptors
+                warn!("failed to accept a health probe: {}", err);
+                tokio::time::sleep(HEALTH_TIMEOUT).await;
+                continue;
+            }
+        };
+        let databases = databases.clone();
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(err) = answer_probe(stream, databases, &metrics).await {
+                debug!("failed to answer a health probe: {}", err);
+            }
+        });
+    }
+}
+
+// answer_probe reads the request of a probe and writes the response.
+async fn answer_probe(
+    stream: TcpStream,
+    databases: Arc<[Arc<Storage>]>,
+    metrics: &Metrics,
+) -> io::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let request_line = tokio::time::timeout(HEALTH_TIMEOUT, read_request(&mut stream))
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/health")) => match check_storage(databases).await {
+            Some(keys) => {
+                let body = format!(
+                    "status:ok\r\nkeys:{}\r\nuptime_in_seconds:{}\r\n",
+                    keys,
+                    metrics.uptime().as_secs()
+                );
+                response("200 OK", &body)
+            }
+            None => {
+                error!("health probe failed: the storage did not respond in time");
+                response("503 Service Unavailable", "status:unavailable\r\n")
+            }
+        },
+        (Some("GET"), Some(_)) => response("404 Not Found", ""),
+        _ => response("400 Bad Request", ""),
+    };
+    let stream = stream.get_mut();
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+// read_request returns the request line, once the headers which follow it are read. Closing the
+// connection before they are read would reset it, and the probe could miss the response.
+async fn read_request(stream: &mut BufReader<TcpStream>) -> io::Result<String> {
+    let mut request_line = Vec::new();
+    (&mut *stream)
+        .take(MAX_REQUEST_LINE)
+        .read_until(b'\n', &mut request_line)
+        .await?;
+    let mut header = Vec::new();
+    for _ in 0..MAX_HEADERS {
+        header.clear();
+        (&mut *stream)
+            .take(MAX_REQUEST_LINE)
+            .read_until(b'\n', &mut header)
+            .await?;
+        // the headers end with an empty line, or the end of the stream
+        if header.is_empty() || header == b"\r\n" || header == b"\n" {
+            break;
+        }
+    }
+    Ok(String::from_utf8_lossy(&request_line).into_owned())
+}
+
+// check_storage reads from every database, on a blocking thread as the shard locks are not async.
+// It returns the total number of keys, or None if the databases did not answer in time.
+async fn check_storage(databases: Arc<[Arc<Storage>]>) -> Option<usize> {
+    let check = tokio::task::spawn_blocking(move || {
+        databases
+            .iter()
+            .map(|storage| {
+                // taking a shard lock tells that the storage is not stuck
+                let _ = storage.get_v(b"mredis:health");
+                storage.len()
+            })
+            .sum()
+    });
+    match tokio::time::timeout(HEALTH_TIMEOUT, check).await {
+        Ok(Ok(keys)) => Some(keys),
+        _ => None,
+    }
+}
+
+// response renders an HTTP/1.0 response with a plain text `body`.
+fn response(status: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.0 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn get(addr: std::net::SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn health_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let databases: Arc<[Arc<Storage>]> =
+            (0..2).map(|_| Arc::new(Storage::new(100, 4))).collect();
+        databases[0].set_kv(b"a", b"1", None).unwrap();
+        databases[1].set_kv(b"b", b"2", None).unwrap();
+        tokio::spawn(serve_health(
+            Arc::new(listener),
+            databases,
+            Arc::new(Metrics::default()),
+        ));
+
+        let response = get(addr, "GET /health HTTP/1.0\r\nHost: localhost\r\n\r\n").await;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert_eq!(head.lines().next(), Some("HTTP/1.0 200 OK"));
+        assert!(head.contains(&format!("Content-Length: {}", body.len())));
+        assert!(body.starts_with("status:ok\r\nkeys:2\r\nuptime_in_seconds:"));
+
+        let response = get(addr, "GET / HTTP/1.0\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.0 404 Not Found\r\n"));
+        let response = get(addr, "PING\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.0 400 Bad Request\r\n"));
+    }
+}
@@ -5,7 +5,17 @@
 #![allow(clippy::missing_safety_doc)]
 #![allow(clippy::upper_case_acronyms)]
 
+pub mod aof;
+pub mod client;
 pub mod config;
 pub mod db;
+mod glob;
+pub mod health;
+pub mod metrics;
 mod parser;
 pub mod server;
+pub mod slowlog;
+pub mod snapshot;
+pub mod tls;
+
+pub use parser::{decode, DecodeError, DecodeLimits, Frame};
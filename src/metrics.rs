@@ -0,0 +1,344 @@
+//! Server metrics, shared by the server and every connection. Counters use relaxed atomics: they
+//! are only read to be reported, so they do not need to be ordered with anything else.
+
+use crate::parser::{CommandType, COMMAND_COUNT, COMMAND_TABLE};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+pub struct Metrics {
+    // when the metrics started to be recorded, which is when the server started
+    started_at: Instant,
+    // Connections the server could not accept because it ran out of resources, like file
+    // descriptors.
+    rejected_connections: AtomicU64,
+    // Accept errors by kind. They are rare, so a lock is fine and keeps the kinds open.
+    accept_errors: Mutex<BTreeMap<io::ErrorKind, u64>>,
+    // Connections being served. It is a gauge, only updated through ConnectionGuard.
+    connected_clients: AtomicU64,
+    // connections served since the server started
+    total_connections: AtomicU64,
+    // commands processed, indexed by command type
+    command_calls: [AtomicU64; COMMAND_COUNT],
+    // bytes read from and written to the clients
+    net_input_bytes: AtomicU64,
+    net_output_bytes: AtomicU64,
+    // whether appending to the AOF failed, after which the writes are no longer persisted
+    aof_write_failed: AtomicBool,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            started_at: Instant::now(),
+            rejected_connections: Default::default(),
+            accept_errors: Default::default(),
+            connected_clients: Default::default(),
+            total_connections: Default::default(),
+            command_calls: std::array::from_fn(|_| AtomicU64::new(0)),
+            net_input_bytes: Default::default(),
+            net_output_bytes: Default::default(),
+            aof_write_failed: Default::default(),
+        }
+    }
+}
+
+impl Metrics {
+    /// connection_opened counts a new client connection. It is counted until the returned guard
+    /// is dropped, which also happens when the task serving the connection panics.
+    pub fn connection_opened(self: &Arc<Self>) -> ConnectionGuard {
+        self.connected_clients.fetch_add(1, Ordering::Relaxed);
+        self.total_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard {
+            metrics: self.clone(),
+        }
+    }
+
+    /// connected_clients returns the number of connections being served.
+    pub fn connected_clients(&self) -> u64 {
+        self.connected_clients.load(Ordering::Relaxed)
+    }
+
+    /// record_command counts a processed command. Erroneous commands are not counted.
+    pub(crate) fn record_command(&self, command_type: CommandType) {
+        if let Some(calls) = self.command_calls.get(command_type as usize) {
+            calls.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// record_net_input counts bytes read from a client.
+    pub fn record_net_input(&self, bytes: usize) {
+        self.net_input_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// record_net_output counts bytes written to a client.
+    pub fn record_net_output(&self, bytes: usize) {
+        self.net_output_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// record_accept_error counts an error returned by accept. `rejected` tells whether the error
+    /// means a connection was turned away, as opposed to a connection aborted by the client.
+    pub fn record_accept_error(&self, err: &io::Error, rejected: bool) {
+        if rejected {
+            self.rejected_connections.fetch_add(1, Ordering::Relaxed);
+        }
+        *self
+            .accept_errors
+            .lock()
+            .unwrap()
+            .entry(err.kind())
+            .or_default() += 1;
+    }
+
+    /// record_aof_write_error records that appending to the AOF failed. It is not retried, so the
+    /// writes applied from then on are not persisted.
+    pub fn record_aof_write_error(&self) {
+        self.aof_write_failed.store(true, Ordering::Relaxed);
+    }
+
+    /// aof_write_failed tells whether appending to the AOF failed.
+    pub fn aof_write_failed(&self) -> bool {
+        self.aof_write_failed.load(Ordering::Relaxed)
+    }
+
+    /// rejected_connections returns the number of connections the server could not accept.
+    pub fn rejected_connections(&self) -> u64 {
+        self.rejected_connections.load(Ordering::Relaxed)
+    }
+
+    /// uptime returns for how long the metrics were recorded, which is the uptime of the server.
+    pub fn uptime(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    /// accept_errors returns the number of errors returned by accept, of any kind.
+    pub fn accept_errors(&self) -> u64 {
+        self.accept_errors.lock().unwrap().values().sum()
+    }
+
+    /// snapshot returns the current value of every metric. INFO is rendered from it, and it is
+    /// meant to export the metrics in any other format without parsing INFO.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let accept_errors = self.accept_errors.lock().unwrap();
+        let mut command_calls: Vec<_> = COMMAND_TABLE
+            .iter()
+            .map(|spec| {
+                let calls = self.command_calls[spec.command_type as usize].load(Ordering::Relaxed);
+                (spec.name.to_lowercase(), calls)
+            })
+            .filter(|(_, calls)| *calls > 0)
+            .collect();
+        command_calls.sort();
+        MetricsSnapshot {
+            uptime_in_seconds: self.uptime().as_secs(),
+            connected_clients: self.connected_clients(),
+            total_connections_received: self.total_connections.load(Ordering::Relaxed),
+            total_commands_processed: command_calls.iter().map(|(_, calls)| calls).sum(),
+            command_calls,
+            total_net_input_bytes: self.net_input_bytes.load(Ordering::Relaxed),
+            total_net_output_bytes: self.net_output_bytes.load(Ordering::Relaxed),
+            rejected_connections: self.rejected_connections(),
+            accept_errors: accept_errors.values().sum(),
+            accept_errors_by_kind: accept_errors
+                .iter()
+                .map(|(kind, count)| (snake_case(kind), *count))
+                .collect(),
+            aof_last_write_failed: self.aof_write_failed(),
+        }
+    }
+}
+
+/// ConnectionGuard keeps a connection counted in the metrics while it is alive.
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    metrics: Arc<Metrics>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.metrics
+            .connected_clients
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// MetricsSnapshot holds the value of the metrics at a point in time. It only holds plain data,
+/// in a deterministic order, so that it is easy to serialize.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub uptime_in_seconds: u64,
+    pub connected_clients: u64,
+    pub total_connections_received: u64,
+    pub total_commands_processed: u64,
+    /// Commands processed by command, like `get`, sorted by command. Commands never processed are
+    /// left out.
+    pub command_calls: Vec<(String, u64)>,
+    pub total_net_input_bytes: u64,
+    pub total_net_output_bytes: u64,
+    pub rejected_connections: u64,
+    pub accept_errors: u64,
+    /// Accept errors by kind, like `connection_aborted`, sorted by kind.
+    pub accept_errors_by_kind: Vec<(String, u64)>,
+    pub aof_last_write_failed: bool,
+}
+
+impl MetricsSnapshot {
+    /// render_server renders the `# Server` section of INFO.
+    pub(crate) fn render_server(&self) -> String {
+        format!(
+            "# Server\r\nredis_version:{}\r\nuptime_in_seconds:{}\r\n",
+            env!("CARGO_PKG_VERSION"),
+            self.uptime_in_seconds
+        )
+    }
+
+    /// render_clients renders the `# Clients` section of INFO.
+    pub(crate) fn render_clients(&self) -> String {
+        format!(
+            "# Clients\r\nconnected_clients:{}\r\n",
+            self.connected_clients
+        )
+    }
+
+    /// render_persistence renders the `# Persistence` section of INFO.
+    pub(crate) fn render_persistence(&self) -> String {
+        let status = if self.aof_last_write_failed {
+            "err"
+        } else {
+            "ok"
+        };
+        format!("# Persistence\r\naof_last_write_status:{}\r\n", status)
+    }
+
+    /// render_stats renders the `# Stats` section of INFO.
+    pub(crate) fn render_stats(&self) -> String {
+        let mut out = String::from("# Stats\r\n");
+        // writing to a String cannot fail
+        let _ = write!(
+            out,
+            "total_connections_received:{}\r\ntotal_commands_processed:{}\r\n\
+             total_net_input_bytes:{}\r\ntotal_net_output_bytes:{}\r\n\
+             rejected_connections:{}\r\naccept_errors:{}\r\n",
+            self.total_connections_received,
+            self.total_commands_processed,
+            self.total_net_input_bytes,
+            self.total_net_output_bytes,
+            self.rejected_connections,
+            self.accept_errors
+        );
+        for (kind, count) in &self.accept_errors_by_kind {
+            let _ = write!(out, "accept_errors_{}:{}\r\n", kind, count);
+        }
+        out
+    }
+
+    /// render_commandstats renders the `# Commandstats` section of INFO.
+    pub(crate) fn render_commandstats(&self) -> String {
+        let mut out = String::from("# Commandstats\r\n");
+        for (command, calls) in &self.command_calls {
+            let _ = write!(out, "cmdstat_{}:calls={}\r\n", command, calls);
+        }
+        out
+    }
+}
+
+// snake_case turns the name of an error kind, like `ConnectionAborted`, into `connection_aborted`.
+fn snake_case(kind: &io::ErrorKind) -> String {
+    let mut name = String::new();
+    for c in format!("{:?}", kind).chars() {
+        if c.is_ascii_uppercase() && !name.is_empty() {
+            name.push('_');
+        }
+        name.push(c.to_ascii_lowercase());
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // parse_info returns the fields of an INFO reply, ignoring the section headers.
+    fn parse_info(info: &str) -> BTreeMap<String, String> {
+        info.split("\r\n")
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_snapshot_matches_info() {
+        let metrics = Arc::new(Metrics::default());
+        let _connection = metrics.connection_opened();
+        metrics.record_command(CommandType::GET);
+        metrics.record_command(CommandType::GET);
+        metrics.record_command(CommandType::SET);
+        metrics.record_command(CommandType::ERROR);
+        metrics.record_net_input(10);
+        metrics.record_net_output(20);
+        metrics.record_accept_error(&io::Error::from(io::ErrorKind::ConnectionReset), false);
+        metrics.record_accept_error(&io::Error::from(io::ErrorKind::OutOfMemory), true);
+        metrics.record_accept_error(&io::Error::from(io::ErrorKind::OutOfMemory), true);
+        metrics.record_aof_write_error();
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(
+            snapshot,
+            MetricsSnapshot {
+                uptime_in_seconds: 0,
+                connected_clients: 1,
+                total_connections_received: 1,
+                total_commands_processed: 3,
+                command_calls: vec![("get".to_string(), 2), ("set".to_string(), 1)],
+                total_net_input_bytes: 10,
+                total_net_output_bytes: 20,
+                rejected_connections: 2,
+                accept_errors: 3,
+                accept_errors_by_kind: vec![
+                    ("connection_reset".to_string(), 1),
+                    ("out_of_memory".to_string(), 2),
+                ],
+                aof_last_write_failed: true,
+            }
+        );
+
+        let info = parse_info(
+            &[
+                snapshot.render_server(),
+                snapshot.render_clients(),
+                snapshot.render_persistence(),
+                snapshot.render_stats(),
+                snapshot.render_commandstats(),
+            ]
+            .concat(),
+        );
+        assert_eq!(
+            info["rejected_connections"],
+            snapshot.rejected_connections.to_string()
+        );
+        assert_eq!(info["accept_errors"], snapshot.accept_errors.to_string());
+        assert_eq!(info["aof_last_write_status"], "err");
+        assert_eq!(
+            info["connected_clients"],
+            snapshot.connected_clients.to_string()
+        );
+        for (kind, count) in &snapshot.accept_errors_by_kind {
+            assert_eq!(info[&format!("accept_errors_{}", kind)], count.to_string());
+        }
+        assert_eq!(info["total_connections_received"], "1");
+        assert_eq!(info["total_commands_processed"], "3");
+        assert_eq!(info["total_net_input_bytes"], "10");
+        assert_eq!(info["total_net_output_bytes"], "20");
+        assert_eq!(info["cmdstat_get"], "calls=2");
+        assert_eq!(info["cmdstat_set"], "calls=1");
+        assert_eq!(info["uptime_in_seconds"], "0");
+        assert_eq!(info["redis_version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(info.len(), 14, "INFO should report the same fields");
+    }
+}
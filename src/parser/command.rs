@@ -1,140 +1,1752 @@
 use crate::parser::Frame;
-use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-#[derive(Eq, PartialEq, Debug)]
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
 pub(crate) enum CommandType {
     PING,
     GET,
     SET,
     DEL,
     EXPIRE,
-    ERROR, // This isn't a command per se. But it is used to send erroneous responses back to the user.
+    PEXPIRE,
+    HKEYS,
+    HVALS,
+    HSCAN,
+    SSCAN,
+    ZSCAN,
+    CLIENT,
+    CAS,
+    GETDEL,
+    INFO,
+    GETSET,
+    PIN,
+    UNPIN,
+    SCAN,
+    GETEX,
+    RENAME,
+    RENAMENX,
+    PERSIST,
+    SETNX,
+    COMMAND,
+    HELLO,
+    INCR,
+    DECR,
+    INCRBY,
+    DECRBY,
+    AUTH,
+    SELECT,
+    APPEND,
+    MULTI,
+    EXEC,
+    DISCARD,
+    LPUSH,
+    RPUSH,
+    LPOP,
+    RPOP,
+    LRANGE,
+    HSET,
+    HGET,
+    HDEL,
+    HGETALL,
+    INCRBYFLOAT,
+    SLOWLOG,
+    OBJECT,
+    MSETNX,
+    DEBUG,
+    SAVE,
+    BGSAVE,
+    UNLINK,
+    RESET,
+    TOUCH,
+    WAIT,
+    DELX,
+    GETRANGE,
+    SETRANGE,
+    PEXPIREAT,
+    SHUTDOWN,
+    BLPOP,
+    BRPOP,
+    // This isn't a command per se. But it is used to send erroneous responses back to the user.
+    // It must stay last, see COMMAND_COUNT.
+    ERROR,
 }
 
-#[derive(Eq, PartialEq, Debug)]
-pub(crate) struct Command {
+/// COMMAND_COUNT is the number of command types, ERROR excluded. The types below it can index an
+/// array, like the per-command metrics.
+pub(crate) const COMMAND_COUNT: usize = CommandType::ERROR as usize;
+
+// Largest string a command can build, like the 512MB proto-max-bulk-len of Redis.
+const MAX_STRING_LEN: usize = 512 * 1024 * 1024;
+
+/// unix_time_ms returns the current unix time in milliseconds, which absolute expiries like
+/// PEXPIREAT are given in.
+pub(crate) fn unix_time_ms() -> i64 {
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    i64::try_from(elapsed.as_millis()).unwrap_or(i64::MAX)
+}
+
+// relative_ms turns the unix time in milliseconds `deadline` into the milliseconds left until
+// it, 0 once it passed.
+fn relative_ms(deadline: &str) -> Option<String> {
+    let deadline = deadline.parse::<i64>().ok()?;
+    Some(deadline.saturating_sub(unix_time_ms()).max(0).to_string())
+}
+
+/// CommandSpec describes a command, the way the COMMAND command reports it.
+#[derive(Debug)]
+pub(crate) struct CommandSpec {
+    pub(crate) name: &'static str,
     pub(crate) command_type: CommandType,
-    pub(crate) args: Vec<String>,
+    /// Number of arguments, the command name included. An arity of -N means at least N.
+    pub(crate) arity: i64,
+    pub(crate) flags: &'static [&'static str],
+    /// Positions of the first and last keys in the arguments, and the step between two keys. They
+    /// are 0 for commands without keys, and a negative last key counts from the end, -1 being the
+    /// last argument.
+    pub(crate) first_key: i64,
+    pub(crate) last_key: i64,
+    pub(crate) step: i64,
+}
+
+// spec builds a CommandSpec, to keep the table below readable.
+const fn spec(
+    name: &'static str,
+    command_type: CommandType,
+    arity: i64,
+    flags: &'static [&'static str],
+    keys: (i64, i64, i64),
+) -> CommandSpec {
+    CommandSpec {
+        name,
+        command_type,
+        arity,
+        flags,
+        first_key: keys.0,
+        last_key: keys.1,
+        step: keys.2,
+    }
+}
+
+const NO_KEY: (i64, i64, i64) = (0, 0, 0);
+const ONE_KEY: (i64, i64, i64) = (1, 1, 1);
+const ALL_KEYS: (i64, i64, i64) = (1, -1, 1);
+
+// MAX_NAME_LEN bounds the length of a command name, see CommandType::from_name.
+const MAX_NAME_LEN: usize = 16;
+
+/// COMMAND_TABLE lists the commands we know of. It is the reference for command names. The
+/// entries are in the order of CommandType, see CommandType::spec.
+pub(crate) const COMMAND_TABLE: &[CommandSpec] = &[
+    spec("PING", CommandType::PING, -1, &["fast"], NO_KEY),
+    spec("GET", CommandType::GET, 2, &["readonly", "fast"], ONE_KEY),
+    spec("SET", CommandType::SET, -3, &["write", "denyoom"], ONE_KEY),
+    spec("DEL", CommandType::DEL, -2, &["write"], ALL_KEYS),
+    spec(
+        "EXPIRE",
+        CommandType::EXPIRE,
+        3,
+        &["write", "fast"],
+        ONE_KEY,
+    ),
+    spec(
+        "PEXPIRE",
+        CommandType::PEXPIRE,
+        3,
+        &["write", "fast"],
+        ONE_KEY,
+    ),
+    spec("HKEYS", CommandType::HKEYS, 2, &["readonly"], ONE_KEY),
+    spec("HVALS", CommandType::HVALS, 2, &["readonly"], ONE_KEY),
+    spec("HSCAN", CommandType::HSCAN, -3, &["readonly"], ONE_KEY),
+    spec("SSCAN", CommandType::SSCAN, -3, &["readonly"], ONE_KEY),
+    spec("ZSCAN", CommandType::ZSCAN, -3, &["readonly"], ONE_KEY),
+    spec("CLIENT", CommandType::CLIENT, -2, &["noscript"], NO_KEY),
+    spec("CAS", CommandType::CAS, -4, &["write", "denyoom"], ONE_KEY),
+    spec(
+        "GETDEL",
+        CommandType::GETDEL,
+        2,
+        &["write", "fast"],
+        ONE_KEY,
+    ),
+    spec("INFO", CommandType::INFO, -1, &["loading", "stale"], NO_KEY),
+    spec(
+        "GETSET",
+        CommandType::GETSET,
+        3,
+        &["write", "denyoom", "fast"],
+        ONE_KEY,
+    ),
+    spec("PIN", CommandType::PIN, -2, &["write", "fast"], ALL_KEYS),
+    spec(
+        "UNPIN",
+        CommandType::UNPIN,
+        -2,
+        &["write", "fast"],
+        ALL_KEYS,
+    ),
+    spec("SCAN", CommandType::SCAN, -2, &["readonly"], NO_KEY),
+    spec("GETEX", CommandType::GETEX, -2, &["write", "fast"], ONE_KEY),
+    spec("RENAME", CommandType::RENAME, 3, &["write"], (1, 2, 1)),
+    spec(
+        "RENAMENX",
+        CommandType::RENAMENX,
+        3,
+        &["write", "fast"],
+        (1, 2, 1),
+    ),
+    spec(
+        "PERSIST",
+        CommandType::PERSIST,
+        2,
+        &["write", "fast"],
+        ONE_KEY,
+    ),
+    spec(
+        "SETNX",
+        CommandType::SETNX,
+        3,
+        &["write", "denyoom", "fast"],
+        ONE_KEY,
+    ),
+    spec(
+        "COMMAND",
+        CommandType::COMMAND,
+        -1,
+        &["loading", "stale"],
+        NO_KEY,
+    ),
+    spec(
+        "HELLO",
+        CommandType::HELLO,
+        -1,
+        &["noscript", "loading", "stale", "fast"],
+        NO_KEY,
+    ),
+    spec(
+        "INCR",
+        CommandType::INCR,
+        2,
+        &["write", "denyoom", "fast"],
+        ONE_KEY,
+    ),
+    spec(
+        "DECR",
+        CommandType::DECR,
+        2,
+        &["write", "denyoom", "fast"],
+        ONE_KEY,
+    ),
+    spec(
+        "INCRBY",
+        CommandType::INCRBY,
+        3,
+        &["write", "denyoom", "fast"],
+        ONE_KEY,
+    ),
+    spec(
+        "DECRBY",
+        CommandType::DECRBY,
+        3,
+        &["write", "denyoom", "fast"],
+        ONE_KEY,
+    ),
+    spec(
+        "AUTH",
+        CommandType::AUTH,
+        -2,
+        &["noscript", "loading", "stale", "fast"],
+        NO_KEY,
+    ),
+    spec(
+        "SELECT",
+        CommandType::SELECT,
+        2,
+        &["loading", "stale", "fast"],
+        NO_KEY,
+    ),
+    spec(
+        "APPEND",
+        CommandType::APPEND,
+        3,
+        &["write", "denyoom", "fast"],
+        ONE_KEY,
+    ),
+    spec(
+        "MULTI",
+        CommandType::MULTI,
+        1,
+        &["noscript", "loading", "stale", "fast"],
+        NO_KEY,
+    ),
+    spec(
+        "EXEC",
+        CommandType::EXEC,
+        1,
+        &["noscript", "loading", "stale"],
+        NO_KEY,
+    ),
+    spec(
+        "DISCARD",
+        CommandType::DISCARD,
+        1,
+        &["noscript", "loading", "stale", "fast"],
+        NO_KEY,
+    ),
+    spec(
+        "LPUSH",
+        CommandType::LPUSH,
+        -3,
+        &["write", "denyoom", "fast"],
+        ONE_KEY,
+    ),
+    spec(
+        "RPUSH",
+        CommandType::RPUSH,
+        -3,
+        &["write", "denyoom", "fast"],
+        ONE_KEY,
+    ),
+    spec("LPOP", CommandType::LPOP, -2, &["write", "fast"], ONE_KEY),
+    spec("RPOP", CommandType::RPOP, -2, &["write", "fast"], ONE_KEY),
+    spec("LRANGE", CommandType::LRANGE, 4, &["readonly"], ONE_KEY),
+    spec(
+        "HSET",
+        CommandType::HSET,
+        -4,
+        &["write", "denyoom", "fast"],
+        ONE_KEY,
+    ),
+    spec("HGET", CommandType::HGET, 3, &["readonly", "fast"], ONE_KEY),
+    spec("HDEL", CommandType::HDEL, -3, &["write", "fast"], ONE_KEY),
+    spec("HGETALL", CommandType::HGETALL, 2, &["readonly"], ONE_KEY),
+    spec(
+        "INCRBYFLOAT",
+        CommandType::INCRBYFLOAT,
+        3,
+        &["write", "denyoom", "fast"],
+        ONE_KEY,
+    ),
+    spec(
+        "SLOWLOG",
+        CommandType::SLOWLOG,
+        -2,
+        &["admin", "random", "loading", "stale"],
+        NO_KEY,
+    ),
+    spec(
+        "OBJECT",
+        CommandType::OBJECT,
+        -2,
+        &["readonly", "random"],
+        (2, 2, 1),
+    ),
+    spec(
+        "MSETNX",
+        CommandType::MSETNX,
+        -3,
+        &["write", "denyoom"],
+        (1, -1, 2),
+    ),
+    spec(
+        "DEBUG",
+        CommandType::DEBUG,
+        -2,
+        &["admin", "noscript", "loading", "stale"],
+        NO_KEY,
+    ),
+    spec("SAVE", CommandType::SAVE, 1, &["admin", "noscript"], NO_KEY),
+    spec(
+        "BGSAVE",
+        CommandType::BGSAVE,
+        1,
+        &["admin", "noscript"],
+        NO_KEY,
+    ),
+    spec(
+        "UNLINK",
+        CommandType::UNLINK,
+        -2,
+        &["write", "fast"],
+        ALL_KEYS,
+    ),
+    spec(
+        "RESET",
+        CommandType::RESET,
+        1,
+        &["noscript", "loading", "stale", "fast"],
+        NO_KEY,
+    ),
+    spec(
+        "TOUCH",
+        CommandType::TOUCH,
+        -2,
+        &["readonly", "fast"],
+        ALL_KEYS,
+    ),
+    spec("WAIT", CommandType::WAIT, 3, &["noscript"], NO_KEY),
+    spec("DELX", CommandType::DELX, -2, &["write"], ALL_KEYS),
+    spec("GETRANGE", CommandType::GETRANGE, 4, &["readonly"], ONE_KEY),
+    spec(
+        "SETRANGE",
+        CommandType::SETRANGE,
+        4,
+        &["write", "denyoom"],
+        ONE_KEY,
+    ),
+    spec(
+        "PEXPIREAT",
+        CommandType::PEXPIREAT,
+        3,
+        &["write", "fast"],
+        ONE_KEY,
+    ),
+    spec(
+        "SHUTDOWN",
+        CommandType::SHUTDOWN,
+        -1,
+        &["admin", "noscript", "loading", "stale"],
+        NO_KEY,
+    ),
+    spec(
+        "BLPOP",
+        CommandType::BLPOP,
+        -3,
+        &["write", "noscript"],
+        (1, -2, 1),
+    ),
+    spec(
+        "BRPOP",
+        CommandType::BRPOP,
+        -3,
+        &["write", "noscript"],
+        (1, -2, 1),
+    ),
+];
+
+impl CommandType {
+    /// from_name returns the type of the command named `name`, in any case. It is called for
+    /// every command, so it matches the name against literals instead of searching the table.
+    pub(crate) fn from_name(name: &[u8]) -> Option<CommandType> {
+        // the name is uppercased on the stack, a name too long for it is not a command
+        let mut buf = [0; MAX_NAME_LEN];
+        let upper = buf.get_mut(..name.len())?;
+        upper.copy_from_slice(name);
+        upper.make_ascii_uppercase();
+        let command_type = match &*upper {
+            b"PING" => CommandType::PING,
+            b"GET" => CommandType::GET,
+            b"SET" => CommandType::SET,
+            b"DEL" => CommandType::DEL,
+            b"EXPIRE" => CommandType::EXPIRE,
+            b"PEXPIRE" => CommandType::PEXPIRE,
+            b"HKEYS" => CommandType::HKEYS,
+            b"HVALS" => CommandType::HVALS,
+            b"HSCAN" => CommandType::HSCAN,
+            b"SSCAN" => CommandType::SSCAN,
+            b"ZSCAN" => CommandType::ZSCAN,
+            b"CLIENT" => CommandType::CLIENT,
+            b"CAS" => CommandType::CAS,
+            b"GETDEL" => CommandType::GETDEL,
+            b"INFO" => CommandType::INFO,
+            b"GETSET" => CommandType::GETSET,
+            b"PIN" => CommandType::PIN,
+            b"UNPIN" => CommandType::UNPIN,
+            b"SCAN" => CommandType::SCAN,
+            b"GETEX" => CommandType::GETEX,
+            b"RENAME" => CommandType::RENAME,
+            b"RENAMENX" => CommandType::RENAMENX,
+            b"PERSIST" => CommandType::PERSIST,
+            b"SETNX" => CommandType::SETNX,
+            b"COMMAND" => CommandType::COMMAND,
+            b"HELLO" => CommandType::HELLO,
+            b"INCR" => CommandType::INCR,
+            b"DECR" => CommandType::DECR,
+            b"INCRBY" => CommandType::INCRBY,
+            b"DECRBY" => CommandType::DECRBY,
+            b"AUTH" => CommandType::AUTH,
+            b"SELECT" => CommandType::SELECT,
+            b"APPEND" => CommandType::APPEND,
+            b"MULTI" => CommandType::MULTI,
+            b"EXEC" => CommandType::EXEC,
+            b"DISCARD" => CommandType::DISCARD,
+            b"LPUSH" => CommandType::LPUSH,
+            b"RPUSH" => CommandType::RPUSH,
+            b"LPOP" => CommandType::LPOP,
+            b"RPOP" => CommandType::RPOP,
+            b"LRANGE" => CommandType::LRANGE,
+            b"HSET" => CommandType::HSET,
+            b"HGET" => CommandType::HGET,
+            b"HDEL" => CommandType::HDEL,
+            b"HGETALL" => CommandType::HGETALL,
+            b"INCRBYFLOAT" => CommandType::INCRBYFLOAT,
+            b"SLOWLOG" => CommandType::SLOWLOG,
+            b"OBJECT" => CommandType::OBJECT,
+            b"MSETNX" => CommandType::MSETNX,
+            b"DEBUG" => CommandType::DEBUG,
+            b"SAVE" => CommandType::SAVE,
+            b"BGSAVE" => CommandType::BGSAVE,
+            b"UNLINK" => CommandType::UNLINK,
+            b"RESET" => CommandType::RESET,
+            b"TOUCH" => CommandType::TOUCH,
+            b"WAIT" => CommandType::WAIT,
+            b"DELX" => CommandType::DELX,
+            b"GETRANGE" => CommandType::GETRANGE,
+            b"SETRANGE" => CommandType::SETRANGE,
+            b"PEXPIREAT" => CommandType::PEXPIREAT,
+            b"SHUTDOWN" => CommandType::SHUTDOWN,
+            b"BLPOP" => CommandType::BLPOP,
+            b"BRPOP" => CommandType::BRPOP,
+            _ => return None,
+        };
+        Some(command_type)
+    }
+
+    /// spec returns the entry of the command in COMMAND_TABLE, None for ERROR. The table is in the
+    /// order of the types, so it is an index, not a search.
+    pub(crate) fn spec(&self) -> Option<&'static CommandSpec> {
+        COMMAND_TABLE.get(*self as usize)
+    }
+
+    /// is_write tells whether the command modifies the keyspace, according to its flags.
+    pub(crate) fn is_write(&self) -> bool {
+        self.spec()
+            .is_some_and(|spec| spec.flags.contains(&"write"))
+    }
+}
+
+/// Command is a request of a client, parsed by Frame::to_command.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Command {
+    pub(crate) command_type: CommandType,
+    /// The arguments are binary safe, keys and values can hold any bytes. The parsers check the
+    /// arguments which must be text, like numbers and options.
+    pub(crate) args: Vec<Vec<u8>>,
 }
 
 impl Command {
-    pub(crate) fn new(cmd_type: CommandType, args: &Vec<String>) -> Self {
+    /// new creates a command from text arguments.
+    pub(crate) fn new(cmd_type: CommandType, args: &[String]) -> Self {
         Command {
             command_type: cmd_type,
-            args: args.to_owned(),
+            args: args.iter().map(|arg| arg.as_bytes().to_vec()).collect(),
         }
     }
 
-    pub(crate) fn make_redis_command_map() -> HashMap<&'static str, CommandType> {
-        let mut map = HashMap::new();
-        map.insert("PING", CommandType::PING);
-        map.insert("GET", CommandType::GET);
-        map.insert("SET", CommandType::SET);
-        map.insert("DEL", CommandType::DEL);
-        map.insert("EXPIRE", CommandType::EXPIRE);
-        map
+    /// arg_str returns an argument which is text, like a number or an option. It is empty if the
+    /// argument is not valid UTF-8, which the parsers rule out for such arguments.
+    pub(crate) fn arg_str(&self, index: usize) -> &str {
+        std::str::from_utf8(&self.args[index]).unwrap_or_default()
+    }
+
+    /// keys returns the keys of the command, at the positions given by its spec. The arguments
+    /// are the ones of the request without the command name, so the positions are shifted by one.
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &[u8]> {
+        let (first, last, step) = match self.command_type.spec() {
+            Some(spec) if spec.first_key > 0 => (spec.first_key, spec.last_key, spec.step),
+            _ => (1, 0, 1),
+        };
+        let last = if last < 0 {
+            self.args.len() as i64 + last
+        } else {
+            last - 1
+        };
+        let positions = (first - 1..=last).step_by(step.max(1) as usize);
+        positions.filter_map(|position| self.args.get(position as usize).map(Vec::as_slice))
     }
 
     pub(crate) fn parse_ping_command(frames: &[Frame]) -> Command {
         if frames.len() > 2 {
             return Command {
                 command_type: CommandType::ERROR,
-                args: vec!["PING command must have at most 1 argument".to_string()],
+                args: vec!["PING command must have at most 1 argument".into()],
             };
         }
 
-        let mut ping_cmd = Command {
+        // the message to echo, if any, a bare PING replies PONG
+        Command {
             command_type: CommandType::PING,
-            args: vec!["PONG".to_string()],
-        };
-
-        if frames.len() == 2 {
-            let ping_msg = frames[1].get_bulk().unwrap();
-            ping_cmd.args[0] = ping_msg.to_string();
+            args: frames
+                .iter()
+                .skip(1)
+                .map(|frame| frame.get_bulk().unwrap().to_vec())
+                .collect(),
         }
-
-        ping_cmd
     }
 
     pub(crate) fn parse_get_command(frames: &[Frame]) -> Command {
         if frames.len() != 2 {
             return Command {
                 command_type: CommandType::ERROR,
-                args: vec!["GET command must have at exactly 1 argument".to_string()],
+                args: vec!["GET command must have at exactly 1 argument".into()],
             };
         }
 
         Command {
             command_type: CommandType::GET,
-            args: vec![frames[1].get_bulk().unwrap().to_string()],
+            args: vec![frames[1].get_bulk().unwrap().to_vec()],
         }
     }
 
+    /// parse_set_command parses `SET key value [PX milliseconds | PXAT unix-time-milliseconds |
+    /// KEEPTTL]` into `[key, value]`, `[key, value, milliseconds]` or `[key, value, "KEEPTTL"]`.
+    /// A PXAT deadline is turned into the milliseconds left until it, 0 if it passed.
     pub(crate) fn parse_set_command(frames: &[Frame]) -> Command {
         // note: we can unwrap get_bulk in this function because the frame
         // has been checked upfront. @TODO: maybe refactor to give a number instead of an option, then.
         let len = frames.len();
-        if len != 3 && len != 5 {
+        if !(3..=5).contains(&len) {
             return Command {
                 command_type: CommandType::ERROR,
-                args: vec!["SET should take 2 or 4 arguments".to_string()],
+                args: vec!["SET should take 2 to 4 arguments".into()],
             };
         }
         let key = frames[1].get_bulk().unwrap();
         let value = frames[2].get_bulk().unwrap();
 
+        // KEEPTTL is passed on as is, the handler keeps the expiry of the key
+        if len == 4 {
+            let option = frames[3].bulk_str().unwrap_or_default();
+            if option.eq_ignore_ascii_case("KEEPTTL") {
+                return Command {
+                    command_type: CommandType::SET,
+                    args: vec![key.to_vec(), value.to_vec(), b"KEEPTTL".to_vec()],
+                };
+            }
+            return Command {
+                command_type: CommandType::ERROR,
+                args: vec![format!(
+                    "unknown option '{}' for SET command",
+                    String::from_utf8_lossy(frames[3].get_bulk().unwrap())
+                )
+                .into_bytes()],
+            };
+        }
+
         // check if we've got the right option to set the time in millis
         if len == 5 {
-            let ping_opt = frames[3].get_bulk().unwrap();
+            let ping_opt = frames[3].bulk_str().unwrap_or_default();
             if ping_opt.to_uppercase() == "PX" {
-                let expiration = frames[4].get_bulk().unwrap();
+                let expiration = frames[4].bulk_str().unwrap_or_default();
                 // also check if expiration can be converted to a number, because we do not want the caller of this method to check anything
                 // Ensure that expiration is convertible to a number
                 if expiration.parse::<u64>().is_err() {
                     return Command {
                         command_type: CommandType::ERROR,
-                        args: vec!["expiration should be a valid number".to_string()],
+                        args: vec!["expiration should be a valid number".into()],
                     };
                 }
                 return Command {
                     command_type: CommandType::SET,
-                    args: vec![key.to_string(), value.to_string(), expiration.to_string()],
+                    args: vec![key.to_vec(), value.to_vec(), expiration.into()],
+                };
+            }
+            if ping_opt.eq_ignore_ascii_case("PXAT") {
+                let Some(expiration) = relative_ms(frames[4].bulk_str().unwrap_or_default()) else {
+                    return Command {
+                        command_type: CommandType::ERROR,
+                        args: vec!["expiration should be a valid number".into()],
+                    };
+                };
+                return Command {
+                    command_type: CommandType::SET,
+                    args: vec![key.to_vec(), value.to_vec(), expiration.into_bytes()],
                 };
             }
             return Command {
                 command_type: CommandType::ERROR,
-                args: vec![format!("unknown option '{}' for SET command", ping_opt)],
+                args: vec![format!(
+                    "unknown option '{}' for SET command",
+                    String::from_utf8_lossy(frames[3].get_bulk().unwrap())
+                )
+                .into_bytes()],
             };
         }
         Command {
             command_type: CommandType::SET,
-            args: vec![key.to_string(), value.to_string()],
+            args: vec![key.to_vec(), value.to_vec()],
         }
     }
 
-    pub(crate) fn parse_del_command(frames: &[Frame]) -> Command {
+    /// parse_del_command parses DEL, DELX, UNLINK and TOUCH, which take a list of keys.
+    pub(crate) fn parse_del_command(
+        frames: &[Frame],
+        cmd_type: CommandType,
+        name: &str,
+    ) -> Command {
         // note: we can unwrap get_bulk in this function because the frame
         // has been checked upfront. @TODO: maybe refactor to give a number instead of an option, then.
         let len = frames.len();
         if len < 2 {
             return Command {
                 command_type: CommandType::ERROR,
-                args: vec!["DEL command must at least one arg".to_string()],
+                args: vec![format!("{} command must at least one arg", name).into_bytes()],
             };
         }
 
         let mut keys = Vec::with_capacity(len - 1);
         for frame in frames.iter().skip(1) {
-            keys.push(frame.get_bulk().unwrap().to_string());
+            keys.push(frame.get_bulk().unwrap().to_vec());
         }
 
         Command {
-            command_type: CommandType::DEL,
+            command_type: cmd_type,
             args: keys,
         }
     }
 
-    pub(crate) fn parse_expire_command(frames: &[Frame]) -> Command {
-        unimplemented!("TODO: implement later")
+    /// parse_expire_command parses `EXPIRE key seconds`, `PEXPIRE key milliseconds` and
+    /// `PEXPIREAT key unix-time-milliseconds` into `[key, milliseconds]`. The TTL can be negative,
+    /// like a deadline in the past, which deletes the key.
+    pub(crate) fn parse_expire_command(frames: &[Frame], cmd_type: CommandType) -> Command {
+        let (name, unit) = match cmd_type {
+            CommandType::PEXPIRE => ("PEXPIRE", 1),
+            CommandType::PEXPIREAT => ("PEXPIREAT", 1),
+            _ => ("EXPIRE", 1000),
+        };
+        let mut command = Self::parse_key_value_command(frames, cmd_type, name);
+        if command.command_type == CommandType::ERROR {
+            return command;
+        }
+        let ttl = match command.arg_str(1).parse::<i64>() {
+            Ok(ttl) => ttl,
+            Err(_) => {
+                let msg = "value is not an integer or out of range".to_string();
+                return Command::new(CommandType::ERROR, &[msg]);
+            }
+        };
+        match ttl.checked_mul(unit) {
+            Some(ttl_ms) => {
+                let ttl_ms = match cmd_type {
+                    CommandType::PEXPIREAT => ttl_ms.saturating_sub(unix_time_ms()),
+                    _ => ttl_ms,
+                };
+                command.args[1] = ttl_ms.to_string().into_bytes();
+                command
+            }
+            None => {
+                let msg = format!("invalid expire time in '{}' command", name.to_lowercase());
+                Command::new(CommandType::ERROR, &[msg])
+            }
+        }
+    }
+
+    pub(crate) fn parse_hkeys_command(frames: &[Frame]) -> Command {
+        Self::parse_single_key_command(frames, CommandType::HKEYS, "HKEYS")
+    }
+
+    pub(crate) fn parse_hgetall_command(frames: &[Frame]) -> Command {
+        Self::parse_single_key_command(frames, CommandType::HGETALL, "HGETALL")
+    }
+
+    pub(crate) fn parse_hget_command(frames: &[Frame]) -> Command {
+        Self::parse_key_value_command(frames, CommandType::HGET, "HGET")
+    }
+
+    /// parse_hset_command parses `HSET key field value [field value ...]` into
+    /// `[key, field, value, ...]`.
+    pub(crate) fn parse_hset_command(frames: &[Frame]) -> Command {
+        if frames.len() < 4 || !frames.len().is_multiple_of(2) {
+            return Command {
+                command_type: CommandType::ERROR,
+                args: vec!["HSET command must have a key and field value pairs".into()],
+            };
+        }
+
+        Command {
+            command_type: CommandType::HSET,
+            args: frames
+                .iter()
+                .skip(1)
+                .map(|frame| frame.get_bulk().unwrap().to_vec())
+                .collect(),
+        }
+    }
+
+    /// parse_msetnx_command parses `MSETNX key value [key value ...]` into
+    /// `[key, value, ...]`.
+    pub(crate) fn parse_msetnx_command(frames: &[Frame]) -> Command {
+        if frames.len() < 3 || frames.len().is_multiple_of(2) {
+            return Command {
+                command_type: CommandType::ERROR,
+                args: vec!["MSETNX command must have key value pairs".into()],
+            };
+        }
+
+        Command {
+            command_type: CommandType::MSETNX,
+            args: frames
+                .iter()
+                .skip(1)
+                .map(|frame| frame.get_bulk().unwrap().to_vec())
+                .collect(),
+        }
+    }
+
+    /// parse_hdel_command parses `HDEL key field [field ...]` into `[key, field, ...]`.
+    pub(crate) fn parse_hdel_command(frames: &[Frame]) -> Command {
+        if frames.len() < 3 {
+            return Command {
+                command_type: CommandType::ERROR,
+                args: vec!["HDEL command must have at least 2 arguments".into()],
+            };
+        }
+
+        Command {
+            command_type: CommandType::HDEL,
+            args: frames
+                .iter()
+                .skip(1)
+                .map(|frame| frame.get_bulk().unwrap().to_vec())
+                .collect(),
+        }
+    }
+
+    pub(crate) fn parse_hvals_command(frames: &[Frame]) -> Command {
+        Self::parse_single_key_command(frames, CommandType::HVALS, "HVALS")
+    }
+
+    pub(crate) fn parse_persist_command(frames: &[Frame]) -> Command {
+        Self::parse_single_key_command(frames, CommandType::PERSIST, "PERSIST")
+    }
+
+    pub(crate) fn parse_getdel_command(frames: &[Frame]) -> Command {
+        Self::parse_single_key_command(frames, CommandType::GETDEL, "GETDEL")
+    }
+
+    /// parse_client_command parses the CLIENT subcommands. The subcommand name is normalized
+    /// to uppercase and put in first position of the args.
+    pub(crate) fn parse_client_command(frames: &[Frame]) -> Command {
+        if frames.len() < 2 {
+            return Command {
+                command_type: CommandType::ERROR,
+                args: vec!["CLIENT command must have at least 1 argument".into()],
+            };
+        }
+        let subcommand = frames[1].bulk_str().unwrap_or_default().to_uppercase();
+        match subcommand.as_str() {
+            "TRACKING" => {
+                // We only support turning tracking on and off, without options.
+                if frames.len() != 3 {
+                    return Command {
+                        command_type: CommandType::ERROR,
+                        args: vec!["CLIENT TRACKING must have exactly 1 argument".into()],
+                    };
+                }
+                let state = frames[2].bulk_str().unwrap_or_default().to_uppercase();
+                if state != "ON" && state != "OFF" {
+                    return Command {
+                        command_type: CommandType::ERROR,
+                        args: vec!["syntax error".into()],
+                    };
+                }
+                Command {
+                    command_type: CommandType::CLIENT,
+                    args: vec![subcommand.into_bytes(), state.into_bytes()],
+                }
+            }
+            "SETNAME" | "GETNAME" | "ID" => {
+                let arity = if subcommand == "SETNAME" { 3 } else { 2 };
+                if frames.len() != arity {
+                    return Command {
+                        command_type: CommandType::ERROR,
+                        args: vec![format!(
+                            "wrong number of arguments for 'client|{}' command",
+                            subcommand.to_lowercase()
+                        )
+                        .into_bytes()],
+                    };
+                }
+                let mut args = vec![subcommand.into_bytes()];
+                if let Some(name) = frames.get(2) {
+                    let name = name.get_bulk().unwrap();
+                    // the name shows up in lists of clients, one per line with space separated
+                    // fields
+                    if name.iter().any(|byte| !(b'!'..=b'~').contains(byte)) {
+                        return Command {
+                            command_type: CommandType::ERROR,
+                            args: vec![
+                                "Client names cannot contain spaces, newlines or special characters."
+                                    .into(),
+                            ],
+                        };
+                    }
+                    args.push(name.to_vec());
+                }
+                Command {
+                    command_type: CommandType::CLIENT,
+                    args,
+                }
+            }
+            _ => Command {
+                command_type: CommandType::ERROR,
+                args: vec![format!(
+                    "unknown subcommand '{}'. Try CLIENT HELP.",
+                    String::from_utf8_lossy(frames[1].get_bulk().unwrap())
+                )
+                .into_bytes()],
+            },
+        }
+    }
+
+    /// parse_slowlog_command parses `SLOWLOG GET [count]`, `SLOWLOG LEN` and `SLOWLOG RESET`. The
+    /// subcommand is normalized to uppercase and put in first position of the args. The count of
+    /// GET defaults to 10, and -1 means every entry.
+    pub(crate) fn parse_slowlog_command(frames: &[Frame]) -> Command {
+        if frames.len() < 2 {
+            return Command {
+                command_type: CommandType::ERROR,
+                args: vec!["SLOWLOG command must have at least 1 argument".into()],
+            };
+        }
+        let subcommand = frames[1].bulk_str().unwrap_or_default().to_uppercase();
+        match (subcommand.as_str(), frames.len()) {
+            ("GET", 2) => Command {
+                command_type: CommandType::SLOWLOG,
+                args: vec![subcommand.into_bytes(), b"10".to_vec()],
+            },
+            ("GET", 3) => {
+                let count = frames[2].bulk_str().unwrap_or_default();
+                if !count.parse::<i64>().is_ok_and(|count| count >= -1) {
+                    return Command {
+                        command_type: CommandType::ERROR,
+                        args: vec!["count should be greater than or equal to -1".into()],
+                    };
+                }
+                Command {
+                    command_type: CommandType::SLOWLOG,
+                    args: vec![subcommand.into_bytes(), count.into()],
+                }
+            }
+            ("LEN" | "RESET", 2) => Command {
+                command_type: CommandType::SLOWLOG,
+                args: vec![subcommand.into_bytes()],
+            },
+            ("GET" | "LEN" | "RESET", _) => Command {
+                command_type: CommandType::ERROR,
+                args: vec![format!(
+                    "wrong number of arguments for 'slowlog|{}' command",
+                    subcommand.to_lowercase()
+                )
+                .into_bytes()],
+            },
+            _ => Command {
+                command_type: CommandType::ERROR,
+                args: vec![format!(
+                    "unknown subcommand '{}'. Try SLOWLOG HELP.",
+                    String::from_utf8_lossy(frames[1].get_bulk().unwrap())
+                )
+                .into_bytes()],
+            },
+        }
+    }
+
+    /// parse_object_command parses `OBJECT ENCODING key` and `OBJECT IDLETIME key`. The
+    /// subcommand is normalized to uppercase and put in first position of the args, the key comes
+    /// next.
+    pub(crate) fn parse_object_command(frames: &[Frame]) -> Command {
+        if frames.len() < 2 {
+            return Command {
+                command_type: CommandType::ERROR,
+                args: vec!["OBJECT command must have at least 1 argument".into()],
+            };
+        }
+        let subcommand = frames[1].bulk_str().unwrap_or_default().to_uppercase();
+        match (subcommand.as_str(), frames.len()) {
+            ("ENCODING" | "IDLETIME", 3) => Command {
+                command_type: CommandType::OBJECT,
+                args: vec![
+                    subcommand.into_bytes(),
+                    frames[2].get_bulk().unwrap().to_vec(),
+                ],
+            },
+            ("ENCODING" | "IDLETIME", _) => Command {
+                command_type: CommandType::ERROR,
+                args: vec![format!(
+                    "wrong number of arguments for 'object|{}' command",
+                    subcommand.to_lowercase()
+                )
+                .into_bytes()],
+            },
+            _ => Command {
+                command_type: CommandType::ERROR,
+                args: vec![format!(
+                    "unknown subcommand '{}'. Try OBJECT HELP.",
+                    String::from_utf8_lossy(frames[1].get_bulk().unwrap())
+                )
+                .into_bytes()],
+            },
+        }
+    }
+
+    /// parse_debug_command parses `DEBUG SLEEP seconds`, the delay being a non negative number
+    /// which can have a fractional part. The subcommand is normalized to uppercase and put in
+    /// first position of the args.
+    pub(crate) fn parse_debug_command(frames: &[Frame]) -> Command {
+        if frames.len() < 2 {
+            return Command {
+                command_type: CommandType::ERROR,
+                args: vec!["DEBUG command must have at least 1 argument".into()],
+            };
+        }
+        let subcommand = frames[1].bulk_str().unwrap_or_default().to_uppercase();
+        match (subcommand.as_str(), frames.len()) {
+            ("SLEEP", 3) => {
+                let seconds = frames[2].bulk_str().unwrap_or_default();
+                let valid = seconds
+                    .parse::<f64>()
+                    .is_ok_and(|seconds| seconds.is_finite() && seconds >= 0.0);
+                if !valid {
+                    return Command {
+                        command_type: CommandType::ERROR,
+                        args: vec!["value is not a valid float".into()],
+                    };
+                }
+                Command {
+                    command_type: CommandType::DEBUG,
+                    args: vec![subcommand.into_bytes(), seconds.into()],
+                }
+            }
+            ("SLEEP", _) => Command {
+                command_type: CommandType::ERROR,
+                args: vec!["wrong number of arguments for 'debug|sleep' command".into()],
+            },
+            ("SHARDS", 2) => Command {
+                command_type: CommandType::DEBUG,
+                args: vec![subcommand.into_bytes()],
+            },
+            ("SHARDS", _) => Command {
+                command_type: CommandType::ERROR,
+                args: vec!["wrong number of arguments for 'debug|shards' command".into()],
+            },
+            _ => Command {
+                command_type: CommandType::ERROR,
+                args: vec![format!(
+                    "unknown subcommand '{}'. Try DEBUG HELP.",
+                    String::from_utf8_lossy(frames[1].get_bulk().unwrap())
+                )
+                .into_bytes()],
+            },
+        }
+    }
+
+    /// parse_info_command parses INFO and its optional section names, normalized to lowercase.
+    pub(crate) fn parse_info_command(frames: &[Frame]) -> Command {
+        Command {
+            command_type: CommandType::INFO,
+            args: frames
+                .iter()
+                .skip(1)
+                .map(|frame| frame.get_bulk().unwrap().to_ascii_lowercase())
+                .collect(),
+        }
+    }
+
+    /// parse_getex_command parses `GETEX key [EX seconds | PX milliseconds | PERSIST]` into
+    /// `[key]`, `[key, "PX", milliseconds]` or `[key, "PERSIST"]`.
+    pub(crate) fn parse_getex_command(frames: &[Frame]) -> Command {
+        let error = |msg: &str| Command::new(CommandType::ERROR, &[msg.to_string()]);
+        if frames.len() < 2 || frames.len() > 4 {
+            return error("GETEX command must have between 1 and 3 arguments");
+        }
+        let mut args = vec![frames[1].get_bulk().unwrap().to_vec()];
+        if frames.len() == 2 {
+            return Command {
+                command_type: CommandType::GETEX,
+                args,
+            };
+        }
+        let option = frames[2].bulk_str().unwrap_or_default().to_uppercase();
+        match (option.as_str(), frames.get(3)) {
+            ("PERSIST", None) => args.push(option.into_bytes()),
+            ("EX" | "PX", Some(frame)) => {
+                let ttl = match frame.bulk_str().unwrap_or_default().parse::<u64>() {
+                    Ok(ttl) => ttl,
+                    Err(_) => return error("value is not an integer or out of range"),
+                };
+                let millis = if option == "EX" {
+                    ttl.checked_mul(1000)
+                } else {
+                    Some(ttl)
+                };
+                match millis {
+                    Some(millis) if millis > 0 => {
+                        args.push(b"PX".to_vec());
+                        args.push(millis.to_string().into_bytes());
+                    }
+                    _ => return error("invalid expire time in 'getex' command"),
+                }
+            }
+            _ => return error("syntax error"),
+        }
+        Command {
+            command_type: CommandType::GETEX,
+            args,
+        }
+    }
+
+    pub(crate) fn parse_rename_command(frames: &[Frame]) -> Command {
+        Self::parse_two_keys_command(frames, CommandType::RENAME, "RENAME")
+    }
+
+    pub(crate) fn parse_renamenx_command(frames: &[Frame]) -> Command {
+        Self::parse_two_keys_command(frames, CommandType::RENAMENX, "RENAMENX")
+    }
+
+    pub(crate) fn parse_getset_command(frames: &[Frame]) -> Command {
+        Self::parse_key_value_command(frames, CommandType::GETSET, "GETSET")
+    }
+
+    pub(crate) fn parse_append_command(frames: &[Frame]) -> Command {
+        Self::parse_key_value_command(frames, CommandType::APPEND, "APPEND")
+    }
+
+    /// parse_setrange_command parses `SETRANGE key offset value`. The offset cannot be negative,
+    /// and the string cannot grow beyond 512MB, the largest bulk string a client can send.
+    pub(crate) fn parse_setrange_command(frames: &[Frame]) -> Command {
+        if frames.len() != 4 {
+            let msg = "SETRANGE command must have exactly 3 arguments".to_string();
+            return Command::new(CommandType::ERROR, &[msg]);
+        }
+        let Ok(offset) = frames[2].bulk_str().unwrap_or_default().parse::<i64>() else {
+            let msg = "value is not an integer or out of range".to_string();
+            return Command::new(CommandType::ERROR, &[msg]);
+        };
+        let value = frames[3].get_bulk().unwrap();
+        if offset < 0 {
+            return Command::new(CommandType::ERROR, &["offset is out of range".to_string()]);
+        }
+        if offset as u64 + value.len() as u64 > MAX_STRING_LEN as u64 {
+            let msg = "string exceeds maximum allowed size (proto-max-bulk-len)".to_string();
+            return Command::new(CommandType::ERROR, &[msg]);
+        }
+        Command {
+            command_type: CommandType::SETRANGE,
+            args: vec![
+                frames[1].get_bulk().unwrap().to_vec(),
+                offset.to_string().into_bytes(),
+                value.to_vec(),
+            ],
+        }
+    }
+
+    pub(crate) fn parse_setnx_command(frames: &[Frame]) -> Command {
+        Self::parse_key_value_command(frames, CommandType::SETNX, "SETNX")
+    }
+
+    /// parse_incr_command parses INCR and DECR into `[key, delta]`, like INCRBY.
+    pub(crate) fn parse_incr_command(frames: &[Frame], cmd_type: CommandType) -> Command {
+        let (name, delta) = match cmd_type {
+            CommandType::DECR => ("DECR", "-1"),
+            _ => ("INCR", "1"),
+        };
+        let mut command = Self::parse_single_key_command(frames, cmd_type, name);
+        if command.command_type != CommandType::ERROR {
+            command.args.push(delta.into());
+        }
+        command
+    }
+
+    /// parse_incrby_command parses `INCRBY key increment` and `DECRBY key decrement` into
+    /// `[key, delta]`, the decrement being negated.
+    pub(crate) fn parse_incrby_command(frames: &[Frame], cmd_type: CommandType) -> Command {
+        let name = match cmd_type {
+            CommandType::DECRBY => "DECRBY",
+            _ => "INCRBY",
+        };
+        let mut command = Self::parse_key_value_command(frames, cmd_type, name);
+        if command.command_type == CommandType::ERROR {
+            return command;
+        }
+        let delta = match command.arg_str(1).parse::<i64>() {
+            Ok(delta) if cmd_type == CommandType::DECRBY => delta.checked_neg(),
+            Ok(delta) => Some(delta),
+            Err(_) => {
+                let msg = "value is not an integer or out of range".to_string();
+                return Command::new(CommandType::ERROR, &[msg]);
+            }
+        };
+        match delta {
+            Some(delta) => {
+                command.args[1] = delta.to_string().into_bytes();
+                command
+            }
+            None => Command::new(
+                CommandType::ERROR,
+                &["decrement would overflow".to_string()],
+            ),
+        }
+    }
+
+    /// parse_push_command parses `LPUSH key element [element ...]` and RPUSH into
+    /// `[key, element, ...]`.
+    pub(crate) fn parse_push_command(frames: &[Frame], cmd_type: CommandType) -> Command {
+        if frames.len() < 3 {
+            let name = if cmd_type == CommandType::LPUSH {
+                "LPUSH"
+            } else {
+                "RPUSH"
+            };
+            return Command {
+                command_type: CommandType::ERROR,
+                args: vec![format!("{} command must have at least 2 arguments", name).into_bytes()],
+            };
+        }
+
+        Command {
+            command_type: cmd_type,
+            args: frames
+                .iter()
+                .skip(1)
+                .map(|frame| frame.get_bulk().unwrap().to_vec())
+                .collect(),
+        }
+    }
+
+    /// parse_pop_command parses `LPOP key [count]` and RPOP into `[key]` or `[key, count]`. The
+    /// count must be a positive integer.
+    pub(crate) fn parse_pop_command(frames: &[Frame], cmd_type: CommandType) -> Command {
+        if frames.len() != 2 && frames.len() != 3 {
+            let name = if cmd_type == CommandType::LPOP {
+                "LPOP"
+            } else {
+                "RPOP"
+            };
+            return Command {
+                command_type: CommandType::ERROR,
+                args: vec![format!("{} command must have 1 or 2 arguments", name).into_bytes()],
+            };
+        }
+
+        let mut args = vec![frames[1].get_bulk().unwrap().to_vec()];
+        if let Some(count) = frames.get(2) {
+            let count = count.bulk_str().unwrap_or_default();
+            if !count.parse::<usize>().is_ok_and(|count| count > 0) {
+                let msg = "value is out of range, must be positive".to_string();
+                return Command::new(CommandType::ERROR, &[msg]);
+            }
+            args.push(count.into());
+        }
+        Command {
+            command_type: cmd_type,
+            args,
+        }
+    }
+
+    /// parse_blocking_pop_command parses `BLPOP key [key ...] timeout` and BRPOP into
+    /// `[key, ..., milliseconds]`. The timeout is in seconds, with decimals, and 0 blocks forever.
+    pub(crate) fn parse_blocking_pop_command(frames: &[Frame], cmd_type: CommandType) -> Command {
+        if frames.len() < 3 {
+            let name = if cmd_type == CommandType::BLPOP {
+                "BLPOP"
+            } else {
+                "BRPOP"
+            };
+            let msg = format!("{} command must have at least 2 arguments", name);
+            return Command::new(CommandType::ERROR, &[msg]);
+        }
+        let (timeout, keys) = frames[1..].split_last().unwrap();
+        let timeout = match timeout.bulk_str().unwrap_or_default().parse::<f64>() {
+            Ok(timeout) if timeout < 0.0 => {
+                return Command::new(CommandType::ERROR, &["timeout is negative".to_string()]);
+            }
+            Ok(timeout) if timeout.is_finite() && timeout * 1000.0 < u64::MAX as f64 => timeout,
+            _ => {
+                let msg = "timeout is not a float or out of range".to_string();
+                return Command::new(CommandType::ERROR, &[msg]);
+            }
+        };
+        let mut args: Vec<Vec<u8>> = keys
+            .iter()
+            .map(|key| key.get_bulk().unwrap().to_vec())
+            .collect();
+        let millis = (timeout * 1000.0).round() as u64;
+        args.push(millis.to_string().into_bytes());
+        Command {
+            command_type: cmd_type,
+            args,
+        }
+    }
+
+    /// parse_range_command parses `LRANGE key start stop` and `GETRANGE key start end`. The
+    /// indices must be integers.
+    pub(crate) fn parse_range_command(
+        frames: &[Frame],
+        cmd_type: CommandType,
+        name: &str,
+    ) -> Command {
+        if frames.len() != 4 {
+            return Command {
+                command_type: CommandType::ERROR,
+                args: vec![format!("{} command must have exactly 3 arguments", name).into_bytes()],
+            };
+        }
+
+        let args: Vec<Vec<u8>> = frames
+            .iter()
+            .skip(1)
+            .map(|frame| frame.get_bulk().unwrap().to_vec())
+            .collect();
+        let command = Command {
+            command_type: cmd_type,
+            args,
+        };
+        if command.arg_str(1).parse::<i64>().is_err() || command.arg_str(2).parse::<i64>().is_err()
+        {
+            let msg = "value is not an integer or out of range".to_string();
+            return Command::new(CommandType::ERROR, &[msg]);
+        }
+        command
+    }
+
+    /// parse_incrbyfloat_command parses `INCRBYFLOAT key increment`. The increment must be a
+    /// finite float.
+    pub(crate) fn parse_incrbyfloat_command(frames: &[Frame]) -> Command {
+        let command =
+            Self::parse_key_value_command(frames, CommandType::INCRBYFLOAT, "INCRBYFLOAT");
+        if command.command_type == CommandType::ERROR {
+            return command;
+        }
+        if !command.arg_str(1).parse::<f64>().is_ok_and(f64::is_finite) {
+            let msg = "value is not a valid float".to_string();
+            return Command::new(CommandType::ERROR, &[msg]);
+        }
+        command
+    }
+
+    // parse_key_value_command parses commands which take a key and a value.
+    fn parse_key_value_command(frames: &[Frame], cmd_type: CommandType, name: &str) -> Command {
+        if frames.len() != 3 {
+            return Command {
+                command_type: CommandType::ERROR,
+                args: vec![format!("{} command must have exactly 2 arguments", name).into_bytes()],
+            };
+        }
+
+        Command {
+            command_type: cmd_type,
+            args: vec![
+                frames[1].get_bulk().unwrap().to_vec(),
+                frames[2].get_bulk().unwrap().to_vec(),
+            ],
+        }
+    }
+
+    pub(crate) fn parse_pin_command(frames: &[Frame]) -> Command {
+        Self::parse_multi_key_command(frames, CommandType::PIN, "PIN")
+    }
+
+    pub(crate) fn parse_unpin_command(frames: &[Frame]) -> Command {
+        Self::parse_multi_key_command(frames, CommandType::UNPIN, "UNPIN")
+    }
+
+    /// parse_command_command parses COMMAND, which lists the commands, and its COUNT and
+    /// `DOCS [command ...]` subcommands. The args are empty, or the uppercase subcommand name.
+    pub(crate) fn parse_command_command(frames: &[Frame]) -> Command {
+        let Some(subcommand) = frames.get(1) else {
+            return Command::new(CommandType::COMMAND, &[]);
+        };
+        let subcommand = String::from_utf8_lossy(subcommand.get_bulk().unwrap());
+        match subcommand.to_uppercase().as_str() {
+            "COUNT" if frames.len() == 2 => {
+                Command::new(CommandType::COMMAND, &["COUNT".to_string()])
+            }
+            // the command names are ignored, there are no docs
+            "DOCS" => Command::new(CommandType::COMMAND, &["DOCS".to_string()]),
+            _ => {
+                let msg = format!("unknown subcommand '{}'. Try COMMAND HELP.", subcommand);
+                Command::new(CommandType::ERROR, &[msg])
+            }
+        }
+    }
+
+    /// parse_hello_command parses `HELLO [protover]`. The version is only checked to be an
+    /// integer, the handler tells whether it is supported.
+    pub(crate) fn parse_hello_command(frames: &[Frame]) -> Command {
+        let error = |msg: &str| Command::new(CommandType::ERROR, &[msg.to_string()]);
+        match frames {
+            [_] => Command::new(CommandType::HELLO, &[]),
+            [_, version] => match version.bulk_str().unwrap_or_default().parse::<i64>() {
+                Ok(version) => Command::new(CommandType::HELLO, &[version.to_string()]),
+                Err(_) => error("Protocol version is not an integer or out of range"),
+            },
+            _ => error("syntax error"),
+        }
+    }
+
+    /// parse_auth_command parses `AUTH [username] password`. The args are `[password]`, or
+    /// `[username, password]` when a username is given.
+    pub(crate) fn parse_auth_command(frames: &[Frame]) -> Command {
+        if frames.len() < 2 || frames.len() > 3 {
+            return Command::new(CommandType::ERROR, &["syntax error".to_string()]);
+        }
+        Command {
+            command_type: CommandType::AUTH,
+            args: frames[1..]
+                .iter()
+                .map(|frame| frame.get_bulk().unwrap().to_vec())
+                .collect(),
+        }
+    }
+
+    /// parse_select_command parses `SELECT index`. The index must be an integer, the handler
+    /// checks that the database exists.
+    pub(crate) fn parse_select_command(frames: &[Frame]) -> Command {
+        if frames.len() != 2 {
+            let msg = "SELECT command must have exactly 1 argument".to_string();
+            return Command::new(CommandType::ERROR, &[msg]);
+        }
+        match frames[1].bulk_str().unwrap_or_default().parse::<i64>() {
+            Ok(index) => Command::new(CommandType::SELECT, &[index.to_string()]),
+            Err(_) => Command::new(
+                CommandType::ERROR,
+                &["value is not an integer or out of range".to_string()],
+            ),
+        }
+    }
+
+    /// parse_wait_command parses `WAIT numreplicas timeout` into `[numreplicas, timeout]`. Both
+    /// must be integers, and the timeout, in milliseconds, cannot be negative.
+    pub(crate) fn parse_wait_command(frames: &[Frame]) -> Command {
+        if frames.len() != 3 {
+            let msg = "WAIT command must have exactly 2 arguments".to_string();
+            return Command::new(CommandType::ERROR, &[msg]);
+        }
+        let parse = |frame: &Frame| frame.bulk_str().unwrap_or_default().parse::<i64>();
+        match (parse(&frames[1]), parse(&frames[2])) {
+            (Ok(_), Ok(timeout)) if timeout < 0 => {
+                Command::new(CommandType::ERROR, &["timeout is negative".to_string()])
+            }
+            (Ok(replicas), Ok(timeout)) => Command::new(
+                CommandType::WAIT,
+                &[replicas.to_string(), timeout.to_string()],
+            ),
+            _ => Command::new(
+                CommandType::ERROR,
+                &["value is not an integer or out of range".to_string()],
+            ),
+        }
+    }
+
+    /// parse_shutdown_command parses `SHUTDOWN [NOSAVE | SAVE]` into `[]` or `["SAVE"]`. Without
+    /// save points, nothing is saved unless SAVE is given.
+    pub(crate) fn parse_shutdown_command(frames: &[Frame]) -> Command {
+        let option = frames
+            .get(1)
+            .map(|frame| frame.bulk_str().unwrap_or_default());
+        match (option, frames.len()) {
+            (None, _) => Command::new(CommandType::SHUTDOWN, &[]),
+            (Some(option), 2) if option.eq_ignore_ascii_case("NOSAVE") => {
+                Command::new(CommandType::SHUTDOWN, &[])
+            }
+            (Some(option), 2) if option.eq_ignore_ascii_case("SAVE") => {
+                Command::new(CommandType::SHUTDOWN, &["SAVE".to_string()])
+            }
+            _ => Command::new(CommandType::ERROR, &["syntax error".to_string()]),
+        }
+    }
+
+    /// parse_cas_command parses `CAS key expected new [PX milliseconds | PXAT
+    /// unix-time-milliseconds]` into `[key, expected, new]` or `[key, expected, new, milliseconds]`.
+    /// A PXAT deadline is turned into the milliseconds left until it, 0 if it passed.
+    pub(crate) fn parse_cas_command(frames: &[Frame]) -> Command {
+        if frames.len() != 4 && frames.len() != 6 {
+            return Command {
+                command_type: CommandType::ERROR,
+                args: vec!["CAS command must have 3 or 5 arguments".into()],
+            };
+        }
+
+        let mut args: Vec<Vec<u8>> = frames[1..4]
+            .iter()
+            .map(|frame| frame.get_bulk().unwrap().to_vec())
+            .collect();
+        if frames.len() == 6 {
+            let option = frames[4].bulk_str().unwrap_or_default();
+            let expiration = frames[5].bulk_str().unwrap_or_default();
+            if option.eq_ignore_ascii_case("PXAT") {
+                let Some(expiration) = relative_ms(expiration) else {
+                    return Command {
+                        command_type: CommandType::ERROR,
+                        args: vec!["expiration should be a valid number".into()],
+                    };
+                };
+                args.push(expiration.into_bytes());
+                return Command {
+                    command_type: CommandType::CAS,
+                    args,
+                };
+            }
+            if !option.eq_ignore_ascii_case("PX") {
+                return Command {
+                    command_type: CommandType::ERROR,
+                    args: vec![format!(
+                        "unknown option '{}' for CAS command",
+                        String::from_utf8_lossy(frames[4].get_bulk().unwrap())
+                    )
+                    .into_bytes()],
+                };
+            }
+            if !expiration.parse::<u64>().is_ok_and(|ms| ms > 0) {
+                return Command {
+                    command_type: CommandType::ERROR,
+                    args: vec!["expiration should be a positive number".into()],
+                };
+            }
+            args.push(expiration.into());
+        }
+        Command {
+            command_type: CommandType::CAS,
+            args,
+        }
+    }
+
+    /// tracked_key returns the key read by the command, if it is a read command whose key should
+    /// be tracked for client side caching.
+    pub(crate) fn tracked_key(&self) -> Option<&[u8]> {
+        match self.command_type {
+            CommandType::GET
+            | CommandType::GETRANGE
+            | CommandType::HKEYS
+            | CommandType::HVALS
+            | CommandType::HSCAN
+            | CommandType::SSCAN
+            | CommandType::ZSCAN
+            | CommandType::LRANGE
+            | CommandType::HGET
+            | CommandType::HGETALL => self.args.first().map(|key| key.as_slice()),
+            _ => None,
+        }
+    }
+
+    // parse_two_keys_command parses commands which take a source and a destination key.
+    fn parse_two_keys_command(frames: &[Frame], cmd_type: CommandType, name: &str) -> Command {
+        if frames.len() != 3 {
+            return Command {
+                command_type: CommandType::ERROR,
+                args: vec![format!("{} command must have exactly 2 arguments", name).into_bytes()],
+            };
+        }
+
+        Command {
+            command_type: cmd_type,
+            args: vec![
+                frames[1].get_bulk().unwrap().to_vec(),
+                frames[2].get_bulk().unwrap().to_vec(),
+            ],
+        }
+    }
+
+    // parse_multi_key_command parses commands which take one or more keys as their only arguments.
+    fn parse_multi_key_command(frames: &[Frame], cmd_type: CommandType, name: &str) -> Command {
+        if frames.len() < 2 {
+            return Command {
+                command_type: CommandType::ERROR,
+                args: vec![format!("{} command must have at least 1 argument", name).into_bytes()],
+            };
+        }
+
+        Command {
+            command_type: cmd_type,
+            args: frames
+                .iter()
+                .skip(1)
+                .map(|frame| frame.get_bulk().unwrap().to_vec())
+                .collect(),
+        }
+    }
+
+    /// parse_no_arg_command parses the commands which take no argument, like MULTI, EXEC, DISCARD,
+    /// SAVE and BGSAVE.
+    pub(crate) fn parse_no_arg_command(
+        frames: &[Frame],
+        cmd_type: CommandType,
+        name: &str,
+    ) -> Command {
+        if frames.len() != 1 {
+            return Command {
+                command_type: CommandType::ERROR,
+                args: vec![format!("{} command does not take arguments", name).into_bytes()],
+            };
+        }
+
+        Command {
+            command_type: cmd_type,
+            args: vec![],
+        }
+    }
+
+    // parse_single_key_command parses commands which take a key as their only argument.
+    fn parse_single_key_command(frames: &[Frame], cmd_type: CommandType, name: &str) -> Command {
+        if frames.len() != 2 {
+            return Command {
+                command_type: CommandType::ERROR,
+                args: vec![format!("{} command must have exactly 1 argument", name).into_bytes()],
+            };
+        }
+
+        Command {
+            command_type: cmd_type,
+            args: vec![frames[1].get_bulk().unwrap().to_vec()],
+        }
+    }
+
+    /// parse_hscan_command parses `HSCAN key cursor [MATCH pattern] [COUNT count] [NOVALUES]`.
+    /// The resulting args are always `[key, cursor, pattern, count, novalues]`, with defaults
+    /// filled in, so that the command handler does not have to deal with options.
+    pub(crate) fn parse_hscan_command(frames: &[Frame]) -> Command {
+        if frames.len() < 3 {
+            return Command {
+                command_type: CommandType::ERROR,
+                args: vec!["HSCAN command must have at least 2 arguments".into()],
+            };
+        }
+        let key = frames[1].get_bulk().unwrap();
+        let mut args = match Self::parse_scan_options(&frames[2..], true) {
+            Ok(args) => args,
+            Err(msg) => return Command::new(CommandType::ERROR, &[msg]),
+        };
+        args.insert(0, key.to_vec());
+        Command {
+            command_type: CommandType::HSCAN,
+            args,
+        }
+    }
+
+    /// parse_scan_command parses `SCAN cursor [MATCH pattern] [COUNT count]` into
+    /// `[cursor, pattern, count]`.
+    pub(crate) fn parse_scan_command(frames: &[Frame]) -> Command {
+        if frames.len() < 2 {
+            return Command {
+                command_type: CommandType::ERROR,
+                args: vec!["SCAN command must have at least 1 argument".into()],
+            };
+        }
+        match Self::parse_scan_options(&frames[1..], false) {
+            Ok(args) => Command {
+                command_type: CommandType::SCAN,
+                args,
+            },
+            Err(msg) => Command::new(CommandType::ERROR, &[msg]),
+        }
+    }
+
+    /// parse_sscan_command parses `SSCAN key cursor [MATCH pattern] [COUNT count]` into
+    /// `[key, cursor, pattern, count]`.
+    pub(crate) fn parse_sscan_command(frames: &[Frame]) -> Command {
+        Self::parse_collection_scan_command(frames, CommandType::SSCAN, "SSCAN")
+    }
+
+    /// parse_zscan_command parses `ZSCAN key cursor [MATCH pattern] [COUNT count]` into
+    /// `[key, cursor, pattern, count]`.
+    pub(crate) fn parse_zscan_command(frames: &[Frame]) -> Command {
+        Self::parse_collection_scan_command(frames, CommandType::ZSCAN, "ZSCAN")
+    }
+
+    fn parse_collection_scan_command(
+        frames: &[Frame],
+        cmd_type: CommandType,
+        name: &str,
+    ) -> Command {
+        if frames.len() < 3 {
+            return Command {
+                command_type: CommandType::ERROR,
+                args: vec![format!("{} command must have at least 2 arguments", name).into_bytes()],
+            };
+        }
+        let key = frames[1].get_bulk().unwrap();
+        let mut args = match Self::parse_scan_options(&frames[2..], false) {
+            Ok(args) => args,
+            Err(msg) => return Command::new(CommandType::ERROR, &[msg]),
+        };
+        args.insert(0, key.to_vec());
+        Command {
+            command_type: cmd_type,
+            args,
+        }
+    }
+
+    // parse_scan_options parses `cursor [MATCH pattern] [COUNT count]`, plus `[NOVALUES]` when
+    // `allow_novalues` is set. It returns `[cursor, pattern, count]`, with `novalues` appended
+    // when allowed ("1" when set, "0" otherwise).
+    fn parse_scan_options(frames: &[Frame], allow_novalues: bool) -> Result<Vec<Vec<u8>>, String> {
+        let cursor = frames[0].bulk_str().unwrap_or_default();
+        if cursor.parse::<usize>().is_err() {
+            return Err("invalid cursor".to_string());
+        }
+        let mut pattern = b"*".to_vec();
+        let mut count = "10".to_string();
+        let mut no_values = false;
+
+        let mut i = 1;
+        while i < frames.len() {
+            let option = frames[i].bulk_str().unwrap_or_default().to_uppercase();
+            match option.as_str() {
+                "MATCH" | "COUNT" => {
+                    let value = match frames.get(i + 1) {
+                        Some(frame) => frame,
+                        None => return Err("syntax error".to_string()),
+                    };
+                    if option == "MATCH" {
+                        pattern = value.get_bulk().unwrap().to_vec();
+                    } else {
+                        match value.bulk_str().unwrap_or_default().parse::<usize>() {
+                            Ok(n) if n > 0 => count = n.to_string(),
+                            _ => return Err("value is not an integer or out of range".to_string()),
+                        }
+                    }
+                    i += 2;
+                }
+                "NOVALUES" if allow_novalues => {
+                    no_values = true;
+                    i += 1;
+                }
+                _ => return Err("syntax error".to_string()),
+            }
+        }
+
+        let mut args = vec![cursor.into(), pattern, count.into_bytes()];
+        if allow_novalues {
+            args.push(if no_values { "1" } else { "0" }.into());
+        }
+        Ok(args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_table() {
+        assert_eq!(COMMAND_TABLE.len(), COMMAND_COUNT);
+        assert!(CommandType::ERROR.spec().is_none());
+        for (index, spec) in COMMAND_TABLE.iter().enumerate() {
+            assert_eq!(
+                spec.command_type as usize, index,
+                "{} should be in the order of the command types",
+                spec.name
+            );
+            assert_eq!(
+                spec.command_type.spec().map(|found| found.name),
+                Some(spec.name)
+            );
+            for name in [spec.name.to_string(), spec.name.to_lowercase()] {
+                assert_eq!(
+                    CommandType::from_name(name.as_bytes()),
+                    Some(spec.command_type),
+                    "{} should be found by its name",
+                    name
+                );
+            }
+            assert_eq!(spec.name, spec.name.to_uppercase());
+            assert!(spec.name.len() <= MAX_NAME_LEN, "{} is too long", spec.name);
+            assert!(
+                (spec.command_type as usize) < COMMAND_COUNT,
+                "ERROR should be the last command type"
+            );
+        }
     }
 }
@@ -0,0 +1,506 @@
+//! The RESP decoder. It reads frames from any buffered reader, so that it can be used outside of
+//! the connections of the server, to replay a file or in a client.
+
+use crate::parser::{Frame, FrameData, FrameID};
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use tokio::io::{self, AsyncBufRead, AsyncBufReadExt, AsyncReadExt, ErrorKind};
+use tracing::{error, trace};
+
+/// DecodeLimits bounds what a client can make the decoder allocate, and what it accepts.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct DecodeLimits {
+    /// Maximum length of a bulk string, in bytes.
+    pub max_bulk_len: usize,
+    /// Maximum number of elements of an aggregate frame, at any nesting level.
+    pub max_array_elements: usize,
+    /// Maximum nesting depth of aggregate frames. A non-nested array has a depth of 1.
+    pub max_depth: usize,
+    /// Whether inline commands, sent as a plain line of text, are accepted.
+    pub inline_commands: bool,
+    /// Maximum length of a line, an inline command or the payload of a simple frame, in bytes.
+    pub max_inline_size: usize,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        DecodeLimits {
+            // 512MB, like Redis proto-max-bulk-len
+            max_bulk_len: 512 * 1024 * 1024,
+            max_array_elements: 1024 * 1024,
+            max_depth: 128,
+            inline_commands: true,
+            // 64KB, like Redis PROTO_INLINE_MAX_SIZE
+            max_inline_size: 64 * 1024,
+        }
+    }
+}
+
+/// DecodeError tells why a frame could not be decoded.
+#[derive(Debug, Eq, PartialEq)]
+pub enum DecodeError {
+    // Need more data to decode frame
+    Incomplete,
+    // Frame is not correctly formatted
+    Invalid,
+    // reached expected EOF
+    Eof,
+    // Unidentified IO error
+    IOError,
+    // UTF8 to Int error
+    UTF8ToInt,
+    // Unknown frame type
+    UnknownFrame,
+    // Empty inline command line, which is ignored
+    EmptyLine,
+    // This is a programming error. It should not happen.
+    Syntax(String),
+    // Fatal network error, the network can no longer process traffic
+    FatalNetworkError,
+    // The client stayed idle for longer than the idle timeout
+    Timeout,
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Incomplete => write!(f, "not enough data to decode a full frame"),
+            DecodeError::Invalid => write!(f, "frame is not correctly formatted"),
+            DecodeError::Eof => write!(f, "seen EOF, this is generally a graceful disconnection"),
+            DecodeError::IOError => write!(f, "unexpected IO error"),
+            DecodeError::UTF8ToInt => write!(f, "utf8 to int decoding error"),
+            DecodeError::UnknownFrame => write!(f, "unable to identify the frame type"),
+            DecodeError::EmptyLine => write!(f, "empty inline command"),
+            DecodeError::Syntax(message) => write!(f, "{}", message),
+            DecodeError::FatalNetworkError => write!(f, "fatal network error occurred"),
+            DecodeError::Timeout => write!(f, "client idle for too long"),
+        }
+    }
+}
+
+// Convert io::Error to DecodeError. Decode error is more specific to what can happen during an
+// attempt to decode a frame. Some of the issues can be IO and some other issues like Atoi or
+// syntax.
+impl From<io::Error> for DecodeError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            ErrorKind::UnexpectedEof => DecodeError::Eof,
+            ErrorKind::TimedOut => DecodeError::Timeout,
+            ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe
+            | ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionReset
+            | ErrorKind::NotConnected => {
+                // log the error here to give more hints to the caller
+                error!("fatal network io error occurred: {}", err);
+                DecodeError::FatalNetworkError
+            }
+            _ => DecodeError::IOError,
+        }
+    }
+}
+
+/// decode reads the next frame from `reader`, within `limits`. It returns `DecodeError::Eof` when
+/// the reader ends between two frames, a graceful close, and `DecodeError::Incomplete` when it
+/// ends in the middle of one, a truncated frame. A frame split across several reads is
+/// accumulated until it is complete.
+///
+/// ```
+/// use mredis::{decode, DecodeLimits, Frame};
+///
+/// # tokio::runtime::Runtime::new().unwrap().block_on(async {
+/// let mut reader: &[u8] = b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n";
+/// let frame = decode(&mut reader, DecodeLimits::default()).await.unwrap();
+/// assert_eq!(frame, Frame::command(&["GET", "key"]));
+/// # });
+/// ```
+pub async fn decode<R>(reader: &mut R, limits: DecodeLimits) -> Result<Frame, DecodeError>
+where
+    R: AsyncBufRead + Unpin,
+{
+    Decoder { reader, limits }.decode_frame().await
+}
+
+// Decoder reads a frame from `reader`. It only lives for the frame.
+struct Decoder<'a, R> {
+    reader: &'a mut R,
+    limits: DecodeLimits,
+}
+
+impl<R> Decoder<'_, R>
+where
+    R: AsyncBufRead + Unpin,
+{
+    // decode_frame decodes the next frame of the reader, see `decode`.
+    async fn decode_frame(&mut self) -> Result<Frame, DecodeError> {
+        trace!("started to decode a frame");
+        let first = self.reader.read_u8().await?;
+        // past the first byte, the end of the stream cuts the frame short
+        self.decode_frame_from(first)
+            .await
+            .map_err(|err| match err {
+                DecodeError::Eof => DecodeError::Incomplete,
+                err => err,
+            })
+    }
+
+    // decode_frame_from decodes the frame starting with `first`, which was already read.
+    async fn decode_frame_from(&mut self, first: u8) -> Result<Frame, DecodeError> {
+        let id = match FrameID::from_u8(&first) {
+            Some(id) => id,
+            None if self.limits.inline_commands => return self.decode_inline_frame(first).await,
+            None => return Err(DecodeError::UnknownFrame),
+        };
+        match id {
+            FrameID::SimpleString
+            | FrameID::SimpleError
+            | FrameID::Null
+            | FrameID::Boolean
+            | FrameID::BigNumber
+            | FrameID::Integer
+            | FrameID::Double => self.decode_simple_frame(id).await,
+
+            FrameID::BulkString | FrameID::BulkError => self.decode_bulk_frame(id).await,
+
+            FrameID::Array | FrameID::Push => {
+                let frame_vec = self.decode_aggregate_frame(id).await?;
+                Ok(Frame {
+                    frame_type: id,
+                    frame_data: FrameData::Nested(frame_vec),
+                })
+            }
+
+            // maps are only sent by the server, from_u8 does not recognize them
+            FrameID::Map => Err(DecodeError::UnknownFrame),
+        }
+    }
+
+    async fn get_frame_id(&mut self) -> Result<FrameID, DecodeError> {
+        let id = self.reader.read_u8().await?;
+        FrameID::from_u8(&id).ok_or(DecodeError::UnknownFrame)
+    }
+
+    /// decode_inline_frame decodes an inline command: a line of words separated by spaces, as
+    /// sent from telnet or by load balancers health checks. It returns an array of bulk strings,
+    /// like a command sent in RESP. `first` is the first byte of the line, which has already
+    /// been read to tell that it is not a RESP frame.
+    async fn decode_inline_frame(&mut self, first: u8) -> Result<Frame, DecodeError> {
+        let mut line = vec![first];
+        if first != b'\n' {
+            self.read_line(&mut line).await?;
+        }
+        if line.last() != Some(&b'\n') {
+            return Err(DecodeError::Incomplete);
+        }
+        let words: Vec<Frame> = line
+            .split(|byte| byte.is_ascii_whitespace())
+            .filter(|word| !word.is_empty())
+            .map(Frame::new_bulk_string)
+            .collect();
+        if words.is_empty() {
+            return Err(DecodeError::EmptyLine);
+        }
+        Ok(Frame::new_array(words))
+    }
+
+    async fn decode_bulk_frame(&mut self, id: FrameID) -> Result<Frame, DecodeError> {
+        let data = self.read_bulk_string().await?;
+        Ok(match data {
+            Some(data) => Frame {
+                frame_type: id,
+                frame_data: FrameData::Bulk(data.into()),
+            },
+            // RESP2 null bulk string
+            None => Frame::new_null(),
+        })
+    }
+
+    /// `read_bulk_string` return a bulk string, or None for the RESP2 null bulk string `$-1\r\n`.
+    /// Bulk strings are binary safe, the bytes are returned as they were sent.
+    async fn read_bulk_string(&mut self) -> Result<Option<Vec<u8>>, DecodeError> {
+        // e.g: "6\r\nfoobar\r\n"
+        let len = self.read_integer().await?;
+        if len == -1 {
+            return Ok(None);
+        }
+        // Check the length before allocating, a client should not be able to make us allocate
+        // whatever it wants.
+        if len < 0 || len as u64 > self.limits.max_bulk_len as u64 {
+            error!("invalid bulk string length: {}", len);
+            return Err(DecodeError::Invalid);
+        }
+        // we have to read len + CRLF
+        let len = len as usize + 2;
+
+        let mut buf = vec![0; len];
+        let size = self.reader.read_exact(&mut buf).await?;
+        // we need to read exact size bytes, terminated by CRLF
+        if size != len || size < 2 || buf[size - 2] != b'\r' || buf[size - 1] != b'\n' {
+            return Err(DecodeError::Invalid);
+        }
+        buf.truncate(len - 2);
+        Ok(Some(buf))
+    }
+
+    async fn read_integer(&mut self) -> Result<i64, DecodeError> {
+        let data = self.read_simple_string().await?;
+        let data = data.parse().map_err(|_err| DecodeError::Invalid)?;
+        Ok(data)
+    }
+
+    async fn decode_simple_frame(&mut self, id: FrameID) -> Result<Frame, DecodeError> {
+        let data = self.read_simple_string().await?;
+        match id {
+            FrameID::Boolean => {
+                let bool = Self::validate_bool(&data)?;
+                Ok(Frame {
+                    frame_type: id,
+                    frame_data: FrameData::Boolean(bool),
+                })
+            }
+            FrameID::Integer => {
+                let data = data.parse().map_err(|_err| DecodeError::UTF8ToInt)?;
+                Ok(Frame {
+                    frame_type: id,
+                    frame_data: FrameData::Integer(data),
+                })
+            }
+            FrameID::Double => {
+                let data = Self::validate_double(&data)?;
+                Ok(Frame {
+                    frame_type: id,
+                    frame_data: FrameData::Double(data),
+                })
+            }
+            FrameID::Null => {
+                if !data.is_empty() {
+                    // nil frame should not contain data
+                    return Err(DecodeError::Invalid);
+                }
+                Ok(Frame {
+                    frame_type: id,
+                    frame_data: FrameData::Null,
+                })
+            }
+            _ => Ok(Frame {
+                frame_type: id,
+                frame_data: FrameData::Simple(data),
+            }),
+        }
+    }
+
+    fn validate_bool(data: &str) -> Result<bool, DecodeError> {
+        match data {
+            "t" => Ok(true),
+            "f" => Ok(false),
+            _ => Err(DecodeError::Invalid),
+        }
+    }
+
+    /// validate_double parses the payload of a double frame. Besides regular numbers, RESP3 allows
+    /// `inf`, `-inf` and `nan`.
+    fn validate_double(data: &str) -> Result<f64, DecodeError> {
+        match data {
+            "inf" => Ok(f64::INFINITY),
+            "-inf" => Ok(f64::NEG_INFINITY),
+            "nan" => Ok(f64::NAN),
+            _ => {
+                // Rust also accepts spellings like "infinity" or "NaN", which are not valid RESP.
+                if data
+                    .chars()
+                    .any(|c| c.is_ascii_alphabetic() && c != 'e' && c != 'E')
+                {
+                    return Err(DecodeError::Invalid);
+                }
+                data.parse().map_err(|_err| DecodeError::Invalid)
+            }
+        }
+    }
+
+    /// `read_simple_string` gets a simple string from the network. As a reminder, such string does
+    /// not contain any CR or LF char in the middle. This method assumes the frame identifier has
+    /// already been taken from the stream. So, for instance, consider you have something like
+    /// `HELLO\r\n` instead of `+HELLO\r\n` in the stream while calling this method.
+    /// A line split across several reads, at a buffer boundary or between two TCP segments, is
+    /// accumulated until its LF comes: `read_until` only stops early at the end of the stream. So
+    /// `DecodeError::Incomplete` means that the stream ended in the middle of the line.
+    /// The error returned is the same as `tokio::io::BufReader::read_until()` or one of the following:
+    async fn read_simple_string(&mut self) -> Result<String, DecodeError> {
+        let mut buf = Vec::new();
+        let size = self.read_line(&mut buf).await?;
+        match size {
+            0 => Err(DecodeError::Eof),
+            _ => {
+                if size < 2 {
+                    return Err(DecodeError::Incomplete);
+                }
+                if buf[size - 1] != b'\n' {
+                    return Err(DecodeError::Incomplete);
+                }
+                if buf[size - 2] != b'\r' {
+                    return Err(DecodeError::Invalid);
+                }
+                // We should also check if there is any CR in the middle, but this check is made upfront.
+                // The reason is to perform this expensive check only if needed. Also, this function result
+                // is used in places that naturally check the correctness of the frame content (for instance, conversion to int).
+                Ok(String::from_utf8_lossy(&buf[0..size - 2]).to_string())
+            }
+        }
+    }
+
+    // read_line appends a line to `buf`, up to and including its LF, and returns how many bytes
+    // were read. A line longer than max_inline_size is an error, and what follows it is not read:
+    // a client must not be able to make us buffer whatever it sends before a LF.
+    async fn read_line(&mut self, buf: &mut Vec<u8>) -> Result<usize, DecodeError> {
+        // the line may be followed by CRLF
+        let max = self.limits.max_inline_size as u64 + 2;
+        let start = buf.len();
+        let size = (&mut self.reader).take(max).read_until(b'\n', buf).await?;
+        if size as u64 == max && buf.last() != Some(&b'\n') {
+            error!(
+                "Protocol error: too big inline request, longer than {} bytes",
+                self.limits.max_inline_size
+            );
+            buf.truncate(start);
+            return Err(DecodeError::Invalid);
+        }
+        Ok(size)
+    }
+
+    /// decode_aggregate_frame decodes a bucket of frames iteratively.
+    /// We have frame ID in the signature because aggregate can be of different types.
+    /// So, we need to keep track of the IDs to construct the right aggregate frame when needed.
+    /// This function can be used to decode Arrays, Pushes, Maps, and Sets.
+    async fn decode_aggregate_frame(&mut self, id: FrameID) -> Result<Vec<Frame>, DecodeError> {
+        // "3\r\n:1\r\n:2\r\n:3\r\n" -> [1, 2, 3]
+        // "*2\r\n:1\r\n*1\r\n+Three\r\n"
+        let count = self.read_aggregate_count().await?;
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        // Do not pre-allocate from the declared count, it is controlled by the client.
+        let frames: Vec<Frame> = Vec::new();
+        let mut stack = Vec::new();
+        stack.push((id, count, frames));
+        loop {
+            let id = self.get_frame_id().await?;
+            let mut frame = match id {
+                FrameID::Array | FrameID::Push => {
+                    let count = self.read_aggregate_count().await?;
+                    if count != 0 {
+                        // Deep nesting is never needed by commands, do not let clients make the
+                        // stack grow without limit.
+                        if stack.len() >= self.limits.max_depth {
+                            error!(
+                                "aggregate frame nested deeper than {}",
+                                self.limits.max_depth
+                            );
+                            return Err(DecodeError::Invalid);
+                        }
+                        let frames: Vec<Frame> = Vec::new();
+                        stack.push((id, count, frames));
+                        continue;
+                    }
+                    // an empty aggregate is complete right away
+                    Frame {
+                        frame_type: id,
+                        frame_data: FrameData::Nested(Vec::new()),
+                    }
+                }
+                _ => self.process_non_aggregate(id).await?,
+            };
+            // Append the frame to its parent. If count == 0, we've decoded an entire aggregate.
+            // So push it to the penultimate aggregate in the stack if any. If there is no more
+            // aggregate in the stack, this means we should return as the total frame was
+            // completely processed. We need to loop to successively pop completed vector of
+            // frames and push them to their parent until we finish piping or find a vector which
+            // is incomplete.
+            loop {
+                let (_, remaining, frames) = stack.last_mut().unwrap();
+                frames.push(frame);
+                // A completed aggregate is popped right away, so the count of the current one
+                // cannot be zero here. Still, never let it wrap around if that changes.
+                *remaining = remaining.checked_sub(1).ok_or(DecodeError::Invalid)?;
+                if *remaining != 0 {
+                    break;
+                }
+                let (id, _, last_vec_of_frames) = stack.pop().unwrap();
+                // The full global frame was decoded, so return
+                if stack.is_empty() {
+                    return Ok(last_vec_of_frames);
+                }
+                // Here is why we needed to keep track of the IDs, to build the right aggregate.
+                frame = Frame {
+                    frame_type: id,
+                    frame_data: FrameData::Nested(last_vec_of_frames),
+                };
+            }
+        }
+    }
+
+    // read_aggregate_count reads the number of elements of an aggregate frame. The count must
+    // not be negative, and is bounded so that a client cannot make us decode frames forever.
+    async fn read_aggregate_count(&mut self) -> Result<usize, DecodeError> {
+        let count = self.read_integer().await?;
+        match usize::try_from(count) {
+            Ok(count) if count <= self.limits.max_array_elements => Ok(count),
+            _ => {
+                error!("invalid aggregate element count: {}", count);
+                Err(DecodeError::Invalid)
+            }
+        }
+    }
+
+    /// process_non_aggregate is a helper to decode non-aggregate frames. It calls the appropriate
+    /// processing method depending on the frame type. It should not receive an aggregate type.
+    async fn process_non_aggregate(&mut self, id: FrameID) -> Result<Frame, DecodeError> {
+        match id {
+            FrameID::Array | FrameID::Push => Err(DecodeError::Syntax(
+                "received aggregate frame in non aggregate decoding".to_string(),
+            )),
+            FrameID::BulkString | FrameID::BulkError => self.decode_bulk_frame(id).await,
+            FrameID::Map => Err(DecodeError::UnknownFrame),
+            _ => self.decode_simple_frame(id).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn decode_test() {
+        // a slice is a buffered reader of its own, it does not need to be wrapped
+        let mut reader: &[u8] = b"+OK\r\n*2\r\n$4\r\nECHO\r\n$2\r\nhi\r\nPING\r\n:4";
+        let limits = DecodeLimits::default();
+        assert_eq!(
+            decode(&mut reader, limits).await,
+            Ok(Frame::new_simple_string("OK"))
+        );
+        assert_eq!(
+            decode(&mut reader, limits).await,
+            Ok(Frame::command(&["ECHO", "hi"]))
+        );
+        assert_eq!(
+            decode(&mut reader, limits).await,
+            Ok(Frame::command(&["PING"])),
+            "inline commands are accepted by default"
+        );
+        assert_eq!(
+            decode(&mut reader, limits).await,
+            Err(DecodeError::Incomplete)
+        );
+        assert_eq!(decode(&mut reader, limits).await, Err(DecodeError::Eof));
+
+        let mut reader: &[u8] = b"PING\r\n";
+        let limits = DecodeLimits {
+            inline_commands: false,
+            ..DecodeLimits::default()
+        };
+        assert_eq!(
+            decode(&mut reader, limits).await,
+            Err(DecodeError::UnknownFrame)
+        );
+    }
+}
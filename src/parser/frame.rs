@@ -1,7 +1,9 @@
 use crate::parser::{Command, CommandType};
+use bytes::Bytes;
 use std::fmt;
 use std::fmt::{Display, Formatter};
-use tracing::debug;
+use std::io::Write;
+use tracing::{debug, trace};
 
 /// `FrameID` is used to mark the beginning of a frame type. We have decided to implement only what
 /// is needed as we go. This is why there are some commented types. We wanted to implement them all
@@ -9,9 +11,8 @@ use tracing::debug;
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 #[repr(u8)]
 pub(crate) enum FrameID {
-    Integer = 58, // ':'
-    // @TODO: remove for now
-    // Double = 44,       // ','
+    Integer = 58,      // ':'
+    Double = 44,       // ','
     SimpleString = 43, // '+'
     SimpleError = 45,  // '-'
     BulkString = 36,   // '$'
@@ -22,17 +23,17 @@ pub(crate) enum FrameID {
     Null = 95,      // '_'
     BigNumber = 40, // '('
     Array = 42,     // '*'
+    Push = 62,      // '>'
+    Map = 37,       // '%', only sent by the server
                     // @TODO: remove for now
-                    // Map = 37,       // '%'
                     // Set = 126,      // '~'
-                    // Push = 62,      // '>'
 }
 
 impl FrameID {
     pub(crate) fn from_u8(from: &u8) -> Option<FrameID> {
         match from {
             58 => Some(FrameID::Integer),
-            // 44 => Some(FrameID::Double),
+            44 => Some(FrameID::Double),
             43 => Some(FrameID::SimpleString),
             45 => Some(FrameID::SimpleError),
             36 => Some(FrameID::BulkString),
@@ -43,47 +44,39 @@ impl FrameID {
             42 => Some(FrameID::Array),
             // 37 => Some(FrameID::Map),
             // 126 => Some(FrameID::Set),
-            // 62 => Some(FrameID::Push),
+            62 => Some(FrameID::Push),
             _ => None,
         }
     }
+
+    #[allow(dead_code)]
+    pub(crate) fn as_u8(&self) -> u8 {
+        *self as u8
+    }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+/// Protocol is the version of RESP spoken with a client. Clients start with RESP2 and can switch
+/// with HELLO.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum Protocol {
+    Resp2,
+    Resp3,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub(crate) enum FrameData {
     Null,
     Simple(String),
     Integer(i64),
+    Double(f64),
     Boolean(bool),
-    Bulk(String),
+    /// Bulk strings are binary safe, they can hold any bytes. They are shared with the storage, so
+    /// that replying with a stored value does not copy it.
+    Bulk(Bytes),
     Nested(Vec<Frame>),
 }
 
 impl FrameData {
-    fn get_integer(&self) -> Option<i64> {
-        match self {
-            FrameData::Integer(value) => Some(*value),
-            _ => None,
-        }
-    }
-    fn get_string(&self) -> Option<&String> {
-        match self {
-            FrameData::Simple(value) => Some(value),
-            _ => None,
-        }
-    }
-    fn get_bulk(&self) -> Option<&String> {
-        match self {
-            FrameData::Bulk(data) => Some(data),
-            _ => None,
-        }
-    }
-    fn get_boolean(&self) -> Option<bool> {
-        match self {
-            FrameData::Boolean(value) => Some(*value),
-            _ => None,
-        }
-    }
     pub(crate) fn get_nested(&self) -> Option<&Vec<Frame>> {
         match self {
             FrameData::Nested(value) => Some(value),
@@ -92,44 +85,117 @@ impl FrameData {
     }
 }
 
+/// InvalidSimpleFrame is returned when creating a simple frame from a string containing CR or LF.
 #[derive(Debug, Eq, PartialEq)]
-pub(crate) struct Frame {
+pub(crate) struct InvalidSimpleFrame;
+
+impl Display for InvalidSimpleFrame {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "simple frames cannot contain CR or LF")
+    }
+}
+
+// checked_simple returns the content of a simple frame, if `inner` can be one.
+fn checked_simple(inner: &str) -> Result<String, InvalidSimpleFrame> {
+    if inner.contains(['\r', '\n']) {
+        return Err(InvalidSimpleFrame);
+    }
+    Ok(inner.to_string())
+}
+
+// sanitize_simple replaces the characters a simple frame cannot contain by spaces.
+fn sanitize_simple(inner: &str) -> String {
+    inner.replace(['\r', '\n'], " ")
+}
+
+/// Frame is a RESP value, as read from or written to a client. Outside of the crate, it is only
+/// meant to build requests, see `Frame::command`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
     pub(crate) frame_type: FrameID,
     pub(crate) frame_data: FrameData,
 }
 
 impl Frame {
+    /// command builds the request of a command: an array of bulk strings, the command name
+    /// followed by its arguments.
+    ///
+    /// ```
+    /// use mredis::Frame;
+    ///
+    /// let request = Frame::command(&["GET", "key"]);
+    /// assert_eq!(request.encode_to_vec(), b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n");
+    /// ```
+    pub fn command<S: AsRef<[u8]>>(args: &[S]) -> Frame {
+        Frame::new_array(args.iter().map(Frame::new_bulk_string).collect())
+    }
+
+    /// encode_to_vec returns the frame as sent on the wire, in RESP2. Requests are spoken the same
+    /// way in RESP2 and RESP3.
+    pub fn encode_to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode(Protocol::Resp2, &mut out);
+        out
+    }
+
     pub(crate) fn get_array(&self) -> Option<&Vec<Frame>> {
         if self.frame_type != FrameID::Array {
             return None;
         }
         self.frame_data.get_nested()
     }
-    pub(crate) fn get_bulk(&self) -> Option<&String> {
+    /// get_bulk returns the payload of a bulk frame, which can hold any bytes.
+    pub(crate) fn get_bulk(&self) -> Option<&[u8]> {
         match &self.frame_data {
             FrameData::Bulk(data) => Some(data),
             _ => None,
         }
     }
 
+    /// bulk_str returns the payload of a bulk frame known to be text, like a command name or an
+    /// option. It is None if the payload is not valid UTF-8.
+    pub(crate) fn bulk_str(&self) -> Option<&str> {
+        std::str::from_utf8(self.get_bulk()?).ok()
+    }
+
+    #[allow(dead_code)]
     pub(crate) fn new_bulk_error(inner: &str) -> Frame {
         Frame {
             frame_type: FrameID::BulkError,
-            frame_data: FrameData::Bulk(inner.to_string()),
+            frame_data: FrameData::Bulk(Bytes::copy_from_slice(inner.as_bytes())),
         }
     }
 
+    /// try_new_simple_string creates a simple string frame. It fails if `inner` contains CR or LF,
+    /// which would end the frame early on the wire.
+    pub(crate) fn try_new_simple_string(inner: &str) -> Result<Frame, InvalidSimpleFrame> {
+        Ok(Frame {
+            frame_type: FrameID::SimpleString,
+            frame_data: FrameData::Simple(checked_simple(inner)?),
+        })
+    }
+
+    /// new_simple_string creates a simple string frame. Any CR or LF in `inner` is replaced by a
+    /// space so that the frame is always well formed.
     pub(crate) fn new_simple_string(inner: &str) -> Frame {
-        Frame {
+        Self::try_new_simple_string(inner).unwrap_or_else(|_| Frame {
             frame_type: FrameID::SimpleString,
-            frame_data: FrameData::Simple(inner.to_string()),
+            frame_data: FrameData::Simple(sanitize_simple(inner)),
+        })
+    }
+
+    pub(crate) fn new_bulk_string(inner: impl AsRef<[u8]>) -> Frame {
+        Frame {
+            frame_type: FrameID::BulkString,
+            frame_data: FrameData::Bulk(Bytes::copy_from_slice(inner.as_ref())),
         }
     }
 
-    pub(crate) fn new_bulk_string(inner: &str) -> Frame {
+    /// new_bulk_bytes creates a bulk string frame sharing `inner`, without copying it.
+    pub(crate) fn new_bulk_bytes(inner: Bytes) -> Frame {
         Frame {
             frame_type: FrameID::BulkString,
-            frame_data: FrameData::Bulk(inner.to_string()),
+            frame_data: FrameData::Bulk(inner),
         }
     }
 
@@ -140,6 +206,15 @@ impl Frame {
         }
     }
 
+    /// new_null_array returns the null of the commands replying with an array, like BLPOP once it
+    /// timed out. RESP2 has a null array of its own, RESP3 only has one null.
+    pub(crate) fn new_null_array() -> Frame {
+        Frame {
+            frame_type: FrameID::Array,
+            frame_data: FrameData::Null,
+        }
+    }
+
     pub(crate) fn new_integer(inner: i64) -> Frame {
         Frame {
             frame_type: FrameID::Integer,
@@ -147,6 +222,15 @@ impl Frame {
         }
     }
 
+    #[allow(dead_code)]
+    pub(crate) fn new_double(inner: f64) -> Frame {
+        Frame {
+            frame_type: FrameID::Double,
+            frame_data: FrameData::Double(inner),
+        }
+    }
+
+    #[allow(dead_code)]
     pub(crate) fn new_bool(inner: bool) -> Frame {
         Frame {
             frame_type: FrameID::Boolean,
@@ -154,14 +238,120 @@ impl Frame {
         }
     }
 
+    /// try_new_simple_error creates a simple error frame. It fails if `inner` contains CR or LF,
+    /// which would end the frame early on the wire.
+    pub(crate) fn try_new_simple_error(inner: &str) -> Result<Frame, InvalidSimpleFrame> {
+        Ok(Frame {
+            frame_type: FrameID::SimpleError,
+            frame_data: FrameData::Simple(checked_simple(inner)?),
+        })
+    }
+
+    /// new_simple_error creates a simple error frame. Any CR or LF in `inner` is replaced by a
+    /// space, error messages often quote user input which can contain them.
     pub(crate) fn new_simple_error(inner: &str) -> Frame {
-        Frame {
+        Self::try_new_simple_error(inner).unwrap_or_else(|_| Frame {
             frame_type: FrameID::SimpleError,
-            frame_data: FrameData::Simple(inner.to_string()),
+            frame_data: FrameData::Simple(sanitize_simple(inner)),
+        })
+    }
+
+    pub(crate) fn new_array(frames: Vec<Frame>) -> Frame {
+        Frame {
+            frame_type: FrameID::Array,
+            frame_data: FrameData::Nested(frames),
+        }
+    }
+
+    /// new_map creates a RESP3 map frame. The pairs are stored flattened, each key followed by its
+    /// value.
+    pub(crate) fn new_map(pairs: Vec<(Frame, Frame)>) -> Frame {
+        Frame {
+            frame_type: FrameID::Map,
+            frame_data: FrameData::Nested(
+                pairs
+                    .into_iter()
+                    .flat_map(|(key, value)| [key, value])
+                    .collect(),
+            ),
+        }
+    }
+
+    /// new_push creates a RESP3 push frame. Push frames are sent by the server without a prior
+    /// request from the client (pub/sub messages, keyspace notifications, etc.).
+    #[allow(dead_code)]
+    pub(crate) fn new_push(frames: Vec<Frame>) -> Frame {
+        Frame {
+            frame_type: FrameID::Push,
+            frame_data: FrameData::Nested(frames),
         }
     }
 
-    pub(crate) fn to_command(&self) -> Command {
+    /// encode appends the frame to `out`, as spoken by `proto`. Display gives the RESP3 form, RESP2
+    /// has no null, map, boolean or double, so they are replaced by their RESP2 counterparts.
+    /// Push frames have no RESP2 form, clients have to switch to RESP3 to get them.
+    pub(crate) fn encode(&self, proto: Protocol, out: &mut Vec<u8>) {
+        trace!("encoding {:?} frame", self.frame_type);
+        // writing to a Vec cannot fail, and a frame with mismatched data is not encoded
+        let _ = match (proto, self.frame_type, &self.frame_data) {
+            (Protocol::Resp2, FrameID::Null, _) => write!(out, "$-1\r\n"),
+            (Protocol::Resp2, FrameID::Array, FrameData::Null) => write!(out, "*-1\r\n"),
+            (Protocol::Resp2, FrameID::Boolean, FrameData::Boolean(value)) => {
+                write!(out, ":{}\r\n", *value as i64)
+            }
+            (Protocol::Resp2, FrameID::Double, FrameData::Double(value)) => {
+                let double = format_double(*value);
+                write!(out, "${}\r\n{}\r\n", double.len(), double)
+            }
+            (Protocol::Resp2, FrameID::Array | FrameID::Map, FrameData::Nested(frames)) => {
+                // maps are sent flattened, as an array of keys and values
+                let _ = write!(out, "*{}\r\n", frames.len());
+                for frame in frames {
+                    frame.encode(proto, out);
+                }
+                Ok(())
+            }
+            (_, FrameID::Integer, FrameData::Integer(value)) => write!(out, ":{}\r\n", value),
+            (_, FrameID::Double, FrameData::Double(value)) => {
+                write!(out, ",{}\r\n", format_double(*value))
+            }
+            (_, FrameID::SimpleString, FrameData::Simple(value)) => write!(out, "+{}\r\n", value),
+            (_, FrameID::SimpleError, FrameData::Simple(value)) => write!(out, "-{}\r\n", value),
+            (_, FrameID::BigNumber, FrameData::Simple(value)) => write!(out, "({}\r\n", value),
+            (_, FrameID::BulkString | FrameID::BulkError, FrameData::Bulk(data)) => {
+                let prefix = if self.frame_type == FrameID::BulkString {
+                    '$'
+                } else {
+                    '!'
+                };
+                let _ = write!(out, "{}{}\r\n", prefix, data.len());
+                out.extend_from_slice(data);
+                out.extend_from_slice(b"\r\n");
+                Ok(())
+            }
+            (_, FrameID::Boolean, FrameData::Boolean(value)) => {
+                write!(out, "#{}\r\n", if *value { "t" } else { "f" })
+            }
+            (_, FrameID::Null, _) | (_, FrameID::Array, FrameData::Null) => write!(out, "_\r\n"),
+            (_, FrameID::Array | FrameID::Push | FrameID::Map, FrameData::Nested(frames)) => {
+                let _ = match self.frame_type {
+                    FrameID::Array => write!(out, "*{}\r\n", frames.len()),
+                    FrameID::Push => write!(out, ">{}\r\n", frames.len()),
+                    // the length of a map is its number of pairs
+                    _ => write!(out, "%{}\r\n", frames.len() / 2),
+                };
+                for frame in frames {
+                    frame.encode(proto, out);
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        };
+    }
+
+    /// to_command parses the command requested by the frame. It never fails, a frame which is not
+    /// a valid command gives a command of type ERROR, holding what is wrong with it.
+    pub fn to_command(&self) -> Command {
         // If self.validate_command_array() returns None, the method continues execution.
         if let Some(command) = self.validate_command_array() {
             return command;
@@ -170,26 +360,117 @@ impl Frame {
         // It is safe to unwrap as we validated the frame array just before.
         // This assumes self.get_array() is infallible after self.validate_command_array() is Some.
         let args_frames = self.get_array().unwrap();
-        let cmd_name = args_frames[0].get_bulk().unwrap().to_uppercase();
+        let cmd_name = args_frames[0].get_bulk().unwrap();
+        if cmd_name.is_empty() {
+            // Nothing to look up, and an empty name would make a confusing log line.
+            debug!("received a command with an empty name");
+            return Command::new(CommandType::ERROR, &["unknown command ''".to_string()]);
+        }
 
-        if let Some(command_type) = Command::make_redis_command_map().get(cmd_name.as_str()) {
+        if let Some(command_type) = CommandType::from_name(cmd_name) {
             return match command_type {
                 CommandType::PING => Command::parse_ping_command(args_frames),
                 CommandType::GET => Command::parse_get_command(args_frames),
                 CommandType::SET => Command::parse_set_command(args_frames),
-                CommandType::DEL => Command::parse_del_command(args_frames),
-                CommandType::EXPIRE => Command::parse_expire_command(args_frames),
+                CommandType::DEL => Command::parse_del_command(args_frames, command_type, "DEL"),
+                CommandType::UNLINK => {
+                    Command::parse_del_command(args_frames, command_type, "UNLINK")
+                }
+                CommandType::TOUCH => {
+                    Command::parse_del_command(args_frames, command_type, "TOUCH")
+                }
+                CommandType::WAIT => Command::parse_wait_command(args_frames),
+                CommandType::SHUTDOWN => Command::parse_shutdown_command(args_frames),
+                CommandType::BLPOP | CommandType::BRPOP => {
+                    Command::parse_blocking_pop_command(args_frames, command_type)
+                }
+                CommandType::DELX => Command::parse_del_command(args_frames, command_type, "DELX"),
+                CommandType::EXPIRE | CommandType::PEXPIRE | CommandType::PEXPIREAT => {
+                    Command::parse_expire_command(args_frames, command_type)
+                }
+                CommandType::HKEYS => Command::parse_hkeys_command(args_frames),
+                CommandType::HVALS => Command::parse_hvals_command(args_frames),
+                CommandType::HSCAN => Command::parse_hscan_command(args_frames),
+                CommandType::SSCAN => Command::parse_sscan_command(args_frames),
+                CommandType::ZSCAN => Command::parse_zscan_command(args_frames),
+                CommandType::CLIENT => Command::parse_client_command(args_frames),
+                CommandType::CAS => Command::parse_cas_command(args_frames),
+                CommandType::GETDEL => Command::parse_getdel_command(args_frames),
+                CommandType::INFO => Command::parse_info_command(args_frames),
+                CommandType::GETSET => Command::parse_getset_command(args_frames),
+                CommandType::PIN => Command::parse_pin_command(args_frames),
+                CommandType::UNPIN => Command::parse_unpin_command(args_frames),
+                CommandType::SCAN => Command::parse_scan_command(args_frames),
+                CommandType::GETEX => Command::parse_getex_command(args_frames),
+                CommandType::RENAME => Command::parse_rename_command(args_frames),
+                CommandType::RENAMENX => Command::parse_renamenx_command(args_frames),
+                CommandType::PERSIST => Command::parse_persist_command(args_frames),
+                CommandType::SETNX => Command::parse_setnx_command(args_frames),
+                CommandType::COMMAND => Command::parse_command_command(args_frames),
+                CommandType::HELLO => Command::parse_hello_command(args_frames),
+                CommandType::AUTH => Command::parse_auth_command(args_frames),
+                CommandType::SELECT => Command::parse_select_command(args_frames),
+                CommandType::APPEND => Command::parse_append_command(args_frames),
+                CommandType::INCR | CommandType::DECR => {
+                    Command::parse_incr_command(args_frames, command_type)
+                }
+                CommandType::INCRBY | CommandType::DECRBY => {
+                    Command::parse_incrby_command(args_frames, command_type)
+                }
+                CommandType::MULTI => {
+                    Command::parse_no_arg_command(args_frames, command_type, "MULTI")
+                }
+                CommandType::EXEC => {
+                    Command::parse_no_arg_command(args_frames, command_type, "EXEC")
+                }
+                CommandType::DISCARD => {
+                    Command::parse_no_arg_command(args_frames, command_type, "DISCARD")
+                }
+                CommandType::RESET => {
+                    Command::parse_no_arg_command(args_frames, command_type, "RESET")
+                }
+                CommandType::LPUSH | CommandType::RPUSH => {
+                    Command::parse_push_command(args_frames, command_type)
+                }
+                CommandType::LPOP | CommandType::RPOP => {
+                    Command::parse_pop_command(args_frames, command_type)
+                }
+                CommandType::LRANGE => {
+                    Command::parse_range_command(args_frames, command_type, "LRANGE")
+                }
+                CommandType::GETRANGE => {
+                    Command::parse_range_command(args_frames, command_type, "GETRANGE")
+                }
+                CommandType::SETRANGE => Command::parse_setrange_command(args_frames),
+                CommandType::HSET => Command::parse_hset_command(args_frames),
+                CommandType::HGET => Command::parse_hget_command(args_frames),
+                CommandType::HDEL => Command::parse_hdel_command(args_frames),
+                CommandType::HGETALL => Command::parse_hgetall_command(args_frames),
+                CommandType::INCRBYFLOAT => Command::parse_incrbyfloat_command(args_frames),
+                CommandType::SLOWLOG => Command::parse_slowlog_command(args_frames),
+                CommandType::OBJECT => Command::parse_object_command(args_frames),
+                CommandType::MSETNX => Command::parse_msetnx_command(args_frames),
+                CommandType::DEBUG => Command::parse_debug_command(args_frames),
+                CommandType::SAVE => {
+                    Command::parse_no_arg_command(args_frames, command_type, "SAVE")
+                }
+                CommandType::BGSAVE => {
+                    Command::parse_no_arg_command(args_frames, command_type, "BGSAVE")
+                }
                 CommandType::ERROR => Command {
                     command_type: CommandType::ERROR,
                     // safe to unwrap as the frame as been checked upfront
-                    args: vec![args_frames[0].get_bulk().unwrap().to_string()],
+                    args: vec![args_frames[0].get_bulk().unwrap().to_vec()],
                 },
             };
         }
 
         // Informing that an unknown command was received.
-        let msg = format!("unknown command '{cmd_name}'");
-        Command::new(CommandType::ERROR, &vec![msg])
+        let msg = format!(
+            "unknown command '{}'",
+            String::from_utf8_lossy(cmd_name).to_uppercase()
+        );
+        Command::new(CommandType::ERROR, &[msg])
     }
 
     // checks if a Frame can be used to successfully parse a command without actually parsing it.
@@ -197,88 +478,42 @@ impl Frame {
     fn validate_command_array(&self) -> Option<Command> {
         if self.frame_type != FrameID::Array {
             let msg = "only array can represent a redis command".to_string();
-            return Some(Command {
-                command_type: CommandType::ERROR,
-                args: vec![msg],
-            });
+            return Some(Command::new(CommandType::ERROR, &[msg]));
         }
 
         // it is safe to unwrap because at this point, we know it is an array
         let array = self.get_array().unwrap();
         if array.is_empty() {
             let msg = "cannot parse command from empty frame array".to_string();
-            return Some(Command {
-                command_type: CommandType::ERROR,
-                args: vec![msg],
-            });
+            return Some(Command::new(CommandType::ERROR, &[msg]));
         }
 
         for frame in array {
             if frame.frame_type != FrameID::BulkString {
                 let msg = format!("invalid frame type: {:?}", frame.frame_type);
-                return Some(Command {
-                    command_type: CommandType::ERROR,
-                    args: vec![msg],
-                });
+                return Some(Command::new(CommandType::ERROR, &[msg]));
             }
         }
         None
     }
 }
 
+// format_double formats a double the way RESP3 spells it. Rust already spells the infinities inf
+// and -inf, but not NaN.
+fn format_double(value: f64) -> String {
+    if value.is_nan() {
+        "nan".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
 impl Display for Frame {
+    // the RESP3 form, bulk strings which are not valid UTF-8 are shown lossily
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        match self.frame_type {
-            FrameID::Integer => {
-                debug!("encoding Integer frame");
-                let value = self.frame_data.get_integer().ok_or(fmt::Error)?;
-                write!(f, ":{}\r\n", value)
-            }
-            FrameID::SimpleString => {
-                debug!("encoding SimpleString frame");
-                let value = self.frame_data.get_string().ok_or(fmt::Error)?;
-                write!(f, "+{}\r\n", value)
-            }
-            FrameID::SimpleError => {
-                debug!("encoding SimpleError frame");
-                let value = self.frame_data.get_string().ok_or(fmt::Error)?;
-                write!(f, "-{}\r\n", value)
-            }
-            FrameID::BulkString => {
-                debug!("encoding BulkString frame");
-                let bulk_data = self.frame_data.get_bulk().ok_or(fmt::Error)?;
-                write!(f, "${}\r\n{}\r\n", bulk_data.len(), bulk_data)
-            }
-            FrameID::BulkError => {
-                debug!("encoding BulkError frame");
-                let bulk_data = self.frame_data.get_bulk().ok_or(fmt::Error)?;
-                write!(f, "!{}\r\n{}\r\n", bulk_data.len(), bulk_data)
-            }
-            FrameID::Boolean => {
-                debug!("encoding Boolean frame");
-                let value = self.frame_data.get_boolean().ok_or(fmt::Error)?;
-                let value = if value { "t" } else { "f" };
-                write!(f, "#{}\r\n", value)
-            }
-            FrameID::Null => {
-                debug!("encoding Null frame");
-                write!(f, "_\r\n")
-            }
-            FrameID::BigNumber => {
-                debug!("encoding BigNumber frame");
-                let value = self.frame_data.get_string().ok_or(fmt::Error)?;
-                write!(f, "({}\r\n", value)
-            }
-            FrameID::Array => {
-                debug!("encoding Array frame");
-                let frames = self.frame_data.get_nested().ok_or(fmt::Error)?;
-                write!(f, "*{}\r\n", frames.len())?;
-                for v in frames {
-                    write!(f, "{}", v)?;
-                }
-                Ok(())
-            }
-        }
+        let mut out = Vec::new();
+        self.encode(Protocol::Resp3, &mut out);
+        write!(f, "{}", String::from_utf8_lossy(&out))
     }
 }
 
@@ -286,13 +521,51 @@ impl Display for Frame {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_command() {
+        let cases: &[(&[&str], &[u8])] = &[
+            (&["PING"], b"*1\r\n$4\r\nPING\r\n"),
+            (
+                &["SET", "k", "v"],
+                b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n",
+            ),
+            (
+                &["ECHO", "", "a b\r\n"],
+                b"*3\r\n$4\r\nECHO\r\n$0\r\n\r\n$5\r\na b\r\n\r\n",
+            ),
+            (&[], b"*0\r\n"),
+        ];
+        for (args, encoded) in cases {
+            assert_eq!(
+                String::from_utf8_lossy(&Frame::command(args).encode_to_vec()),
+                String::from_utf8_lossy(encoded),
+                "encoding of {:?}",
+                args
+            );
+        }
+
+        // binary arguments are sent as they are
+        let frame = Frame::command(&[b"SET".as_slice(), b"k", &[0, 255]]);
+        assert_eq!(
+            frame.encode_to_vec(),
+            b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$2\r\n\x00\xff\r\n"
+        );
+        assert_eq!(
+            frame.to_command(),
+            Command {
+                command_type: CommandType::SET,
+                args: vec![b"k".to_vec(), vec![0, 255]],
+            }
+        );
+    }
+
     #[test]
     fn test_frame_to_command_ping() {
         let ping_frame = Frame {
             frame_type: FrameID::Array,
             frame_data: FrameData::Nested(vec![Frame::new_bulk_string("PING")]),
         };
-        let response = Command::new(CommandType::PING, &vec!["PONG".to_string()]);
+        let response = Command::new(CommandType::PING, &[]);
         assert_eq!(
             ping_frame.to_command(),
             response,
@@ -306,7 +579,7 @@ mod tests {
                 Frame::new_bulk_string("Hello"),
             ]),
         };
-        let response = Command::new(CommandType::PING, &vec!["Hello".to_string()]);
+        let response = Command::new(CommandType::PING, &["Hello".to_string()]);
         assert_eq!(
             ping_frame.to_command(),
             response,
@@ -323,7 +596,7 @@ mod tests {
         };
         let response = Command::new(
             CommandType::ERROR,
-            &vec!["PING command must have at most 1 argument".to_string()],
+            &["PING command must have at most 1 argument".to_string()],
         );
         assert_eq!(
             ping_frame.to_command(),
@@ -337,19 +610,197 @@ mod tests {
         let ping_frame = Frame {
             frame_type: FrameID::Array,
             frame_data: FrameData::Nested(vec![
-                Frame::new_bulk_string("PIN"),
+                Frame::new_bulk_string("PIG"),
                 Frame::new_bulk_string("Hello"),
                 Frame::new_bulk_string("World"),
             ]),
         };
-        let response = Command::new(
-            CommandType::ERROR,
-            &vec!["unknown command 'PIN'".to_string()],
-        );
+        let response = Command::new(CommandType::ERROR, &["unknown command 'PIG'".to_string()]);
         assert_eq!(
             ping_frame.to_command(),
             response,
             "can spot ping command with wrong number of args"
         );
     }
+
+    #[test]
+    fn test_frame_to_command_hscan() {
+        let hscan_frame = Frame::new_array(vec![
+            Frame::new_bulk_string("hscan"),
+            Frame::new_bulk_string("hash"),
+            Frame::new_bulk_string("0"),
+        ]);
+        let response = Command::new(
+            CommandType::HSCAN,
+            &[
+                "hash".to_string(),
+                "0".to_string(),
+                "*".to_string(),
+                "10".to_string(),
+                "0".to_string(),
+            ],
+        );
+        assert_eq!(
+            hscan_frame.to_command(),
+            response,
+            "can parse hscan command with default options"
+        );
+
+        let hscan_frame = Frame::new_array(vec![
+            Frame::new_bulk_string("HSCAN"),
+            Frame::new_bulk_string("hash"),
+            Frame::new_bulk_string("12"),
+            Frame::new_bulk_string("novalues"),
+            Frame::new_bulk_string("COUNT"),
+            Frame::new_bulk_string("100"),
+            Frame::new_bulk_string("match"),
+            Frame::new_bulk_string("user:*"),
+        ]);
+        let response = Command::new(
+            CommandType::HSCAN,
+            &[
+                "hash".to_string(),
+                "12".to_string(),
+                "user:*".to_string(),
+                "100".to_string(),
+                "1".to_string(),
+            ],
+        );
+        assert_eq!(
+            hscan_frame.to_command(),
+            response,
+            "can parse hscan command with options in any order"
+        );
+
+        let hscan_frame = Frame::new_array(vec![
+            Frame::new_bulk_string("HSCAN"),
+            Frame::new_bulk_string("hash"),
+            Frame::new_bulk_string("0"),
+            Frame::new_bulk_string("COUNT"),
+        ]);
+        let response = Command::new(CommandType::ERROR, &["syntax error".to_string()]);
+        assert_eq!(
+            hscan_frame.to_command(),
+            response,
+            "can spot an option without value"
+        );
+    }
+
+    #[test]
+    fn test_frame_to_command_incr() {
+        let frame =
+            |words: &[&str]| Frame::new_array(words.iter().map(Frame::new_bulk_string).collect());
+        let args = |key: &str, delta: &str| vec![key.to_string(), delta.to_string()];
+        assert_eq!(
+            frame(&["DECR", "counter"]).to_command(),
+            Command::new(CommandType::DECR, &args("counter", "-1")),
+            "DECR is a decrement by 1"
+        );
+        assert_eq!(
+            frame(&["decrby", "counter", "5"]).to_command(),
+            Command::new(CommandType::DECRBY, &args("counter", "-5")),
+            "DECRBY negates its decrement"
+        );
+        assert_eq!(
+            frame(&["DECRBY", "counter", &i64::MIN.to_string()]).to_command(),
+            Command::new(
+                CommandType::ERROR,
+                &["decrement would overflow".to_string()]
+            ),
+        );
+        assert_eq!(
+            frame(&["INCRBY", "counter", "1.5"]).to_command(),
+            Command::new(
+                CommandType::ERROR,
+                &["value is not an integer or out of range".to_string()]
+            ),
+        );
+        assert_eq!(
+            frame(&["INCRBYFLOAT", "counter", "3.0e3"]).to_command(),
+            Command::new(CommandType::INCRBYFLOAT, &args("counter", "3.0e3")),
+        );
+        for delta in ["ten", "inf", "NaN", ""] {
+            assert_eq!(
+                frame(&["INCRBYFLOAT", "counter", delta]).to_command(),
+                Command::new(
+                    CommandType::ERROR,
+                    &["value is not a valid float".to_string()]
+                ),
+                "{:?} is not a valid increment",
+                delta
+            );
+        }
+    }
+
+    #[test]
+    fn test_frame_to_command_empty_name() {
+        let frame = Frame::new_array(vec![Frame::new_bulk_string("")]);
+        let response = Command::new(CommandType::ERROR, &["unknown command ''".to_string()]);
+        assert_eq!(
+            frame.to_command(),
+            response,
+            "can spot a command with an empty name"
+        );
+    }
+
+    #[test]
+    fn test_encode() {
+        let encode = |frame: &Frame, proto| {
+            let mut out = Vec::new();
+            frame.encode(proto, &mut out);
+            String::from_utf8(out).unwrap()
+        };
+        let null = Frame::new_null();
+        assert_eq!(encode(&null, Protocol::Resp3), "_\r\n");
+        assert_eq!(encode(&null, Protocol::Resp2), "$-1\r\n", "RESP2 null bulk");
+        let null_array = Frame::new_null_array();
+        assert_eq!(encode(&null_array, Protocol::Resp3), "_\r\n");
+        assert_eq!(
+            encode(&null_array, Protocol::Resp2),
+            "*-1\r\n",
+            "RESP2 null array"
+        );
+
+        let nested = Frame::new_array(vec![
+            Frame::new_null(),
+            Frame::new_map(vec![(Frame::new_bulk_string("k"), Frame::new_bool(true))]),
+            Frame::new_double(1.5),
+        ]);
+        assert_eq!(
+            encode(&nested, Protocol::Resp3),
+            "*3\r\n_\r\n%1\r\n$1\r\nk\r\n#t\r\n,1.5\r\n"
+        );
+        assert_eq!(
+            encode(&nested, Protocol::Resp2),
+            "*3\r\n$-1\r\n*2\r\n$1\r\nk\r\n:1\r\n$3\r\n1.5\r\n",
+            "nested frames are encoded in RESP2 too"
+        );
+    }
+
+    #[test]
+    fn test_simple_frames_reject_crlf() {
+        assert_eq!(
+            Frame::try_new_simple_string("hel\r\nlo"),
+            Err(InvalidSimpleFrame)
+        );
+        assert_eq!(
+            Frame::try_new_simple_error("hel\nlo"),
+            Err(InvalidSimpleFrame)
+        );
+        assert_eq!(
+            Frame::try_new_simple_string("hello").unwrap().to_string(),
+            "+hello\r\n"
+        );
+
+        assert_eq!(
+            Frame::new_simple_string("hel\r\nlo").to_string(),
+            "+hel  lo\r\n",
+            "unchecked constructor should never produce a malformed frame"
+        );
+        assert_eq!(
+            Frame::new_simple_error("ERR unknown command 'a\r\n+OK'").to_string(),
+            "-ERR unknown command 'a  +OK'\r\n",
+            "user input in an error cannot inject a frame"
+        );
+    }
 }
@@ -1,74 +1,107 @@
-use crate::db::Storage;
-use crate::parser::{Command, CommandType, Frame, FrameData, FrameID};
-use std::fmt;
-use std::fmt::{Display, Formatter};
+use crate::db::{BlockingPop, ExpiryUpdate, RenameResult, SetCondition, Storage};
+use crate::metrics::Metrics;
+use crate::parser::stream::ClientStream;
+use crate::parser::{
+    decode, unix_time_ms, Command, CommandType, DecodeError, DecodeLimits, Frame, FrameID,
+    Protocol, COMMAND_TABLE,
+};
+use crate::slowlog::SlowLog;
+use crate::snapshot::save_snapshot;
+use std::collections::HashSet;
+use std::future::poll_fn;
+use std::path::Path;
+use std::pin::Pin;
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::io::{self, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream, ErrorKind};
-use tracing::{debug, error};
+use std::task::Poll;
+use std::time::{Duration, Instant};
+use tokio::io::{self, AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, watch, Notify, OwnedRwLockReadGuard, OwnedRwLockWriteGuard};
+use tracing::{debug, error, info, trace};
+
+// Longest part of a key written to the access log, the keys can be as big as the values.
+const LOGGED_KEY_LEN: usize = 64;
 
 pub struct Parser<T>
 where
     T: AsyncReadExt + AsyncWriteExt + Unpin,
 {
-    buf_stream: BufStream<T>,
+    buf_stream: BufStream<ClientStream<T>>,
+    // the selected database
     storage: Arc<Storage>,
+    // every database, SELECT switches between them
+    databases: Arc<[Arc<Storage>]>,
+    // index of the selected database
+    db_index: usize,
+    metrics: Arc<Metrics>,
+    // the commands slower than its threshold are recorded there, it is shared by the connections
+    slowlog: Arc<SlowLog>,
+    // Whether the command being applied holds gates of the storage, see apply_gated. Its replies
+    // are then held in held_replies, and sent once the gates are released so that a slow client
+    // does not keep them. The buffer is reused from one command to the next.
+    holding_gates: bool,
+    held_replies: Vec<u8>,
+    limits: DecodeLimits,
+    // client side caching state, set when the client enabled tracking
+    tracking: Option<Tracking>,
+    // Whether write_frame leaves the replies in the buffer, for process_frames to flush them in
+    // batches. Otherwise, every reply is flushed right away.
+    defer_flush: bool,
+    // whether some replies were written but not flushed yet
+    unflushed: bool,
+    // Password to send with AUTH, if the server requires one
+    requirepass: Option<Arc<str>>,
+    // whether the client may run commands, always true when no password is required
+    authenticated: bool,
+    // identifier of the connection, unique for the server
+    client_id: u64,
+    // name set with CLIENT SETNAME, empty until the client sets one
+    name: String,
+    // whether the client may run DEBUG
+    debug_commands: bool,
+    // names of the commands disabled by the configuration, in uppercase
+    disabled_commands: Arc<HashSet<String>>,
+    // where SAVE and BGSAVE write the snapshot, they fail without one
+    snapshot_path: Option<Arc<Path>>,
+    // RESP version negotiated with HELLO, RESP2 until the client asks for another one
+    protocol: Protocol,
+    // where the write commands are fed once applied, if anything consumes them
+    write_sink: Option<mpsc::UnboundedSender<PropagatedWrite>>,
+    // what SHUTDOWN notifies to stop the server, it fails without it
+    shutdown: Option<Arc<Notify>>,
+    // turns true once the server shuts down, the connection is then closed between two commands
+    server_closing: Option<watch::Receiver<bool>>,
+    // whether the connection must be closed once the command is applied, after SHUTDOWN
+    closing: bool,
+    // key the last BLPOP or BRPOP popped from, which is propagated as the pop it did
+    served_pop: Option<Vec<u8>>,
+    // type of the last reply, to tell if a write command did apply and for the access log
+    last_reply: Option<FrameID>,
+    // commands queued since MULTI, which EXEC applies. None outside of a transaction.
+    queued: Option<Vec<Command>>,
+    // requests of the queued commands, to propagate the writes once EXEC applied them
+    queued_frames: Vec<Frame>,
+    // whether a command sent after MULTI was invalid, which makes EXEC discard the transaction
+    queue_failed: bool,
+    // replies of the commands applied by EXEC, which write_frame collects instead of sending them
+    exec_replies: Option<Vec<Frame>>,
 }
 
-#[derive(Debug, Eq, PartialEq)]
-pub(crate) enum DecodeError {
-    // Need more data to decode frame
-    Incomplete,
-    // Frame is not correctly formatted
-    Invalid,
-    // reached expected EOF
-    Eof,
-    // Unidentified IO error
-    IOError,
-    // UTF8 to Int error
-    UTF8ToInt,
-    // Unknown frame type
-    UnknownFrame,
-    // This is a programming error. It should not happen.
-    Syntax(String),
-    // Fatal network error, the network can no longer process traffic
-    FatalNetworkError,
-}
-
-impl Display for DecodeError {
-    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        match self {
-            DecodeError::Incomplete => write!(f, "not enough data to decode a full frame"),
-            DecodeError::Invalid => write!(f, "frame is not correctly formatted"),
-            DecodeError::Eof => write!(f, "seen EOF, this is generally a graceful disconnection"),
-            DecodeError::IOError => write!(f, "unexpected IO error"),
-            DecodeError::UTF8ToInt => write!(f, "utf8 to int decoding error"),
-            DecodeError::UnknownFrame => write!(f, "unable to identify the frame type"),
-            DecodeError::Syntax(message) => write!(f, "{}", message),
-            DecodeError::FatalNetworkError => write!(f, "fatal network error occurred"),
-        }
-    }
+/// Tracking holds the client side caching state of a connection: the keys read since tracking
+/// was enabled, and the feed of modified keys used to invalidate them.
+struct Tracking {
+    keys: HashSet<Vec<u8>>,
+    events: broadcast::Receiver<Vec<u8>>,
 }
 
-// Convert io::Error to DecodeError. Decode error is more specific to what can happen during an
-// attempt to decode a frame. Some of the issues can be IO and some other issues like Atoi or
-// syntax.
-impl From<io::Error> for DecodeError {
-    fn from(err: io::Error) -> Self {
-        match err.kind() {
-            ErrorKind::UnexpectedEof => DecodeError::Eof,
-            ErrorKind::ConnectionAborted
-            | ErrorKind::BrokenPipe
-            | ErrorKind::ConnectionRefused
-            | ErrorKind::ConnectionReset
-            | ErrorKind::NotConnected => {
-                // log the error here to give more hints to the caller
-                error!("fatal network io error occurred: {}", err);
-                DecodeError::FatalNetworkError
-            }
-            _ => DecodeError::IOError,
-        }
-    }
+/// PropagatedWrite is a write command which was applied, as fed to the write sink of a parser.
+/// This is what an append-only file or a replica needs to apply the same change.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PropagatedWrite {
+    /// Index of the database the command was applied to.
+    pub db: usize,
+    /// The command, as sent by the client in RESP.
+    pub command: Vec<u8>,
 }
 
 impl<T> Parser<T>
@@ -76,465 +109,1872 @@ where
     T: AsyncReadExt + AsyncWriteExt + Unpin,
 {
     pub async fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
-        self.buf_stream
-            .write_all(frame.to_string().as_bytes())
-            .await?;
+        self.last_reply = Some(frame.frame_type);
+        if let Some(replies) = self.exec_replies.as_mut() {
+            replies.push(frame.clone());
+            return Ok(());
+        }
+        if self.holding_gates {
+            frame.encode(self.protocol, &mut self.held_replies);
+            return Ok(());
+        }
+        let mut out = Vec::new();
+        frame.encode(self.protocol, &mut out);
+        self.write_out(&out).await
+    }
+
+    // write_out writes encoded replies to the client, flushing them unless the flush is deferred.
+    async fn write_out(&mut self, out: &[u8]) -> io::Result<()> {
+        self.buf_stream.write_all(out).await?;
+        self.unflushed = true;
+        if self.defer_flush {
+            return Ok(());
+        }
+        self.flush().await
+    }
+
+    /// flush sends the replies written so far to the client, if there are any.
+    pub async fn flush(&mut self) -> io::Result<()> {
+        if !self.unflushed {
+            return Ok(());
+        }
+        self.unflushed = false;
         self.buf_stream.flush().await
     }
 
-    pub fn new(stream: T, storage: Arc<Storage>, buffer_size: usize) -> Self {
+    pub fn new(
+        stream: T,
+        storage: Arc<Storage>,
+        metrics: Arc<Metrics>,
+        buffer_size: usize,
+        limits: DecodeLimits,
+    ) -> Self {
         debug!("created a new parser instance");
         Self {
-            buf_stream: BufStream::with_capacity(buffer_size, buffer_size, stream),
+            buf_stream: BufStream::with_capacity(
+                buffer_size,
+                buffer_size,
+                ClientStream::new(stream, metrics.clone()),
+            ),
+            databases: Arc::new([storage.clone()]),
+            db_index: 0,
             storage,
+            metrics,
+            slowlog: Arc::new(SlowLog::default()),
+            holding_gates: false,
+            held_replies: Vec::new(),
+            limits,
+            tracking: None,
+            defer_flush: false,
+            unflushed: false,
+            protocol: Protocol::Resp2,
+            requirepass: None,
+            authenticated: true,
+            client_id: 0,
+            name: String::new(),
+            debug_commands: false,
+            disabled_commands: Arc::default(),
+            snapshot_path: None,
+            write_sink: None,
+            shutdown: None,
+            server_closing: None,
+            closing: false,
+            served_pop: None,
+            last_reply: None,
+            queued: None,
+            queued_frames: Vec::new(),
+            queue_failed: false,
+            exec_replies: None,
         }
     }
 
+    /// with_databases lets the client switch between `databases` with SELECT. The client starts on
+    /// the first one.
+    pub fn with_databases(mut self, databases: Arc<[Arc<Storage>]>) -> Self {
+        self.storage = databases[0].clone();
+        self.databases = databases;
+        self
+    }
+
+    /// with_write_sink feeds every write command applied by the client to `sink`, see
+    /// PropagatedWrite. Without a sink, nothing is recorded.
+    pub fn with_write_sink(mut self, sink: Option<mpsc::UnboundedSender<PropagatedWrite>>) -> Self {
+        self.write_sink = sink;
+        self
+    }
+
+    /// with_shutdown lets the client stop the server with SHUTDOWN, which notifies `shutdown`.
+    pub fn with_shutdown(mut self, shutdown: Option<Arc<Notify>>) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// with_server_closing closes the connection once `closing` turns true, without waiting for
+    /// the next command of the client. The command being applied is completed first, so that the
+    /// server knows that no more write comes from the connection once it is closed.
+    pub fn with_server_closing(mut self, closing: watch::Receiver<bool>) -> Self {
+        self.server_closing = Some(closing);
+        self
+    }
+
+    /// with_idle_timeout closes the connection once the client sent nothing, and was sent nothing,
+    /// for `timeout`. The timer starts now. Without a timeout, idle clients are kept forever.
+    pub fn with_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.buf_stream.get_mut().set_timeout(timeout);
+        self
+    }
+
+    /// with_slowlog records the slow commands of the client in `slowlog`, instead of a log of its
+    /// own.
+    pub fn with_slowlog(mut self, slowlog: Arc<SlowLog>) -> Self {
+        self.slowlog = slowlog;
+        self
+    }
+
+    /// with_requirepass makes the client authenticate with `password`, if any, before it can run
+    /// commands other than AUTH, HELLO and PING.
+    pub fn with_requirepass(mut self, password: Option<Arc<str>>) -> Self {
+        self.authenticated = password.is_none();
+        self.requirepass = password;
+        self
+    }
+
+    /// with_snapshot_path makes SAVE and BGSAVE write the snapshot of the databases to `path`.
+    /// Without a path, they fail.
+    pub fn with_snapshot_path(mut self, path: Option<Arc<Path>>) -> Self {
+        self.snapshot_path = path;
+        self
+    }
+
+    /// with_client_id sets the identifier CLIENT ID replies with. The server gives each connection
+    /// its own, in increasing order.
+    pub fn with_client_id(mut self, id: u64) -> Self {
+        self.client_id = id;
+        self
+    }
+
+    /// with_debug_commands lets the client run DEBUG, which is rejected otherwise.
+    pub fn with_debug_commands(mut self, enabled: bool) -> Self {
+        self.debug_commands = enabled;
+        self
+    }
+
+    /// with_disabled_commands makes the commands named in `names`, in uppercase, reply as unknown
+    /// commands.
+    pub fn with_disabled_commands(mut self, names: Arc<HashSet<String>>) -> Self {
+        self.disabled_commands = names;
+        self
+    }
+
+    /// decode_frame decodes the next frame sent by the client, see `decode`.
     pub async fn decode_frame(&mut self) -> Result<Frame, DecodeError> {
-        {
-            debug!("started to debug a frame");
-            let id = self.get_frame_id().await?;
-            match id {
-                FrameID::SimpleString
-                | FrameID::SimpleError
-                | FrameID::Null
-                | FrameID::Boolean
-                | FrameID::BigNumber
-                | FrameID::Integer => self.decode_simple_frame(id).await,
-
-                FrameID::BulkString | FrameID::BulkError => self.decode_bulk_frame(id).await,
-
-                FrameID::Array => {
-                    let frame_vec = self.decode_aggregate_frame(id).await?;
-                    Ok(Frame {
-                        frame_type: FrameID::Array,
-                        frame_data: FrameData::Nested(frame_vec),
-                    })
-                }
-            }
-        }
+        decode(&mut self.buf_stream, self.limits).await
+    }
+
+    // read_frame waits for the next frame and decodes it. It only fails if an invalidation message
+    // cannot be written while waiting, see wait_for_input.
+    async fn read_frame(&mut self) -> io::Result<Result<Frame, DecodeError>> {
+        self.wait_for_input().await?;
+        Ok(self.decode_frame().await)
     }
 
     pub async fn process_frames(&mut self) {
         debug!("starting frames decoding loop");
+        // Replies to pipelined commands are flushed together, once there is no more input to
+        // process right away. This saves a syscall per reply, and a lone command is still
+        // answered as soon as it is applied.
+        self.defer_flush = true;
         loop {
-            let frame = self.decode_frame().await;
+            if !self.input_ready().await {
+                if let Err(err) = self.flush().await {
+                    error!("failed to write to network, closing connection: {}", err);
+                    return;
+                }
+            }
+            let mut server_closing = self.server_closing.clone();
+            let frame = tokio::select! {
+                frame = self.read_frame() => frame,
+                _ = closed(&mut server_closing) => {
+                    debug!("closing connection, the server is shutting down");
+                    return;
+                }
+            };
+            let frame = match frame {
+                Ok(frame) => frame,
+                Err(err) => {
+                    error!("failed to write to network, closing connection: {}", err);
+                    return;
+                }
+            };
             match frame {
                 Ok(frame) => {
-                    debug!("command frame received!");
-                    let command = frame.to_command();
-                    self.apply_command(&command).await;
-                }
-                Err(err) => match err {
-                    DecodeError::FatalNetworkError => {
-                        error!("process_frames: fatal network error occurred");
+                    trace!("command frame received!");
+                    let command = self.check_disabled(frame.to_command());
+                    // A failed write or flush means the client is gone, or at least that the
+                    // responses it is waiting for are lost. Stop here so we do not keep applying
+                    // commands whose replies would go to a dead buffer. Returning drops any
+                    // connection-scoped state along with the parser.
+                    let applied = self.apply_gated(&command).await;
+                    let queued = self.queued.as_ref().map_or(0, Vec::len);
+                    if queued > self.queued_frames.len() {
+                        // the command is propagated once EXEC applies it
+                        self.queued_frames.push(frame);
+                    } else {
+                        // the command applied even if its reply could not be sent
+                        self.propagate(&command, &frame);
+                    }
+                    if let Err(err) = applied {
+                        error!("failed to write to network, closing connection: {}", err);
                         return;
                     }
-                    DecodeError::Eof => {
-                        debug!("client gracefully closed connection");
+                    if self.closing {
+                        debug!("closing connection, the server is shutting down");
                         return;
                     }
-                    _ => {
-                        debug!("non fatal decode error occurred")
+                }
+                Err(err) => {
+                    match err {
+                        DecodeError::FatalNetworkError => {
+                            error!("process_frames: fatal network error occurred");
+                            return;
+                        }
+                        DecodeError::Eof => {
+                            debug!("client gracefully closed connection");
+                            return;
+                        }
+                        DecodeError::Incomplete => {
+                            // frames are accumulated across reads, so only the end of the stream
+                            // can cut one short
+                            error!("client closed connection in the middle of a frame, it is truncated");
+                            return;
+                        }
+                        DecodeError::Timeout => {
+                            info!("closing connection of a client idle for too long");
+                            return;
+                        }
+                        DecodeError::IOError => {
+                            error!("process_frames: failed to read from the client");
+                            return;
+                        }
+                        DecodeError::EmptyLine => {
+                            debug!("ignoring an empty inline command")
+                        }
+                        DecodeError::Invalid
+                        | DecodeError::UnknownFrame
+                        | DecodeError::UTF8ToInt
+                        | DecodeError::Syntax(_) => {
+                            // we cannot tell where the next frame starts, so like Redis, tell the
+                            // client what went wrong and close the connection
+                            info!("closing connection after a protocol error: {}", err);
+                            let error = format!("ERR Protocol error: {}", err);
+                            if self
+                                .write_frame(&Frame::new_simple_error(&error))
+                                .await
+                                .is_ok()
+                            {
+                                let _ = self.flush().await;
+                            }
+                            return;
+                        }
                     }
-                },
+                }
             }
         }
     }
 
-    async fn get_frame_id(&mut self) -> Result<FrameID, DecodeError> {
-        let id = self.buf_stream.read_u8().await?;
-        FrameID::from_u8(&id).ok_or(DecodeError::UnknownFrame)
+    // propagate feeds `frame` to the write sink, if there is one and if it is a write command
+    // which did apply, with its expiry made absolute. Commands failing with an error reply are
+    // not fed, as they changed nothing.
+    fn propagate(&mut self, command: &Command, frame: &Frame) {
+        let served_pop = self.served_pop.take();
+        let Some(sink) = self.write_sink.as_ref() else {
+            return;
+        };
+        if self.last_reply == Some(FrameID::SimpleError) || !command.command_type.is_write() {
+            return;
+        }
+        let request = match command.command_type {
+            // a blocking pop is replayed as the pop it did, if it did one, so it cannot block
+            CommandType::BLPOP | CommandType::BRPOP => {
+                let Some(key) = served_pop else {
+                    return;
+                };
+                let pop = if command.command_type == CommandType::BLPOP {
+                    b"LPOP".to_vec()
+                } else {
+                    b"RPOP".to_vec()
+                };
+                Frame::command(&[pop, key])
+            }
+            _ => Self::absolute_expiry(command, frame),
+        };
+        let mut encoded = Vec::new();
+        request.encode(Protocol::Resp3, &mut encoded);
+        let write = PropagatedWrite {
+            db: self.db_index,
+            command: encoded,
+        };
+        if sink.send(write).is_err() {
+            debug!("the write sink is closed, detaching it");
+            self.write_sink = None;
+        }
     }
 
-    async fn decode_bulk_frame(&mut self, id: FrameID) -> Result<Frame, DecodeError> {
-        let data = self.read_bulk_string().await?;
-        Ok(Frame {
-            frame_type: id,
-            frame_data: FrameData::Bulk(data),
+    // wait_closed returns once the client closed the connection, or it failed. Anything the
+    // client sends in the meantime stays buffered, to be decoded later.
+    async fn wait_closed(&mut self) {
+        let mut stream = Pin::new(&mut self.buf_stream);
+        poll_fn(|cx| match stream.as_mut().poll_fill_buf(cx) {
+            Poll::Ready(Ok(buf)) if !buf.is_empty() => Poll::Pending,
+            Poll::Ready(_) => Poll::Ready(()),
+            Poll::Pending => Poll::Pending,
         })
+        .await
     }
 
-    /// `read_bulk_string` return a bulk string and its size
-    async fn read_bulk_string(&mut self) -> Result<String, DecodeError>
-    where
-        T: AsyncReadExt + Unpin,
-    {
-        // e.g: "6\r\nfoobar\r\n"
-        let len = self.read_integer().await?;
-        // we have to read len + CRLF
-        let len = len as usize + 2;
-
-        let mut buf = vec![0; len];
-        let size = self.buf_stream.read_exact(&mut buf).await?;
-        // we need to read exact size bytes
-        if size != len || size < 2 || buf[size - 2] != b'\r' {
-            return Err(DecodeError::Invalid);
-        }
-        Ok(String::from_utf8_lossy(&buf[0..len - 2]).to_string())
-    }
-
-    async fn read_integer(&mut self) -> Result<i64, DecodeError>
-    where
-        T: AsyncReadExt + Unpin,
-    {
-        let data = self.read_simple_string().await?;
-        let data = data.parse().map_err(|_err| DecodeError::Invalid)?;
-        Ok(data)
-    }
-
-    async fn decode_simple_frame(&mut self, id: FrameID) -> Result<Frame, DecodeError> {
-        let data = self.read_simple_string().await?;
-        match id {
-            FrameID::Boolean => {
-                let bool = Self::validate_bool(&data)?;
-                Ok(Frame {
-                    frame_type: id,
-                    frame_data: FrameData::Boolean(bool),
-                })
+    // absolute_expiry returns the request to propagate for `command`, with its relative expiry
+    // turned into a deadline in unix time, like Redis does. Replayed later, the key expires when
+    // it did, instead of a whole TTL after the replay. The other requests are propagated as sent.
+    fn absolute_expiry(command: &Command, frame: &Frame) -> Frame {
+        let deadline = |index: usize| {
+            let ttl_ms = command.arg_str(index).parse::<i64>().unwrap_or(0);
+            unix_time_ms()
+                .saturating_add(ttl_ms)
+                .to_string()
+                .into_bytes()
+        };
+        let args = &command.args;
+        match command.command_type {
+            CommandType::EXPIRE | CommandType::PEXPIRE | CommandType::PEXPIREAT => {
+                Frame::command(&[b"PEXPIREAT".to_vec(), args[0].clone(), deadline(1)])
             }
-            FrameID::Integer => {
-                let data = data.parse().map_err(|_err| DecodeError::UTF8ToInt)?;
-                Ok(Frame {
-                    frame_type: id,
-                    frame_data: FrameData::Integer(data),
-                })
+            CommandType::GETEX if args.get(1).is_some_and(|option| option == b"PX") => {
+                Frame::command(&[b"PEXPIREAT".to_vec(), args[0].clone(), deadline(2)])
             }
-            FrameID::Null => {
-                if !data.is_empty() {
-                    // nil frame should not contain data
-                    return Err(DecodeError::Invalid);
-                }
-                Ok(Frame {
-                    frame_type: id,
-                    frame_data: FrameData::Null,
-                })
+            CommandType::SET if args.len() == 3 && args[2] != b"KEEPTTL" => {
+                let (key, value) = (args[0].clone(), args[1].clone());
+                Frame::command(&[b"SET".to_vec(), key, value, b"PXAT".to_vec(), deadline(2)])
+            }
+            CommandType::CAS if args.len() == 4 => {
+                let mut request = vec![b"CAS".to_vec()];
+                request.extend(args[..3].iter().cloned());
+                request.extend([b"PXAT".to_vec(), deadline(3)]);
+                Frame::command(&request)
             }
-            _ => Ok(Frame {
-                frame_type: id,
-                frame_data: FrameData::Simple(data),
-            }),
+            _ => frame.clone(),
         }
     }
 
-    fn validate_bool(data: &str) -> Result<bool, DecodeError> {
-        match data {
-            "t" => Ok(true),
-            "f" => Ok(false),
-            _ => Err(DecodeError::Invalid),
-        }
+    // input_ready tells whether there is input to decode without waiting, either already buffered
+    // or readable right away. It only reads what the stream has available.
+    async fn input_ready(&mut self) -> bool {
+        let mut stream = Pin::new(&mut self.buf_stream);
+        poll_fn(|cx| {
+            let ready = matches!(
+                stream.as_mut().poll_fill_buf(cx),
+                Poll::Ready(Ok(buf)) if !buf.is_empty()
+            );
+            Poll::Ready(ready)
+        })
+        .await
     }
 
-    /// `read_simple_string` gets a simple string from the network. As a reminder, such string does
-    /// not contain any CR or LF char in the middle. This method assumes the frame identifier has
-    /// already been taken from the stream. So, for instance, consider you have something like
-    /// `HELLO\r\n` instead of `+HELLO\r\n` in the stream while calling this method.
-    /// The error returned is the same as `tokio::io::BufReader::read_until()` or one of the following:
-    async fn read_simple_string(&mut self) -> Result<String, DecodeError>
-    where
-        T: AsyncReadExt + Unpin,
-    {
-        let mut buf = Vec::new();
-        let size = self.buf_stream.read_until(b'\n', &mut buf).await?;
-        match size {
-            0 => Err(DecodeError::Eof),
-            _ => {
-                if size < 2 {
-                    return Err(DecodeError::Incomplete);
+    /// wait_for_input returns when there is data to decode. In the meantime, it sends invalidation
+    /// messages to a tracking client. It fails only if such a message cannot be written.
+    async fn wait_for_input(&mut self) -> io::Result<()> {
+        loop {
+            let tracking = match self.tracking.as_mut() {
+                Some(tracking) => tracking,
+                None => return Ok(()),
+            };
+            let event = tokio::select! {
+                // fill_buf does not consume the buffered data, so it is fine to drop it when a
+                // key event comes first. A read error will be seen again while decoding.
+                _ = self.buf_stream.fill_buf() => return Ok(()),
+                event = tracking.events.recv() => event,
+            };
+            let invalidated = match event {
+                Ok(key) => {
+                    // A key is only invalidated once, until it is read again.
+                    if !tracking.keys.remove(&key) {
+                        continue;
+                    }
+                    Some(vec![Frame::new_bulk_string(&key)])
                 }
-                if buf[size - 1] != b'\n' {
-                    return Err(DecodeError::Incomplete);
+                Err(RecvError::Lagged(missed)) => {
+                    // We cannot tell which keys changed, so invalidate everything.
+                    debug!("missed {} key events, invalidating all the keys", missed);
+                    tracking.keys.clear();
+                    None
                 }
-                if buf[size - 2] != b'\r' {
-                    return Err(DecodeError::Invalid);
+                Err(RecvError::Closed) => {
+                    self.tracking = None;
+                    return Ok(());
                 }
-                // We should also check if there is any CR in the middle, but this check is made upfront.
-                // The reason is to perform this expensive check only if needed. Also, this function result
-                // is used in places that naturally check the correctness of the frame content (for instance, conversion to int).
-                Ok(String::from_utf8_lossy(&buf[0..size - 2]).to_string())
-            }
+            };
+            self.write_push(&Self::invalidation_message(invalidated))
+                .await?;
+            self.flush().await?;
         }
     }
 
-    /// decode_aggregate_frame decodes a bucket of frames iteratively.
-    /// We have frame ID in the signature because aggregate can be of different types.
-    /// So, we need to keep track of the IDs to construct the right aggregate frame when needed.
-    /// This function can be used to decode Arrays, Maps, and Sets.
-    async fn decode_aggregate_frame(&mut self, id: FrameID) -> Result<Vec<Frame>, DecodeError> {
-        // "3\r\n:1\r\n:2\r\n:3\r\n" -> [1, 2, 3]
-        // "*2\r\n:1\r\n*1\r\n+Three\r\n"
-        let count = self.read_integer().await?;
-        let frames: Vec<Frame> = Vec::new();
-        let mut stack = Vec::new();
-        stack.push((id, count, frames));
-        loop {
-            let id = self.get_frame_id().await?;
-            match id {
-                FrameID::Array => {
-                    let count = self.read_integer().await?;
-                    let frames: Vec<Frame> = Vec::new();
-                    stack.push((id, count, frames));
-                }
-                _ => {
-                    // we have a non-aggregate frame, and there is nothing in the stack it can be appended
-                    // to so this is an error.
-                    if stack.is_empty() {
-                        return Err(DecodeError::Invalid);
-                    }
-                    let frame = self.process_non_aggregate(id).await?;
-                    let (_, count, frames) = stack.last_mut().unwrap();
-                    frames.push(frame);
-                    *count -= 1;
-                    // If count == 0, we've decoded an entire array. So push it to the penultimate
-                    // aggregate in the stack if any. If there is no more array in the stack, this means
-                    // we should return as the total frame was completely processed.
-                    if *count == 0 {
-                        // We need to loop to successively pop completed vector of frames and push
-                        // them to their parent
-                        // until we finish piping or find a vector which is incomplete.
-                        loop {
-                            let (_, _, last_vec_of_frames) = stack.pop().unwrap();
-                            // The full global frame was decoded, so return
-                            if stack.is_empty() {
-                                return Ok(last_vec_of_frames);
-                            }
-                            // we fully decoded an aggregate but not the full global frame
-                            let (id, count, frames) = stack.last_mut().unwrap();
-                            // Here is why we needed to keep track of the IDs,
-                            // to build the right aggregate.
-                            frames.push(Frame {
-                                frame_type: *id,
-                                frame_data: FrameData::Nested(last_vec_of_frames),
-                            });
-                            *count -= 1;
-                            if *count != 0 {
-                                break;
-                            }
-                        }
-                    }
+    // write_push writes a push frame. Pushes are out of band, they are not the reply of the
+    // current command: they are never collected by EXEC, nor taken for the status of a command.
+    async fn write_push(&mut self, frame: &Frame) -> io::Result<()> {
+        let mut out = Vec::new();
+        frame.encode(self.protocol, &mut out);
+        self.buf_stream.write_all(&out).await?;
+        self.unflushed = true;
+        Ok(())
+    }
+
+    // invalidation_message builds the push frame telling a client to drop the given keys from its
+    // cache. No keys means that the client should drop all of them.
+    fn invalidation_message(keys: Option<Vec<Frame>>) -> Frame {
+        let keys = match keys {
+            Some(keys) => Frame::new_array(keys),
+            None => Frame::new_null(),
+        };
+        Frame::new_push(vec![Frame::new_bulk_string("invalidate"), keys])
+    }
+
+    // check_disabled replaces a command disabled by the configuration by the error of an unknown
+    // command, so that it fails as if it did not exist.
+    fn check_disabled(&self, command: Command) -> Command {
+        if self.disabled_commands.is_empty() {
+            return command;
+        }
+        match command.command_type.spec() {
+            Some(spec) if self.disabled_commands.contains(spec.name) => Command::new(
+                CommandType::ERROR,
+                &[format!("unknown command '{}'", spec.name)],
+            ),
+            _ => command,
+        }
+    }
+
+    /// apply_gated applies a command while no transaction runs on its keys: it holds the gates of
+    /// its keys shared, see Storage::gate. EXEC takes the gates itself, and a blocking pop would
+    /// keep them while it waits, so they run without them, like the commands queued by MULTI.
+    async fn apply_gated(&mut self, command: &Command) -> io::Result<()> {
+        let ungated = matches!(
+            command.command_type,
+            CommandType::EXEC | CommandType::BLPOP | CommandType::BRPOP
+        );
+        let mut gates = Vec::new();
+        if !ungated && self.queued.is_none() {
+            self.gates_of(self.db_index, command, &mut gates);
+        }
+        if gates.is_empty() {
+            return self.apply_command(command).await;
+        }
+        let held = enter_gates_shared(self.databases.clone(), gates).await;
+        self.holding_gates = true;
+        let applied = self.apply_command(command).await;
+        self.holding_gates = false;
+        drop(held);
+        let replies = std::mem::take(&mut self.held_replies);
+        let written = match applied {
+            Ok(()) if !replies.is_empty() => self.write_out(&replies).await,
+            applied => applied,
+        };
+        self.held_replies = replies;
+        self.held_replies.clear();
+        written
+    }
+
+    // gates_of adds to `gates` the gates `command` holds while it runs on the database `db`, as
+    // (database, gate) pairs. They are the gates of its keys, or every gate for the commands on
+    // the whole keyspace, like SCAN, and of every database for the admin ones.
+    fn gates_of(&self, db: usize, command: &Command, gates: &mut Vec<(usize, usize)>) {
+        let Some(spec) = command.command_type.spec() else {
+            return;
+        };
+        if spec.first_key > 0 {
+            let storage = &self.databases[db];
+            gates.extend(command.keys().map(|key| (db, storage.gate_index(key))));
+            return;
+        }
+        let databases = if spec.flags.contains(&"admin") {
+            0..self.databases.len()
+        } else if spec.flags.contains(&"readonly") || spec.flags.contains(&"write") {
+            db..db + 1
+        } else {
+            return;
+        };
+        for db in databases {
+            gates.extend((0..self.databases[db].gate_count()).map(|gate| (db, gate)));
+        }
+    }
+
+    /// apply_command executes a command and writes its response. An error means the response
+    /// could not be written to the network, so the connection is no longer usable.
+    async fn apply_command(&mut self, command: &Command) -> io::Result<()> {
+        if !self.authenticated
+            && !matches!(
+                command.command_type,
+                CommandType::AUTH
+                    | CommandType::HELLO
+                    | CommandType::PING
+                    | CommandType::RESET
+                    | CommandType::ERROR
+            )
+        {
+            let error = Frame::new_simple_error("NOAUTH Authentication required.");
+            return self.write_frame(&error).await;
+        }
+        if self.queued.is_some() {
+            return self.queue_command(command).await;
+        }
+        let started = Instant::now();
+        let result = self.dispatch_command(command).await;
+        let elapsed = started.elapsed();
+        // the errors of the parser are not commands, they have no spec
+        if let Some(spec) = command.command_type.spec() {
+            // the key positions of the spec count the command name
+            let key = usize::try_from(spec.first_key - 1)
+                .ok()
+                .and_then(|index| command.args.get(index))
+                .map(|key| &key[..key.len().min(LOGGED_KEY_LEN)]);
+            debug!(
+                command = spec.name,
+                key = %String::from_utf8_lossy(key.unwrap_or_default()),
+                status = self.reply_status(),
+                duration_us = elapsed.as_micros() as u64,
+                "command processed"
+            );
+            self.slowlog.record(elapsed, || {
+                let mut args = vec![spec.name.as_bytes().to_vec()];
+                args.extend(command.args.iter().cloned());
+                args
+            });
+        }
+        result
+    }
+
+    // reply_status tells how the last command went, for the access log: it failed with an error,
+    // it found nothing, or it succeeded.
+    fn reply_status(&self) -> &'static str {
+        match self.last_reply {
+            Some(FrameID::SimpleError | FrameID::BulkError) => "error",
+            Some(FrameID::Null) => "miss",
+            _ => "ok",
+        }
+    }
+
+    // queue_command handles a command sent after MULTI. The transaction commands apply right
+    // away, the other ones are queued for EXEC.
+    async fn queue_command(&mut self, command: &Command) -> io::Result<()> {
+        match command.command_type {
+            CommandType::EXEC => {
+                self.metrics.record_command(command.command_type);
+                self.apply_exec_command().await
+            }
+            CommandType::DISCARD => {
+                self.metrics.record_command(command.command_type);
+                self.discard_transaction();
+                self.write_frame(&Frame::new_simple_string("OK")).await
+            }
+            CommandType::MULTI => {
+                let error = Frame::new_simple_error("ERR MULTI calls can not be nested");
+                self.write_frame(&error).await
+            }
+            CommandType::RESET => {
+                self.metrics.record_command(command.command_type);
+                self.apply_reset_command().await
+            }
+            CommandType::ERROR => {
+                self.queue_failed = true;
+                self.apply_error_command(command).await
+            }
+            _ => {
+                if let Some(queued) = self.queued.as_mut() {
+                    queued.push(command.clone());
                 }
+                self.write_frame(&Frame::new_simple_string("QUEUED")).await
             }
         }
     }
 
-    /// process_non_aggregate is a helper to decode non-aggregate frames. It calls the appropriate
-    /// processing method depending on the frame type. It should not receive an aggregate type.
-    pub async fn process_non_aggregate(&mut self, id: FrameID) -> Result<Frame, DecodeError> {
-        match id {
-            FrameID::Array => Err(DecodeError::Syntax(
-                "received aggregate frame in non aggregate decoding".to_string(),
-            )),
-            FrameID::BulkString | FrameID::BulkError => self.decode_bulk_frame(id).await,
-            _ => self.decode_simple_frame(id).await,
-        }
+    // discard_transaction drops the commands queued since MULTI, and leaves the transaction.
+    fn discard_transaction(&mut self) {
+        self.queued = None;
+        self.queued_frames.clear();
+        self.queue_failed = false;
     }
 
-    async fn apply_command(&mut self, command: &Command) {
+    // dispatch_command applies a command, outside of a transaction or when EXEC applies it.
+    async fn dispatch_command(&mut self, command: &Command) -> io::Result<()> {
+        // like Redis, the writes are refused once they can no longer be persisted, rather than
+        // acknowledged and lost on restart
+        if self.metrics.aof_write_failed() && command.command_type.is_write() {
+            let error = Frame::new_simple_error(
+                "MISCONF Errors writing to the AOF file, writes are refused until it is fixed",
+            );
+            return self.write_frame(&error).await;
+        }
+        self.metrics.record_command(command.command_type);
+        if let (Some(tracking), Some(key)) = (self.tracking.as_mut(), command.tracked_key()) {
+            tracking.keys.insert(key.to_vec());
+        }
         match command.command_type {
-            CommandType::PING => {
-                self.apply_ping_command(command).await;
+            CommandType::PING => self.apply_ping_command(command).await,
+            CommandType::GET => self.apply_get_command(command).await,
+            CommandType::SET => self.apply_set_command(command).await,
+            CommandType::DEL => self.apply_del_command(command).await,
+            CommandType::UNLINK => self.apply_unlink_command(command).await,
+            CommandType::DELX => self.apply_delx_command(command).await,
+            CommandType::GETRANGE => self.apply_getrange_command(command).await,
+            CommandType::SETRANGE => self.apply_setrange_command(command).await,
+            CommandType::WAIT => self.apply_wait_command(command).await,
+            CommandType::SHUTDOWN => self.apply_shutdown_command(command).await,
+            CommandType::TOUCH => self.apply_touch_command(command).await,
+            CommandType::EXPIRE | CommandType::PEXPIRE | CommandType::PEXPIREAT => {
+                self.apply_expire_command(command).await
             }
-            CommandType::GET => {
-                self.apply_get_command(command).await;
+            CommandType::HKEYS => self.apply_hkeys_command(command).await,
+            CommandType::HVALS => self.apply_hvals_command(command).await,
+            CommandType::HSCAN => self.apply_hscan_command(command).await,
+            CommandType::SSCAN => self.apply_sscan_command(command).await,
+            CommandType::ZSCAN => self.apply_zscan_command(command).await,
+            CommandType::CLIENT => self.apply_client_command(command).await,
+            CommandType::CAS => self.apply_cas_command(command).await,
+            CommandType::GETDEL => self.apply_getdel_command(command).await,
+            CommandType::INFO => self.apply_info_command(command).await,
+            CommandType::GETSET => self.apply_getset_command(command).await,
+            CommandType::PIN => self.apply_pin_command(command, true).await,
+            CommandType::UNPIN => self.apply_pin_command(command, false).await,
+            CommandType::SCAN => self.apply_scan_command(command).await,
+            CommandType::GETEX => self.apply_getex_command(command).await,
+            CommandType::RENAME => self.apply_rename_command(command, false).await,
+            CommandType::RENAMENX => self.apply_rename_command(command, true).await,
+            CommandType::PERSIST => self.apply_persist_command(command).await,
+            CommandType::SETNX => self.apply_setnx_command(command).await,
+            CommandType::COMMAND => self.apply_command_command(command).await,
+            CommandType::HELLO => self.apply_hello_command(command).await,
+            CommandType::AUTH => self.apply_auth_command(command).await,
+            CommandType::SELECT => self.apply_select_command(command).await,
+            CommandType::APPEND => self.apply_append_command(command).await,
+            CommandType::INCR | CommandType::DECR | CommandType::INCRBY | CommandType::DECRBY => {
+                self.apply_incr_command(command).await
             }
-            CommandType::SET => {
-                self.apply_set_command(command).await;
+            CommandType::LPUSH | CommandType::RPUSH => self.apply_push_command(command).await,
+            CommandType::LPOP | CommandType::RPOP => self.apply_pop_command(command).await,
+            CommandType::BLPOP | CommandType::BRPOP => {
+                self.apply_blocking_pop_command(command).await
             }
-            CommandType::DEL => {
-                self.apply_del_command(command).await;
+            CommandType::LRANGE => self.apply_lrange_command(command).await,
+            CommandType::HSET => self.apply_hset_command(command).await,
+            CommandType::HGET => self.apply_hget_command(command).await,
+            CommandType::HDEL => self.apply_hdel_command(command).await,
+            CommandType::HGETALL => self.apply_hgetall_command(command).await,
+            CommandType::INCRBYFLOAT => self.apply_incrbyfloat_command(command).await,
+            CommandType::SLOWLOG => self.apply_slowlog_command(command).await,
+            CommandType::OBJECT => self.apply_object_command(command).await,
+            CommandType::MSETNX => self.apply_msetnx_command(command).await,
+            CommandType::DEBUG => self.apply_debug_command(command).await,
+            CommandType::SAVE | CommandType::BGSAVE => self.apply_save_command(command).await,
+            CommandType::MULTI => {
+                self.queued = Some(Vec::new());
+                self.write_frame(&Frame::new_simple_string("OK")).await
             }
-            CommandType::EXPIRE => {
-                self.apply_expire_command(command).await;
+            CommandType::EXEC => {
+                let error = Frame::new_simple_error("ERR EXEC without MULTI");
+                self.write_frame(&error).await
             }
-            CommandType::ERROR => {
-                self.apply_error_command(command).await;
+            CommandType::DISCARD => {
+                let error = Frame::new_simple_error("ERR DISCARD without MULTI");
+                self.write_frame(&error).await
+            }
+            CommandType::RESET => self.apply_reset_command().await,
+            CommandType::ERROR => self.apply_error_command(command).await,
+        }
+    }
+
+    // apply_reset_command returns the connection to the state it had when it was opened: it leaves
+    // any transaction, stops tracking, selects the first database, goes back to RESP2 and, when a
+    // password is required, must authenticate again.
+    async fn apply_reset_command(&mut self) -> io::Result<()> {
+        trace!("receive reset command, resetting the connection");
+        self.discard_transaction();
+        self.tracking = None;
+        self.storage = self.databases[0].clone();
+        self.db_index = 0;
+        self.protocol = Protocol::Resp2;
+        self.authenticated = self.requirepass.is_none();
+        self.write_frame(&Frame::new_simple_string("RESET")).await
+    }
+
+    // apply_exec_command applies the commands queued since MULTI, in order, and replies with the
+    // array of their replies. The transaction is discarded instead if one of them was invalid.
+    async fn apply_exec_command(&mut self) -> io::Result<()> {
+        let commands = self.queued.take().unwrap_or_default();
+        let frames = std::mem::take(&mut self.queued_frames);
+        if std::mem::take(&mut self.queue_failed) {
+            let error = Frame::new_simple_error(
+                "EXECABORT Transaction discarded because of previous errors.",
+            );
+            return self.write_frame(&error).await;
+        }
+        self.exec_replies = Some(Vec::with_capacity(commands.len()));
+        // The clients using the keys of the transaction wait for the whole of it, whose replies
+        // are only collected. The gates are found by following the SELECTs of the transaction.
+        let mut gates = Vec::new();
+        let mut db = self.db_index;
+        for command in &commands {
+            match command.command_type {
+                CommandType::SELECT => {
+                    if let Some(index) = command
+                        .arg_str(0)
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|index| *index < self.databases.len())
+                    {
+                        db = index;
+                    }
+                }
+                _ => self.gates_of(db, command, &mut gates),
+            }
+        }
+        let held = enter_gates_exclusive(self.databases.clone(), gates).await;
+        for (index, command) in commands.iter().enumerate() {
+            // the replies are collected, writing them cannot fail
+            self.dispatch_command(command).await?;
+            if let Some(frame) = frames.get(index) {
+                self.propagate(command, frame);
             }
         }
+        drop(held);
+        let replies = self.exec_replies.take().unwrap_or_default();
+        self.write_frame(&Frame::new_array(replies)).await
     }
 
-    async fn apply_ping_command(&mut self, command: &Command) {
-        debug!("receive ping command, processing it: {:?}", command);
+    async fn apply_ping_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive ping command, processing it: {:?}", command);
         let response_frame = if command.args.len() == 1 {
-            Frame::new_bulk_string(&command.args[0].clone())
+            Frame::new_bulk_string(&command.args[0])
         } else {
             Frame::new_simple_string("PONG")
         };
-        if let Err(err) = self.write_frame(&response_frame).await {
-            error!("failed to write to network: {}", err);
+        self.write_frame(&response_frame).await
+    }
+
+    // apply_shutdown_command asks the server to stop, once SAVE wrote a snapshot. Like Redis, the
+    // connection is closed without a reply, the server then syncs the AOF before it returns. The
+    // server keeps running if the snapshot cannot be saved.
+    async fn apply_shutdown_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive shutdown command, processing it: {:?}", command);
+        let Some(shutdown) = self.shutdown.clone() else {
+            let error = Frame::new_simple_error("ERR SHUTDOWN is not allowed on this connection");
+            return self.write_frame(&error).await;
+        };
+        if !command.args.is_empty() {
+            let saved = match self.snapshot_path.clone() {
+                Some(path) => {
+                    let databases = self.databases.clone();
+                    tokio::task::spawn_blocking(move || save_snapshot(&path, &databases))
+                        .await
+                        .map_err(io::Error::other)
+                        .and_then(|saved| saved)
+                }
+                None => Err(io::Error::other("no snapshot file is configured")),
+            };
+            if let Err(err) = saved {
+                error!("failed to save the snapshot before shutting down: {}", err);
+                let error = Frame::new_simple_error("ERR Errors trying to SHUTDOWN. Check logs.");
+                return self.write_frame(&error).await;
+            }
         }
+        info!("shutdown requested by client {}", self.client_id);
+        shutdown.notify_one();
+        self.closing = true;
+        Ok(())
+    }
+
+    // apply_wait_command replies how many replicas acknowledged the writes of the client. There is
+    // no replication, so none ever will: the reply is 0, right away rather than after the timeout.
+    async fn apply_wait_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive wait command, processing it: {:?}", command);
+        self.write_frame(&Frame::new_integer(0)).await
     }
 
-    async fn apply_get_command(&mut self, command: &Command) {
-        debug!("receive get command, processing it: {:?}", command);
+    async fn apply_get_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive get command, processing it: {:?}", command);
         let value = self.storage.get_v(&command.args[0]);
         let response_frame = match value {
-            Some(value) => Frame::new_simple_string(&value),
-            None => Frame::new_null(),
+            Ok(Some(value)) => Frame::new_bulk_bytes(value),
+            Ok(None) => Frame::new_null(),
+            Err(err) => Frame::new_simple_error(&err.to_string()),
         };
 
-        if let Err(err) = self.write_frame(&response_frame).await {
-            error!("failed to write to network: {}", err);
-        }
+        self.write_frame(&response_frame).await
     }
 
-    async fn apply_set_command(&mut self, command: &Command) {
-        debug!("receive set command, processing it: {:?}", command);
-        // this conversion is guaranteed to succeed because we check while parsing a frame to a command
-        let expiration = if command.args.len() == 3 {
-            command.args[2].parse::<u64>().unwrap_or(0)
-        } else {
-            0
+    async fn apply_set_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive set command, processing it: {:?}", command);
+        let (key, value) = (&command.args[0], &command.args[1]);
+        let set = match command.args.get(2).map(Vec::as_slice) {
+            Some(b"KEEPTTL") => self.storage.set_kv_keepttl(key, value),
+            // this conversion is guaranteed to succeed because we check while parsing a frame to a command
+            ttl => {
+                let ttl = ttl
+                    .map(|_| Duration::from_millis(command.arg_str(2).parse::<u64>().unwrap_or(0)));
+                self.storage.set_kv(key, value, ttl)
+            }
         };
-        let ttl = Duration::from_millis(expiration);
-        self.storage.set_kv(&command.args[0], &command.args[1], ttl);
-
-        let response_frame = Frame::new_simple_string("OK");
-        if let Err(err) = self.write_frame(&response_frame).await {
-            error!("failed to write to network: {}", err);
-        }
+        let response_frame = match set {
+            Ok(_) => Frame::new_simple_string("OK"),
+            Err(err) => Frame::new_simple_error(&err.to_string()),
+        };
+        self.write_frame(&response_frame).await
     }
 
-    async fn apply_error_command(&mut self, command: &Command) {
-        debug!("receive error command, processing it");
-        let response_frame = Frame::new_simple_error(&command.args[0].clone());
-        if let Err(err) = self.write_frame(&response_frame).await {
-            error!("failed to write to network: {}", err);
-        }
+    async fn apply_error_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive error command, processing it");
+        // Generic errors start with ERR, like in Redis, so that clients can tell them apart.
+        let response_frame = Frame::new_simple_error(&format!("ERR {}", command.arg_str(0)));
+        self.write_frame(&response_frame).await
     }
 
-    async fn apply_del_command(&mut self, command: &Command) {
-        debug!("receive del command, processing it: {:?}", command);
+    async fn apply_del_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive del command, processing it: {:?}", command);
 
         let num_deleted = self.storage.del_entries(&command.args);
 
         let response_frame = Frame::new_integer(num_deleted as i64);
 
-        if let Err(err) = self.write_frame(&response_frame).await {
-            error!("failed to write to network: {}", err);
-        }
+        self.write_frame(&response_frame).await
     }
 
-    async fn apply_expire_command(&mut self, command: &Command) {
-        debug!("receive expire command, processing it: {:?}", command);
-        unimplemented!("implement me");
+    // apply_delx_command removes the keys like DEL, but replies for each of them whether it
+    // existed, 1 or 0, in the order they were given.
+    async fn apply_delx_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive delx command, processing it: {:?}", command);
+
+        let removed = self
+            .storage
+            .del_entries_detailed(&command.args)
+            .into_iter()
+            .map(|removed| Frame::new_integer(removed as i64))
+            .collect();
+
+        self.write_frame(&Frame::new_array(removed)).await
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    // apply_unlink_command removes the keys right away, their values are freed in the background.
+    async fn apply_unlink_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive unlink command, processing it: {:?}", command);
 
-    #[tokio::test]
-    async fn test_decode_frame_integer() {
-        let (mut client, server) = io::duplex(1024);
-        let storage = Arc::new(Storage::new(1000000, 4));
-        let mut parser = Parser::new(server, storage, 1024);
+        let num_unlinked = self.storage.unlink_entries(&command.args);
 
-        // Simulate client writing to the stream
-        tokio::spawn(async move {
-            let data = b":33\r\n:0\r\n:-50\r\n:hello\r\n";
-            client.write_all(data).await.unwrap();
-            client.flush().await.unwrap();
-        });
+        let response_frame = Frame::new_integer(num_unlinked as i64);
 
-        // simple string
-        let frame = parser.decode_frame().await.unwrap();
-        let mut response_frame = Frame::new_integer(33);
-        assert_eq!(frame, response_frame, "can decode a positive number");
+        self.write_frame(&response_frame).await
+    }
 
-        let frame = parser.decode_frame().await.unwrap();
-        response_frame = Frame::new_integer(0);
-        assert_eq!(frame, response_frame, "can decode 0 as a number");
+    // apply_touch_command counts the given keys which exist, and marks them as accessed.
+    async fn apply_touch_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive touch command, processing it: {:?}", command);
 
-        let frame = parser.decode_frame().await.unwrap();
-        response_frame = Frame::new_integer(-50);
-        assert_eq!(frame, response_frame, "can decode a negative number");
+        let num_touched = command
+            .args
+            .iter()
+            .filter(|key| self.storage.touch(key))
+            .count();
 
-        let frame = parser.decode_frame().await;
-        assert_eq!(
-            frame,
-            Err(DecodeError::UTF8ToInt),
-            "cannot convert an non-number  frame to a number"
-        );
+        let response_frame = Frame::new_integer(num_touched as i64);
+
+        self.write_frame(&response_frame).await
     }
 
-    #[tokio::test]
-    async fn test_decode_frame_simple_string() {
-        let (mut client, server) = io::duplex(1024);
-        let storage = Arc::new(Storage::new(1000000, 4));
-        let mut parser = Parser::new(server, storage, 1024);
+    // apply_expire_command applies EXPIRE, PEXPIRE and PEXPIREAT, whose args are normalized to
+    // `[key, milliseconds]`.
+    async fn apply_expire_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive expire command, processing it: {:?}", command);
+        // the parser made sure that the TTL is an integer
+        let ttl_ms = command.arg_str(1).parse::<i64>().unwrap();
+        let existed = self.storage.expire(&command.args[0], ttl_ms);
+        self.write_frame(&Frame::new_integer(existed as i64)).await
+    }
 
-        // Simulate client writing to the stream
-        tokio::spawn(async move {
-            let data = b"+hello\r\n+58\r\n+\r\n+hello\n+Incompet";
-            client.write_all(data).await.unwrap();
-            client.flush().await.unwrap();
-        });
+    async fn apply_hkeys_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive hkeys command, processing it: {:?}", command);
+        let response_frame = match self.storage.hkeys(&command.args[0]) {
+            Ok(fields) => Self::bulk_string_array(&fields),
+            Err(err) => Frame::new_simple_error(&err.to_string()),
+        };
+        self.write_frame(&response_frame).await
+    }
 
-        // simple string
-        let frame = parser.decode_frame().await.unwrap();
-        let mut response_frame = Frame::new_simple_string("hello");
-        assert_eq!(frame, response_frame, "can decode a simple string");
+    async fn apply_hset_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive hset command, processing it: {:?}", command);
+        // the parser made sure that the fields come with a value
+        let pairs: Vec<(Vec<u8>, Vec<u8>)> = command.args[1..]
+            .chunks(2)
+            .map(|pair| (pair[0].clone(), pair[1].clone()))
+            .collect();
+        let response_frame = match self.storage.hset(&command.args[0], &pairs) {
+            Ok(added) => Frame::new_integer(added as i64),
+            Err(err) => Frame::new_simple_error(&err.to_string()),
+        };
+        self.write_frame(&response_frame).await
+    }
 
-        let frame = parser.decode_frame().await.unwrap();
-        response_frame = Frame::new_simple_string("58");
-        assert_eq!(
-            frame, response_frame,
-            "can decode a simple string which is a number"
-        );
+    // apply_msetnx_command replies with 1 if every key was set, 0 if none was because one of them
+    // already existed.
+    async fn apply_msetnx_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive msetnx command, processing it: {:?}", command);
+        // the parser made sure that the keys come with a value
+        let pairs: Vec<(Vec<u8>, Vec<u8>)> = command
+            .args
+            .chunks(2)
+            .map(|pair| (pair[0].clone(), pair[1].clone()))
+            .collect();
+        let response_frame = match self.storage.msetnx(&pairs) {
+            Ok(set) => Frame::new_integer(set as i64),
+            Err(err) => Frame::new_simple_error(&err.to_string()),
+        };
+        self.write_frame(&response_frame).await
+    }
 
-        let frame = parser.decode_frame().await.unwrap();
-        response_frame = Frame::new_simple_string("");
-        assert_eq!(
-            frame, response_frame,
-            "can decode a simple string which is empty"
-        );
+    async fn apply_hget_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive hget command, processing it: {:?}", command);
+        let response_frame = match self.storage.hget(&command.args[0], &command.args[1]) {
+            Ok(Some(value)) => Frame::new_bulk_string(&value),
+            Ok(None) => Frame::new_null(),
+            Err(err) => Frame::new_simple_error(&err.to_string()),
+        };
+        self.write_frame(&response_frame).await
+    }
 
-        let frame = parser.decode_frame().await;
-        assert_eq!(
-            frame,
-            Err(DecodeError::Invalid),
-            "simple frame cannot be terminated with a single LF"
-        );
+    async fn apply_hdel_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive hdel command, processing it: {:?}", command);
+        let response_frame = match self.storage.hdel(&command.args[0], &command.args[1..]) {
+            Ok(removed) => Frame::new_integer(removed as i64),
+            Err(err) => Frame::new_simple_error(&err.to_string()),
+        };
+        self.write_frame(&response_frame).await
+    }
 
-        let frame = parser.decode_frame().await;
-        assert_eq!(
-            frame,
-            Err(DecodeError::Incomplete),
-            "frames are terminated with CRLF"
-        );
+    // apply_hgetall_command replies with the fields and values of the hash: a map in RESP3, a
+    // flat array of fields and values in RESP2.
+    async fn apply_hgetall_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive hgetall command, processing it: {:?}", command);
+        let response_frame = match self.storage.hgetall(&command.args[0]) {
+            Ok(pairs) => Frame::new_map(
+                pairs
+                    .iter()
+                    .map(|(field, value)| {
+                        (Frame::new_bulk_string(field), Frame::new_bulk_string(value))
+                    })
+                    .collect(),
+            ),
+            Err(err) => Frame::new_simple_error(&err.to_string()),
+        };
+        self.write_frame(&response_frame).await
     }
 
-    #[tokio::test]
-    async fn test_decode_frame_simple_error() {
-        let (mut client, server) = io::duplex(1024);
-        let storage = Arc::new(Storage::new(1000000, 4));
-        let mut parser = Parser::new(server, storage, 1024);
+    async fn apply_hvals_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive hvals command, processing it: {:?}", command);
+        let response_frame = match self.storage.hvals(&command.args[0]) {
+            Ok(values) => Self::bulk_string_array(&values),
+            Err(err) => Frame::new_simple_error(&err.to_string()),
+        };
+        self.write_frame(&response_frame).await
+    }
 
-        // Simulate client writing to the stream
-        tokio::spawn(async move {
-            let data = b"-hello\r\n-58\r\n-\r\n-hello\n-Incompet";
+    async fn apply_hscan_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive hscan command, processing it: {:?}", command);
+        // these conversions are guaranteed to succeed because we check while parsing a frame to a command
+        let cursor = command.arg_str(1).parse::<usize>().unwrap_or(0);
+        let count = command.arg_str(3).parse::<usize>().unwrap_or(10);
+        let no_values = command.arg_str(4) == "1";
+        let result =
+            self.storage
+                .hscan(&command.args[0], cursor, &command.args[2], count, no_values);
+        let response_frame = match result {
+            Ok((cursor, items)) => Self::scan_reply(cursor, &items),
+            Err(err) => Frame::new_simple_error(&err.to_string()),
+        };
+        self.write_frame(&response_frame).await
+    }
+
+    async fn apply_scan_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive scan command, processing it: {:?}", command);
+        // these conversions are guaranteed to succeed because we check while parsing a frame to a command
+        let cursor = command.arg_str(0).parse::<usize>().unwrap_or(0);
+        let count = command.arg_str(2).parse::<usize>().unwrap_or(10);
+        let (cursor, keys) = self.storage.scan(cursor, &command.args[1], count);
+        self.write_frame(&Self::scan_reply(cursor, &keys)).await
+    }
+
+    async fn apply_sscan_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive sscan command, processing it: {:?}", command);
+        // these conversions are guaranteed to succeed because we check while parsing a frame to a command
+        let cursor = command.arg_str(1).parse::<usize>().unwrap_or(0);
+        let count = command.arg_str(3).parse::<usize>().unwrap_or(10);
+        let result = self
+            .storage
+            .sscan(&command.args[0], cursor, &command.args[2], count);
+        let response_frame = match result {
+            Ok((cursor, items)) => Self::scan_reply(cursor, &items),
+            Err(err) => Frame::new_simple_error(&err.to_string()),
+        };
+        self.write_frame(&response_frame).await
+    }
+
+    async fn apply_zscan_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive zscan command, processing it: {:?}", command);
+        // these conversions are guaranteed to succeed because we check while parsing a frame to a command
+        let cursor = command.arg_str(1).parse::<usize>().unwrap_or(0);
+        let count = command.arg_str(3).parse::<usize>().unwrap_or(10);
+        let result = self
+            .storage
+            .zscan(&command.args[0], cursor, &command.args[2], count);
+        let response_frame = match result {
+            Ok((cursor, items)) => Self::scan_reply(cursor, &items),
+            Err(err) => Frame::new_simple_error(&err.to_string()),
+        };
+        self.write_frame(&response_frame).await
+    }
+
+    async fn apply_client_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive client command, processing it: {:?}", command);
+        // the subcommands are validated while parsing a frame to a command
+        match command.arg_str(0) {
+            "TRACKING" => {
+                if command.arg_str(1) == "ON" {
+                    // the invalidations are pushes, which a RESP2 client cannot parse
+                    if self.protocol == Protocol::Resp2 {
+                        let error = Frame::new_simple_error(
+                            "ERR CLIENT TRACKING ON requires RESP3, switch with HELLO 3 first",
+                        );
+                        return self.write_frame(&error).await;
+                    }
+                    if self.tracking.is_none() {
+                        self.tracking = Some(Tracking {
+                            keys: HashSet::new(),
+                            events: self.storage.subscribe_key_events(),
+                        });
+                    }
+                } else {
+                    self.tracking = None;
+                }
+            }
+            "SETNAME" => self.name = command.arg_str(1).to_string(),
+            "GETNAME" => return self.write_frame(&Frame::new_bulk_string(&self.name)).await,
+            "ID" => {
+                let id = Frame::new_integer(self.client_id as i64);
+                return self.write_frame(&id).await;
+            }
+            _ => {}
+        }
+        self.write_frame(&Frame::new_simple_string("OK")).await
+    }
+
+    async fn apply_cas_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive cas command, processing it: {:?}", command);
+        // the expiration was checked while parsing the command
+        let ttl = command
+            .args
+            .get(3)
+            .map(|_| Duration::from_millis(command.arg_str(3).parse::<u64>().unwrap_or(0)));
+        let result = self.storage.compare_and_set(
+            &command.args[0],
+            Some(&command.args[1]),
+            &command.args[2],
+            ttl,
+        );
+        let response_frame = match result {
+            Ok(swapped) => Frame::new_integer(swapped as i64),
+            Err(err) => Frame::new_simple_error(&err.to_string()),
+        };
+        self.write_frame(&response_frame).await
+    }
+
+    async fn apply_getdel_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive getdel command, processing it: {:?}", command);
+        let response_frame = match self.storage.get_del(&command.args[0]) {
+            Ok(Some(value)) => Frame::new_bulk_string(&value),
+            Ok(None) => Frame::new_null(),
+            Err(err) => Frame::new_simple_error(&err.to_string()),
+        };
+        self.write_frame(&response_frame).await
+    }
+
+    async fn apply_getex_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive getex command, processing it: {:?}", command);
+        // this conversion is guaranteed to succeed because we check while parsing a frame to a command
+        let update = match command.args.get(1).map(|option| option.as_slice()) {
+            Some(b"PERSIST") => ExpiryUpdate::Persist,
+            Some(_) => ExpiryUpdate::After(Duration::from_millis(
+                command.arg_str(2).parse::<u64>().unwrap_or(0),
+            )),
+            None => ExpiryUpdate::Keep,
+        };
+        let response_frame = match self.storage.get_ex(&command.args[0], update) {
+            Ok(Some(value)) => Frame::new_bulk_string(&value),
+            Ok(None) => Frame::new_null(),
+            Err(err) => Frame::new_simple_error(&err.to_string()),
+        };
+        self.write_frame(&response_frame).await
+    }
+
+    // apply_rename_command applies RENAME, or RENAMENX when `nx` is set.
+    async fn apply_rename_command(&mut self, command: &Command, nx: bool) -> io::Result<()> {
+        trace!("receive rename command, processing it: {:?}", command);
+        let result = self.storage.rename(&command.args[0], &command.args[1], nx);
+        let response_frame = match result {
+            RenameResult::NoSuchKey => Frame::new_simple_error("ERR no such key"),
+            RenameResult::Renamed if nx => Frame::new_integer(1),
+            RenameResult::Renamed => Frame::new_simple_string("OK"),
+            RenameResult::DestinationExists => Frame::new_integer(0),
+        };
+        self.write_frame(&response_frame).await
+    }
+
+    async fn apply_persist_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive persist command, processing it: {:?}", command);
+        let persisted = self.storage.persist(&command.args[0]);
+        self.write_frame(&Frame::new_integer(persisted as i64))
+            .await
+    }
+
+    async fn apply_setnx_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive setnx command, processing it: {:?}", command);
+        let result = self.storage.set_kv_conditional(
+            &command.args[0],
+            &command.args[1],
+            None,
+            SetCondition::NotExists,
+        );
+        let response_frame = match result {
+            Ok(set) => Frame::new_integer(set as i64),
+            Err(err) => Frame::new_simple_error(&err.to_string()),
+        };
+        self.write_frame(&response_frame).await
+    }
+
+    // apply_command_command replies to COMMAND with the description of every command:
+    // `[name, arity, flags, first key, last key, step]`.
+    async fn apply_command_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive command command, processing it: {:?}", command);
+        // the disabled commands are not listed
+        let enabled = COMMAND_TABLE
+            .iter()
+            .filter(|spec| !self.disabled_commands.contains(spec.name));
+        match command.args.first().map(Vec::as_slice) {
+            Some(b"COUNT") => {
+                let count = Frame::new_integer(enabled.count() as i64);
+                return self.write_frame(&count).await;
+            }
+            // redis-cli asks for docs to help with the commands, but it does not need them
+            Some(b"DOCS") => return self.write_frame(&Frame::new_map(vec![])).await,
+            _ => {}
+        }
+        let specs = enabled
+            .map(|spec| {
+                let flags = spec
+                    .flags
+                    .iter()
+                    .map(|flag| Frame::new_simple_string(flag))
+                    .collect();
+                Frame::new_array(vec![
+                    Frame::new_bulk_string(spec.name.to_lowercase()),
+                    Frame::new_integer(spec.arity),
+                    Frame::new_array(flags),
+                    Frame::new_integer(spec.first_key),
+                    Frame::new_integer(spec.last_key),
+                    Frame::new_integer(spec.step),
+                ])
+            })
+            .collect();
+        self.write_frame(&Frame::new_array(specs)).await
+    }
+
+    // apply_auth_command authenticates the client if it sent the right password. The args are
+    // `[password]` or `[username, password]`, and the only user is "default".
+    async fn apply_auth_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive auth command");
+        let Some(requirepass) = self.requirepass.as_ref() else {
+            let msg = "ERR AUTH <password> called without any password configured for the default \
+                       user. Are you sure your configuration is correct?";
+            return self.write_frame(&Frame::new_simple_error(msg)).await;
+        };
+        let (user, password) = match command.args.as_slice() {
+            [password] => (b"default".as_slice(), password),
+            [user, password] => (user.as_slice(), password),
+            _ => unreachable!("the parser checks the number of args"),
+        };
+        let valid = user == b"default" && constant_time_eq(password, requirepass.as_bytes());
+        let response_frame = if valid {
+            self.authenticated = true;
+            Frame::new_simple_string("OK")
+        } else {
+            Frame::new_simple_error("ERR invalid password")
+        };
+        self.write_frame(&response_frame).await
+    }
+
+    async fn apply_append_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive append command, processing it: {:?}", command);
+        let response_frame = match self.storage.append(&command.args[0], &command.args[1]) {
+            Ok(len) => Frame::new_integer(len as i64),
+            Err(err) => Frame::new_simple_error(&err.to_string()),
+        };
+        self.write_frame(&response_frame).await
+    }
+
+    // apply_select_command switches the client to another database.
+    async fn apply_select_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive select command, processing it: {:?}", command);
+        // the parser made sure that the index is an integer
+        let index = command.arg_str(0).parse::<i64>().unwrap();
+        let response_frame = match usize::try_from(index)
+            .ok()
+            .filter(|index| *index < self.databases.len())
+        {
+            Some(index) => {
+                self.storage = self.databases[index].clone();
+                self.db_index = index;
+                self.track_selected_database().await?;
+                Frame::new_simple_string("OK")
+            }
+            None => Frame::new_simple_error("ERR DB index is out of range"),
+        };
+        self.write_frame(&response_frame).await
+    }
+
+    // track_selected_database makes tracking follow a SELECT: the key events now come from the
+    // selected database. The ones of the previous database are no longer received, so the client
+    // is told to drop every key it cached.
+    async fn track_selected_database(&mut self) -> io::Result<()> {
+        let Some(tracking) = self.tracking.as_mut() else {
+            return Ok(());
+        };
+        tracking.events = self.storage.subscribe_key_events();
+        if tracking.keys.is_empty() {
+            return Ok(());
+        }
+        tracking.keys.clear();
+        self.write_push(&Self::invalidation_message(None)).await
+    }
+
+    // apply_incr_command applies the INCR family, whose args are normalized to `[key, delta]`.
+    async fn apply_incr_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive incr command, processing it: {:?}", command);
+        // the parser made sure that the delta is an integer
+        let delta = command.arg_str(1).parse::<i64>().unwrap();
+        let response_frame = match self.storage.incr_by(&command.args[0], delta) {
+            Ok(value) => Frame::new_integer(value),
+            Err(err) => Frame::new_simple_error(&err.to_string()),
+        };
+        self.write_frame(&response_frame).await
+    }
+
+    async fn apply_push_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive push command, processing it: {:?}", command);
+        let (key, elements) = command.args.split_first().unwrap();
+        let result = match command.command_type {
+            CommandType::LPUSH => self.storage.lpush(key, elements),
+            _ => self.storage.rpush(key, elements),
+        };
+        let response_frame = match result {
+            Ok(len) => Frame::new_integer(len as i64),
+            Err(err) => Frame::new_simple_error(&err.to_string()),
+        };
+        self.write_frame(&response_frame).await
+    }
+
+    // apply_pop_command replies with the popped element, or with an array of them when a count
+    // was given. A missing key gets a null reply either way.
+    async fn apply_pop_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive pop command, processing it: {:?}", command);
+        // the parser made sure that the count is a positive integer
+        let count = command
+            .args
+            .get(1)
+            .map(|_| command.arg_str(1).parse::<usize>().unwrap());
+        let result = match command.command_type {
+            CommandType::LPOP => self.storage.lpop(&command.args[0], count.unwrap_or(1)),
+            _ => self.storage.rpop(&command.args[0], count.unwrap_or(1)),
+        };
+        let response_frame = match result {
+            Ok(popped) if popped.is_empty() => Frame::new_null(),
+            Ok(popped) if count.is_some() => Self::bulk_string_array(&popped),
+            Ok(popped) => Frame::new_bulk_string(&popped[0]),
+            Err(err) => Frame::new_simple_error(&err.to_string()),
+        };
+        self.write_frame(&response_frame).await
+    }
+
+    // apply_blocking_pop_command applies BLPOP and BRPOP. When the lists are empty, the client
+    // waits for a push until the timeout, which it does not while EXEC applies the command, like
+    // in Redis. A client closing the connection while it waits, or the server shutting down,
+    // stops the wait, and an element popped for it in the meantime is given back.
+    async fn apply_blocking_pop_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive blocking pop command, processing it: {:?}", command);
+        // the parser made sure that the timeout is an integer, after at least a key
+        let (timeout, keys) = command.args.split_last().unwrap();
+        let timeout = std::str::from_utf8(timeout)
+            .unwrap()
+            .parse::<u64>()
+            .unwrap();
+        let head = command.command_type == CommandType::BLPOP;
+        let storage = self.storage.clone();
+        let mut waiter = match storage.blocking_pop(keys, head) {
+            Ok(BlockingPop::Popped(key, element)) => return self.reply_popped(key, element).await,
+            Ok(BlockingPop::Blocked(waiter)) => waiter,
+            Err(err) => {
+                return self
+                    .write_frame(&Frame::new_simple_error(&err.to_string()))
+                    .await
+            }
+        };
+        let mut gone = false;
+        let mut server_closing = self.server_closing.clone();
+        let popped = if self.exec_replies.is_some() {
+            None
+        } else {
+            let expired = async {
+                match timeout {
+                    0 => std::future::pending().await,
+                    timeout => tokio::time::sleep(Duration::from_millis(timeout)).await,
+                }
+            };
+            tokio::select! {
+                popped = waiter.popped() => popped,
+                _ = expired => None,
+                _ = self.wait_closed() => {
+                    gone = true;
+                    None
+                }
+                _ = closed(&mut server_closing) => {
+                    gone = true;
+                    None
+                }
+            }
+        };
+        // a push may have served the client while it stopped waiting
+        let popped = popped.or(storage.cancel_pop(waiter));
+        if gone {
+            debug!("connection closed while blocked");
+            if let Some((key, element)) = popped {
+                let given_back = if head {
+                    storage.lpush(&key, &[element])
+                } else {
+                    storage.rpush(&key, &[element])
+                };
+                if let Err(err) = given_back {
+                    error!(
+                        "failed to give back an element popped for a gone client: {}",
+                        err
+                    );
+                }
+            }
+            self.closing = true;
+            return Ok(());
+        }
+        match popped {
+            Some((key, element)) => self.reply_popped(key, element).await,
+            None => self.write_frame(&Frame::new_null_array()).await,
+        }
+    }
+
+    // reply_popped replies with the key and the element popped by BLPOP or BRPOP.
+    async fn reply_popped(&mut self, key: Vec<u8>, element: Vec<u8>) -> io::Result<()> {
+        let reply = Frame::new_array(vec![
+            Frame::new_bulk_string(&key),
+            Frame::new_bulk_string(&element),
+        ]);
+        self.served_pop = Some(key);
+        self.write_frame(&reply).await
+    }
+
+    async fn apply_lrange_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive lrange command, processing it: {:?}", command);
+        // the parser made sure that the indices are integers
+        let start = command.arg_str(1).parse::<i64>().unwrap();
+        let stop = command.arg_str(2).parse::<i64>().unwrap();
+        let response_frame = match self.storage.lrange(&command.args[0], start, stop) {
+            Ok(elements) => Self::bulk_string_array(&elements),
+            Err(err) => Frame::new_simple_error(&err.to_string()),
+        };
+        self.write_frame(&response_frame).await
+    }
+
+    async fn apply_getrange_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive getrange command, processing it: {:?}", command);
+        // the parser made sure that the offsets are integers
+        let start = command.arg_str(1).parse::<i64>().unwrap();
+        let end = command.arg_str(2).parse::<i64>().unwrap();
+        let response_frame = match self.storage.getrange(&command.args[0], start, end) {
+            Ok(range) => Frame::new_bulk_bytes(range),
+            Err(err) => Frame::new_simple_error(&err.to_string()),
+        };
+        self.write_frame(&response_frame).await
+    }
+
+    async fn apply_setrange_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive setrange command, processing it: {:?}", command);
+        // the parser made sure that the offset is a valid length
+        let offset = command.arg_str(1).parse::<usize>().unwrap();
+        let response_frame = match self
+            .storage
+            .setrange(&command.args[0], offset, &command.args[2])
+        {
+            Ok(len) => Frame::new_integer(len as i64),
+            Err(err) => Frame::new_simple_error(&err.to_string()),
+        };
+        self.write_frame(&response_frame).await
+    }
+
+    async fn apply_incrbyfloat_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive incrbyfloat command, processing it: {:?}", command);
+        // the parser made sure that the increment is a float
+        let delta = command.arg_str(1).parse::<f64>().unwrap();
+        let response_frame = match self.storage.incr_by_float(&command.args[0], delta) {
+            Ok(value) => Frame::new_bulk_string(value),
+            Err(err) => Frame::new_simple_error(&err.to_string()),
+        };
+        self.write_frame(&response_frame).await
+    }
+
+    // apply_slowlog_command replies to GET with an array of entries, each being an array of the
+    // entry id, its UNIX timestamp, the duration in microseconds and the command arguments.
+    async fn apply_slowlog_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive slowlog command, processing it: {:?}", command);
+        // the subcommands and the count are validated while parsing a frame to a command
+        let response_frame = match command.arg_str(0) {
+            "GET" => {
+                let count = usize::try_from(command.arg_str(1).parse::<i64>().unwrap());
+                let entries = self.slowlog.get(count.unwrap_or(usize::MAX));
+                Frame::new_array(
+                    entries
+                        .iter()
+                        .map(|entry| {
+                            Frame::new_array(vec![
+                                Frame::new_integer(entry.id as i64),
+                                Frame::new_integer(entry.timestamp as i64),
+                                Frame::new_integer(entry.duration.as_micros() as i64),
+                                Self::bulk_string_array(&entry.args),
+                            ])
+                        })
+                        .collect(),
+                )
+            }
+            "LEN" => Frame::new_integer(self.slowlog.len() as i64),
+            _ => {
+                self.slowlog.reset();
+                Frame::new_simple_string("OK")
+            }
+        };
+        self.write_frame(&response_frame).await
+    }
+
+    // apply_object_command replies to ENCODING with the name of the encoding and to IDLETIME with
+    // the number of seconds since the key was last accessed.
+    async fn apply_object_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive object command, processing it: {:?}", command);
+        let key = &command.args[1];
+        // the subcommand is validated while parsing a frame to a command
+        let response_frame = match command.arg_str(0) {
+            "ENCODING" => self
+                .storage
+                .object_encoding(key)
+                .map(Frame::new_simple_string),
+            _ => self
+                .storage
+                .object_idletime(key)
+                .map(|idle| Frame::new_integer(idle.as_secs() as i64)),
+        };
+        let response_frame =
+            response_frame.unwrap_or_else(|| Frame::new_simple_error("ERR no such key"));
+        self.write_frame(&response_frame).await
+    }
+
+    // apply_save_command writes a snapshot of every database. SAVE replies once the snapshot is
+    // written, BGSAVE right away, the outcome of the save being logged. The snapshot is written
+    // on a blocking thread, so that the other connections keep being served.
+    async fn apply_save_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive save command, processing it: {:?}", command);
+        let Some(path) = self.snapshot_path.clone() else {
+            let error = Frame::new_simple_error("ERR no snapshot file is configured");
+            return self.write_frame(&error).await;
+        };
+        let databases = self.databases.clone();
+        let save = tokio::task::spawn_blocking(move || save_snapshot(&path, &databases));
+        if command.command_type == CommandType::BGSAVE {
+            tokio::spawn(async move {
+                match save.await {
+                    Ok(Ok(())) => info!("background saving terminated with success"),
+                    Ok(Err(err)) => error!("background saving failed: {}", err),
+                    Err(err) => error!("background saving panicked: {}", err),
+                }
+            });
+            let started = Frame::new_simple_string("Background saving started");
+            return self.write_frame(&started).await;
+        }
+        let response_frame = match save.await {
+            Ok(Ok(())) => Frame::new_simple_string("OK"),
+            Ok(Err(err)) => Frame::new_simple_error(&format!("ERR failed to save: {}", err)),
+            Err(err) => Frame::new_simple_error(&format!("ERR failed to save: {}", err)),
+        };
+        self.write_frame(&response_frame).await
+    }
+
+    // apply_debug_command runs DEBUG SLEEP, which blocks the connection for the given number of
+    // seconds before replying. DEBUG is only allowed when enabled in the configuration.
+    async fn apply_debug_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive debug command, processing it: {:?}", command);
+        if !self.debug_commands {
+            return self
+                .write_frame(&Frame::new_simple_error("ERR DEBUG command not allowed"))
+                .await;
+        }
+        if command.arg_str(0) == "SHARDS" {
+            // one array of fields and values per shard of the selected database
+            let shards = self
+                .storage
+                .shard_stats()
+                .iter()
+                .enumerate()
+                .map(|(index, stat)| {
+                    Frame::new_array(vec![
+                        Frame::new_bulk_string("shard"),
+                        Frame::new_integer(index as i64),
+                        Frame::new_bulk_string("keys"),
+                        Frame::new_integer(stat.keys as i64),
+                        Frame::new_bulk_string("reads"),
+                        Frame::new_integer(stat.reads as i64),
+                        Frame::new_bulk_string("writes"),
+                        Frame::new_integer(stat.writes as i64),
+                        Frame::new_bulk_string("contended"),
+                        Frame::new_integer(stat.contended as i64),
+                    ])
+                })
+                .collect();
+            return self.write_frame(&Frame::new_array(shards)).await;
+        }
+        // the delay of SLEEP was validated while parsing
+        let seconds = command.arg_str(1).parse::<f64>().unwrap();
+        tokio::time::sleep(Duration::from_secs_f64(seconds)).await;
+        self.write_frame(&Frame::new_simple_string("OK")).await
+    }
+
+    // apply_hello_command switches to the requested protocol, if any, and replies with the server
+    // properties: a map in RESP3, a flat array of fields and values in RESP2.
+    async fn apply_hello_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive hello command, processing it: {:?}", command);
+        if let Some(version) = command.args.first() {
+            match version.as_slice() {
+                b"2" => {
+                    // the invalidations could no longer be sent
+                    self.tracking = None;
+                    self.protocol = Protocol::Resp2;
+                }
+                b"3" => self.protocol = Protocol::Resp3,
+                _ => {
+                    let error = Frame::new_simple_error("NOPROTO unsupported protocol version");
+                    return self.write_frame(&error).await;
+                }
+            }
+        }
+        let proto = match self.protocol {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        };
+        let properties = vec![
+            ("server", Frame::new_bulk_string("mredis")),
+            ("version", Frame::new_bulk_string(env!("CARGO_PKG_VERSION"))),
+            ("proto", Frame::new_integer(proto)),
+            ("mode", Frame::new_bulk_string("standalone")),
+            ("role", Frame::new_bulk_string("master")),
+            ("modules", Frame::new_array(vec![])),
+        ];
+        let properties = properties
+            .into_iter()
+            .map(|(name, value)| (Frame::new_bulk_string(name), value))
+            .collect();
+        // write_frame sends the map as a flat array in RESP2
+        self.write_frame(&Frame::new_map(properties)).await
+    }
+
+    async fn apply_getset_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive getset command, processing it: {:?}", command);
+        let response_frame = match self.storage.get_set(&command.args[0], &command.args[1]) {
+            Ok(Some(old)) => Frame::new_bulk_string(&old),
+            Ok(None) => Frame::new_null(),
+            Err(err) => Frame::new_simple_error(&err.to_string()),
+        };
+        self.write_frame(&response_frame).await
+    }
+
+    // apply_pin_command applies PIN and UNPIN, which reply with the number of existing keys.
+    async fn apply_pin_command(&mut self, command: &Command, pinned: bool) -> io::Result<()> {
+        trace!("receive pin command, processing it: {:?}", command);
+        let count = self.storage.set_pinned(&command.args, pinned);
+        self.write_frame(&Frame::new_integer(count as i64)).await
+    }
+
+    async fn apply_info_command(&mut self, command: &Command) -> io::Result<()> {
+        trace!("receive info command, processing it: {:?}", command);
+        // without argument, every section is returned
+        let wanted = |section: &str| {
+            command.args.is_empty()
+                || command
+                    .args
+                    .iter()
+                    .any(|arg| arg == section.as_bytes() || arg == b"all" || arg == b"everything")
+        };
+        let metrics = self.metrics.snapshot();
+        let mut info = String::new();
+        if wanted("server") {
+            info.push_str(&metrics.render_server());
+        }
+        if wanted("clients") {
+            info.push_str(&metrics.render_clients());
+        }
+        if wanted("memory") {
+            let used: usize = self.databases.iter().map(|db| db.used_memory()).sum();
+            info.push_str(&format!("# Memory\r\nused_memory:{}\r\n", used));
+        }
+        if wanted("persistence") {
+            info.push_str(&metrics.render_persistence());
+        }
+        if wanted("stats") {
+            info.push_str(&metrics.render_stats());
+            let contention: u64 = self
+                .databases
+                .iter()
+                .flat_map(|db| db.lock_contention())
+                .sum();
+            info.push_str(&format!("shard_lock_contention:{}\r\n", contention));
+        }
+        // like Redis, the command statistics are only returned when asked for
+        if command
+            .args
+            .iter()
+            .any(|arg| arg == b"commandstats" || arg == b"all" || arg == b"everything")
+        {
+            info.push_str(&metrics.render_commandstats());
+        }
+        if wanted("keyspace") {
+            info.push_str("# Keyspace\r\n");
+            for (index, db) in self.databases.iter().enumerate() {
+                // like Redis, only the databases holding keys are listed
+                if !db.is_empty() {
+                    info.push_str(&format!("db{}:keys={}\r\n", index, db.len()));
+                }
+            }
+        }
+        self.write_frame(&Frame::new_bulk_string(&info)).await
+    }
+
+    fn bulk_string_array(items: &[Vec<u8>]) -> Frame {
+        Frame::new_array(items.iter().map(Frame::new_bulk_string).collect())
+    }
+
+    // scan_reply builds the reply shared by the SCAN family: the next cursor followed by the
+    // array of elements found in this step.
+    fn scan_reply(cursor: usize, items: &[Vec<u8>]) -> Frame {
+        Frame::new_array(vec![
+            Frame::new_bulk_string(cursor.to_string()),
+            Self::bulk_string_array(items),
+        ])
+    }
+}
+
+// constant_time_eq compares two passwords in a time which does not depend on where they differ,
+// so that a client cannot guess a password byte after byte. Only the length can leak.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// closed returns once the server closes the connections, see Parser::with_server_closing. It never
+// returns without a server, or once the server is gone without closing them.
+async fn closed(server_closing: &mut Option<watch::Receiver<bool>>) {
+    if let Some(closing) = server_closing {
+        if closing.wait_for(|closing| *closing).await.is_ok() {
+            return;
+        }
+    }
+    std::future::pending().await
+}
+
+// enter_gates_shared takes the `gates` of `databases` shared, in order so that it cannot deadlock
+// with a transaction taking them exclusively.
+async fn enter_gates_shared(
+    databases: Arc<[Arc<Storage>]>,
+    mut gates: Vec<(usize, usize)>,
+) -> Vec<OwnedRwLockReadGuard<()>> {
+    gates.sort_unstable();
+    gates.dedup();
+    let mut held = Vec::with_capacity(gates.len());
+    for (db, gate) in gates {
+        held.push(databases[db].gate(gate).read_owned().await);
+    }
+    held
+}
+
+// enter_gates_exclusive takes the `gates` of `databases` exclusively, in order, see
+// enter_gates_shared.
+async fn enter_gates_exclusive(
+    databases: Arc<[Arc<Storage>]>,
+    mut gates: Vec<(usize, usize)>,
+) -> Vec<OwnedRwLockWriteGuard<()>> {
+    gates.sort_unstable();
+    gates.dedup();
+    let mut held = Vec::with_capacity(gates.len());
+    for (db, gate) in gates {
+        held.push(databases[db].gate(gate).write_owned().await);
+    }
+    held
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EvictionPolicy;
+    use crate::parser::FrameData;
+    use bytes::Bytes;
+    use std::collections::VecDeque;
+    use std::io::ErrorKind;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::Context;
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+    /// BrokenWriteStream serves its input one chunk at a time, as if the client waited for the
+    /// replies before sending the next chunk. It accepts at most `write_budget` bytes of output
+    /// before failing every write as if the peer had gone away.
+    struct BrokenWriteStream {
+        chunks: VecDeque<Vec<u8>>,
+        // whether the next read is pending, it is after every chunk
+        paused: bool,
+        write_budget: usize,
+    }
+
+    impl AsyncRead for BrokenWriteStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            if self.paused {
+                self.paused = false;
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            if let Some(chunk) = self.chunks.pop_front() {
+                buf.put_slice(&chunk);
+                self.paused = true;
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for BrokenWriteStream {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            if self.write_budget == 0 {
+                return Poll::Ready(Err(io::Error::from(ErrorKind::BrokenPipe)));
+            }
+            let n = buf.len().min(self.write_budget);
+            self.write_budget -= n;
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_decode_frame_integer() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        let mut parser = Parser::new(
+            server,
+            storage,
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+
+        // Simulate client writing to the stream
+        tokio::spawn(async move {
+            let data = b":33\r\n:0\r\n:-50\r\n:hello\r\n";
             client.write_all(data).await.unwrap();
             client.flush().await.unwrap();
         });
 
         // simple string
         let frame = parser.decode_frame().await.unwrap();
-        let mut response_frame = Frame::new_simple_error("hello");
-        assert_eq!(frame, response_frame, "can decode a simple error");
+        let mut response_frame = Frame::new_integer(33);
+        assert_eq!(frame, response_frame, "can decode a positive number");
 
         let frame = parser.decode_frame().await.unwrap();
-        response_frame = Frame::new_simple_error("58");
+        response_frame = Frame::new_integer(0);
+        assert_eq!(frame, response_frame, "can decode 0 as a number");
+
+        let frame = parser.decode_frame().await.unwrap();
+        response_frame = Frame::new_integer(-50);
+        assert_eq!(frame, response_frame, "can decode a negative number");
+
+        let frame = parser.decode_frame().await;
+        assert_eq!(
+            frame,
+            Err(DecodeError::UTF8ToInt),
+            "cannot convert an non-number  frame to a number"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_decode_frame_simple_string() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        let mut parser = Parser::new(
+            server,
+            storage,
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+
+        // Simulate client writing to the stream
+        tokio::spawn(async move {
+            let data = b"+hello\r\n+58\r\n+\r\n+hello\n+Incompet";
+            client.write_all(data).await.unwrap();
+            client.flush().await.unwrap();
+        });
+
+        // simple string
+        let frame = parser.decode_frame().await.unwrap();
+        let mut response_frame = Frame::new_simple_string("hello");
+        assert_eq!(frame, response_frame, "can decode a simple string");
+
+        let frame = parser.decode_frame().await.unwrap();
+        response_frame = Frame::new_simple_string("58");
         assert_eq!(
             frame, response_frame,
-            "can decode a simple error which is a number"
+            "can decode a simple string which is a number"
         );
 
         let frame = parser.decode_frame().await.unwrap();
-        response_frame = Frame::new_simple_error("");
+        response_frame = Frame::new_simple_string("");
         assert_eq!(
             frame, response_frame,
-            "can decode a simple error which is empty"
+            "can decode a simple string which is empty"
         );
 
         let frame = parser.decode_frame().await;
@@ -544,224 +1984,3550 @@ mod tests {
             "simple frame cannot be terminated with a single LF"
         );
 
-        let frame = parser.decode_frame().await;
+        let frame = parser.decode_frame().await;
+        assert_eq!(
+            frame,
+            Err(DecodeError::Incomplete),
+            "frames are terminated with CRLF"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_decode_frame_max_inline_size() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        let mut parser = Parser::new(
+            server,
+            storage,
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+        tokio::spawn(async move {
+            let mut data = b"+".to_vec();
+            data.extend_from_slice(&vec![b'a'; 64 * 1024]);
+            data.extend_from_slice(b"\r\n");
+            client.write_all(&data).await.unwrap();
+            // no LF at all, inline and simple
+            client.write_all(&vec![b'a'; 100 * 1024]).await.unwrap();
+            client.flush().await.unwrap();
+        });
+
+        let frame = parser.decode_frame().await.unwrap();
+        assert_eq!(
+            frame,
+            Frame::new_simple_string(&"a".repeat(64 * 1024)),
+            "a line of the maximum size is accepted"
+        );
+        assert_eq!(
+            parser.decode_frame().await,
+            Err(DecodeError::Invalid),
+            "a line longer than the maximum size is rejected"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_decode_frame_truncated() {
+        let requests: &[&[u8]] = &[b"$5\r\nhel", b"$5", b"*2\r\n$4\r\nECHO\r\n", b"+OK"];
+        for request in requests {
+            let (mut client, server) = io::duplex(1024);
+            let storage = Arc::new(Storage::new(1000000, 4));
+            let mut parser = Parser::new(
+                server,
+                storage,
+                Arc::new(Metrics::default()),
+                1024,
+                DecodeLimits::default(),
+            );
+            client.write_all(request).await.unwrap();
+            drop(client);
+            assert_eq!(
+                parser.decode_frame().await,
+                Err(DecodeError::Incomplete),
+                "{:?} is truncated, not a graceful close",
+                String::from_utf8_lossy(request)
+            );
+            assert_eq!(parser.decode_frame().await, Err(DecodeError::Eof));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_decode_frame_split_across_reads() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        let mut parser = Parser::new(
+            server,
+            storage,
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+
+        tokio::spawn(async move {
+            let parts: &[&[u8]] = &[b"+hel", b"lo\r\n", b"*1\r\n$4\r\nPI", b"NG\r", b"\n"];
+            for part in parts {
+                client.write_all(part).await.unwrap();
+                client.flush().await.unwrap();
+                // let the parser read each part on its own
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+
+        let frame = parser.decode_frame().await.unwrap();
+        assert_eq!(frame, Frame::new_simple_string("hello"));
+        let frame = parser.decode_frame().await.unwrap();
+        assert_eq!(
+            frame,
+            Frame::new_array(vec![Frame::new_bulk_string("PING")])
+        );
+        assert_eq!(parser.decode_frame().await, Err(DecodeError::Eof));
+    }
+
+    #[tokio::test]
+    async fn test_decode_frame_simple_error() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        let mut parser = Parser::new(
+            server,
+            storage,
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+
+        // Simulate client writing to the stream
+        tokio::spawn(async move {
+            let data = b"-hello\r\n-58\r\n-\r\n-hello\n-Incompet";
+            client.write_all(data).await.unwrap();
+            client.flush().await.unwrap();
+        });
+
+        // simple string
+        let frame = parser.decode_frame().await.unwrap();
+        let mut response_frame = Frame::new_simple_error("hello");
+        assert_eq!(frame, response_frame, "can decode a simple error");
+
+        let frame = parser.decode_frame().await.unwrap();
+        response_frame = Frame::new_simple_error("58");
+        assert_eq!(
+            frame, response_frame,
+            "can decode a simple error which is a number"
+        );
+
+        let frame = parser.decode_frame().await.unwrap();
+        response_frame = Frame::new_simple_error("");
+        assert_eq!(
+            frame, response_frame,
+            "can decode a simple error which is empty"
+        );
+
+        let frame = parser.decode_frame().await;
+        assert_eq!(
+            frame,
+            Err(DecodeError::Invalid),
+            "simple frame cannot be terminated with a single LF"
+        );
+
+        let frame = parser.decode_frame().await;
+        assert_eq!(
+            frame,
+            Err(DecodeError::Incomplete),
+            "frames are terminated with CRLF"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_decode_frame_bulk_string() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        let mut parser = Parser::new(
+            server,
+            storage,
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+
+        // Simulate client writing to the stream
+        tokio::spawn(async move {
+            let data = b"$5\r\nhello\r\n$6\r\nhel\rlo\r\n$6\r\nhel\nlo\r\n$6\r\nhellojj\r";
+            client.write_all(data).await.unwrap();
+            client.flush().await.unwrap();
+        });
+
+        // simple string
+        let frame = parser.decode_frame().await.unwrap();
+        let mut response_frame = Frame::new_bulk_string("hello");
+        assert_eq!(frame, response_frame, "can decode a bulk string");
+
+        let frame = parser.decode_frame().await.unwrap();
+        response_frame = Frame::new_bulk_string("hel\rlo");
+        assert_eq!(
+            frame, response_frame,
+            "bulk frame can contain CR in the middle"
+        );
+
+        let frame = parser.decode_frame().await.unwrap();
+        response_frame = Frame::new_bulk_string("hel\nlo");
+        assert_eq!(
+            frame, response_frame,
+            "bulk frame can contain LF in the middle"
+        );
+
+        let frame = parser.decode_frame().await;
+        assert_eq!(
+            frame,
+            Err(DecodeError::Invalid),
+            "bulk string is terminated by CRLF"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_decode_frame_bulk_string_bad_terminator() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        let mut parser = Parser::new(
+            server,
+            storage,
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+
+        // Simulate client writing to the stream
+        tokio::spawn(async move {
+            let data = b"$5\r\nhello\r\r";
+            client.write_all(data).await.unwrap();
+            client.flush().await.unwrap();
+        });
+
+        let frame = parser.decode_frame().await;
+        assert_eq!(
+            frame,
+            Err(DecodeError::Invalid),
+            "bulk string must end with LF after CR"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_decode_frame_bulk_string_length() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        let mut parser = Parser::new(
+            server,
+            storage,
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+
+        // Simulate client writing to the stream
+        tokio::spawn(async move {
+            let data = b"$-1\r\n$-5\r\n$999999999999\r\n";
+            client.write_all(data).await.unwrap();
+            client.flush().await.unwrap();
+        });
+
+        let frame = parser.decode_frame().await.unwrap();
+        assert_eq!(
+            frame,
+            Frame::new_null(),
+            "can decode the RESP2 null bulk string"
+        );
+
+        let frame = parser.decode_frame().await;
+        assert_eq!(
+            frame,
+            Err(DecodeError::Invalid),
+            "bulk string length cannot be negative"
+        );
+
+        let frame = parser.decode_frame().await;
+        assert_eq!(
+            frame,
+            Err(DecodeError::Invalid),
+            "bulk string length cannot exceed the limit"
+        );
+
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        let limits = DecodeLimits {
+            max_bulk_len: 5,
+            ..DecodeLimits::default()
+        };
+        let mut parser = Parser::new(server, storage, Arc::new(Metrics::default()), 1024, limits);
+        tokio::spawn(async move {
+            let data = b"$5\r\nhello\r\n$6\r\nhello!\r\n";
+            client.write_all(data).await.unwrap();
+            client.flush().await.unwrap();
+        });
+
+        let frame = parser.decode_frame().await.unwrap();
+        assert_eq!(
+            frame,
+            Frame::new_bulk_string("hello"),
+            "can decode a bulk string at the limit"
+        );
+
+        let frame = parser.decode_frame().await;
+        assert_eq!(
+            frame,
+            Err(DecodeError::Invalid),
+            "the bulk string limit is configurable"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_decode_frame_bulk_error() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        let mut parser = Parser::new(
+            server,
+            storage,
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+
+        // Simulate client writing to the stream
+        tokio::spawn(async move {
+            let data = b"!5\r\nhello\r\n!6\r\nhel\rlo\r\n!6\r\nhel\nlo\r\n!6\r\nhellojj\r";
+            client.write_all(data).await.unwrap();
+            client.flush().await.unwrap();
+        });
+
+        // simple string
+        let frame = parser.decode_frame().await.unwrap();
+        let mut response_frame = Frame::new_bulk_error("hello");
+        assert_eq!(frame, response_frame, "can decode a bulk string");
+
+        let frame = parser.decode_frame().await.unwrap();
+        response_frame = Frame::new_bulk_error("hel\rlo");
+        assert_eq!(
+            frame, response_frame,
+            "bulk frame can contain CR in the middle"
+        );
+
+        let frame = parser.decode_frame().await.unwrap();
+        response_frame = Frame::new_bulk_error("hel\nlo");
+        assert_eq!(
+            frame, response_frame,
+            "bulk frame can contain LF in the middle"
+        );
+
+        let frame = parser.decode_frame().await;
+        assert_eq!(
+            frame,
+            Err(DecodeError::Invalid),
+            "bulk string is terminated by CRLF"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_decode_frame_bool() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        let mut parser = Parser::new(
+            server,
+            storage,
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+
+        // Simulate client writing to the stream
+        tokio::spawn(async move {
+            let data = b"#t\r\n#f\r\n$u\r\n";
+            client.write_all(data).await.unwrap();
+            client.flush().await.unwrap();
+        });
+
+        let frame = parser.decode_frame().await.unwrap();
+        let mut response_frame = Frame::new_bool(true);
+        assert_eq!(
+            frame, response_frame,
+            "can decode a bool frame with value true"
+        );
+
+        let frame = parser.decode_frame().await.unwrap();
+        response_frame = Frame::new_bool(false);
+        assert_eq!(
+            frame, response_frame,
+            "can decode a bool frame with value false"
+        );
+
+        let frame = parser.decode_frame().await;
+        assert_eq!(
+            frame,
+            Err(DecodeError::Invalid),
+            "can detect an invalid bool frame (value other than t or f)"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_decode_frame_null() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        let mut parser = Parser::new(
+            server,
+            storage,
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+
+        // Simulate client writing to the stream
+        tokio::spawn(async move {
+            let data = b"_\r\n_f\r\n$u\r\n";
+            client.write_all(data).await.unwrap();
+            client.flush().await.unwrap();
+        });
+
+        let frame = parser.decode_frame().await.unwrap();
+        let response_frame = Frame::new_null();
+        assert_eq!(frame, response_frame, "can decode a null frame");
+
+        let frame = parser.decode_frame().await;
+        assert_eq!(
+            frame,
+            Err(DecodeError::Invalid),
+            "can spot a null frame which has value, null should not have one"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_decode_frame_array() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        let mut parser = Parser::new(
+            server,
+            storage,
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+
+        // Simulate client writing to the stream
+        tokio::spawn(async move {
+            let data = b"*3\r\n:1\r\n+Two\r\n$5\r\nThree\r\n*2\r\n:1\r\n*1\r\n+Three\r\n*1\r\n$4\r\nPING\r\n";
+            client.write_all(data).await.unwrap();
+            client.flush().await.unwrap();
+        });
+
+        let frame = parser.decode_frame().await.unwrap();
+        let frame_data = FrameData::Nested(vec![
+            Frame {
+                frame_type: FrameID::Integer,
+                frame_data: FrameData::Integer(1),
+            },
+            Frame {
+                frame_type: FrameID::SimpleString,
+                frame_data: FrameData::Simple("Two".to_string()),
+            },
+            Frame {
+                frame_type: FrameID::BulkString,
+                frame_data: FrameData::Bulk(Bytes::from_static(b"Three")),
+            },
+        ]);
+        let response_frame = Frame {
+            frame_type: FrameID::Array,
+            frame_data,
+        };
+        assert_eq!(
+            frame, response_frame,
+            "can decode a non nested array with mixed elements"
+        );
+
+        let frame_data_nested = FrameData::Nested(vec![
+            Frame {
+                frame_type: FrameID::Integer,
+                frame_data: FrameData::Integer(1),
+            },
+            Frame {
+                frame_type: FrameID::Array,
+                frame_data: FrameData::Nested(vec![Frame {
+                    frame_type: FrameID::SimpleString,
+                    frame_data: FrameData::Simple("Three".to_string()),
+                }]),
+            },
+        ]);
+
+        let response_frame_nested = Frame {
+            frame_type: FrameID::Array,
+            frame_data: frame_data_nested,
+        };
+        let frame_nested = parser.decode_frame().await.unwrap();
+        assert_eq!(
+            frame_nested, response_frame_nested,
+            "can decode a nested array"
+        );
+
+        let frame_ping = FrameData::Nested(vec![Frame {
+            frame_type: FrameID::BulkString,
+            frame_data: FrameData::Bulk(Bytes::from_static(b"PING")),
+        }]);
+        let response_frame_ping = Frame {
+            frame_type: FrameID::Array,
+            frame_data: frame_ping,
+        };
+        let frame_ping = parser.decode_frame().await.unwrap();
+        assert_eq!(frame_ping, response_frame_ping, "can decode ping command");
+    }
+
+    #[tokio::test]
+    async fn test_decode_frame_push() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        let mut parser = Parser::new(
+            server,
+            storage,
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+
+        let push_frame = Frame::new_push(vec![
+            Frame::new_bulk_string("invalidate"),
+            Frame::new_integer(7),
+            Frame::new_simple_string("key"),
+        ]);
+        let encoded = push_frame.to_string();
+        assert_eq!(
+            encoded, ">3\r\n$10\r\ninvalidate\r\n:7\r\n+key\r\n",
+            "can encode a push frame"
+        );
+
+        // Simulate client writing to the stream
+        tokio::spawn(async move {
+            client.write_all(encoded.as_bytes()).await.unwrap();
+            client.flush().await.unwrap();
+        });
+
+        let frame = parser.decode_frame().await.unwrap();
+        assert_eq!(frame, push_frame, "can round-trip a push frame");
+    }
+
+    #[tokio::test]
+    #[allow(clippy::approx_constant)]
+    async fn test_decode_frame_double() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        let mut parser = Parser::new(
+            server,
+            storage,
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+
+        // Simulate client writing to the stream
+        tokio::spawn(async move {
+            let data = b",3.14\r\n,inf\r\n,-inf\r\n,nan\r\n,abc\r\n";
+            client.write_all(data).await.unwrap();
+            client.flush().await.unwrap();
+        });
+
+        let frame = parser.decode_frame().await.unwrap();
+        assert_eq!(frame, Frame::new_double(3.14), "can decode a double");
+        assert_eq!(frame.to_string(), ",3.14\r\n", "can encode a double");
+
+        let frame = parser.decode_frame().await.unwrap();
+        assert_eq!(
+            frame,
+            Frame::new_double(f64::INFINITY),
+            "can decode a positive infinity"
+        );
+        assert_eq!(frame.to_string(), ",inf\r\n", "can encode inf");
+
+        let frame = parser.decode_frame().await.unwrap();
+        assert_eq!(
+            frame,
+            Frame::new_double(f64::NEG_INFINITY),
+            "can decode a negative infinity"
+        );
+        assert_eq!(frame.to_string(), ",-inf\r\n", "can encode -inf");
+
+        // NaN is not equal to itself, so compare the encoding instead
+        let frame = parser.decode_frame().await.unwrap();
+        assert_eq!(frame.to_string(), ",nan\r\n", "can decode and encode nan");
+
+        let frame = parser.decode_frame().await;
+        assert_eq!(
+            frame,
+            Err(DecodeError::Invalid),
+            "can detect an invalid double frame"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_process_frames_stops_on_flush_failure() {
+        let storage = Arc::new(Storage::new(1000000, 4));
+        let chunks = [
+            b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n".to_vec(),
+            b"*3\r\n$3\r\nSET\r\n$1\r\nb\r\n$1\r\n2\r\n".to_vec(),
+            b"*3\r\n$3\r\nSET\r\n$1\r\nc\r\n$1\r\n3\r\n".to_vec(),
+        ];
+        // Only the first "+OK\r\n" reply can make it to the network.
+        let stream = BrokenWriteStream {
+            chunks: chunks.into(),
+            paused: false,
+            write_budget: 5,
+        };
+        let mut parser = Parser::new(
+            stream,
+            storage.clone(),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+
+        // must return instead of looping on the dead connection
+        parser.process_frames().await;
+
+        assert_eq!(storage.get_v(b"a"), Ok(Some(Bytes::from_static(b"1"))));
+        assert_eq!(
+            storage.get_v(b"b"),
+            Ok(Some(Bytes::from_static(b"2"))),
+            "the command whose reply failed was applied"
+        );
+        assert_eq!(
+            storage.get_v(b"c"),
+            Ok(None),
+            "no command should be applied after a failed flush"
+        );
+    }
+
+    /// CountingStream serves a fixed input, then EOF. It discards the output but counts the bytes
+    /// written and the flushes.
+    struct CountingStream {
+        input: Vec<u8>,
+        read_pos: usize,
+        written: Arc<AtomicUsize>,
+        flushes: Arc<AtomicUsize>,
+    }
+
+    impl AsyncRead for CountingStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let remaining = &self.input[self.read_pos..];
+            let n = remaining.len().min(buf.remaining());
+            buf.put_slice(&remaining[..n]);
+            self.read_pos += n;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for CountingStream {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.written.fetch_add(buf.len(), Ordering::Relaxed);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.flushes.fetch_add(1, Ordering::Relaxed);
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_process_frames_flushes_pipelined_replies_once() {
+        let written = Arc::new(AtomicUsize::new(0));
+        let flushes = Arc::new(AtomicUsize::new(0));
+        let stream = CountingStream {
+            input: b"*1\r\n$4\r\nPING\r\n".repeat(1000),
+            read_pos: 0,
+            written: written.clone(),
+            flushes: flushes.clone(),
+        };
+        let mut parser = Parser::new(
+            stream,
+            Arc::new(Storage::new(1000000, 4)),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+
+        parser.process_frames().await;
+
+        assert_eq!(
+            written.load(Ordering::Relaxed),
+            b"+PONG\r\n".len() * 1000,
+            "every PING should be answered"
+        );
+        assert_eq!(
+            flushes.load(Ordering::Relaxed),
+            1,
+            "pipelined replies should be flushed once"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_process_frames_empty_command_name() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        let mut parser = Parser::new(
+            server,
+            storage,
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
+
+        client.write_all(b"*1\r\n$0\r\n\r\n").await.unwrap();
+        client.flush().await.unwrap();
+        let mut buf = vec![0; 25];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(
+            buf, b"-ERR unknown command ''\r\n",
+            "an empty command name is reported as unknown"
+        );
+
+        // the connection is still usable
+        client
+            .write_all(b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n")
+            .await
+            .unwrap();
+        client.flush().await.unwrap();
+        let mut buf = vec![0; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, b"$-1\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_process_frames_idle_timeout() {
+        // the client never writes, but keeps its end of the connection open
+        let (_client, server) = io::duplex(1024);
+        let mut parser = Parser::new(
+            server,
+            Arc::new(Storage::new(1000000, 4)),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        )
+        .with_idle_timeout(Some(Duration::from_millis(50)));
+
+        tokio::time::timeout(Duration::from_secs(5), parser.process_frames())
+            .await
+            .expect("an idle client should be disconnected");
+    }
+
+    #[tokio::test]
+    async fn test_process_frames_idle_timeout_slow_client() {
+        let (mut client, server) = io::duplex(1024);
+        let mut parser = Parser::new(
+            server,
+            Arc::new(Storage::new(1000000, 4)),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        )
+        .with_idle_timeout(Some(Duration::from_millis(200)));
+        let connection = tokio::spawn(async move {
+            parser.process_frames().await;
+        });
+
+        // the frame takes longer than the timeout to arrive, but bytes keep coming
+        for byte in b"*1\r\n$4\r\nPING\r\n" {
+            client.write_all(&[*byte]).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let mut buf = vec![0; 7];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(
+            buf, b"+PONG\r\n",
+            "a slow client should not be disconnected"
+        );
+
+        // then the client goes idle
+        tokio::time::timeout(Duration::from_secs(5), connection)
+            .await
+            .expect("an idle client should be disconnected")
+            .unwrap();
+    }
+
+    // hello_3 switches a connection to RESP3, and skips the reply of HELLO.
+    async fn hello_3(client: &mut io::DuplexStream) {
+        client
+            .write_all(b"*2\r\n$5\r\nHELLO\r\n$1\r\n3\r\n")
+            .await
+            .unwrap();
+        // the reply ends with the empty modules array
+        let mut hello = Vec::new();
+        while !hello.ends_with(b"*0\r\n") {
+            hello.push(client.read_u8().await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_tracking_select() {
+        let databases: Arc<[Arc<Storage>]> =
+            (0..2).map(|_| Arc::new(Storage::new(1000, 4))).collect();
+        let (mut client, server) = io::duplex(1024);
+        let mut parser = Parser::new(
+            server,
+            databases[0].clone(),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        )
+        .with_databases(databases.clone());
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
+
+        hello_3(&mut client).await;
+        let requests: &[(&[u8], &[u8])] = &[
+            (
+                b"*3\r\n$6\r\nCLIENT\r\n$8\r\nTRACKING\r\n$2\r\non\r\n",
+                b"+OK\r\n",
+            ),
+            (b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n", b"_\r\n"),
+            // the keys of the previous database can no longer be invalidated, they all are
+            (
+                b"*2\r\n$6\r\nSELECT\r\n$1\r\n1\r\n",
+                b">2\r\n$10\r\ninvalidate\r\n_\r\n+OK\r\n",
+            ),
+            (b"*2\r\n$3\r\nGET\r\n$1\r\nb\r\n", b"_\r\n"),
+        ];
+        for (request, reply) in requests {
+            client.write_all(request).await.unwrap();
+            let mut buf = vec![0; reply.len()];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(
+                String::from_utf8_lossy(&buf),
+                String::from_utf8_lossy(reply),
+                "reply to {:?}",
+                String::from_utf8_lossy(request)
+            );
+        }
+
+        // the keys read in the selected database are invalidated
+        databases[1].set_kv(b"b", b"1", None).unwrap();
+        let expected = b">2\r\n$10\r\ninvalidate\r\n*1\r\n$1\r\nb\r\n";
+        let mut buf = vec![0; expected.len()];
+        tokio::time::timeout(Duration::from_secs(1), client.read_exact(&mut buf))
+            .await
+            .expect("an invalidation message should arrive")
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&buf),
+            String::from_utf8_lossy(expected)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_tracking_invalidation() {
+        let storage = Arc::new(Storage::new(1000000, 4));
+        let (mut tracking_client, server) = io::duplex(1024);
+        let mut parser = Parser::new(
+            server,
+            storage.clone(),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
+        let (mut other_client, server) = io::duplex(1024);
+        let mut parser = Parser::new(
+            server,
+            storage,
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
+
+        let tracking_on = b"*3\r\n$6\r\nCLIENT\r\n$8\r\nTRACKING\r\n$2\r\non\r\n";
+        tracking_client.write_all(tracking_on).await.unwrap();
+        let expected = b"-ERR CLIENT TRACKING ON requires RESP3, switch with HELLO 3 first\r\n";
+        let mut buf = vec![0; expected.len()];
+        tracking_client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, expected, "a RESP2 client cannot receive pushes");
+
+        hello_3(&mut tracking_client).await;
+        tracking_client.write_all(tracking_on).await.unwrap();
+        let mut buf = vec![0; 5];
+        tracking_client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, b"+OK\r\n", "can enable tracking");
+
+        tracking_client
+            .write_all(b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n")
+            .await
+            .unwrap();
+        let mut buf = vec![0; 3];
+        tracking_client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, b"_\r\n", "key does not exist yet");
+
+        // modifying an untracked key does not notify anyone
+        other_client
+            .write_all(b"*3\r\n$3\r\nSET\r\n$5\r\nother\r\n$5\r\nvalue\r\n")
+            .await
+            .unwrap();
+        let mut buf = vec![0; 5];
+        other_client.read_exact(&mut buf).await.unwrap();
+        other_client
+            .write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n")
+            .await
+            .unwrap();
+        let mut buf = vec![0; 5];
+        other_client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, b"+OK\r\n");
+
+        let expected = b">2\r\n$10\r\ninvalidate\r\n*1\r\n$3\r\nkey\r\n";
+        let mut buf = vec![0; expected.len()];
+        tokio::time::timeout(Duration::from_secs(1), tracking_client.read_exact(&mut buf))
+            .await
+            .expect("an invalidation message should arrive")
+            .unwrap();
+        assert_eq!(buf, expected, "tracking client is told the key changed");
+    }
+
+    #[tokio::test]
+    async fn test_decode_frame_array_count() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        let limits = DecodeLimits {
+            max_array_elements: 3,
+            ..DecodeLimits::default()
+        };
+        let mut parser = Parser::new(server, storage, Arc::new(Metrics::default()), 1024, limits);
+
+        // Simulate client writing to the stream
+        tokio::spawn(async move {
+            let data = b"*0\r\n*2\r\n*0\r\n:1\r\n*3\r\n:1\r\n:2\r\n:3\r\n*4\r\n";
+            client.write_all(data).await.unwrap();
+            client.flush().await.unwrap();
+        });
+
+        let frame = parser.decode_frame().await.unwrap();
+        assert_eq!(frame, Frame::new_array(vec![]), "can decode an empty array");
+
+        let frame = parser.decode_frame().await.unwrap();
+        assert_eq!(
+            frame,
+            Frame::new_array(vec![Frame::new_array(vec![]), Frame::new_integer(1)]),
+            "can decode a nested empty array"
+        );
+
+        let frame = parser.decode_frame().await.unwrap();
+        assert_eq!(
+            frame,
+            Frame::new_array(vec![
+                Frame::new_integer(1),
+                Frame::new_integer(2),
+                Frame::new_integer(3)
+            ]),
+            "can decode an array at the limit"
+        );
+
+        let frame = parser.decode_frame().await;
+        assert_eq!(
+            frame,
+            Err(DecodeError::Invalid),
+            "array element count cannot exceed the limit"
+        );
+
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        let limits = DecodeLimits {
+            max_array_elements: 3,
+            ..DecodeLimits::default()
+        };
+        let mut parser = Parser::new(server, storage, Arc::new(Metrics::default()), 1024, limits);
+        tokio::spawn(async move {
+            let data = b"*1\r\n*5\r\n*-2\r\n";
+            client.write_all(data).await.unwrap();
+            client.flush().await.unwrap();
+        });
+
+        let frame = parser.decode_frame().await;
+        assert_eq!(
+            frame,
+            Err(DecodeError::Invalid),
+            "nested array element count cannot exceed the limit"
+        );
+
+        let frame = parser.decode_frame().await;
+        assert_eq!(
+            frame,
+            Err(DecodeError::Invalid),
+            "array element count cannot be negative"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_decode_frame_array_count_mismatch() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        let mut parser = Parser::new(
+            server,
+            storage.clone(),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+        // the inner array declares 1 element but 2 follow, the second one belongs to the outer
+        // array, and the outer array gets complete before the last element
+        client
+            .write_all(b"*2\r\n*1\r\n:1\r\n:2\r\n:3\r\n")
+            .await
+            .unwrap();
+        let frame = parser.decode_frame().await.unwrap();
+        assert_eq!(
+            frame,
+            Frame::new_array(vec![
+                Frame::new_array(vec![Frame::new_integer(1)]),
+                Frame::new_integer(2)
+            ]),
+            "elements are attributed by the declared counts"
+        );
+        let frame = parser.decode_frame().await.unwrap();
+        assert_eq!(
+            frame,
+            Frame::new_integer(3),
+            "an extra element is a frame of its own"
+        );
+
+        let (mut client, server) = io::duplex(1024);
+        let mut parser = Parser::new(
+            server,
+            storage,
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+        // the array declares more elements than what is sent before the client leaves
+        client
+            .write_all(b"*3\r\n*2\r\n:1\r\n:2\r\n:3\r\n")
+            .await
+            .unwrap();
+        drop(client);
+        assert_eq!(parser.decode_frame().await, Err(DecodeError::Incomplete));
+
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        let mut parser = Parser::new(
+            server,
+            storage,
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+        client.write_all(b"*1\r\n*-2\r\n").await.unwrap();
+        assert_eq!(
+            parser.decode_frame().await,
+            Err(DecodeError::Invalid),
+            "a nested count cannot be negative"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_decode_frame_array_depth() {
+        let (mut client, server) = io::duplex(8192);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        let limits = DecodeLimits {
+            max_depth: 128,
+            ..DecodeLimits::default()
+        };
+        let mut parser = Parser::new(server, storage, Arc::new(Metrics::default()), 1024, limits);
+
+        // Simulate client writing to the stream
+        tokio::spawn(async move {
+            let mut data = b"*1\r\n".repeat(128);
+            data.extend_from_slice(b":1\r\n");
+            data.extend_from_slice(&b"*1\r\n".repeat(1000));
+            data.extend_from_slice(b":1\r\n");
+            client.write_all(&data).await.unwrap();
+            client.flush().await.unwrap();
+        });
+
+        let mut frame = parser.decode_frame().await.unwrap();
+        let mut depth = 0;
+        while let FrameData::Nested(mut frames) = frame.frame_data {
+            depth += 1;
+            frame = frames.pop().unwrap();
+        }
+        assert_eq!(depth, 128, "can decode an array nested up to the limit");
+
+        let frame = parser.decode_frame().await;
+        assert_eq!(
+            frame,
+            Err(DecodeError::Invalid),
+            "array cannot be nested deeper than the limit"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_set_over_max_memory() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::with_memory_limit(
+            100,
+            4,
+            10,
+            EvictionPolicy::Noeviction,
+        ));
+        let mut parser = Parser::new(
+            server,
+            storage,
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
+
+        client
+            .write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$10\r\n0123456789\r\n")
+            .await
+            .unwrap();
+        let mut buf = vec![0; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, b"+OK\r\n", "can write below the memory limit");
+
+        client
+            .write_all(b"*3\r\n$3\r\nSET\r\n$4\r\nkey2\r\n$1\r\nv\r\n")
+            .await
+            .unwrap();
+        let expected = b"-OOM command not allowed when used memory > 'maxmemory'.\r\n";
+        let mut buf = vec![0; expected.len()];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, expected, "cannot write over the memory limit");
+
+        client
+            .write_all(b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n")
+            .await
+            .unwrap();
+        let mut buf = vec![0; 17];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, b"$10\r\n0123456789\r\n", "can still read");
+    }
+
+    #[tokio::test]
+    async fn test_get_multiline_value() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        let mut parser = Parser::new(
+            server,
+            storage,
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
+
+        client
+            .write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$12\r\nline1\r\nline2\r\n")
+            .await
+            .unwrap();
+        let mut buf = vec![0; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, b"+OK\r\n");
+
+        client
+            .write_all(b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n")
+            .await
+            .unwrap();
+        let expected = b"$12\r\nline1\r\nline2\r\n";
+        let mut buf = vec![0; expected.len()];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, expected, "value should round-trip as a bulk string");
+    }
+
+    #[tokio::test]
+    async fn test_get_binary_value() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        let mut parser = Parser::new(
+            server,
+            storage.clone(),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
+
+        client
+            .write_all(b"*3\r\n$3\r\nSET\r\n$2\r\n\xfe\0\r\n$4\r\n\0\xff\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = vec![0; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, b"+OK\r\n");
+        assert_eq!(
+            storage.get_v(b"\xfe\0"),
+            Ok(Some(Bytes::from_static(b"\0\xff\r\n")))
+        );
+
+        client
+            .write_all(b"*2\r\n$3\r\nGET\r\n$2\r\n\xfe\0\r\n")
+            .await
+            .unwrap();
+        let expected = b"$4\r\n\0\xff\r\n\r\n";
+        let mut buf = vec![0; expected.len()];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, expected, "any bytes should round-trip");
+    }
+
+    #[tokio::test]
+    async fn test_get_family_wrong_type() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        storage.sadd(b"set", &[b"member".to_vec()]).unwrap();
+        let mut parser = Parser::new(
+            server,
+            storage.clone(),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
+
+        let expected = b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n";
+        let commands: [&[u8]; 2] = [
+            b"*2\r\n$6\r\nGETDEL\r\n$3\r\nset\r\n",
+            b"*4\r\n$5\r\nGETEX\r\n$3\r\nset\r\n$2\r\nPX\r\n$1\r\n1\r\n",
+        ];
+        for command in commands {
+            client.write_all(command).await.unwrap();
+            let mut buf = vec![0; expected.len()];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, expected);
+        }
+
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert_eq!(
+            storage.sscan(b"set", 0, b"*", 10),
+            Ok((0, vec![b"member".to_vec()])),
+            "the key should be left intact"
+        );
+    }
+
+    // LogBuffer collects the logs of a test.
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_inline_ping_health_check() {
+        let logs = LogBuffer::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        let mut parser = Parser::new(
+            server,
+            storage,
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+
+        client.write_all(b"PING\r\n").await.unwrap();
+        client.shutdown().await.unwrap();
+        // the load balancer closes right after its check, the parser must see it and return
+        parser.process_frames().await;
+        drop(parser);
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"+PONG\r\n");
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(
+            logs.contains("client gracefully closed connection"),
+            "disconnection should be graceful: {}",
+            logs
+        );
+        assert!(
+            !logs.contains("ERROR"),
+            "nothing should be logged as an error: {}",
+            logs
+        );
+    }
+
+    #[tokio::test]
+    async fn test_access_log() {
+        let logs = LogBuffer::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let (mut client, server) = io::duplex(1024);
+        let mut parser = Parser::new(
+            server,
+            Arc::new(Storage::new(1000, 4)),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+        let long_key = "k".repeat(100);
+        let request = format!("GET missing\r\nGET {}\r\nINCR\r\n", long_key);
+        client.write_all(request.as_bytes()).await.unwrap();
+        client.shutdown().await.unwrap();
+        parser.process_frames().await;
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = logs
+            .lines()
+            .filter(|line| line.contains("command processed"))
+            .collect();
+        assert_eq!(lines.len(), 2, "one line per command: {}", logs);
+        for field in [
+            "command=\"GET\"",
+            "key=missing",
+            "status=\"miss\"",
+            "duration_us=",
+        ] {
+            assert!(
+                lines[0].contains(field),
+                "{} is missing: {}",
+                field,
+                lines[0]
+            );
+        }
+        assert!(
+            lines[1].contains(&format!("key={} ", &long_key[..LOGGED_KEY_LEN])),
+            "the key should be truncated: {}",
+            lines[1]
+        );
+        assert!(
+            !logs.contains("receive get command"),
+            "the per command logs are only traces: {}",
+            logs
+        );
+    }
+
+    #[tokio::test]
+    async fn test_decode_inline_command() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        let mut parser = Parser::new(
+            server,
+            storage.clone(),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+        client
+            .write_all(b"SET  key value\n\r\nGET key\r\n")
+            .await
+            .unwrap();
+        let frame = parser.decode_frame().await.unwrap();
+        assert_eq!(
+            frame,
+            Frame::new_array(vec![
+                Frame::new_bulk_string("SET"),
+                Frame::new_bulk_string("key"),
+                Frame::new_bulk_string("value"),
+            ]),
+            "words can be separated by several spaces, lines can end with LF only"
+        );
+        assert_eq!(parser.decode_frame().await, Err(DecodeError::EmptyLine));
+        assert_eq!(
+            parser
+                .decode_frame()
+                .await
+                .unwrap()
+                .to_command()
+                .command_type,
+            CommandType::GET
+        );
+
+        let (mut client, server) = io::duplex(1024);
+        let limits = DecodeLimits {
+            inline_commands: false,
+            ..DecodeLimits::default()
+        };
+        let mut parser = Parser::new(server, storage, Arc::new(Metrics::default()), 1024, limits);
+        client.write_all(b"PING\r\n").await.unwrap();
+        assert_eq!(
+            parser.decode_frame().await,
+            Err(DecodeError::UnknownFrame),
+            "inline commands can be disabled"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_command_command() {
+        let (client, server) = io::duplex(64 * 1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        let mut parser = Parser::new(
+            server,
+            storage,
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+        let command = Command::new(CommandType::COMMAND, &[]);
+        parser.apply_command(&command).await.unwrap();
+
+        // decode the reply with a parser of our own
+        let storage = Arc::new(Storage::new(1000000, 4));
+        let mut reader = Parser::new(
+            client,
+            storage,
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+        let reply = reader.decode_frame().await.unwrap();
+        let specs = reply.frame_data.get_nested().unwrap();
+        assert_eq!(
+            specs.len(),
+            COMMAND_TABLE.len(),
+            "should list every command"
+        );
+
+        let get = specs
+            .iter()
+            .map(|spec| spec.frame_data.get_nested().unwrap())
+            .find(|spec| spec[0] == Frame::new_bulk_string("get"))
+            .expect("GET should be listed");
+        assert_eq!(get[1], Frame::new_integer(2), "GET has an arity of 2");
+        assert!(get[2]
+            .frame_data
+            .get_nested()
+            .unwrap()
+            .contains(&Frame::new_simple_string("readonly")));
+        assert_eq!(
+            &get[3..],
+            &[
+                Frame::new_integer(1),
+                Frame::new_integer(1),
+                Frame::new_integer(1)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_command_subcommands() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        let mut parser = Parser::new(
+            server,
+            storage,
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
+
+        let count = format!(":{}\r\n", COMMAND_TABLE.len());
+        let requests: &[(&[u8], &[u8])] = &[
+            (b"*2\r\n$7\r\nCOMMAND\r\n$5\r\ncount\r\n", count.as_bytes()),
+            (b"*2\r\n$7\r\nCOMMAND\r\n$4\r\nDOCS\r\n", b"*0\r\n"),
+            (
+                b"*3\r\n$7\r\nCOMMAND\r\n$4\r\nDOCS\r\n$3\r\nGET\r\n",
+                b"*0\r\n",
+            ),
+            (
+                b"*2\r\n$7\r\nCOMMAND\r\n$4\r\nINFO\r\n",
+                b"-ERR unknown subcommand 'INFO'. Try COMMAND HELP.\r\n",
+            ),
+        ];
+        for (request, reply) in requests {
+            client.write_all(request).await.unwrap();
+            let mut buf = vec![0; reply.len()];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(
+                &buf,
+                reply,
+                "reply to {:?}",
+                String::from_utf8_lossy(request)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hello_command() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        let mut parser = Parser::new(
+            server,
+            storage,
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
+
+        async fn reply(client: &mut io::DuplexStream, request: &[u8], len: usize) -> String {
+            client.write_all(request).await.unwrap();
+            let mut buf = vec![0; len];
+            client.read_exact(&mut buf).await.unwrap();
+            String::from_utf8(buf).unwrap()
+        }
+        let properties = format!(
+            "$6\r\nserver\r\n$6\r\nmredis\r\n$7\r\nversion\r\n${}\r\n{}\r\n\
+             $5\r\nproto\r\n:3\r\n$4\r\nmode\r\n$10\r\nstandalone\r\n\
+             $4\r\nrole\r\n$6\r\nmaster\r\n$7\r\nmodules\r\n*0\r\n",
+            env!("CARGO_PKG_VERSION").len(),
+            env!("CARGO_PKG_VERSION")
+        );
+
+        let expected = format!("%6\r\n{}", properties);
+        let got = reply(
+            &mut client,
+            b"*2\r\n$5\r\nHELLO\r\n$1\r\n3\r\n",
+            expected.len(),
+        )
+        .await;
+        assert_eq!(got, expected, "RESP3 properties are a map");
+
+        let expected = "-NOPROTO unsupported protocol version\r\n";
+        let got = reply(
+            &mut client,
+            b"*2\r\n$5\r\nHELLO\r\n$1\r\n4\r\n",
+            expected.len(),
+        )
+        .await;
+        assert_eq!(got, expected);
+
+        // a failed HELLO keeps the protocol, so it is still 3 here
+        let expected = format!("%6\r\n{}", properties);
+        let got = reply(&mut client, b"*1\r\n$5\r\nHELLO\r\n", expected.len()).await;
+        assert_eq!(got, expected, "bare HELLO keeps the protocol");
+
+        let expected = format!("*12\r\n{}", properties.replace(":3", ":2"));
+        let got = reply(
+            &mut client,
+            b"*2\r\n$5\r\nHELLO\r\n$1\r\n2\r\n",
+            expected.len(),
+        )
+        .await;
+        assert_eq!(got, expected, "RESP2 properties are a flat array");
+
+        let expected = "-ERR Protocol version is not an integer or out of range\r\n";
+        let got = reply(
+            &mut client,
+            b"*2\r\n$5\r\nHELLO\r\n$1\r\nx\r\n",
+            expected.len(),
+        )
+        .await;
+        assert_eq!(got, expected);
+    }
+
+    #[tokio::test]
+    async fn test_get_miss_encoding() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        let mut parser = Parser::new(
+            server,
+            storage,
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
+
+        let get = b"*2\r\n$3\r\nGET\r\n$4\r\nnope\r\n";
+        client.write_all(get).await.unwrap();
+        let mut buf = vec![0; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, b"$-1\r\n", "RESP2 null bulk string");
+
+        client
+            .write_all(b"*2\r\n$5\r\nHELLO\r\n$1\r\n3\r\n")
+            .await
+            .unwrap();
+        let mut buf = vec![0; 4];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, b"%6\r\n");
+        // skip the rest of the HELLO reply, it ends with the empty modules array
+        let mut hello = Vec::new();
+        while !hello.ends_with(b"*0\r\n") {
+            hello.push(client.read_u8().await.unwrap());
+        }
+
+        client.write_all(get).await.unwrap();
+        let mut buf = vec![0; 3];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, b"_\r\n", "RESP3 null");
+    }
+
+    #[tokio::test]
+    async fn test_auth_command() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        let mut parser = Parser::new(
+            server,
+            storage.clone(),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        )
+        .with_requirepass(Some(Arc::from("secret")));
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
+
+        async fn reply(client: &mut io::DuplexStream, request: &[u8], len: usize) -> Vec<u8> {
+            client.write_all(request).await.unwrap();
+            let mut buf = vec![0; len];
+            client.read_exact(&mut buf).await.unwrap();
+            buf
+        }
+        let set = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n";
+        let noauth = b"-NOAUTH Authentication required.\r\n";
+
+        let got = reply(&mut client, set, noauth.len()).await;
+        assert_eq!(got, noauth, "commands are rejected before AUTH");
+        assert_eq!(
+            storage.get_v(b"key"),
+            Ok(None),
+            "rejected command is not applied"
+        );
+        let got = reply(&mut client, b"*1\r\n$4\r\nPING\r\n", 7).await;
+        assert_eq!(got, b"+PONG\r\n", "PING is allowed before AUTH");
+
+        let expected = b"-ERR invalid password\r\n";
+        let auth = b"*2\r\n$4\r\nAUTH\r\n$5\r\nwrong\r\n";
+        let got = reply(&mut client, auth, expected.len()).await;
+        assert_eq!(got, expected);
+        let auth = b"*3\r\n$4\r\nAUTH\r\n$5\r\nalice\r\n$6\r\nsecret\r\n";
+        let got = reply(&mut client, auth, expected.len()).await;
+        assert_eq!(got, expected, "there is no other user than default");
+        let got = reply(&mut client, set, noauth.len()).await;
+        assert_eq!(got, noauth, "still not authenticated");
+
+        let auth = b"*2\r\n$4\r\nAUTH\r\n$6\r\nsecret\r\n";
+        let got = reply(&mut client, auth, 5).await;
+        assert_eq!(got, b"+OK\r\n");
+        let got = reply(&mut client, set, 5).await;
+        assert_eq!(got, b"+OK\r\n", "commands are accepted after AUTH");
+        assert_eq!(
+            storage.get_v(b"key"),
+            Ok(Some(Bytes::from_static(b"value")))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_select_command() {
+        let (mut client, server) = io::duplex(1024);
+        let databases: Vec<_> = (0..4).map(|_| Arc::new(Storage::new(1000, 4))).collect();
+        databases[2].set_kv(b"key", b"value", None).unwrap();
+        let mut parser = Parser::new(
+            server,
+            databases[0].clone(),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        )
+        .with_databases(databases.into());
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
+
+        async fn reply(client: &mut io::DuplexStream, request: &[u8], len: usize) -> Vec<u8> {
+            client.write_all(request).await.unwrap();
+            let mut buf = vec![0; len];
+            client.read_exact(&mut buf).await.unwrap();
+            buf
+        }
+        let get = b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n";
+
+        let got = reply(&mut client, b"*2\r\n$6\r\nSELECT\r\n$1\r\n2\r\n", 5).await;
+        assert_eq!(got, b"+OK\r\n", "can select an existing database");
+        let got = reply(&mut client, get, 11).await;
+        assert_eq!(got, b"$5\r\nvalue\r\n", "reads from the selected database");
+
+        let out_of_range = b"-ERR DB index is out of range\r\n";
+        for index in [&b"4"[..], b"-1"] {
+            let mut select = format!("*2\r\n$6\r\nSELECT\r\n${}\r\n", index.len()).into_bytes();
+            select.extend_from_slice(index);
+            select.extend_from_slice(b"\r\n");
+            let got = reply(&mut client, &select, out_of_range.len()).await;
+            assert_eq!(got, out_of_range, "index out of range");
+        }
+        let not_integer = b"-ERR value is not an integer or out of range\r\n";
+        let select = b"*2\r\n$6\r\nSELECT\r\n$3\r\none\r\n";
+        let got = reply(&mut client, select, not_integer.len()).await;
+        assert_eq!(got, not_integer);
+
+        let got = reply(&mut client, get, 11).await;
+        assert_eq!(
+            got, b"$5\r\nvalue\r\n",
+            "a failed SELECT keeps the selected database"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_databases_are_isolated() {
+        let (mut client, server) = io::duplex(1024);
+        let databases: Vec<_> = (0..16).map(|_| Arc::new(Storage::new(1000, 4))).collect();
+        let mut parser = Parser::new(
+            server,
+            databases[0].clone(),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        )
+        .with_databases(databases.clone().into());
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
+
+        let select =
+            |index: &str| format!("*2\r\n$6\r\nSELECT\r\n${}\r\n{}\r\n", index.len(), index);
+        let requests: &[(Vec<u8>, &[u8])] = &[
+            (
+                b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$1\r\n0\r\n".to_vec(),
+                b"+OK\r\n",
+            ),
+            (select("1").into_bytes(), b"+OK\r\n"),
+            (b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n".to_vec(), b"$-1\r\n"),
+            (b"*2\r\n$3\r\nDEL\r\n$3\r\nkey\r\n".to_vec(), b":0\r\n"),
+            (
+                b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$1\r\n1\r\n".to_vec(),
+                b"+OK\r\n",
+            ),
+            (select("15").into_bytes(), b"+OK\r\n"),
+            (select("0").into_bytes(), b"+OK\r\n"),
+            (b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n".to_vec(), b"$1\r\n0\r\n"),
+        ];
+        for (request, reply) in requests {
+            client.write_all(request).await.unwrap();
+            let mut buf = vec![0; reply.len()];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(
+                &buf,
+                reply,
+                "reply to {:?}",
+                String::from_utf8_lossy(request)
+            );
+        }
+        assert_eq!(
+            databases[0].get_v(b"key"),
+            Ok(Some(Bytes::from_static(b"0")))
+        );
+        assert_eq!(
+            databases[1].get_v(b"key"),
+            Ok(Some(Bytes::from_static(b"1")))
+        );
+        assert!(databases[2..].iter().all(|db| db.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn test_write_sink() {
+        let (mut client, server) = io::duplex(1024);
+        let databases: Vec<_> = (0..2).map(|_| Arc::new(Storage::new(1000, 4))).collect();
+        let (sink, mut writes) = mpsc::unbounded_channel();
+        let mut parser = Parser::new(
+            server,
+            databases[0].clone(),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        )
+        .with_databases(databases.into())
+        .with_write_sink(Some(sink));
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
+
+        let set = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$1\r\n1\r\n";
+        let get = b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n";
+        let incr = b"*2\r\n$4\r\nINCR\r\n$3\r\nkey\r\n";
+        let del = b"*2\r\n$3\r\nDEL\r\n$3\r\nkey\r\n";
+        let select = b"*2\r\n$6\r\nSELECT\r\n$1\r\n1\r\n";
+        let replies: &[(&[u8], &[u8])] = &[
+            (set, b"+OK\r\n"),
+            (get, b"$1\r\n1\r\n"),
+            (incr, b":2\r\n"),
+            (del, b":1\r\n"),
+            (select, b"+OK\r\n"),
+            (set, b"+OK\r\n"),
+        ];
+        for (request, reply) in replies {
+            client.write_all(request).await.unwrap();
+            let mut buf = vec![0; reply.len()];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, reply);
+        }
+        // a failed write changes nothing, so it is not fed
+        let set_bad = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$1\r\nx\r\n";
+        client.write_all(set_bad).await.unwrap();
+        let mut buf = vec![0; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        client.write_all(incr).await.unwrap();
+        let expected = b"-ERR value is not an integer or out of range\r\n";
+        let mut buf = vec![0; expected.len()];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, expected);
+
+        let expected = [
+            (0, &set[..]),
+            (0, &incr[..]),
+            (0, &del[..]),
+            (1, &set[..]),
+            (1, &set_bad[..]),
+        ];
+        for (db, command) in expected {
+            let write = writes.try_recv().expect("a write should be fed");
+            assert_eq!(
+                write,
+                PropagatedWrite {
+                    db,
+                    command: command.to_vec()
+                }
+            );
+        }
+        assert!(writes.try_recv().is_err(), "only the writes should be fed");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_transaction_isolation() {
+        let storage = Arc::new(Storage::new(1000, 4));
+        let connect = || {
+            let (client, server) = io::duplex(64 * 1024);
+            let mut parser = Parser::new(
+                server,
+                storage.clone(),
+                Arc::new(Metrics::default()),
+                1024,
+                DecodeLimits::default(),
+            );
+            tokio::spawn(async move {
+                parser.process_frames().await;
+            });
+            client
+        };
+        let (mut transactions, mut other) = (connect(), connect());
+
+        // another client keeps resetting the counter the transactions increment
+        let stop = Arc::new(Notify::new());
+        let resetting = tokio::spawn({
+            let stop = stop.clone();
+            async move {
+                let reset = b"*3\r\n$3\r\nSET\r\n$7\r\ncounter\r\n$4\r\n1000\r\n";
+                let mut reply = [0; 5];
+                loop {
+                    tokio::select! {
+                        _ = stop.notified() => return,
+                        _ = other.write_all(reset) => {}
+                    }
+                    other.read_exact(&mut reply).await.unwrap();
+                    assert_eq!(&reply, b"+OK\r\n");
+                }
+            }
+        });
+
+        let incr = "*2\r\n$4\r\nINCR\r\n$7\r\ncounter\r\n";
+        let mut request =
+            "*1\r\n$5\r\nMULTI\r\n*3\r\n$3\r\nSET\r\n$7\r\ncounter\r\n$1\r\n0\r\n".to_string();
+        request.push_str(&incr.repeat(1000));
+        request.push_str("*1\r\n$4\r\nEXEC\r\n");
+        let mut expected = format!("+OK\r\n{}*1001\r\n+OK\r\n", "+QUEUED\r\n".repeat(1001));
+        for i in 1..=1000 {
+            expected.push_str(&format!(":{}\r\n", i));
+        }
+        for _ in 0..20 {
+            transactions.write_all(request.as_bytes()).await.unwrap();
+            let mut reply = vec![0; expected.len()];
+            transactions.read_exact(&mut reply).await.unwrap();
+            assert_eq!(
+                String::from_utf8_lossy(&reply),
+                expected,
+                "the transaction saw the writes of another client"
+            );
+        }
+        stop.notify_one();
+        resetting.await.unwrap();
+
+        // only the commands on the shards of the transaction wait for it
+        let (mut blocked, mut free) = (connect(), connect());
+        let gate = storage.gate_index(b"counter");
+        let other = (0..)
+            .map(|i| format!("other:{}", i))
+            .find(|key| storage.gate_index(key.as_bytes()) != gate)
+            .unwrap();
+        let transaction = storage.gate(gate).write_owned().await;
+        blocked
+            .write_all(b"*2\r\n$3\r\nGET\r\n$7\r\ncounter\r\n")
+            .await
+            .unwrap();
+        let get = format!("*2\r\n$3\r\nGET\r\n${}\r\n{}\r\n", other.len(), other);
+        free.write_all(get.as_bytes()).await.unwrap();
+        let mut reply = [0; 5];
+        free.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"$-1\r\n");
+        let waiting = tokio::time::timeout(Duration::from_millis(50), blocked.read(&mut reply));
+        assert!(
+            waiting.await.is_err(),
+            "GET did not wait for the transaction"
+        );
+        drop(transaction);
+        blocked.read_exact(&mut reply[..4]).await.unwrap();
+        assert_eq!(&reply[..4], b"$4\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_absolute_expiry() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000, 4));
+        let (sink, mut writes) = mpsc::unbounded_channel();
+        let mut parser = Parser::new(
+            server,
+            storage.clone(),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        )
+        .with_write_sink(Some(sink));
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
+
+        let now = unix_time_ms();
+        let (future, past) = ((now + 60_000).to_string(), (now - 1).to_string());
+        let (future, past) = (future.as_str(), past.as_str());
+        let requests: &[(&[&str], &[u8])] = &[
+            (&["SET", "a", "1", "PXAT", future], b"+OK\r\n"),
+            (&["SET", "b", "2", "PXAT", past], b"+OK\r\n"),
+            (&["CAS", "a", "1", "3", "PXAT", future], b":1\r\n"),
+            (&["PEXPIREAT", "a", past], b":1\r\n"),
+            (&["SET", "c", "1", "PX", "60000"], b"+OK\r\n"),
+            (&["EXPIRE", "c", "60"], b":1\r\n"),
+            (&["GETEX", "c", "EX", "60"], b"$1\r\n1\r\n"),
+            (
+                &["PEXPIREAT", "c", "soon"],
+                b"-ERR value is not an integer or out of range\r\n",
+            ),
+        ];
+        for (request, reply) in requests {
+            client
+                .write_all(&Frame::command(request).encode_to_vec())
+                .await
+                .unwrap();
+            let mut buf = vec![0; reply.len()];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(
+                String::from_utf8_lossy(&buf),
+                String::from_utf8_lossy(reply),
+                "reply to {:?}",
+                request
+            );
+        }
+        assert_eq!(
+            storage.get_v(b"a"),
+            Ok(None),
+            "a deadline in the past deletes"
+        );
+        assert_eq!(
+            storage.get_v(b"b"),
+            Ok(None),
+            "a deadline in the past deletes"
+        );
+        assert_eq!(storage.get_v(b"c"), Ok(Some(Bytes::from_static(b"1"))));
+
+        // the relative expiries are fed as deadlines, which the last argument holds
+        let in_a_minute = now + 60_000;
+        let expected: &[(&[&str], i64)] = &[
+            (&["SET", "a", "1", "PXAT"], in_a_minute),
+            (&["SET", "b", "2", "PXAT"], now),
+            (&["CAS", "a", "1", "3", "PXAT"], in_a_minute),
+            (&["PEXPIREAT", "a"], now),
+            (&["SET", "c", "1", "PXAT"], in_a_minute),
+            (&["PEXPIREAT", "c"], in_a_minute),
+            (&["PEXPIREAT", "c"], in_a_minute),
+        ];
+        for (args, deadline) in expected {
+            let write = writes.try_recv().expect("a write should be fed");
+            let request = String::from_utf8(write.command).unwrap();
+            let fed: Vec<_> = request
+                .split("\r\n")
+                .filter(|line| !line.is_empty() && !line.starts_with(['*', '$']))
+                .collect();
+            assert_eq!(&fed[..fed.len() - 1], *args);
+            let fed_deadline = fed[fed.len() - 1].parse::<i64>().unwrap();
+            assert!(
+                (fed_deadline - deadline).abs() < 1000,
+                "{:?} fed with the deadline {}, expected {}",
+                args,
+                fed_deadline,
+                deadline
+            );
+        }
+        assert!(writes.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_aof_write_failure() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000, 4));
+        storage.set_kv(b"key", b"1", None).unwrap();
+        let metrics = Arc::new(Metrics::default());
+        let mut parser = Parser::new(
+            server,
+            storage.clone(),
+            metrics.clone(),
+            1024,
+            DecodeLimits::default(),
+        );
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
+        metrics.record_aof_write_error();
+
+        let misconf: &[u8] =
+            b"-MISCONF Errors writing to the AOF file, writes are refused until it is fixed\r\n";
+        let requests: &[(&[u8], &[u8])] = &[
+            (b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$1\r\n2\r\n", misconf),
+            (b"*2\r\n$4\r\nINCR\r\n$3\r\nkey\r\n", misconf),
+            (b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n", b"$1\r\n1\r\n"),
+            (b"*1\r\n$5\r\nMULTI\r\n", b"+OK\r\n"),
+            (b"*2\r\n$3\r\nDEL\r\n$3\r\nkey\r\n", b"+QUEUED\r\n"),
+            (
+                b"*1\r\n$4\r\nEXEC\r\n",
+                b"*1\r\n-MISCONF Errors writing to the AOF file, writes are refused until it is fixed\r\n",
+            ),
+        ];
+        for (request, reply) in requests {
+            client.write_all(request).await.unwrap();
+            let mut buf = vec![0; reply.len()];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(
+                String::from_utf8_lossy(&buf),
+                String::from_utf8_lossy(reply),
+                "reply to {}",
+                String::from_utf8_lossy(request)
+            );
+        }
+        assert_eq!(storage.get_v(b"key"), Ok(Some(Bytes::from_static(b"1"))));
+
+        let info = b"*2\r\n$4\r\nINFO\r\n$11\r\npersistence\r\n";
+        client.write_all(info).await.unwrap();
+        let expected = "# Persistence\r\naof_last_write_status:err\r\n";
+        let mut buf = vec![0; format!("${}\r\n{}\r\n", expected.len(), expected).len()];
+        client.read_exact(&mut buf).await.unwrap();
+        assert!(
+            String::from_utf8_lossy(&buf).contains(expected),
+            "{}",
+            String::from_utf8_lossy(&buf)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_transaction() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000, 4));
+        let (sink, mut writes) = mpsc::unbounded_channel();
+        let mut parser = Parser::new(
+            server,
+            storage.clone(),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        )
+        .with_write_sink(Some(sink));
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
+
+        let multi = b"*1\r\n$5\r\nMULTI\r\n";
+        let exec = b"*1\r\n$4\r\nEXEC\r\n";
+        let discard = b"*1\r\n$7\r\nDISCARD\r\n";
+        let set = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$1\r\n1\r\n";
+        let incr = b"*2\r\n$4\r\nINCR\r\n$3\r\nkey\r\n";
+        let del = b"*2\r\n$3\r\nDEL\r\n$3\r\nkey\r\n";
+        let get_bad = b"*1\r\n$3\r\nGET\r\n";
+        let replies: &[(&[u8], &[u8])] = &[
+            (exec, b"-ERR EXEC without MULTI\r\n"),
+            (discard, b"-ERR DISCARD without MULTI\r\n"),
+            // a committed transaction
+            (multi, b"+OK\r\n"),
+            (set, b"+QUEUED\r\n"),
+            (multi, b"-ERR MULTI calls can not be nested\r\n"),
+            (incr, b"+QUEUED\r\n"),
+            (exec, b"*2\r\n+OK\r\n:2\r\n"),
+            (exec, b"-ERR EXEC without MULTI\r\n"),
+            // a discarded transaction
+            (multi, b"+OK\r\n"),
+            (del, b"+QUEUED\r\n"),
+            (discard, b"+OK\r\n"),
+            // an invalid command aborts the transaction
+            (multi, b"+OK\r\n"),
+            (del, b"+QUEUED\r\n"),
+            (
+                get_bad,
+                b"-ERR GET command must have at exactly 1 argument\r\n",
+            ),
+            (
+                exec,
+                b"-EXECABORT Transaction discarded because of previous errors.\r\n",
+            ),
+            (multi, b"+OK\r\n"),
+            (exec, b"*0\r\n"),
+        ];
+        for (request, reply) in replies {
+            client.write_all(request).await.unwrap();
+            let mut buf = vec![0; reply.len()];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(
+                String::from_utf8_lossy(&buf),
+                String::from_utf8_lossy(reply),
+                "unexpected reply to {}",
+                String::from_utf8_lossy(request)
+            );
+        }
+        assert_eq!(
+            storage.get_v(b"key"),
+            Ok(Some(Bytes::from_static(b"2"))),
+            "only the committed transaction applies"
+        );
+
+        for command in [&set[..], &incr[..]] {
+            let write = writes.try_recv().expect("a write should be fed");
+            assert_eq!(
+                write,
+                PropagatedWrite {
+                    db: 0,
+                    command: command.to_vec()
+                },
+                "the writes are fed once applied"
+            );
+        }
+        assert!(writes.try_recv().is_err(), "discarded writes are not fed");
+    }
+
+    #[tokio::test]
+    async fn test_info_command() {
+        let databases: Vec<_> = (0..3).map(|_| Arc::new(Storage::new(1000, 4))).collect();
+        databases[0].set_kv(b"a", b"1", None).unwrap();
+        databases[2].set_kv(b"b", b"22", None).unwrap();
+        databases[2].set_kv(b"c", b"333", None).unwrap();
+        let metrics = Arc::new(Metrics::default());
+        let _connection = metrics.connection_opened();
+        let (client, server) = io::duplex(64 * 1024);
+        let mut parser = Parser::new(
+            server,
+            databases[0].clone(),
+            metrics.clone(),
+            1024,
+            DecodeLimits::default(),
+        )
+        .with_databases(databases.into());
+        let mut reader = Parser::new(
+            client,
+            Arc::new(Storage::new(1, 1)),
+            metrics.clone(),
+            1024,
+            DecodeLimits::default(),
+        );
+
+        let command = Command::new(CommandType::INFO, &[]);
+        parser.apply_command(&command).await.unwrap();
+        let info = reader.decode_frame().await.unwrap();
+        let info = info.bulk_str().unwrap();
+        let sections: Vec<_> = info.lines().filter(|line| line.starts_with('#')).collect();
+        assert_eq!(
+            sections,
+            [
+                "# Server",
+                "# Clients",
+                "# Memory",
+                "# Persistence",
+                "# Stats",
+                "# Keyspace"
+            ]
+        );
+        for field in [
+            &format!("redis_version:{}", env!("CARGO_PKG_VERSION")),
+            "uptime_in_seconds:",
+            "connected_clients:1\r\n",
+            "used_memory:9\r\n",
+            "db0:keys=1\r\n",
+            "db2:keys=2\r\n",
+        ] {
+            assert!(info.contains(field), "INFO should contain {}", field);
+        }
+        assert!(!info.contains("db1:"), "empty databases are not listed");
+
+        let command = Command::new(CommandType::INFO, &["keyspace".to_string()]);
+        parser.apply_command(&command).await.unwrap();
+        let info = reader.decode_frame().await.unwrap();
+        assert_eq!(
+            info.bulk_str().unwrap(),
+            "# Keyspace\r\ndb0:keys=1\r\ndb2:keys=2\r\n",
+            "can select a section"
+        );
+
+        let command = Command::new(CommandType::INFO, &["commandstats".to_string()]);
+        parser.apply_command(&command).await.unwrap();
+        let info = reader.decode_frame().await.unwrap();
+        assert_eq!(
+            info.bulk_str().unwrap(),
+            "# Commandstats\r\ncmdstat_info:calls=3\r\n",
+            "commands should be counted"
+        );
+        let stats = metrics.snapshot();
+        assert!(
+            stats.total_net_output_bytes > 0,
+            "replies should be counted"
+        );
+        assert_eq!(
+            stats.total_net_input_bytes, stats.total_net_output_bytes,
+            "the reader reads what the parser writes"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_expire_command() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        storage.set_kv(b"key", b"value", None).unwrap();
+        storage.set_kv(b"other", b"value", None).unwrap();
+        let mut parser = Parser::new(
+            server,
+            storage.clone(),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
+
+        let requests: &[(&[u8], &[u8])] = &[
+            (
+                b"*3\r\n$6\r\nEXPIRE\r\n$3\r\nkey\r\n$3\r\n100\r\n",
+                b":1\r\n",
+            ),
+            (
+                b"*3\r\n$6\r\nEXPIRE\r\n$4\r\nnope\r\n$3\r\n100\r\n",
+                b":0\r\n",
+            ),
+            (b"*3\r\n$6\r\nEXPIRE\r\n$3\r\nkey\r\n$1\r\n0\r\n", b":1\r\n"),
+            (
+                b"*3\r\n$6\r\nEXPIRE\r\n$4\r\nnope\r\n$1\r\n0\r\n",
+                b":0\r\n",
+            ),
+            (
+                b"*3\r\n$7\r\nPEXPIRE\r\n$5\r\nother\r\n$2\r\n-1\r\n",
+                b":1\r\n",
+            ),
+            (
+                b"*3\r\n$6\r\nEXPIRE\r\n$3\r\nkey\r\n$3\r\nten\r\n",
+                b"-ERR value is not an integer or out of range\r\n",
+            ),
+            (
+                b"*3\r\n$6\r\nEXPIRE\r\n$3\r\nkey\r\n$19\r\n9223372036854775807\r\n",
+                b"-ERR invalid expire time in 'expire' command\r\n",
+            ),
+        ];
+        for (request, reply) in requests {
+            client.write_all(request).await.unwrap();
+            let mut buf = vec![0; reply.len()];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(
+                &buf,
+                reply,
+                "reply to {:?}",
+                String::from_utf8_lossy(request)
+            );
+        }
+        assert!(storage.is_empty(), "non-positive TTLs delete the keys");
+    }
+
+    #[tokio::test]
+    async fn test_list_commands() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        storage.set_kv(b"string", b"value", None).unwrap();
+        let mut parser = Parser::new(
+            server,
+            storage.clone(),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
+
+        let wrong_type = b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n";
+        let requests: &[(&[u8], &[u8])] = &[
+            (
+                b"*4\r\n$5\r\nRPUSH\r\n$4\r\nlist\r\n$1\r\nb\r\n$1\r\nc\r\n",
+                b":2\r\n",
+            ),
+            (b"*3\r\n$5\r\nLPUSH\r\n$4\r\nlist\r\n$1\r\na\r\n", b":3\r\n"),
+            (
+                b"*4\r\n$6\r\nLRANGE\r\n$4\r\nlist\r\n$1\r\n0\r\n$2\r\n-1\r\n",
+                b"*3\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n",
+            ),
+            (
+                b"*4\r\n$6\r\nLRANGE\r\n$4\r\nlist\r\n$1\r\n0\r\n$3\r\none\r\n",
+                b"-ERR value is not an integer or out of range\r\n",
+            ),
+            (b"*2\r\n$4\r\nLPOP\r\n$4\r\nlist\r\n", b"$1\r\na\r\n"),
+            (
+                b"*3\r\n$4\r\nRPOP\r\n$4\r\nlist\r\n$2\r\n10\r\n",
+                b"*2\r\n$1\r\nc\r\n$1\r\nb\r\n",
+            ),
+            (b"*2\r\n$4\r\nLPOP\r\n$4\r\nlist\r\n", b"$-1\r\n"),
+            (
+                b"*3\r\n$4\r\nLPOP\r\n$4\r\nlist\r\n$1\r\n0\r\n",
+                b"-ERR value is out of range, must be positive\r\n",
+            ),
+            (
+                b"*3\r\n$5\r\nLPUSH\r\n$6\r\nstring\r\n$1\r\na\r\n",
+                wrong_type,
+            ),
+            (b"*2\r\n$4\r\nRPOP\r\n$6\r\nstring\r\n", wrong_type),
+            (
+                b"*4\r\n$6\r\nLRANGE\r\n$6\r\nstring\r\n$1\r\n0\r\n$1\r\n1\r\n",
+                wrong_type,
+            ),
+        ];
+        for (request, reply) in requests {
+            client.write_all(request).await.unwrap();
+            let mut buf = vec![0; reply.len()];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(
+                String::from_utf8_lossy(&buf),
+                String::from_utf8_lossy(reply),
+                "reply to {:?}",
+                String::from_utf8_lossy(request)
+            );
+        }
+        assert_eq!(storage.len(), 1, "the emptied list should be removed");
+    }
+
+    #[tokio::test]
+    async fn test_blocking_pop_commands() {
+        let storage = Arc::new(Storage::new(1000, 4));
+        let (sink, mut writes) = mpsc::unbounded_channel();
+        let connect = || {
+            let (client, server) = io::duplex(1024);
+            let mut parser = Parser::new(
+                server,
+                storage.clone(),
+                Arc::new(Metrics::default()),
+                1024,
+                DecodeLimits::default(),
+            )
+            .with_write_sink(Some(sink.clone()));
+            tokio::spawn(async move {
+                parser.process_frames().await;
+            });
+            client
+        };
+        let (mut first, mut second, mut pusher) = (connect(), connect(), connect());
+        let blpop = b"*3\r\n$5\r\nBLPOP\r\n$4\r\nlist\r\n$1\r\n0\r\n";
+
+        // the clients blocked on the list are woken in the order they blocked
+        first.write_all(blpop).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        second.write_all(blpop).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let mut buf = [0; 1];
+        let blocked = tokio::time::timeout(Duration::from_millis(50), first.read(&mut buf));
+        assert!(
+            blocked.await.is_err(),
+            "BLPOP should block on an empty list"
+        );
+        let rpush = b"*4\r\n$5\r\nRPUSH\r\n$4\r\nlist\r\n$1\r\na\r\n$1\r\nb\r\n";
+        pusher.write_all(rpush).await.unwrap();
+        let mut reply = [0; 4];
+        pusher.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b":2\r\n");
+        for (client, element) in [(&mut first, "a"), (&mut second, "b")] {
+            let expected = format!("*2\r\n$4\r\nlist\r\n$1\r\n{}\r\n", element);
+            let mut reply = vec![0; expected.len()];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(String::from_utf8_lossy(&reply), expected);
+        }
+        assert!(
+            storage.is_empty(),
+            "the list was emptied by the blocked clients"
+        );
+
+        let requests: &[(&[u8], &[u8])] = &[
+            // BRPOP takes the first non-empty list, from its tail
+            (rpush, b":2\r\n"),
+            (
+                b"*4\r\n$5\r\nBRPOP\r\n$5\r\nempty\r\n$4\r\nlist\r\n$1\r\n1\r\n",
+                b"*2\r\n$4\r\nlist\r\n$1\r\nb\r\n",
+            ),
+            (b"*2\r\n$4\r\nLPOP\r\n$4\r\nlist\r\n", b"$1\r\na\r\n"),
+            // the timeout is in seconds, the reply is a null array once it expired
+            (
+                b"*3\r\n$5\r\nBLPOP\r\n$5\r\nempty\r\n$4\r\n0.05\r\n",
+                b"*-1\r\n",
+            ),
+            (
+                b"*3\r\n$5\r\nBLPOP\r\n$4\r\nlist\r\n$2\r\n-1\r\n",
+                b"-ERR timeout is negative\r\n",
+            ),
+            (
+                b"*3\r\n$5\r\nBLPOP\r\n$4\r\nlist\r\n$3\r\nnow\r\n",
+                b"-ERR timeout is not a float or out of range\r\n",
+            ),
+            // a transaction does not block
+            (b"*1\r\n$5\r\nMULTI\r\n", b"+OK\r\n"),
+            (
+                b"*3\r\n$5\r\nBLPOP\r\n$5\r\nempty\r\n$1\r\n0\r\n",
+                b"+QUEUED\r\n",
+            ),
+            (b"*1\r\n$4\r\nEXEC\r\n", b"*1\r\n*-1\r\n"),
+        ];
+        for (request, reply) in requests {
+            pusher.write_all(request).await.unwrap();
+            let mut buf = vec![0; reply.len()];
+            pusher.read_exact(&mut buf).await.unwrap();
+            assert_eq!(
+                String::from_utf8_lossy(&buf),
+                String::from_utf8_lossy(reply),
+                "reply to {:?}",
+                String::from_utf8_lossy(request)
+            );
+        }
+
+        // an element popped for a client which goes away is given back
+        first.write_all(blpop).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        pusher.write_all(rpush).await.unwrap();
+        pusher.read_exact(&mut reply).await.unwrap();
+        assert_eq!(
+            storage.lrange(b"list", 0, -1),
+            Ok(vec![b"a".to_vec(), b"b".to_vec()])
+        );
+
+        // the blocking pops are fed as the pops they did, the ones which timed out are not
+        let mut fed = Vec::new();
+        while let Ok(write) = writes.try_recv() {
+            fed.push(String::from_utf8(write.command).unwrap());
+        }
+        let lpop = "*2\r\n$4\r\nLPOP\r\n$4\r\nlist\r\n";
+        let rpop = "*2\r\n$4\r\nRPOP\r\n$4\r\nlist\r\n";
+        let rpush = String::from_utf8_lossy(rpush).to_string();
+        assert_eq!(fed, [&rpush, lpop, lpop, &rpush, rpop, lpop, &rpush]);
+    }
+
+    #[tokio::test]
+    async fn test_hash_commands() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        storage.set_kv(b"string", b"value", None).unwrap();
+        let mut parser = Parser::new(
+            server,
+            storage.clone(),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
+
+        let hgetall = b"*2\r\n$7\r\nHGETALL\r\n$4\r\nhash\r\n";
+        let requests: &[(&[u8], &[u8])] = &[
+            (
+                b"*6\r\n$4\r\nHSET\r\n$4\r\nhash\r\n$1\r\na\r\n$1\r\n1\r\n$1\r\nb\r\n$1\r\n2\r\n",
+                b":2\r\n",
+            ),
+            (
+                b"*4\r\n$4\r\nHSET\r\n$4\r\nhash\r\n$1\r\na\r\n$2\r\n10\r\n",
+                b":0\r\n",
+            ),
+            (
+                b"*3\r\n$4\r\nHSET\r\n$4\r\nhash\r\n$1\r\na\r\n",
+                b"-ERR HSET command must have a key and field value pairs\r\n",
+            ),
+            (
+                b"*3\r\n$4\r\nHGET\r\n$4\r\nhash\r\n$1\r\na\r\n",
+                b"$2\r\n10\r\n",
+            ),
+            (b"*3\r\n$4\r\nHGET\r\n$4\r\nhash\r\n$1\r\nz\r\n", b"$-1\r\n"),
+            (
+                b"*4\r\n$4\r\nHDEL\r\n$4\r\nhash\r\n$1\r\nb\r\n$1\r\nz\r\n",
+                b":1\r\n",
+            ),
+            (hgetall, b"*2\r\n$1\r\na\r\n$2\r\n10\r\n"),
+            (
+                b"*2\r\n$7\r\nHGETALL\r\n$6\r\nstring\r\n",
+                b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n",
+            ),
+        ];
+        for (request, reply) in requests {
+            client.write_all(request).await.unwrap();
+            let mut buf = vec![0; reply.len()];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(
+                String::from_utf8_lossy(&buf),
+                String::from_utf8_lossy(reply),
+                "reply to {:?}",
+                String::from_utf8_lossy(request)
+            );
+        }
+
+        // RESP3 clients get a map
+        let (mut client, server) = io::duplex(1024);
+        let mut parser = Parser::new(
+            server,
+            storage,
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+        parser.protocol = Protocol::Resp3;
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
+        client.write_all(hgetall).await.unwrap();
+        let expected = b"%1\r\n$1\r\na\r\n$2\r\n10\r\n";
+        let mut buf = vec![0; expected.len()];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, expected);
+    }
+
+    #[tokio::test]
+    async fn test_incrby_command() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        let mut parser = Parser::new(
+            server,
+            storage.clone(),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
+
+        let requests: &[(&[u8], &[u8])] = &[
+            (
+                b"*3\r\n$6\r\nINCRBY\r\n$3\r\nkey\r\n$19\r\n9223372036854775806\r\n",
+                b":9223372036854775806\r\n",
+            ),
+            (
+                b"*3\r\n$6\r\nINCRBY\r\n$3\r\nkey\r\n$1\r\n1\r\n",
+                b":9223372036854775807\r\n",
+            ),
+            (
+                b"*3\r\n$6\r\nINCRBY\r\n$3\r\nkey\r\n$1\r\n1\r\n",
+                b"-ERR increment or decrement would overflow\r\n",
+            ),
+            (
+                b"*3\r\n$6\r\nDECRBY\r\n$3\r\nkey\r\n$19\r\n9223372036854775807\r\n",
+                b":0\r\n",
+            ),
+            (
+                b"*3\r\n$6\r\nINCRBY\r\n$3\r\nkey\r\n$20\r\n-9223372036854775808\r\n",
+                b":-9223372036854775808\r\n",
+            ),
+            (
+                b"*3\r\n$6\r\nDECRBY\r\n$3\r\nkey\r\n$1\r\n1\r\n",
+                b"-ERR increment or decrement would overflow\r\n",
+            ),
+            (
+                b"*3\r\n$6\r\nINCRBY\r\n$5\r\nother\r\n$3\r\n1.5\r\n",
+                b"-ERR value is not an integer or out of range\r\n",
+            ),
+            (
+                b"*3\r\n$6\r\nINCRBY\r\n$5\r\nother\r\n$19\r\n9223372036854775808\r\n",
+                b"-ERR value is not an integer or out of range\r\n",
+            ),
+        ];
+        for (request, reply) in requests {
+            client.write_all(request).await.unwrap();
+            let mut buf = vec![0; reply.len()];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(
+                String::from_utf8_lossy(&buf),
+                String::from_utf8_lossy(reply),
+                "reply to {:?}",
+                String::from_utf8_lossy(request)
+            );
+        }
+        assert_eq!(
+            storage.get_v(b"key"),
+            Ok(Some(i64::MIN.to_string().into())),
+            "a rejected increment leaves the value as it is"
+        );
+        assert_eq!(
+            storage.get_v(b"other"),
+            Ok(None),
+            "invalid deltas are rejected upfront"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_slowlog_command() {
+        let slowlog = Arc::new(SlowLog::new(Some(Duration::from_millis(30)), 128));
+        // a tiny pipe, so that a command is slow until the client reads its reply
+        let (client, server) = io::duplex(16);
+        let mut parser = Parser::new(
+            server,
+            Arc::new(Storage::new(1000, 4)),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        )
+        .with_slowlog(slowlog.clone());
+        let mut reader = Parser::new(
+            client,
+            Arc::new(Storage::new(1, 1)),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+
+        let payload = "x".repeat(64);
+        let slow = Command::new(CommandType::PING, std::slice::from_ref(&payload));
+        let (applied, reply) = tokio::join!(parser.apply_command(&slow), async {
+            tokio::time::sleep(Duration::from_millis(60)).await;
+            reader.decode_frame().await
+        });
+        applied.unwrap();
+        assert_eq!(reply, Ok(Frame::new_bulk_string(&payload)));
+        let fast = Command::new(CommandType::PING, &[]);
+        let (applied, _) = tokio::join!(parser.apply_command(&fast), reader.decode_frame());
+        applied.unwrap();
+        assert_eq!(slowlog.len(), 1, "only the slow command should be logged");
+        assert!(slowlog.get(1)[0].duration >= Duration::from_millis(30));
+
+        let get = Command::new(CommandType::SLOWLOG, &["GET".to_string(), "-1".to_string()]);
+        let (applied, reply) = tokio::join!(parser.apply_command(&get), reader.decode_frame());
+        applied.unwrap();
+        let reply = reply.unwrap();
+        let entries = reply.get_array().unwrap();
+        assert_eq!(entries.len(), 1);
+        let entry = entries[0].get_array().unwrap();
+        assert_eq!(entry[0], Frame::new_integer(0), "entry id");
         assert_eq!(
-            frame,
-            Err(DecodeError::Incomplete),
-            "frames are terminated with CRLF"
+            entry[3],
+            Parser::<io::DuplexStream>::bulk_string_array(&[
+                b"PING".to_vec(),
+                payload.into_bytes()
+            ])
         );
+
+        let reset = Command::new(CommandType::SLOWLOG, &["RESET".to_string()]);
+        let (applied, reply) = tokio::join!(parser.apply_command(&reset), reader.decode_frame());
+        applied.unwrap();
+        assert_eq!(reply, Ok(Frame::new_simple_string("OK")));
+        assert!(slowlog.is_empty(), "RESET should clear the log");
     }
 
     #[tokio::test]
-    async fn test_decode_frame_bulk_string() {
+    async fn test_cas_command() {
         let (mut client, server) = io::duplex(1024);
         let storage = Arc::new(Storage::new(1000000, 4));
-        let mut parser = Parser::new(server, storage, 1024);
-
-        // Simulate client writing to the stream
+        storage.set_kv(b"lock", b"owner", None).unwrap();
+        let mut parser = Parser::new(
+            server,
+            storage.clone(),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
         tokio::spawn(async move {
-            let data = b"$5\r\nhello\r\n$6\r\nhel\rlo\r\n$6\r\nhel\nlo\r\n$6\r\nhellojj\r";
-            client.write_all(data).await.unwrap();
-            client.flush().await.unwrap();
+            parser.process_frames().await;
         });
 
-        // simple string
-        let frame = parser.decode_frame().await.unwrap();
-        let mut response_frame = Frame::new_bulk_string("hello");
-        assert_eq!(frame, response_frame, "can decode a bulk string");
-
-        let frame = parser.decode_frame().await.unwrap();
-        response_frame = Frame::new_bulk_string("hel\rlo");
+        let requests: &[(&[u8], &[u8])] = &[
+            (
+                b"*4\r\n$3\r\nCAS\r\n$4\r\nlock\r\n$5\r\nother\r\n$3\r\nnew\r\n",
+                b":0\r\n",
+            ),
+            (
+                b"*6\r\n$3\r\nCAS\r\n$4\r\nlock\r\n$5\r\nowner\r\n$5\r\nowner\r\n$2\r\npx\r\n$5\r\n60000\r\n",
+                b":1\r\n",
+            ),
+            (
+                b"*6\r\n$3\r\nCAS\r\n$4\r\nlock\r\n$5\r\nowner\r\n$3\r\nnew\r\n$2\r\nEX\r\n$1\r\n1\r\n",
+                b"-ERR unknown option 'EX' for CAS command\r\n",
+            ),
+            (
+                b"*6\r\n$3\r\nCAS\r\n$4\r\nlock\r\n$5\r\nowner\r\n$3\r\nnew\r\n$2\r\nPX\r\n$1\r\n0\r\n",
+                b"-ERR expiration should be a positive number\r\n",
+            ),
+            (
+                b"*3\r\n$3\r\nCAS\r\n$4\r\nlock\r\n$5\r\nowner\r\n",
+                b"-ERR CAS command must have 3 or 5 arguments\r\n",
+            ),
+        ];
+        for (request, reply) in requests {
+            client.write_all(request).await.unwrap();
+            let mut buf = vec![0; reply.len()];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(
+                &buf,
+                reply,
+                "reply to {:?}",
+                String::from_utf8_lossy(request)
+            );
+        }
         assert_eq!(
-            frame, response_frame,
-            "bulk frame can contain CR in the middle"
+            storage.get_v(b"lock"),
+            Ok(Some(Bytes::from_static(b"owner")))
         );
 
-        let frame = parser.decode_frame().await.unwrap();
-        response_frame = Frame::new_bulk_string("hel\nlo");
-        assert_eq!(
-            frame, response_frame,
-            "bulk frame can contain LF in the middle"
-        );
+        client
+            .write_all(b"*6\r\n$3\r\nCAS\r\n$4\r\nlock\r\n$5\r\nowner\r\n$3\r\nnew\r\n$2\r\nPX\r\n$2\r\n10\r\n")
+            .await
+            .unwrap();
+        let mut buf = vec![0; 4];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, b":1\r\n");
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(storage.get_v(b"lock"), Ok(None), "PX should set the expiry");
+    }
 
-        let frame = parser.decode_frame().await;
-        assert_eq!(
-            frame,
-            Err(DecodeError::Invalid),
-            "bulk string is terminated by CRLF"
+    #[tokio::test]
+    async fn test_object_command() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        storage.set_kv(b"counter", b"12", None).unwrap();
+        storage.set_kv(b"name", b"mredis", None).unwrap();
+        storage.lpush(b"list", &[b"a".to_vec()]).unwrap();
+        let mut parser = Parser::new(
+            server,
+            storage.clone(),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
         );
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
+
+        let requests: &[(&[u8], &[u8])] = &[
+            (
+                b"*3\r\n$6\r\nOBJECT\r\n$8\r\nENCODING\r\n$7\r\ncounter\r\n",
+                b"+int\r\n",
+            ),
+            (
+                b"*3\r\n$6\r\nobject\r\n$8\r\nencoding\r\n$4\r\nname\r\n",
+                b"+embstr\r\n",
+            ),
+            (
+                b"*3\r\n$6\r\nOBJECT\r\n$8\r\nENCODING\r\n$4\r\nlist\r\n",
+                b"+quicklist\r\n",
+            ),
+            (
+                b"*3\r\n$6\r\nOBJECT\r\n$8\r\nIDLETIME\r\n$4\r\nname\r\n",
+                b":0\r\n",
+            ),
+            (
+                b"*3\r\n$6\r\nOBJECT\r\n$8\r\nIDLETIME\r\n$7\r\nmissing\r\n",
+                b"-ERR no such key\r\n",
+            ),
+            (
+                b"*2\r\n$6\r\nOBJECT\r\n$8\r\nENCODING\r\n",
+                b"-ERR wrong number of arguments for 'object|encoding' command\r\n",
+            ),
+            (
+                b"*3\r\n$6\r\nOBJECT\r\n$4\r\nFREQ\r\n$4\r\nname\r\n",
+                b"-ERR unknown subcommand 'FREQ'. Try OBJECT HELP.\r\n",
+            ),
+        ];
+        for (request, reply) in requests {
+            client.write_all(request).await.unwrap();
+            let mut buf = vec![0; reply.len()];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(
+                String::from_utf8_lossy(&buf),
+                String::from_utf8_lossy(reply),
+                "reply to {:?}",
+                String::from_utf8_lossy(request)
+            );
+        }
     }
 
     #[tokio::test]
-    async fn test_decode_frame_bulk_error() {
+    async fn test_msetnx_command() {
         let (mut client, server) = io::duplex(1024);
         let storage = Arc::new(Storage::new(1000000, 4));
-        let mut parser = Parser::new(server, storage, 1024);
-
-        // Simulate client writing to the stream
+        let mut parser = Parser::new(
+            server,
+            storage.clone(),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
         tokio::spawn(async move {
-            let data = b"!5\r\nhello\r\n!6\r\nhel\rlo\r\n!6\r\nhel\nlo\r\n!6\r\nhellojj\r";
-            client.write_all(data).await.unwrap();
-            client.flush().await.unwrap();
+            parser.process_frames().await;
         });
 
-        // simple string
-        let frame = parser.decode_frame().await.unwrap();
-        let mut response_frame = Frame::new_bulk_error("hello");
-        assert_eq!(frame, response_frame, "can decode a bulk string");
+        let requests: &[(&[u8], &[u8])] = &[
+            (
+                b"*5\r\n$6\r\nMSETNX\r\n$1\r\na\r\n$1\r\n1\r\n$1\r\nb\r\n$1\r\n2\r\n",
+                b":1\r\n",
+            ),
+            (
+                b"*5\r\n$6\r\nMSETNX\r\n$1\r\nc\r\n$1\r\n3\r\n$1\r\nb\r\n$1\r\n4\r\n",
+                b":0\r\n",
+            ),
+            (b"*2\r\n$3\r\nGET\r\n$1\r\nb\r\n", b"$1\r\n2\r\n"),
+            (b"*2\r\n$3\r\nGET\r\n$1\r\nc\r\n", b"$-1\r\n"),
+            (
+                b"*4\r\n$6\r\nMSETNX\r\n$1\r\nc\r\n$1\r\n3\r\n$1\r\nd\r\n",
+                b"-ERR MSETNX command must have key value pairs\r\n",
+            ),
+        ];
+        for (request, reply) in requests {
+            client.write_all(request).await.unwrap();
+            let mut buf = vec![0; reply.len()];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(
+                String::from_utf8_lossy(&buf),
+                String::from_utf8_lossy(reply),
+                "reply to {:?}",
+                String::from_utf8_lossy(request)
+            );
+        }
+    }
 
-        let frame = parser.decode_frame().await.unwrap();
-        response_frame = Frame::new_bulk_error("hel\rlo");
-        assert_eq!(
-            frame, response_frame,
-            "bulk frame can contain CR in the middle"
+    #[tokio::test]
+    async fn test_debug_sleep_command() {
+        let sleep = b"*3\r\n$5\r\nDEBUG\r\n$5\r\nSLEEP\r\n$4\r\n0.05\r\n";
+        let (mut client, server) = io::duplex(1024);
+        let mut parser = Parser::new(
+            server,
+            Arc::new(Storage::new(100, 4)),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
         );
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
+        client.write_all(sleep).await.unwrap();
+        let reply = b"-ERR DEBUG command not allowed\r\n";
+        let mut buf = vec![0; reply.len()];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, reply, "DEBUG is disabled by default");
 
-        let frame = parser.decode_frame().await.unwrap();
-        response_frame = Frame::new_bulk_error("hel\nlo");
-        assert_eq!(
-            frame, response_frame,
-            "bulk frame can contain LF in the middle"
+        let (mut client, server) = io::duplex(1024);
+        let mut parser = Parser::new(
+            server,
+            Arc::new(Storage::new(100, 4)),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        )
+        .with_debug_commands(true);
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
+        let started = tokio::time::Instant::now();
+        client.write_all(sleep).await.unwrap();
+        let mut buf = [0; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"+OK\r\n");
+        let elapsed = started.elapsed();
+        assert!(
+            elapsed >= Duration::from_millis(50) && elapsed < Duration::from_secs(1),
+            "the reply should come after the delay, it came after {:?}",
+            elapsed
         );
 
-        let frame = parser.decode_frame().await;
+        let requests: &[(&[u8], &[u8])] = &[
+            (
+                b"*3\r\n$5\r\nDEBUG\r\n$5\r\nSLEEP\r\n$2\r\n-1\r\n",
+                b"-ERR value is not a valid float\r\n",
+            ),
+            (
+                b"*2\r\n$5\r\nDEBUG\r\n$5\r\nSLEEP\r\n",
+                b"-ERR wrong number of arguments for 'debug|sleep' command\r\n",
+            ),
+            (
+                b"*2\r\n$5\r\nDEBUG\r\n$6\r\nOBJECT\r\n",
+                b"-ERR unknown subcommand 'OBJECT'. Try DEBUG HELP.\r\n",
+            ),
+        ];
+        for (request, reply) in requests {
+            client.write_all(request).await.unwrap();
+            let mut buf = vec![0; reply.len()];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(
+                String::from_utf8_lossy(&buf),
+                String::from_utf8_lossy(reply),
+                "reply to {:?}",
+                String::from_utf8_lossy(request)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_debug_shards_command() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(100, 2));
+        storage.set_kv(b"a", b"1", None).unwrap();
+        let mut parser = Parser::new(
+            server,
+            storage.clone(),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        )
+        .with_debug_commands(true);
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
+
+        let stats = storage.shard_stats();
+        let mut reply = b"*2\r\n".to_vec();
+        for (index, stat) in stats.iter().enumerate() {
+            reply.extend_from_slice(
+                format!(
+                    "*10\r\n$5\r\nshard\r\n:{}\r\n$4\r\nkeys\r\n:{}\r\n$5\r\nreads\r\n:{}\r\n\
+                     $6\r\nwrites\r\n:{}\r\n$9\r\ncontended\r\n:{}\r\n",
+                    index, stat.keys, stat.reads, stat.writes, stat.contended
+                )
+                .as_bytes(),
+            );
+        }
+        client
+            .write_all(b"*2\r\n$5\r\nDEBUG\r\n$6\r\nshards\r\n")
+            .await
+            .unwrap();
+        let mut buf = vec![0; reply.len()];
+        client.read_exact(&mut buf).await.unwrap();
         assert_eq!(
-            frame,
-            Err(DecodeError::Invalid),
-            "bulk string is terminated by CRLF"
+            String::from_utf8_lossy(&buf),
+            String::from_utf8_lossy(&reply)
         );
+        assert_eq!(stats.iter().map(|stat| stat.keys).sum::<usize>(), 1);
+        assert_eq!(stats.iter().map(|stat| stat.writes).sum::<u64>(), 1);
     }
 
     #[tokio::test]
-    async fn test_decode_frame_bool() {
+    async fn test_save_command() {
+        let path = std::env::temp_dir().join(format!("mredis-save-{}.rdb", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let storage = Arc::new(Storage::new(100, 4));
+        storage.set_kv(b"key", b"value", None).unwrap();
+        let save = b"*1\r\n$4\r\nSAVE\r\n";
+
         let (mut client, server) = io::duplex(1024);
-        let storage = Arc::new(Storage::new(1000000, 4));
-        let mut parser = Parser::new(server, storage, 1024);
+        let mut parser = Parser::new(
+            server,
+            storage.clone(),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
+        client.write_all(save).await.unwrap();
+        let reply = b"-ERR no snapshot file is configured\r\n";
+        let mut buf = vec![0; reply.len()];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, reply);
 
-        // Simulate client writing to the stream
+        let (mut client, server) = io::duplex(1024);
+        let mut parser = Parser::new(
+            server,
+            storage.clone(),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        )
+        .with_snapshot_path(Some(path.clone().into()));
         tokio::spawn(async move {
-            let data = b"#t\r\n#f\r\n$u\r\n";
-            client.write_all(data).await.unwrap();
-            client.flush().await.unwrap();
+            parser.process_frames().await;
         });
+        client.write_all(save).await.unwrap();
+        let mut buf = [0; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"+OK\r\n");
+        let loaded = Arc::new(Storage::new(100, 4));
+        crate::snapshot::load_snapshot(&path, std::slice::from_ref(&loaded)).unwrap();
+        assert_eq!(loaded.get_v(b"key"), Ok(Some(Bytes::from_static(b"value"))));
 
-        let frame = parser.decode_frame().await.unwrap();
-        let mut response_frame = Frame::new_bool(true);
-        assert_eq!(
-            frame, response_frame,
-            "can decode a bool frame with value true"
-        );
+        client.write_all(b"*1\r\n$6\r\nBGSAVE\r\n").await.unwrap();
+        let reply = b"+Background saving started\r\n";
+        let mut buf = vec![0; reply.len()];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, reply);
+        // let the background save finish before removing its snapshot
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::remove_file(&path).unwrap();
+    }
 
-        let frame = parser.decode_frame().await.unwrap();
-        response_frame = Frame::new_bool(false);
-        assert_eq!(
-            frame, response_frame,
-            "can decode a bool frame with value false"
+    #[tokio::test]
+    async fn test_unlink_command() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000000, 4));
+        let mut parser = Parser::new(
+            server,
+            storage.clone(),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
         );
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
 
-        let frame = parser.decode_frame().await;
-        assert_eq!(
-            frame,
-            Err(DecodeError::Invalid),
-            "can detect an invalid bool frame (value other than t or f)"
+        let requests: &[(&[u8], &[u8])] = &[
+            (b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n", b"+OK\r\n"),
+            (b"*3\r\n$3\r\nSET\r\n$1\r\nb\r\n$1\r\n2\r\n", b"+OK\r\n"),
+            (
+                b"*4\r\n$6\r\nUNLINK\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n",
+                b":2\r\n",
+            ),
+            (b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n", b"$-1\r\n"),
+            (b"*2\r\n$6\r\nUNLINK\r\n$1\r\na\r\n", b":0\r\n"),
+            (
+                b"*1\r\n$6\r\nUNLINK\r\n",
+                b"-ERR UNLINK command must at least one arg\r\n",
+            ),
+        ];
+        for (request, reply) in requests {
+            client.write_all(request).await.unwrap();
+            let mut buf = vec![0; reply.len()];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(
+                String::from_utf8_lossy(&buf),
+                String::from_utf8_lossy(reply),
+                "reply to {:?}",
+                String::from_utf8_lossy(request)
+            );
+        }
+        assert!(storage.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reset_command() {
+        let (mut client, server) = io::duplex(1024);
+        let databases: Arc<[Arc<Storage>]> =
+            (0..2).map(|_| Arc::new(Storage::new(1000, 4))).collect();
+        let mut parser = Parser::new(
+            server,
+            databases[0].clone(),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        )
+        .with_databases(databases.clone())
+        .with_requirepass(Some(Arc::from("secret")));
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
+
+        let reset = b"*1\r\n$5\r\nRESET\r\n";
+        let auth = b"*2\r\n$4\r\nAUTH\r\n$6\r\nsecret\r\n";
+        let get = b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n";
+        let set = b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n";
+        let requests: &[(&[u8], &[u8])] = &[
+            (reset, b"+RESET\r\n"),
+            (auth, b"+OK\r\n"),
+            // the queued commands are discarded
+            (b"*1\r\n$5\r\nMULTI\r\n", b"+OK\r\n"),
+            (set, b"+QUEUED\r\n"),
+            (reset, b"+RESET\r\n"),
+            (auth, b"+OK\r\n"),
+            (b"*1\r\n$4\r\nEXEC\r\n", b"-ERR EXEC without MULTI\r\n"),
+            (get, b"$-1\r\n"),
+            // the first database is selected again
+            (b"*2\r\n$6\r\nSELECT\r\n$1\r\n1\r\n", b"+OK\r\n"),
+            (set, b"+OK\r\n"),
+            (reset, b"+RESET\r\n"),
+            (get, b"-NOAUTH Authentication required.\r\n"),
+            (auth, b"+OK\r\n"),
+            (get, b"$-1\r\n"),
+            (
+                b"*2\r\n$5\r\nRESET\r\n$1\r\nx\r\n",
+                b"-ERR RESET command does not take arguments\r\n",
+            ),
+        ];
+        for (request, reply) in requests {
+            client.write_all(request).await.unwrap();
+            let mut buf = vec![0; reply.len()];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(
+                String::from_utf8_lossy(&buf),
+                String::from_utf8_lossy(reply),
+                "reply to {:?}",
+                String::from_utf8_lossy(request)
+            );
+        }
+        assert_eq!(databases[0].len(), 0);
+        assert_eq!(databases[1].len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_client_name() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000, 4));
+        let mut parser = Parser::new(
+            server,
+            storage,
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
         );
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
+
+        let getname = b"*2\r\n$6\r\nCLIENT\r\n$7\r\nGETNAME\r\n";
+        let requests: &[(&[u8], &[u8])] = &[
+            (getname, b"$0\r\n\r\n"),
+            (
+                b"*3\r\n$6\r\nCLIENT\r\n$7\r\nsetname\r\n$6\r\nworker\r\n",
+                b"+OK\r\n",
+            ),
+            (getname, b"$6\r\nworker\r\n"),
+            (
+                b"*3\r\n$6\r\nCLIENT\r\n$7\r\nSETNAME\r\n$3\r\na b\r\n",
+                b"-ERR Client names cannot contain spaces, newlines or special characters.\r\n",
+            ),
+            (
+                b"*3\r\n$6\r\nCLIENT\r\n$7\r\nSETNAME\r\n$3\r\na\nb\r\n",
+                b"-ERR Client names cannot contain spaces, newlines or special characters.\r\n",
+            ),
+            (getname, b"$6\r\nworker\r\n"),
+            (
+                b"*2\r\n$6\r\nCLIENT\r\n$7\r\nSETNAME\r\n",
+                b"-ERR wrong number of arguments for 'client|setname' command\r\n",
+            ),
+            // an empty name removes the name
+            (
+                b"*3\r\n$6\r\nCLIENT\r\n$7\r\nSETNAME\r\n$0\r\n\r\n",
+                b"+OK\r\n",
+            ),
+            (getname, b"$0\r\n\r\n"),
+        ];
+        for (request, reply) in requests {
+            client.write_all(request).await.unwrap();
+            let mut buf = vec![0; reply.len()];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(
+                String::from_utf8_lossy(&buf),
+                String::from_utf8_lossy(reply),
+                "reply to {:?}",
+                String::from_utf8_lossy(request)
+            );
+        }
     }
 
     #[tokio::test]
-    async fn test_decode_frame_null() {
+    async fn test_touch_command() {
         let (mut client, server) = io::duplex(1024);
-        let storage = Arc::new(Storage::new(1000000, 4));
-        let mut parser = Parser::new(server, storage, 1024);
+        let storage = Arc::new(Storage::new(1000, 4));
+        storage.set_kv(b"a", b"1", None).unwrap();
+        storage.set_kv(b"b", b"2", None).unwrap();
+        let mut parser = Parser::new(
+            server,
+            storage,
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
 
-        // Simulate client writing to the stream
+        let requests: &[(&[u8], &[u8])] = &[
+            (
+                b"*4\r\n$5\r\nTOUCH\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n",
+                b":2\r\n",
+            ),
+            (
+                b"*1\r\n$5\r\nTOUCH\r\n",
+                b"-ERR TOUCH command must at least one arg\r\n",
+            ),
+        ];
+        for (request, reply) in requests {
+            client.write_all(request).await.unwrap();
+            let mut buf = vec![0; reply.len()];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(
+                String::from_utf8_lossy(&buf),
+                String::from_utf8_lossy(reply),
+                "reply to {:?}",
+                String::from_utf8_lossy(request)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ping_command() {
+        let (mut client, server) = io::duplex(1024);
+        let mut parser = Parser::new(
+            server,
+            Arc::new(Storage::new(1000, 4)),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
         tokio::spawn(async move {
-            let data = b"_\r\n_f\r\n$u\r\n";
-            client.write_all(data).await.unwrap();
-            client.flush().await.unwrap();
+            parser.process_frames().await;
         });
 
-        let frame = parser.decode_frame().await.unwrap();
-        let response_frame = Frame::new_null();
-        assert_eq!(frame, response_frame, "can decode a null frame");
+        let requests: &[(&[u8], &[u8])] = &[
+            // a bare PING replies a simple string, a message is echoed as a bulk string
+            (b"*1\r\n$4\r\nPING\r\n", b"+PONG\r\n"),
+            (b"*2\r\n$4\r\nPING\r\n$2\r\nhi\r\n", b"$2\r\nhi\r\n"),
+            (b"*2\r\n$4\r\nPING\r\n$4\r\nPONG\r\n", b"$4\r\nPONG\r\n"),
+            (
+                b"*3\r\n$4\r\nPING\r\n$1\r\na\r\n$1\r\nb\r\n",
+                b"-ERR PING command must have at most 1 argument\r\n",
+            ),
+        ];
+        for (request, reply) in requests {
+            client.write_all(request).await.unwrap();
+            let mut buf = vec![0; reply.len()];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(
+                String::from_utf8_lossy(&buf),
+                String::from_utf8_lossy(reply),
+                "reply to {:?}",
+                String::from_utf8_lossy(request)
+            );
+        }
+    }
 
-        let frame = parser.decode_frame().await;
-        assert_eq!(
-            frame,
-            Err(DecodeError::Invalid),
-            "can spot a null frame which has value, null should not have one"
+    #[tokio::test]
+    async fn test_set_keepttl() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000, 4));
+        let mut parser = Parser::new(
+            server,
+            storage.clone(),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
         );
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
+
+        let requests: &[(&[u8], &[u8])] = &[
+            (
+                b"*5\r\n$3\r\nSET\r\n$1\r\nk\r\n$2\r\nv1\r\n$2\r\nPX\r\n$2\r\n50\r\n",
+                b"+OK\r\n",
+            ),
+            (
+                b"*4\r\n$3\r\nSET\r\n$1\r\nk\r\n$2\r\nv2\r\n$7\r\nkeepttl\r\n",
+                b"+OK\r\n",
+            ),
+            (b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n", b"$2\r\nv2\r\n"),
+            (
+                b"*4\r\n$3\r\nSET\r\n$1\r\nk\r\n$2\r\nv3\r\n$2\r\nNX\r\n",
+                b"-ERR unknown option 'NX' for SET command\r\n",
+            ),
+        ];
+        for (request, reply) in requests {
+            client.write_all(request).await.unwrap();
+            let mut buf = vec![0; reply.len()];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(
+                String::from_utf8_lossy(&buf),
+                String::from_utf8_lossy(reply),
+                "reply to {:?}",
+                String::from_utf8_lossy(request)
+            );
+        }
+
+        // the key still expires after the 50ms of the first SET
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        client
+            .write_all(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n")
+            .await
+            .unwrap();
+        let mut buf = [0; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"$-1\r\n");
     }
 
     #[tokio::test]
-    async fn test_decode_frame_array() {
+    async fn test_getrange_command() {
         let (mut client, server) = io::duplex(1024);
-        let storage = Arc::new(Storage::new(1000000, 4));
-        let mut parser = Parser::new(server, storage, 1024);
+        let storage = Arc::new(Storage::new(1000, 4));
+        storage.set_kv(b"k", b"Hello", None).unwrap();
+        let mut parser = Parser::new(
+            server,
+            storage,
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
 
-        // Simulate client writing to the stream
+        let requests: &[(&[u8], &[u8])] = &[
+            (
+                b"*4\r\n$8\r\nGETRANGE\r\n$1\r\nk\r\n$1\r\n1\r\n$1\r\n3\r\n",
+                b"$3\r\nell\r\n",
+            ),
+            (
+                b"*4\r\n$8\r\nGETRANGE\r\n$1\r\nk\r\n$2\r\n-3\r\n$2\r\n-1\r\n",
+                b"$3\r\nllo\r\n",
+            ),
+            (
+                b"*4\r\n$8\r\nGETRANGE\r\n$1\r\nk\r\n$1\r\n3\r\n$1\r\n1\r\n",
+                b"$0\r\n\r\n",
+            ),
+            (
+                b"*4\r\n$8\r\nGETRANGE\r\n$1\r\nm\r\n$1\r\n0\r\n$2\r\n-1\r\n",
+                b"$0\r\n\r\n",
+            ),
+            (
+                b"*4\r\n$8\r\nGETRANGE\r\n$1\r\nk\r\n$1\r\na\r\n$1\r\n1\r\n",
+                b"-ERR value is not an integer or out of range\r\n",
+            ),
+            (
+                b"*2\r\n$8\r\nGETRANGE\r\n$1\r\nk\r\n",
+                b"-ERR GETRANGE command must have exactly 3 arguments\r\n",
+            ),
+        ];
+        for (request, reply) in requests {
+            client.write_all(request).await.unwrap();
+            let mut buf = vec![0; reply.len()];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(
+                String::from_utf8_lossy(&buf),
+                String::from_utf8_lossy(reply),
+                "reply to {:?}",
+                String::from_utf8_lossy(request)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_setrange_command() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000, 4));
+        storage.set_kv(b"k", b"Hello", None).unwrap();
+        let mut parser = Parser::new(
+            server,
+            storage,
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
+        );
         tokio::spawn(async move {
-            let data = b"*3\r\n:1\r\n+Two\r\n$5\r\nThree\r\n*2\r\n:1\r\n*1\r\n+Three\r\n*1\r\n$4\r\nPING\r\n";
-            client.write_all(data).await.unwrap();
-            client.flush().await.unwrap();
+            parser.process_frames().await;
         });
 
-        let frame = parser.decode_frame().await.unwrap();
-        let frame_data = FrameData::Nested(vec![
-            Frame {
-                frame_type: FrameID::Integer,
-                frame_data: FrameData::Integer(1),
-            },
-            Frame {
-                frame_type: FrameID::SimpleString,
-                frame_data: FrameData::Simple("Two".to_string()),
-            },
-            Frame {
-                frame_type: FrameID::BulkString,
-                frame_data: FrameData::Bulk("Three".to_string()),
-            },
-        ]);
-        let response_frame = Frame {
-            frame_type: FrameID::Array,
-            frame_data,
-        };
-        assert_eq!(
-            frame, response_frame,
-            "can decode a non nested array with mixed elements"
+        let requests: &[(&[u8], &[u8])] = &[
+            (
+                b"*4\r\n$8\r\nSETRANGE\r\n$1\r\nk\r\n$1\r\n1\r\n$2\r\nip\r\n",
+                b":5\r\n",
+            ),
+            (b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n", b"$5\r\nHiplo\r\n"),
+            (
+                b"*4\r\n$8\r\nSETRANGE\r\n$1\r\nn\r\n$1\r\n2\r\n$1\r\nx\r\n",
+                b":3\r\n",
+            ),
+            (b"*2\r\n$3\r\nGET\r\n$1\r\nn\r\n", b"$3\r\n\0\0x\r\n"),
+            (
+                b"*4\r\n$8\r\nSETRANGE\r\n$1\r\nk\r\n$2\r\n-1\r\n$1\r\nx\r\n",
+                b"-ERR offset is out of range\r\n",
+            ),
+            (
+                b"*4\r\n$8\r\nSETRANGE\r\n$1\r\nk\r\n$9\r\n536870912\r\n$1\r\nx\r\n",
+                b"-ERR string exceeds maximum allowed size (proto-max-bulk-len)\r\n",
+            ),
+            (
+                b"*4\r\n$8\r\nSETRANGE\r\n$1\r\nk\r\n$1\r\na\r\n$1\r\nx\r\n",
+                b"-ERR value is not an integer or out of range\r\n",
+            ),
+        ];
+        for (request, reply) in requests {
+            client.write_all(request).await.unwrap();
+            let mut buf = vec![0; reply.len()];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(
+                String::from_utf8_lossy(&buf),
+                String::from_utf8_lossy(reply),
+                "reply to {:?}",
+                String::from_utf8_lossy(request)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delx_command() {
+        let (mut client, server) = io::duplex(1024);
+        let storage = Arc::new(Storage::new(1000, 4));
+        storage.set_kv(b"a", b"1", None).unwrap();
+        storage.set_kv(b"c", b"3", None).unwrap();
+        let mut parser = Parser::new(
+            server,
+            storage.clone(),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
         );
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
 
-        let frame_data_nested = FrameData::Nested(vec![
-            Frame {
-                frame_type: FrameID::Integer,
-                frame_data: FrameData::Integer(1),
-            },
-            Frame {
-                frame_type: FrameID::Array,
-                frame_data: FrameData::Nested(vec![Frame {
-                    frame_type: FrameID::SimpleString,
-                    frame_data: FrameData::Simple("Three".to_string()),
-                }]),
-            },
-        ]);
+        let requests: &[(&[u8], &[u8])] = &[
+            (
+                b"*4\r\n$4\r\nDELX\r\n$1\r\nb\r\n$1\r\na\r\n$1\r\nc\r\n",
+                b"*3\r\n:0\r\n:1\r\n:1\r\n",
+            ),
+            (b"*2\r\n$4\r\nDELX\r\n$1\r\na\r\n", b"*1\r\n:0\r\n"),
+            (
+                b"*1\r\n$4\r\nDELX\r\n",
+                b"-ERR DELX command must at least one arg\r\n",
+            ),
+        ];
+        for (request, reply) in requests {
+            client.write_all(request).await.unwrap();
+            let mut buf = vec![0; reply.len()];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(
+                String::from_utf8_lossy(&buf),
+                String::from_utf8_lossy(reply),
+                "reply to {:?}",
+                String::from_utf8_lossy(request)
+            );
+        }
+        assert!(storage.is_empty());
+    }
 
-        let response_frame_nested = Frame {
-            frame_type: FrameID::Array,
-            frame_data: frame_data_nested,
-        };
-        let frame_nested = parser.decode_frame().await.unwrap();
-        assert_eq!(
-            frame_nested, response_frame_nested,
-            "can decode a nested array"
+    #[tokio::test]
+    async fn test_wait_command() {
+        let (mut client, server) = io::duplex(1024);
+        let mut parser = Parser::new(
+            server,
+            Arc::new(Storage::new(1000, 4)),
+            Arc::new(Metrics::default()),
+            1024,
+            DecodeLimits::default(),
         );
+        tokio::spawn(async move {
+            parser.process_frames().await;
+        });
 
-        let frame_ping = FrameData::Nested(vec![Frame {
-            frame_type: FrameID::BulkString,
-            frame_data: FrameData::Bulk("PING".to_string()),
-        }]);
-        let response_frame_ping = Frame {
-            frame_type: FrameID::Array,
-            frame_data: frame_ping,
-        };
-        let frame_ping = parser.decode_frame().await.unwrap();
-        assert_eq!(frame_ping, response_frame_ping, "can decode ping command");
+        let requests: &[(&[u8], &[u8])] = &[
+            (b"*3\r\n$4\r\nWAIT\r\n$1\r\n0\r\n$3\r\n100\r\n", b":0\r\n"),
+            // no replica will ever acknowledge, there is no point in blocking
+            (b"*3\r\n$4\r\nWAIT\r\n$1\r\n1\r\n$1\r\n0\r\n", b":0\r\n"),
+            (
+                b"*3\r\n$4\r\nWAIT\r\n$1\r\na\r\n$1\r\n0\r\n",
+                b"-ERR value is not an integer or out of range\r\n",
+            ),
+            (
+                b"*3\r\n$4\r\nWAIT\r\n$1\r\n0\r\n$2\r\n-1\r\n",
+                b"-ERR timeout is negative\r\n",
+            ),
+            (
+                b"*2\r\n$4\r\nWAIT\r\n$1\r\n0\r\n",
+                b"-ERR WAIT command must have exactly 2 arguments\r\n",
+            ),
+        ];
+        for (request, reply) in requests {
+            client.write_all(request).await.unwrap();
+            let mut buf = vec![0; reply.len()];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(
+                String::from_utf8_lossy(&buf),
+                String::from_utf8_lossy(reply),
+                "reply to {:?}",
+                String::from_utf8_lossy(request)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_protocol_error_closes_connection() {
+        let cases: &[(&[u8], &[u8])] = &[
+            (
+                b"*1\r\n$4\r\nPING\r\n*1\r\n$x\r\nPING\r\n*1\r\n$4\r\nPING\r\n",
+                b"+PONG\r\n-ERR Protocol error: frame is not correctly formatted\r\n",
+            ),
+            (
+                b"*1\r\n*-5\r\n",
+                b"-ERR Protocol error: frame is not correctly formatted\r\n",
+            ),
+            (
+                b"%1\r\n",
+                b"-ERR Protocol error: unable to identify the frame type\r\n",
+            ),
+        ];
+        for (request, reply) in cases {
+            let (mut client, server) = io::duplex(1024);
+            let limits = DecodeLimits {
+                inline_commands: false,
+                ..DecodeLimits::default()
+            };
+            let mut parser = Parser::new(
+                server,
+                Arc::new(Storage::new(1000, 4)),
+                Arc::new(Metrics::default()),
+                1024,
+                limits,
+            );
+            let connection = tokio::spawn(async move {
+                parser.process_frames().await;
+            });
+            client.write_all(request).await.unwrap();
+            // the connection is closed after the error, nothing else is read
+            let mut buf = Vec::new();
+            client.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(
+                String::from_utf8_lossy(&buf),
+                String::from_utf8_lossy(reply),
+                "reply to {:?}",
+                String::from_utf8_lossy(request)
+            );
+            connection.await.unwrap();
+        }
     }
 }
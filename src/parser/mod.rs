@@ -1,7 +1,12 @@
 mod command;
+mod decoder;
 mod frame;
 mod handler;
+mod stream;
 
 pub(crate) use command::*;
+pub use decoder::{decode, DecodeError, DecodeLimits};
+pub use frame::Frame;
 pub(crate) use frame::*;
+pub use handler::PropagatedWrite;
 pub(crate) use handler::*;
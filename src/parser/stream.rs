@@ -0,0 +1,101 @@
+//! ClientStream wraps the connection of a client. It counts the bytes going through in the
+//! metrics, and closes connections which stay idle for too long: it fails a read with a
+//! `TimedOut` error once no byte went through the stream, in either direction, for the idle
+//! timeout. Because any byte resets the timer, a slow client sending a large frame bit by bit is
+//! not cut off in the middle of it.
+
+use crate::metrics::Metrics;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{self, AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+pub(crate) struct ClientStream<T> {
+    inner: T,
+    metrics: Arc<Metrics>,
+    // the idle timeout and the timer firing once it elapsed, None when there is no timeout
+    idle: Option<(Duration, Pin<Box<Sleep>>)>,
+}
+
+impl<T> ClientStream<T> {
+    pub(crate) fn new(inner: T, metrics: Arc<Metrics>) -> Self {
+        ClientStream {
+            inner,
+            metrics,
+            idle: None,
+        }
+    }
+
+    /// set_timeout sets the idle timeout, counted from now. None disables it.
+    pub(crate) fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.idle = timeout.map(|timeout| (timeout, Box::pin(tokio::time::sleep(timeout))));
+    }
+
+    // touch resets the timer, some bytes went through the stream
+    fn touch(&mut self) {
+        if let Some((timeout, sleep)) = self.idle.as_mut() {
+            sleep.as_mut().reset(Instant::now() + *timeout);
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for ClientStream<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                let read = buf.filled().len() - before;
+                if read > 0 {
+                    self.metrics.record_net_input(read);
+                    self.touch();
+                }
+                Poll::Ready(result)
+            }
+            Poll::Pending => {
+                let elapsed = match self.idle.as_mut() {
+                    Some((_, sleep)) => sleep.as_mut().poll(cx).is_ready(),
+                    None => false,
+                };
+                if elapsed {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "client idle for too long",
+                    )));
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for ClientStream<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            if written > 0 {
+                self.metrics.record_net_output(written);
+                self.touch();
+            }
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
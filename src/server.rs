@@ -1,24 +1,90 @@
+use crate::aof::{open_aof, replay_aof, write_aof};
 use crate::config::Config;
 use crate::db::Storage;
-use crate::parser::Parser;
+use crate::health::serve_health;
+use crate::metrics::Metrics;
+pub use crate::parser::PropagatedWrite;
+use crate::parser::{CommandType, DecodeLimits, Parser};
+use crate::slowlog::SlowLog;
+use crate::snapshot::load_snapshot;
+use crate::tls::{load_tls_acceptor, TLS_HANDSHAKE_TIMEOUT};
+use socket2::{SockRef, TcpKeepalive};
+use std::collections::HashSet;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
 use std::process;
-use std::sync::Arc;
-use tokio::net::TcpListener;
-use tokio::sync::Semaphore;
-use tracing::{debug, error, info};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot, watch, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, warn};
 
 // @TODO: implement Tracing
-// @TODO: implement Metrics
-// @TODO: Implement graceful shutdown
 // @TODO: Implement Semaphore
 
 pub struct Server {
-    storage: Arc<Storage>,
+    // logical databases, a connection starts on the first one
+    databases: Arc<[Arc<Storage>]>,
     tcp_listener: TcpListener,
+    // the handshake of the connections, if they use TLS
+    tls_acceptor: Option<TlsAcceptor>,
+    // where the health probes connect, if the endpoint is enabled
+    health_listener: Option<Arc<TcpListener>>,
     net_buffer_size: usize,
     conn_limit: Arc<Semaphore>,
+    // number of permits of conn_limit, all of them are back once every connection is closed
+    max_conn: usize,
+    decode_limits: DecodeLimits,
+    metrics: Arc<Metrics>,
+    // identifier of the next connection, for CLIENT ID
+    next_client_id: Arc<AtomicU64>,
+    slowlog: Arc<SlowLog>,
+    requirepass: Option<Arc<str>>,
+    // whether the clients may run DEBUG
+    debug_commands: bool,
+    // names of the commands the clients cannot run, in uppercase
+    disabled_commands: Arc<HashSet<String>>,
+    // where SAVE and BGSAVE write the snapshot
+    snapshot_path: Arc<Path>,
+    // socket options applied to the accepted connections
+    tcp_nodelay: bool,
+    tcp_keepalive: Option<Duration>,
+    // how often the expired keys are removed in the background, and how many per shard and pass
+    eviction_interval: Duration,
+    eviction_sample_size: usize,
+    // how long a client can stay idle before its connection is closed
+    idle_timeout: Option<Duration>,
+    // where the connections feed the write commands they apply, if anything consumes them
+    write_sink: Option<mpsc::UnboundedSender<PropagatedWrite>>,
+    // the task appending to the AOF, until the server shuts down
+    aof_writer: Mutex<Option<AofWriter>>,
+    // notified by SHUTDOWN
+    shutdown: Arc<Notify>,
+    // set once the server shuts down, to close the connections
+    closing: watch::Sender<bool>,
 }
 
+// AofWriter is the task appending the writes to the AOF, with what tells it to stop.
+struct AofWriter {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+// Bounds of the delay between two accepts once the server runs out of resources. The delay
+// doubles on each error and is reset by the next accepted connection.
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(10);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+// How long a shutdown waits for the connections to close, before it syncs the AOF anyway. A
+// connection only takes longer when its client does not read the replies it is sent.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
 impl Server {
     pub async fn new(cfg: &Config) -> Self {
         let tcp_listener = match TcpListener::bind((cfg.ip_addr.to_owned(), cfg.port)).await {
@@ -28,19 +94,229 @@ impl Server {
                 process::exit(1);
             }
         };
-        let storage = Arc::new(Storage::new(cfg.capacity, cfg.shard_count));
+        let health_listener = match cfg.health_port {
+            Some(port) => match TcpListener::bind((cfg.ip_addr.to_owned(), port)).await {
+                Ok(listener) => Some(Arc::new(listener)),
+                Err(e) => {
+                    error!("failed to start the health endpoint: {}", e);
+                    process::exit(1);
+                }
+            },
+            None => None,
+        };
+        // a bad certificate is reported now, rather than by every connection
+        let tls_acceptor = match (&cfg.tls_cert, &cfg.tls_key) {
+            (Some(cert), Some(key)) => match load_tls_acceptor(Path::new(cert), Path::new(key)) {
+                Ok(acceptor) => Some(acceptor),
+                Err(err) => {
+                    error!("failed to load the TLS certificate: {}", err);
+                    process::exit(1);
+                }
+            },
+            _ => None,
+        };
+        // there is always a database for the connections to start on
+        let databases = (0..cfg.databases.max(1))
+            .map(|_| {
+                Arc::new(Storage::with_memory_limit(
+                    cfg.capacity,
+                    cfg.shard_count,
+                    cfg.maxmemory,
+                    cfg.maxmemory_policy,
+                ))
+            })
+            .collect();
+        let decode_limits = DecodeLimits {
+            max_bulk_len: cfg.max_bulk_len,
+            max_array_elements: cfg.max_array_elements,
+            max_depth: cfg.max_nesting_depth,
+            inline_commands: cfg.inline_commands,
+            max_inline_size: cfg.max_inline_size,
+        };
+        let snapshot_path: Arc<Path> = Path::new(&cfg.dir).join(&cfg.dbfilename).into();
+        let metrics = Arc::new(Metrics::default());
+        // the append-only file has every write, the snapshot would only be overwritten
+        let (write_sink, aof_writer) = if cfg.appendonly {
+            let (sink, writer) =
+                Self::start_aof(cfg, &databases, decode_limits, metrics.clone()).await;
+            (Some(sink), Some(writer))
+        } else {
+            if let Err(err) = load_snapshot(&snapshot_path, &databases) {
+                error!(
+                    "failed to load the snapshot {}: {}",
+                    snapshot_path.display(),
+                    err
+                );
+                process::exit(1);
+            }
+            (None, None)
+        };
         let conn_limit = Arc::new(Semaphore::new(cfg.max_conn));
-        info!("Starting mredis server: {:?}", cfg);
+        let disabled_commands: HashSet<String> = cfg
+            .disable_command
+            .iter()
+            .map(|name| name.to_uppercase())
+            .collect();
+        for name in &disabled_commands {
+            if CommandType::from_name(name.as_bytes()).is_none() {
+                warn!("cannot disable the command {}, it does not exist", name);
+            }
+        }
+        // do not write the password to the logs
+        let mut shown = cfg.clone();
+        if shown.requirepass.is_some() {
+            shown.requirepass = Some("<redacted>".to_string());
+        }
+        info!("Starting mredis server: {:?}", shown);
         Server {
-            storage,
+            databases,
             tcp_listener,
+            tls_acceptor,
+            health_listener,
             net_buffer_size: cfg.network_buffer_size,
             conn_limit,
+            max_conn: cfg.max_conn,
+            decode_limits,
+            metrics,
+            // like Redis, the identifiers start at 1
+            next_client_id: Arc::new(AtomicU64::new(1)),
+            slowlog: Arc::new(SlowLog::new(
+                u64::try_from(cfg.slowlog_log_slower_than)
+                    .ok()
+                    .map(Duration::from_micros),
+                cfg.slowlog_max_len,
+            )),
+            requirepass: cfg.requirepass.as_deref().map(Arc::from),
+            debug_commands: cfg.enable_debug_commands,
+            disabled_commands: Arc::new(disabled_commands),
+            snapshot_path,
+            tcp_nodelay: cfg.tcp_nodelay,
+            tcp_keepalive: (cfg.tcp_keepalive > 0).then(|| Duration::from_secs(cfg.tcp_keepalive)),
+            idle_timeout: (cfg.timeout > 0).then(|| Duration::from_secs(cfg.timeout)),
+            eviction_interval: Duration::from_millis(cfg.eviction_interval_ms),
+            eviction_sample_size: cfg.eviction_sample_size as usize,
+            write_sink,
+            aof_writer: Mutex::new(aof_writer),
+            shutdown: Arc::new(Notify::new()),
+            closing: watch::Sender::new(false),
+        }
+    }
+
+    // start_aof replays the append-only file into the databases, then starts appending the write
+    // commands to it. It returns where the connections feed their writes, and the task appending
+    // them. If appending fails, it is recorded in `metrics`, which makes the connections refuse
+    // the writes.
+    async fn start_aof(
+        cfg: &Config,
+        databases: &Arc<[Arc<Storage>]>,
+        decode_limits: DecodeLimits,
+        metrics: Arc<Metrics>,
+    ) -> (mpsc::UnboundedSender<PropagatedWrite>, AofWriter) {
+        let path = Path::new(&cfg.dir).join(&cfg.appendfilename);
+        if let Err(err) = replay_aof(&path, databases.clone(), decode_limits).await {
+            error!("failed to replay the AOF {}: {}", path.display(), err);
+            process::exit(1);
+        }
+        let file = match open_aof(&path).await {
+            Ok(file) => file,
+            Err(err) => {
+                error!("failed to open the AOF {}: {}", path.display(), err);
+                process::exit(1);
+            }
+        };
+        let (sink, writes) = mpsc::unbounded_channel();
+        let (stop, stopped) = oneshot::channel();
+        let fsync = cfg.appendfsync;
+        let task = tokio::spawn(async move {
+            let stopped = async {
+                let _ = stopped.await;
+            };
+            if let Err(err) = write_aof(file, writes, fsync, stopped).await {
+                metrics.record_aof_write_error();
+                error!(
+                    "failed to append to the AOF, writes are refused from now on: {}",
+                    err
+                );
+            }
+        });
+        (sink, AofWriter { stop, task })
+    }
+
+    // stop_aof makes the task appending to the AOF write and sync every write it was fed, and
+    // waits for it. Called once the connections are closed, the writes acknowledged to the
+    // clients survive a restart.
+    async fn stop_aof(&self) {
+        let writer = self.aof_writer.lock().unwrap().take();
+        if let Some(AofWriter { stop, task }) = writer {
+            let _ = stop.send(());
+            match task.await {
+                Ok(()) => info!("the AOF is synced"),
+                Err(err) => error!("the task appending to the AOF panicked: {}", err),
+            }
         }
     }
 
+    /// with_write_sink feeds every write command applied by the clients to `sink`, in the order
+    /// each client applied them. This is the hook an append-only file or replicas build on.
+    pub fn with_write_sink(mut self, sink: mpsc::UnboundedSender<PropagatedWrite>) -> Self {
+        self.write_sink = Some(sink);
+        self
+    }
+
+    /// local_addr returns the address the server listens on, to find the port it was given when
+    /// started on port 0.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp_listener.local_addr()
+    }
+
+    /// listen serves the clients until the server is asked to shut down, by SHUTDOWN, SIGTERM or
+    /// Ctrl-C. It then stops accepting connections, closes them once their command is applied,
+    /// and syncs the AOF before returning.
     pub async fn listen(&self) {
-        debug!("server start listening for new connections");
+        match self.local_addr() {
+            Ok(addr) => info!("listening for connections on {}", addr),
+            Err(err) => warn!("failed to get the address of the listener: {}", err),
+        }
+        for storage in self.databases.iter() {
+            tokio::spawn(
+                storage
+                    .clone()
+                    .run_active_eviction(self.eviction_interval, self.eviction_sample_size),
+            );
+        }
+        if let Some(listener) = &self.health_listener {
+            tokio::spawn(serve_health(
+                listener.clone(),
+                self.databases.clone(),
+                self.metrics.clone(),
+            ));
+        }
+        tokio::select! {
+            _ = self.accept_connections() => {}
+            _ = self.shutdown.notified() => info!("shutting down, as a client asked"),
+            _ = terminated() => info!("shutting down, as the process was asked to stop"),
+        }
+        self.close_connections().await;
+        self.stop_aof().await;
+    }
+
+    // close_connections closes the connections, and waits until they are all closed. From then
+    // on, no write is applied, so the AOF gets every write acknowledged to a client.
+    async fn close_connections(&self) {
+        self.closing.send_replace(true);
+        let permits = u32::try_from(self.max_conn).unwrap_or(u32::MAX);
+        let closed = tokio::time::timeout(SHUTDOWN_TIMEOUT, self.conn_limit.acquire_many(permits));
+        if closed.await.is_err() {
+            warn!(
+                "connections are still open after {:?}, their last writes may be lost",
+                SHUTDOWN_TIMEOUT
+            );
+        }
+    }
+
+    // accept_connections accepts the connections and spawns the tasks serving them, forever.
+    async fn accept_connections(&self) {
+        let mut backoff = ACCEPT_BACKOFF_MIN;
         loop {
             // Check if there is room to get a new connection before
             // We can unwrap because there is only one way this can fail:
@@ -60,24 +336,394 @@ impl Server {
             match conn_string {
                 Ok((stream, addr)) => {
                     debug!("new connection established: {}", addr);
-
-                    let state = self.storage.clone();
-                    let mut parser = Parser::new(stream, state, self.net_buffer_size);
-
-                    tokio::spawn(async move {
-                        debug!("server initiated a new session");
-                        parser.process_frames().await;
-                        // we no longer need the connection at this point, so drop it before
-                        // we release the semaphore.
-                        drop(parser);
-                        // release the semaphore
+                    backoff = ACCEPT_BACKOFF_MIN;
+                    self.configure_socket(&stream);
+                    match &self.tls_acceptor {
+                        Some(acceptor) => self.serve_tls(stream, acceptor.clone(), permit),
+                        None => self.serve(stream, permit),
+                    };
+                }
+                Err(err) => {
+                    if let Some(delay) = self.on_accept_error(&err, &mut backoff) {
                         drop(permit);
-                    });
+                        tokio::time::sleep(delay).await;
+                    }
                 }
+            }
+        }
+    }
+
+    // configure_socket applies the socket options to an accepted connection. They only tune the
+    // connection, so it is served even if they cannot be applied.
+    fn configure_socket(&self, stream: &TcpStream) {
+        if let Err(err) = stream.set_nodelay(self.tcp_nodelay) {
+            warn!("failed to set TCP_NODELAY on a client connection: {}", err);
+        }
+        if let Some(time) = self.tcp_keepalive {
+            let keepalive = TcpKeepalive::new().with_time(time);
+            if let Err(err) = SockRef::from(stream).set_tcp_keepalive(&keepalive) {
+                warn!(
+                    "failed to set TCP keepalive on a client connection: {}",
+                    err
+                );
+            }
+        }
+    }
+
+    // serve spawns the task processing the frames of a new connection.
+    fn serve<T>(&self, stream: T, permit: OwnedSemaphorePermit) -> JoinHandle<()>
+    where
+        T: AsyncReadExt + AsyncWriteExt + Unpin + Send + 'static,
+    {
+        self.serve_after(async move { Ok(stream) }, permit)
+    }
+
+    // serve_tls spawns the task of a new TLS connection. The handshake is done by the task, so
+    // that a slow client does not hold the accept loop.
+    fn serve_tls(
+        &self,
+        stream: TcpStream,
+        acceptor: TlsAcceptor,
+        permit: OwnedSemaphorePermit,
+    ) -> JoinHandle<()> {
+        let handshake = async move {
+            tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+        };
+        self.serve_after(handshake, permit)
+    }
+
+    // serve_after spawns the task processing the frames of a new connection, once `handshake`
+    // returns its stream. The task owns the permit and the connection guard, so they are released
+    // when it ends, even if it panics.
+    fn serve_after<T, H>(&self, handshake: H, permit: OwnedSemaphorePermit) -> JoinHandle<()>
+    where
+        T: AsyncReadExt + AsyncWriteExt + Unpin + Send + 'static,
+        H: Future<Output = io::Result<T>> + Send + 'static,
+    {
+        let connection = self.metrics.connection_opened();
+        // the parser is built by the task, once the stream is ready
+        let session = {
+            let databases = self.databases.clone();
+            let metrics = self.metrics.clone();
+            let (buffer_size, limits) = (self.net_buffer_size, self.decode_limits);
+            let client_id = self.next_client_id.fetch_add(1, Ordering::Relaxed);
+            let requirepass = self.requirepass.clone();
+            let debug_commands = self.debug_commands;
+            let disabled_commands = self.disabled_commands.clone();
+            let snapshot_path = self.snapshot_path.clone();
+            let idle_timeout = self.idle_timeout;
+            let slowlog = self.slowlog.clone();
+            let write_sink = self.write_sink.clone();
+            let shutdown = self.shutdown.clone();
+            let closing = self.closing.subscribe();
+            move |stream: T| {
+                Parser::new(stream, databases[0].clone(), metrics, buffer_size, limits)
+                    .with_databases(databases)
+                    .with_client_id(client_id)
+                    .with_requirepass(requirepass)
+                    .with_debug_commands(debug_commands)
+                    .with_disabled_commands(disabled_commands)
+                    .with_snapshot_path(Some(snapshot_path))
+                    .with_idle_timeout(idle_timeout)
+                    .with_slowlog(slowlog)
+                    .with_write_sink(write_sink)
+                    .with_shutdown(Some(shutdown))
+                    .with_server_closing(closing)
+            }
+        };
+        tokio::spawn(async move {
+            let stream = match handshake.await {
+                Ok(stream) => stream,
                 Err(err) => {
-                    debug!("error accepting client connection: {:?}", err);
+                    warn!("failed to set up a client connection: {}", err);
+                    return;
                 }
+            };
+            let mut parser = session(stream);
+            debug!("server initiated a new session");
+            parser.process_frames().await;
+            // we no longer need the connection at this point, so drop it before
+            // we release the semaphore.
+            drop(parser);
+            drop(connection);
+            // release the semaphore
+            drop(permit);
+        })
+    }
+
+    // on_accept_error records an accept error and returns how long to wait before accepting
+    // again, if needed. An error about a single connection, aborted before we could accept it,
+    // does not prevent accepting the next ones. Any other error most likely means that the
+    // process ran out of resources, like file descriptors when the server is flooded with
+    // connections: retrying right away would spin on the same error, so we back off.
+    fn on_accept_error(&self, err: &io::Error, backoff: &mut Duration) -> Option<Duration> {
+        let per_connection = matches!(
+            err.kind(),
+            io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::Interrupted
+        );
+        self.metrics.record_accept_error(err, !per_connection);
+        if per_connection {
+            debug!("error accepting client connection: {:?}", err);
+            return None;
+        }
+        let delay = *backoff;
+        warn!(
+            "cannot accept connections: {}, retrying in {:?}",
+            err, delay
+        );
+        *backoff = (delay * 2).min(ACCEPT_BACKOFF_MAX);
+        Some(delay)
+    }
+}
+
+// terminated returns once the process is asked to stop, by SIGTERM or Ctrl-C. It never returns if
+// the signals cannot be listened to.
+async fn terminated() {
+    #[cfg(unix)]
+    let sigterm = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
             }
+            Err(err) => {
+                warn!("cannot listen to SIGTERM: {}", err);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let sigterm = std::future::pending::<()>();
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            warn!("cannot listen to Ctrl-C: {}", err);
+            std::future::pending::<()>().await;
+        }
+    };
+    tokio::select! {
+        _ = sigterm => {}
+        _ = ctrl_c => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser as _;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+    /// PanickingStream panics as soon as the connection reads from it.
+    struct PanickingStream;
+
+    impl AsyncRead for PanickingStream {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            panic!("connection failure");
+        }
+    }
+
+    impl AsyncWrite for PanickingStream {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connected_clients() {
+        let cfg = Config::parse_from(["mredis", "--port", "0"]);
+        let server = Server::new(&cfg).await;
+
+        let mut clients = Vec::new();
+        let mut connections = Vec::new();
+        for _ in 0..5 {
+            let (client, stream) = tokio::io::duplex(1024);
+            let permit = server.conn_limit.clone().try_acquire_owned().unwrap();
+            connections.push(server.serve(stream, permit));
+            clients.push(client);
         }
+        assert_eq!(server.metrics.connected_clients(), 5);
+
+        // the connections end when their client goes away
+        clients.truncate(2);
+        for connection in connections.drain(2..) {
+            connection.await.unwrap();
+        }
+        assert_eq!(server.metrics.connected_clients(), 2);
+        drop(clients);
+        for connection in connections {
+            connection.await.unwrap();
+        }
+        assert_eq!(server.metrics.connected_clients(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_connection_cleanup_on_panic() {
+        let cfg = Config::parse_from(["mredis", "--port", "0", "--limit", "2"]);
+        let server = Server::new(&cfg).await;
+
+        let permit = server.conn_limit.clone().try_acquire_owned().unwrap();
+        let connection = server.serve(PanickingStream, permit);
+        let result = connection.await;
+        assert!(
+            result.unwrap_err().is_panic(),
+            "the connection should panic"
+        );
+
+        assert_eq!(
+            server.metrics.connected_clients(),
+            0,
+            "the connection should no longer be counted"
+        );
+        assert_eq!(
+            server.conn_limit.available_permits(),
+            2,
+            "the permit should be released"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_id() {
+        let cfg = Config::parse_from(["mredis", "--port", "0"]);
+        let server = Server::new(&cfg).await;
+
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let (mut client, stream) = tokio::io::duplex(1024);
+            let permit = server.conn_limit.clone().try_acquire_owned().unwrap();
+            let connection = server.serve(stream, permit);
+            client
+                .write_all(b"*2\r\n$6\r\nCLIENT\r\n$2\r\nID\r\n")
+                .await
+                .unwrap();
+            let mut reply = [0; 4];
+            client.read_exact(&mut reply).await.unwrap();
+            ids.push(String::from_utf8_lossy(&reply).into_owned());
+            drop(client);
+            connection.await.unwrap();
+        }
+        assert_eq!(ids, [":1\r\n", ":2\r\n"]);
+    }
+
+    #[tokio::test]
+    async fn test_disable_command() {
+        let cfg = Config::parse_from(["mredis", "--port", "0", "--disable-command", "del"]);
+        let server = Server::new(&cfg).await;
+        let (mut client, stream) = tokio::io::duplex(1024);
+        let permit = server.conn_limit.clone().try_acquire_owned().unwrap();
+        server.serve(stream, permit);
+
+        let count = (crate::parser::COMMAND_TABLE.len() - 1).to_string();
+        let requests: &[(&[u8], String)] = &[
+            (
+                b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n",
+                "+OK\r\n".to_string(),
+            ),
+            (
+                b"*2\r\n$3\r\nDEL\r\n$1\r\na\r\n",
+                "-ERR unknown command 'DEL'\r\n".to_string(),
+            ),
+            (
+                b"*2\r\n$3\r\ndel\r\n$1\r\na\r\n",
+                "-ERR unknown command 'DEL'\r\n".to_string(),
+            ),
+            (b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n", "$1\r\n1\r\n".to_string()),
+            (
+                b"*2\r\n$7\r\nCOMMAND\r\n$5\r\nCOUNT\r\n",
+                format!(":{}\r\n", count),
+            ),
+        ];
+        for (request, expected) in requests {
+            client.write_all(request).await.unwrap();
+            let mut reply = vec![0; expected.len()];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(String::from_utf8_lossy(&reply), *expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_local_addr() {
+        let cfg = Config::parse_from(["mredis", "--port", "0"]);
+        let server = Server::new(&cfg).await;
+        let addr = server.local_addr().unwrap();
+        assert_ne!(addr.port(), 0);
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (stream, _) = server.tcp_listener.accept().await.unwrap();
+        let permit = server.conn_limit.clone().try_acquire_owned().unwrap();
+        server.serve(stream, permit);
+        client.write_all(b"*1\r\n$4\r\nPING\r\n").await.unwrap();
+        let mut reply = [0; 7];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"+PONG\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_configure_socket() {
+        let cfg = Config::parse_from(["mredis", "--port", "0", "--tcp-keepalive", "60"]);
+        let server = Server::new(&cfg).await;
+
+        let addr = server.tcp_listener.local_addr().unwrap();
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let (stream, _) = server.tcp_listener.accept().await.unwrap();
+        server.configure_socket(&stream);
+
+        assert!(stream.nodelay().unwrap(), "TCP_NODELAY should be set");
+        let socket = SockRef::from(&stream);
+        assert!(socket.keepalive().unwrap(), "keepalive should be enabled");
+        assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn test_accept_errors() {
+        let cfg = Config::parse_from(["mredis", "--port", "0"]);
+        let server = Server::new(&cfg).await;
+        let mut backoff = ACCEPT_BACKOFF_MIN;
+
+        let aborted = io::Error::from(io::ErrorKind::ConnectionAborted);
+        assert_eq!(
+            server.on_accept_error(&aborted, &mut backoff),
+            None,
+            "an aborted connection does not need a backoff"
+        );
+        assert_eq!(server.metrics.accept_errors(), 1);
+        assert_eq!(server.metrics.rejected_connections(), 0);
+
+        let exhausted = io::Error::from(io::ErrorKind::OutOfMemory);
+        let delays: Vec<_> = (0..8)
+            .map(|_| server.on_accept_error(&exhausted, &mut backoff).unwrap())
+            .collect();
+        assert_eq!(delays[0], ACCEPT_BACKOFF_MIN);
+        assert_eq!(delays[1], ACCEPT_BACKOFF_MIN * 2, "backoff should double");
+        assert_eq!(delays[7], ACCEPT_BACKOFF_MAX, "backoff should be capped");
+        assert_eq!(server.metrics.accept_errors(), 9);
+        assert_eq!(server.metrics.rejected_connections(), 8);
+
+        let stats = server.metrics.snapshot().render_stats();
+        assert!(stats.contains("rejected_connections:8\r\n"));
+        assert!(stats.contains("accept_errors:9\r\n"));
+        assert!(stats.contains("accept_errors_connection_aborted:1\r\n"));
+        assert!(stats.contains("accept_errors_out_of_memory:8\r\n"));
     }
 }
@@ -0,0 +1,166 @@
+//! The slow log records the commands which took longer than a threshold to run, to debug latency
+//! spikes. It is shared by every connection and keeps a bounded number of entries, dropping the
+//! oldest ones first. Comparing a duration to the threshold takes no lock, so only the slow
+//! commands contend on the log.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Like in Redis, an entry keeps at most this many arguments, and this many bytes of each one, so
+// that a huge command does not make the log huge too.
+const MAX_ARGS: usize = 32;
+const MAX_ARG_LEN: usize = 128;
+
+/// SlowLogEntry is a command which ran for longer than the threshold.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlowLogEntry {
+    /// Unique and increasing identifier of the entry.
+    pub id: u64,
+    /// When the command was recorded, in seconds since the UNIX epoch.
+    pub timestamp: u64,
+    pub duration: Duration,
+    /// The command name and its arguments, truncated.
+    pub args: Vec<Vec<u8>>,
+}
+
+#[derive(Debug)]
+pub struct SlowLog {
+    // commands running for at least this long are recorded, none are without a threshold
+    threshold: Option<Duration>,
+    max_len: usize,
+    next_id: AtomicU64,
+    // entries from the newest to the oldest
+    entries: Mutex<VecDeque<SlowLogEntry>>,
+}
+
+impl Default for SlowLog {
+    // the defaults of Redis: 10 milliseconds, 128 entries
+    fn default() -> Self {
+        SlowLog::new(Some(Duration::from_millis(10)), 128)
+    }
+}
+
+impl SlowLog {
+    /// new creates a slow log recording the commands running for at least `threshold`, keeping
+    /// the `max_len` most recent ones. Nothing is recorded without a threshold.
+    pub fn new(threshold: Option<Duration>, max_len: usize) -> Self {
+        SlowLog {
+            threshold,
+            max_len,
+            next_id: AtomicU64::new(0),
+            entries: Mutex::new(VecDeque::with_capacity(max_len)),
+        }
+    }
+
+    /// record adds a command which ran for `duration` to the log, if it is slow enough. `args`
+    /// returns the command name and its arguments, it is only called for slow commands.
+    pub fn record(&self, duration: Duration, args: impl FnOnce() -> Vec<Vec<u8>>) {
+        if self.threshold.is_none_or(|threshold| duration < threshold) || self.max_len == 0 {
+            return;
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        let entry = SlowLogEntry {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            timestamp,
+            duration,
+            args: truncate_args(args()),
+        };
+        let mut entries = self.entries.lock().unwrap();
+        entries.truncate(self.max_len - 1);
+        entries.push_front(entry);
+    }
+
+    /// get returns up to `count` entries, the most recent first.
+    pub fn get(&self, count: usize) -> Vec<SlowLogEntry> {
+        let entries = self.entries.lock().unwrap();
+        entries.iter().take(count).cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// reset removes every entry.
+    pub fn reset(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+// truncate_args bounds the number and the length of the arguments kept in an entry, telling how
+// much was left out.
+fn truncate_args(mut args: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    if args.len() > MAX_ARGS {
+        let more = args.len() - MAX_ARGS + 1;
+        args.truncate(MAX_ARGS - 1);
+        args.push(format!("... ({} more arguments)", more).into_bytes());
+    }
+    for arg in args.iter_mut() {
+        if arg.len() > MAX_ARG_LEN {
+            let more = arg.len() - MAX_ARG_LEN;
+            arg.truncate(MAX_ARG_LEN);
+            arg.extend_from_slice(format!("... ({} more bytes)", more).as_bytes());
+        }
+    }
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(words: &[&str]) -> Vec<Vec<u8>> {
+        words.iter().map(|word| word.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_record() {
+        let slowlog = SlowLog::new(Some(Duration::from_millis(10)), 3);
+        slowlog.record(Duration::from_millis(9), || {
+            panic!("fast commands are not recorded")
+        });
+        assert!(slowlog.is_empty());
+
+        for i in 0..5 {
+            let key = format!("key:{}", i);
+            slowlog.record(Duration::from_millis(10 + i), || command(&["GET", &key]));
+        }
+        assert_eq!(slowlog.len(), 3, "the log is bounded");
+        let entries = slowlog.get(10);
+        let ids: Vec<_> = entries.iter().map(|entry| entry.id).collect();
+        assert_eq!(ids, [4, 3, 2], "the most recent entries come first");
+        assert_eq!(entries[0].args, command(&["GET", "key:4"]));
+        assert_eq!(entries[0].duration, Duration::from_millis(14));
+        assert!(entries[0].timestamp > 0);
+        assert_eq!(slowlog.get(1).len(), 1);
+
+        slowlog.reset();
+        assert!(slowlog.is_empty());
+        slowlog.record(Duration::from_secs(1), || command(&["PING"]));
+        assert_eq!(slowlog.get(1)[0].id, 5, "ids keep increasing after a reset");
+
+        let disabled = SlowLog::new(None, 128);
+        disabled.record(Duration::from_secs(10), || panic!("nothing is recorded"));
+        assert!(disabled.is_empty());
+    }
+
+    #[test]
+    fn test_truncate_args() {
+        let args: Vec<_> = (0..40).map(|i| i.to_string().into_bytes()).collect();
+        let truncated = truncate_args(args);
+        assert_eq!(truncated.len(), MAX_ARGS);
+        assert_eq!(truncated[MAX_ARGS - 1], b"... (9 more arguments)");
+
+        let truncated = truncate_args(vec![vec![b'a'; 200]]);
+        let mut expected = vec![b'a'; MAX_ARG_LEN];
+        expected.extend_from_slice(b"... (72 more bytes)");
+        assert_eq!(truncated, [expected]);
+    }
+}
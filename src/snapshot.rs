@@ -0,0 +1,397 @@
+//! Snapshots are point-in-time copies of the databases in a compact binary file, written by SAVE
+//! and BGSAVE and loaded on startup.
+//!
+//! The file starts with a magic number and a version, followed by each database which holds keys:
+//! a `DB` opcode with the database index, its entries, and an `END` opcode. Each entry is a type
+//! byte, a flags byte, the expiry deadline in milliseconds since the UNIX epoch (0 when the key does
+//! not expire), the key, and the value. Lengths are 32 bits, every number is little endian.
+//!
+//! Each shard is copied under its read lock, so a snapshot is consistent per shard, not across
+//! the whole storage.
+
+use crate::db::{Storage, Value};
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+const MAGIC: &[u8] = b"MREDIS";
+const VERSION: u8 = 1;
+
+// opcodes of the file, they cannot be mistaken for a value type
+const OPCODE_DB: u8 = 0xFE;
+const OPCODE_END: u8 = 0xFF;
+
+const TYPE_STR: u8 = 0;
+const TYPE_HASH: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_LIST: u8 = 4;
+
+const FLAG_PINNED: u8 = 1;
+
+/// SnapshotEntry is a key as stored in a snapshot.
+pub(crate) struct SnapshotEntry {
+    pub(crate) key: Vec<u8>,
+    pub(crate) value: Value,
+    /// When the key expires, None if it does not.
+    pub(crate) expires_at: Option<SystemTime>,
+    pub(crate) pinned: bool,
+}
+
+/// encode_entry appends an entry to `out`.
+pub(crate) fn encode_entry(
+    out: &mut Vec<u8>,
+    key: &[u8],
+    value: &Value,
+    expires_at: Option<SystemTime>,
+    pinned: bool,
+) {
+    let value_type = match value {
+        Value::Str(_) => TYPE_STR,
+        Value::Hash(_) => TYPE_HASH,
+        Value::Set(_) => TYPE_SET,
+        Value::ZSet(_) => TYPE_ZSET,
+        Value::List(_) => TYPE_LIST,
+    };
+    out.push(value_type);
+    out.push(if pinned { FLAG_PINNED } else { 0 });
+    let deadline = expires_at.map_or(0, |deadline| {
+        // a deadline before the epoch has passed long ago, 1 keeps it apart from no deadline
+        let elapsed = deadline.duration_since(UNIX_EPOCH).unwrap_or_default();
+        (elapsed.as_millis() as u64).max(1)
+    });
+    out.extend_from_slice(&deadline.to_le_bytes());
+    encode_bytes(out, key);
+    match value {
+        Value::Str(value) => encode_bytes(out, value),
+        Value::Hash(hash) => {
+            encode_len(out, hash.len());
+            for (field, value) in hash {
+                encode_bytes(out, field);
+                encode_bytes(out, value);
+            }
+        }
+        Value::Set(set) => {
+            encode_len(out, set.len());
+            for member in set {
+                encode_bytes(out, member);
+            }
+        }
+        Value::ZSet(zset) => {
+            encode_len(out, zset.len());
+            for (member, score) in zset {
+                encode_bytes(out, member);
+                out.extend_from_slice(&score.to_le_bytes());
+            }
+        }
+        Value::List(list) => {
+            encode_len(out, list.len());
+            for element in list {
+                encode_bytes(out, element);
+            }
+        }
+    }
+}
+
+/// encode_end marks the end of the entries of a database.
+pub(crate) fn encode_end(out: &mut Vec<u8>) {
+    out.push(OPCODE_END);
+}
+
+fn encode_len(out: &mut Vec<u8>, len: usize) {
+    out.extend_from_slice(&(len as u32).to_le_bytes());
+}
+
+fn encode_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    encode_len(out, bytes.len());
+    out.extend_from_slice(bytes);
+}
+
+/// decode_entry reads the next entry of a database, None once its entries are over.
+pub(crate) fn decode_entry(r: &mut impl Read) -> io::Result<Option<SnapshotEntry>> {
+    let value_type = read_u8(r)?;
+    if value_type == OPCODE_END {
+        return Ok(None);
+    }
+    let flags = read_u8(r)?;
+    let deadline = read_u64(r)?;
+    let key = read_bytes(r)?;
+    let value = match value_type {
+        TYPE_STR => Value::Str(read_bytes(r)?.into()),
+        TYPE_HASH => {
+            let len = read_len(r)?;
+            let mut hash = FxHashMap::default();
+            for _ in 0..len {
+                hash.insert(read_bytes(r)?, read_bytes(r)?);
+            }
+            Value::Hash(hash)
+        }
+        TYPE_SET => {
+            let len = read_len(r)?;
+            let mut set = FxHashSet::default();
+            for _ in 0..len {
+                set.insert(read_bytes(r)?);
+            }
+            Value::Set(set)
+        }
+        TYPE_ZSET => {
+            let len = read_len(r)?;
+            let mut zset = FxHashMap::default();
+            for _ in 0..len {
+                let member = read_bytes(r)?;
+                zset.insert(member, f64::from_bits(read_u64(r)?));
+            }
+            Value::ZSet(zset)
+        }
+        TYPE_LIST => {
+            let len = read_len(r)?;
+            let mut list = VecDeque::new();
+            for _ in 0..len {
+                list.push_back(read_bytes(r)?);
+            }
+            Value::List(list)
+        }
+        other => return Err(invalid_data(format!("unknown value type {}", other))),
+    };
+    Ok(Some(SnapshotEntry {
+        key,
+        value,
+        expires_at: (deadline > 0).then(|| UNIX_EPOCH + Duration::from_millis(deadline)),
+        pinned: flags & FLAG_PINNED != 0,
+    }))
+}
+
+fn read_u8(r: &mut impl Read) -> io::Result<u8> {
+    let mut buf = [0; 1];
+    r.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_len(r: &mut impl Read) -> io::Result<usize> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf) as usize)
+}
+
+fn read_bytes(r: &mut impl Read) -> io::Result<Vec<u8>> {
+    let len = read_len(r)?;
+    let mut bytes = Vec::new();
+    // the length is not trusted for the allocation, a corrupted one fails on the short read
+    r.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes)
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Distinguishes the temporary files of concurrent saves.
+static SAVE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// save_snapshot writes a snapshot of `databases` to `path`. The snapshot is written to a
+/// temporary file first, which then replaces the previous snapshot, so that a failed save leaves
+/// the previous one intact.
+pub fn save_snapshot(path: &Path, databases: &[Arc<Storage>]) -> io::Result<()> {
+    let temporary = path.with_extension(format!(
+        "tmp-{}-{}",
+        std::process::id(),
+        SAVE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let result =
+        write_snapshot(&temporary, databases).and_then(|()| std::fs::rename(&temporary, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temporary);
+    }
+    result
+}
+
+fn write_snapshot(path: &Path, databases: &[Arc<Storage>]) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    w.write_all(MAGIC)?;
+    w.write_all(&[VERSION])?;
+    for (index, storage) in databases.iter().enumerate() {
+        if storage.is_empty() {
+            continue;
+        }
+        w.write_all(&[OPCODE_DB])?;
+        w.write_all(&(index as u32).to_le_bytes())?;
+        storage.snapshot(&mut w)?;
+    }
+    w.write_all(&[OPCODE_END])?;
+    w.into_inner().map_err(|err| err.into_error())?.sync_all()
+}
+
+/// load_snapshot loads the snapshot at `path` into `databases`. A missing file is an empty
+/// snapshot. The keys which expired since the snapshot was taken are skipped.
+pub fn load_snapshot(path: &Path, databases: &[Arc<Storage>]) -> io::Result<()> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    let mut r = BufReader::new(file);
+    let mut header = [0; MAGIC.len() + 1];
+    r.read_exact(&mut header)?;
+    if &header[..MAGIC.len()] != MAGIC {
+        return Err(invalid_data("not a snapshot file".to_string()));
+    }
+    if header[MAGIC.len()] != VERSION {
+        return Err(invalid_data(format!(
+            "unsupported snapshot version {}",
+            header[MAGIC.len()]
+        )));
+    }
+    loop {
+        match read_u8(&mut r)? {
+            OPCODE_END => break,
+            OPCODE_DB => {
+                let index = read_len(&mut r)?;
+                let storage = databases.get(index).ok_or_else(|| {
+                    invalid_data(format!("the snapshot holds database {}", index))
+                })?;
+                storage.load(&mut r)?;
+            }
+            other => return Err(invalid_data(format!("unknown opcode {}", other))),
+        }
+    }
+    let keys: usize = databases.iter().map(|storage| storage.len()).sum();
+    info!("loaded the snapshot {}, {} keys", path.display(), keys);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn sorted<T: Ord>(mut items: Vec<T>) -> Vec<T> {
+        items.sort();
+        items
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let path = std::env::temp_dir().join(format!("mredis-snapshot-{}.rdb", std::process::id()));
+        let databases: Vec<Arc<Storage>> = (0..3).map(|_| Arc::new(Storage::new(100, 4))).collect();
+        let storage = &databases[0];
+        storage.set_kv(b"str", b"\x00binary\xff", None).unwrap();
+        storage
+            .set_kv(b"long", b"v", Some(Duration::from_secs(3600)))
+            .unwrap();
+        storage
+            .set_kv(b"short", b"v", Some(Duration::from_millis(100)))
+            .unwrap();
+        storage
+            .set_kv(b"expired", b"v", Some(Duration::from_millis(1)))
+            .unwrap();
+        storage
+            .hset(
+                b"hash",
+                &[
+                    (b"f1".to_vec(), b"v1".to_vec()),
+                    (b"f2".to_vec(), b"v2".to_vec()),
+                ],
+            )
+            .unwrap();
+        storage
+            .sadd(b"set", &[b"a".to_vec(), b"b".to_vec()])
+            .unwrap();
+        storage
+            .zadd(b"zset", &[(1.5, b"one".to_vec()), (-2.0, b"two".to_vec())])
+            .unwrap();
+        storage
+            .rpush(b"list", &[b"x".to_vec(), b"y".to_vec(), b"z".to_vec()])
+            .unwrap();
+        storage.set_pinned(&[b"str".to_vec()], true);
+        databases[2].set_kv(b"str", b"db2", None).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        save_snapshot(&path, &databases).unwrap();
+
+        let loaded: Vec<Arc<Storage>> = (0..3).map(|_| Arc::new(Storage::new(100, 4))).collect();
+        load_snapshot(&path, &loaded).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let storage = &loaded[0];
+        assert_eq!(storage.len(), 7, "the expired key is not saved");
+        assert_eq!(
+            storage.get_v(b"str"),
+            Ok(Some(Bytes::from_static(b"\x00binary\xff")))
+        );
+        assert_eq!(storage.set_pinned(&[b"str".to_vec()], false), 1);
+        assert_eq!(
+            sorted(storage.hgetall(b"hash").unwrap()),
+            [
+                (b"f1".to_vec(), b"v1".to_vec()),
+                (b"f2".to_vec(), b"v2".to_vec())
+            ]
+        );
+        assert_eq!(
+            sorted(storage.sscan(b"set", 0, b"*", 10).unwrap().1),
+            [b"a".to_vec(), b"b".to_vec()]
+        );
+        let (_, zset) = storage.zscan(b"zset", 0, b"*", 10).unwrap();
+        let zset: Vec<_> = zset.chunks(2).map(<[Vec<u8>]>::to_vec).collect();
+        assert_eq!(
+            sorted(zset),
+            [
+                vec![b"one".to_vec(), b"1.5".to_vec()],
+                vec![b"two".to_vec(), b"-2".to_vec()]
+            ]
+        );
+        assert_eq!(
+            storage.lrange(b"list", 0, -1),
+            Ok(vec![b"x".to_vec(), b"y".to_vec(), b"z".to_vec()])
+        );
+        assert!(loaded[1].is_empty());
+        assert_eq!(
+            loaded[2].get_v(b"str"),
+            Ok(Some(Bytes::from_static(b"db2")))
+        );
+
+        // the expiries are kept
+        std::thread::sleep(Duration::from_millis(150));
+        assert_eq!(storage.get_v(b"short"), Ok(None));
+        assert_eq!(storage.get_v(b"long"), Ok(Some(Bytes::from_static(b"v"))));
+    }
+
+    #[test]
+    fn load_invalid_test() {
+        let path = std::env::temp_dir().join(format!("mredis-invalid-{}.rdb", std::process::id()));
+        let databases = [Arc::new(Storage::new(100, 4))];
+        assert!(load_snapshot(&path, &databases).is_ok(), "no snapshot yet");
+
+        std::fs::write(&path, b"*1\r\n$4\r\nPING\r\n").unwrap();
+        let err = load_snapshot(&path, &databases).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // a snapshot cut short
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&[VERSION, OPCODE_DB, 0, 0, 0, 0]);
+        encode_entry(
+            &mut out,
+            b"key",
+            &Value::Str(Bytes::from_static(b"v")),
+            None,
+            false,
+        );
+        out.truncate(out.len() - 1);
+        std::fs::write(&path, &out).unwrap();
+        let err = load_snapshot(&path, &databases).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
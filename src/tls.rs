@@ -0,0 +1,74 @@
+//! TLS termination of the client connections, with rustls. The certificate chain and its private
+//! key are loaded once on startup, from PEM files, and every accepted connection does its
+//! handshake before the first command is read.
+
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+/// How long a client has to complete the TLS handshake, before its connection is closed.
+pub const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// load_tls_acceptor reads the certificate chain in `cert` and its private key in `key`, both in
+/// PEM, and returns what performs the server side of the TLS handshakes.
+pub fn load_tls_acceptor(cert: &Path, key: &Path) -> io::Result<TlsAcceptor> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| invalid_file(cert, err))?;
+    if certs.is_empty() {
+        return Err(invalid_file(cert, "no certificate found"));
+    }
+    let private_key = PrivateKeyDer::from_pem_file(key).map_err(|err| invalid_file(key, err))?;
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|config| {
+            config
+                .with_no_client_auth()
+                .with_single_cert(certs, private_key)
+        })
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// invalid_file returns the error of a file that cannot be loaded, naming the file.
+fn invalid_file(path: &Path, err: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{}: {}", path.display(), err),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_tls_acceptor_test() {
+        let dir = std::env::temp_dir();
+        let cert_path = dir.join(format!("mredis-tls-{}.crt", std::process::id()));
+        let key_path = dir.join(format!("mredis-tls-{}.key", std::process::id()));
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        std::fs::write(&cert_path, certified.cert.pem()).unwrap();
+        std::fs::write(&key_path, certified.key_pair.serialize_pem()).unwrap();
+        assert!(load_tls_acceptor(&cert_path, &key_path).is_ok());
+
+        // the errors name the file which cannot be loaded
+        let load_err = |cert: &Path, key: &Path| load_tls_acceptor(cert, key).err().unwrap();
+        let missing = dir.join("mredis-missing.crt");
+        let err = load_err(&missing, &key_path);
+        assert!(err.to_string().contains("mredis-missing.crt"), "{}", err);
+        let err = load_err(&key_path, &key_path);
+        assert!(err.to_string().contains("no certificate found"), "{}", err);
+        let err = load_err(&cert_path, &cert_path);
+        assert!(err.to_string().contains(".crt"), "{}", err);
+
+        std::fs::remove_file(cert_path).unwrap();
+        std::fs::remove_file(key_path).unwrap();
+    }
+}
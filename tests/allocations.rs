@@ -0,0 +1,46 @@
+//! Allocations of the command parsing, which runs for every request. The counting allocator is
+//! the global allocator of this test binary only, it does not slow down the other tests.
+
+use mredis::Frame;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+// CountingAllocator counts the allocations of each thread, so that tests running in parallel do
+// not see each other's allocations.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+#[test]
+fn to_command_allocates_only_the_arguments() {
+    for name in ["GET", "get", "gEt"] {
+        let frame = Frame::command(&[name, "key"]);
+        let before = ALLOCATIONS.with(Cell::get);
+        let command = frame.to_command();
+        let after = ALLOCATIONS.with(Cell::get);
+        assert_eq!(
+            after - before,
+            2,
+            "parsing {} should only allocate the arguments and the key",
+            name
+        );
+        let parsed = format!("{:?}", command);
+        assert!(parsed.contains("GET"), "{} parsed as {}", name, parsed);
+    }
+}
@@ -0,0 +1,220 @@
+//! End to end tests: a real server listening on an ephemeral port, and clients talking RESP to it
+//! over TCP.
+
+use bytes::Bytes;
+use clap::Parser;
+use mredis::aof::replay_aof;
+use mredis::config::Config;
+use mredis::db::Storage;
+use mredis::server::Server;
+use mredis::snapshot::load_snapshot;
+use mredis::DecodeLimits;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+// start starts a server with the extra command line `args`, and returns its address.
+async fn start(args: &[&str]) -> SocketAddr {
+    let cfg = Config::parse_from(["mredis", "--port", "0"].iter().chain(args));
+    let server = Arc::new(Server::new(&cfg).await);
+    let addr = server.local_addr().unwrap();
+    tokio::spawn(async move { server.listen().await });
+    addr
+}
+
+// exchange sends each request and checks the reply it gets.
+async fn exchange(stream: &mut TcpStream, requests: &[(&[u8], &[u8])]) {
+    for (request, expected) in requests {
+        stream.write_all(request).await.unwrap();
+        let mut reply = vec![0; expected.len()];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&reply),
+            String::from_utf8_lossy(expected),
+            "reply to {}",
+            String::from_utf8_lossy(request)
+        );
+    }
+}
+
+#[tokio::test]
+async fn ping_set_get_del() {
+    let addr = start(&[]).await;
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let requests: &[(&[u8], &[u8])] = &[
+        (b"*1\r\n$4\r\nPING\r\n", b"+PONG\r\n"),
+        (b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n", b"$-1\r\n"),
+        (
+            b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n",
+            b"+OK\r\n",
+        ),
+        (b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n", b"$5\r\nvalue\r\n"),
+        (
+            b"*3\r\n$3\r\nDEL\r\n$3\r\nkey\r\n$7\r\nmissing\r\n",
+            b":1\r\n",
+        ),
+        (b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n", b"$-1\r\n"),
+    ];
+    exchange(&mut stream, requests).await;
+
+    // the keys are shared by the connections
+    let mut other = TcpStream::connect(addr).await.unwrap();
+    exchange(
+        &mut stream,
+        &[(b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n", b"+OK\r\n")],
+    )
+    .await;
+    exchange(
+        &mut other,
+        &[(b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n", b"$1\r\n1\r\n")],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn connection_limit_released_on_disconnect() {
+    let addr = start(&["--limit", "1"]).await;
+    let ping: &[(&[u8], &[u8])] = &[(b"*1\r\n$4\r\nPING\r\n", b"+PONG\r\n")];
+    let mut first = TcpStream::connect(addr).await.unwrap();
+    exchange(&mut first, ping).await;
+
+    // the second connection waits for the first one to give its permit back
+    let mut second = TcpStream::connect(addr).await.unwrap();
+    second.write_all(b"*1\r\n$4\r\nPING\r\n").await.unwrap();
+    let mut reply = [0; 7];
+    let waiting = tokio::time::timeout(Duration::from_millis(200), second.read_exact(&mut reply));
+    assert!(waiting.await.is_err(), "served over the connection limit");
+
+    drop(first);
+    tokio::time::timeout(Duration::from_secs(5), second.read_exact(&mut reply))
+        .await
+        .expect("the permit was not released")
+        .unwrap();
+    assert_eq!(&reply, b"+PONG\r\n");
+}
+
+#[tokio::test]
+async fn tls_ping() {
+    let dir = std::env::temp_dir();
+    let cert_path = dir.join(format!("mredis-it-{}.crt", std::process::id()));
+    let key_path = dir.join(format!("mredis-it-{}.key", std::process::id()));
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    std::fs::write(&cert_path, certified.cert.pem()).unwrap();
+    std::fs::write(&key_path, certified.key_pair.serialize_pem()).unwrap();
+    let addr = start(&[
+        "--tls-cert",
+        cert_path.to_str().unwrap(),
+        "--tls-key",
+        key_path.to_str().unwrap(),
+    ])
+    .await;
+    std::fs::remove_file(cert_path).unwrap();
+    std::fs::remove_file(key_path).unwrap();
+
+    let mut roots = RootCertStore::empty();
+    roots.add(certified.cert.der().clone()).unwrap();
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let mut stream = TlsConnector::from(Arc::new(config))
+        .connect(ServerName::try_from("localhost").unwrap(), stream)
+        .await
+        .unwrap();
+    stream.write_all(b"*1\r\n$4\r\nPING\r\n").await.unwrap();
+    let mut reply = [0; 7];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"+PONG\r\n");
+}
+
+#[tokio::test]
+async fn shutdown_syncs_the_aof() {
+    let dir = std::env::temp_dir().join(format!("mredis-shutdown-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let _ = std::fs::remove_file(dir.join("appendonly.aof"));
+    // without fsync, only the shutdown syncs the file
+    let cfg = Config::parse_from([
+        "mredis",
+        "--port",
+        "0",
+        "--appendonly",
+        "--appendfsync",
+        "no",
+        "--dir",
+        dir.to_str().unwrap(),
+    ]);
+    let server = Arc::new(Server::new(&cfg).await);
+    let addr = server.local_addr().unwrap();
+    let listening = tokio::spawn(async move { server.listen().await });
+
+    // the other connections are closed, the idle ones and the blocked ones
+    let mut idle = TcpStream::connect(addr).await.unwrap();
+    let write: &[(&[u8], &[u8])] = &[(b"*3\r\n$3\r\nSET\r\n$1\r\nb\r\n$1\r\n1\r\n", b"+OK\r\n")];
+    exchange(&mut idle, write).await;
+    let mut blocked = TcpStream::connect(addr).await.unwrap();
+    blocked
+        .write_all(b"*3\r\n$5\r\nBLPOP\r\n$4\r\nlist\r\n$1\r\n0\r\n")
+        .await
+        .unwrap();
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let requests: &[(&[u8], &[u8])] = &[
+        (b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n", b"+OK\r\n"),
+        (b"*3\r\n$3\r\nSET\r\n$4\r\nlast\r\n$1\r\n2\r\n", b"+OK\r\n"),
+        (
+            b"*2\r\n$8\r\nSHUTDOWN\r\n$3\r\nBAD\r\n",
+            b"-ERR syntax error\r\n",
+        ),
+    ];
+    exchange(&mut stream, requests).await;
+    stream
+        .write_all(b"*2\r\n$8\r\nSHUTDOWN\r\n$4\r\nSAVE\r\n")
+        .await
+        .unwrap();
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).await.unwrap();
+    assert!(
+        reply.is_empty(),
+        "SHUTDOWN closes the connection without a reply"
+    );
+    tokio::time::timeout(Duration::from_secs(5), listening)
+        .await
+        .expect("the server did not stop")
+        .unwrap();
+    for mut other in [idle, blocked] {
+        let mut reply = Vec::new();
+        other.read_to_end(&mut reply).await.unwrap();
+        assert!(
+            reply.is_empty(),
+            "the connections are closed without a reply"
+        );
+    }
+
+    // the last write is found by a fresh load of the AOF, and of the snapshot
+    let replayed: Arc<[Arc<Storage>]> = [Arc::new(Storage::new(100, 4))].into();
+    replay_aof(
+        &dir.join("appendonly.aof"),
+        replayed.clone(),
+        DecodeLimits::default(),
+    )
+    .await
+    .unwrap();
+    let loaded = [Arc::new(Storage::new(100, 4))];
+    load_snapshot(&dir.join("dump.rdb"), &loaded).unwrap();
+    for databases in [&replayed[..], &loaded[..]] {
+        assert_eq!(
+            databases[0].get_v(b"last"),
+            Ok(Some(Bytes::from_static(b"2")))
+        );
+        assert_eq!(databases[0].len(), 3);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}